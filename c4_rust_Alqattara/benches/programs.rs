@@ -0,0 +1,24 @@
+//! Times the bundled C benchmarks on every backend (see `c4_rust::bench`)
+//!
+//! `cargo bench --bench programs [NAME...]` runs those named, or all of them.
+
+use std::env;
+use std::process;
+
+use c4_rust::bench::{BACKENDS, BENCHMARKS};
+
+fn main() {
+    // cargo passes --bench; anything else names a benchmark
+    let names: Vec<String> = env::args().skip(1).filter(|arg| !arg.starts_with("--")).collect();
+    for benchmark in BENCHMARKS.iter().filter(|b| names.is_empty() || names.iter().any(|name| name == b.name)) {
+        for backend in BACKENDS {
+            match benchmark.run(backend) {
+                Ok(result) => println!("{}", result),
+                Err(e) => {
+                    eprintln!("{} on {:?}: {}", benchmark.name, backend, e);
+                    process::exit(1);
+                },
+            }
+        }
+    }
+}
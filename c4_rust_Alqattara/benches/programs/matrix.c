@@ -0,0 +1,42 @@
+// Multiply two 40x40 integer matrices and print a checksum of the product
+
+int n;
+
+int *matrix(int seed)
+{
+  int *m, i;
+
+  m = malloc(n * n * sizeof(int));
+  i = 0;
+  while (i < n * n) { m[i] = (i * seed + 7) % 11 - 5; ++i; }
+  return m;
+}
+
+int main()
+{
+  int *a, *b, *c, i, j, k, sum, checksum;
+
+  n = 40;
+  a = matrix(3);
+  b = matrix(7);
+  c = malloc(n * n * sizeof(int));
+  i = 0;
+  while (i < n) {
+    j = 0;
+    while (j < n) {
+      sum = 0;
+      k = 0;
+      while (k < n) { sum = sum + a[i * n + k] * b[k * n + j]; ++k; }
+      c[i * n + j] = sum;
+      ++j;
+    }
+    ++i;
+  }
+
+  checksum = 0;
+  i = 0;
+  while (i < n * n) { checksum = checksum + c[i] * (i % 7 + 1); ++i; }
+  printf("checksum %d\n", checksum);
+  free(a); free(b); free(c);
+  return checksum & 255;
+}
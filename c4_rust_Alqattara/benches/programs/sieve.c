@@ -0,0 +1,26 @@
+// Count the primes below 200000 with the sieve of Eratosthenes
+
+int main()
+{
+  int n, i, j, count;
+  char *composite;
+
+  n = 200000;
+  composite = malloc(n);
+  i = 0;
+  while (i < n) { composite[i] = 0; ++i; }
+
+  count = 0;
+  i = 2;
+  while (i < n) {
+    if (!composite[i]) {
+      ++count;
+      j = i + i;
+      while (j < n) { composite[j] = 1; j = j + i; }
+    }
+    ++i;
+  }
+  printf("%d primes below %d\n", count, n);
+  free(composite);
+  return count % 256;
+}
@@ -0,0 +1,39 @@
+// Build a long string, count its words and vowels, then reverse it in
+// place and hash the result
+
+int main()
+{
+  char *phrase, *text, *p, *q, c;
+  int len, copies, words, vowels, in_word, hash;
+
+  phrase = "the quick brown fox jumps over the lazy dog ";
+  copies = 2000;
+  text = malloc(copies * 44 + 1);
+  p = text;
+  while (copies) {
+    q = phrase;
+    while (*q) *p++ = *q++;
+    --copies;
+  }
+  *p = 0;
+  len = p - text;
+
+  words = 0; vowels = 0; in_word = 0;
+  p = text;
+  while (*p) {
+    if (*p == ' ') in_word = 0;
+    else if (!in_word) { in_word = 1; ++words; }
+    if (*p == 'a' || *p == 'e' || *p == 'i' || *p == 'o' || *p == 'u') ++vowels;
+    ++p;
+  }
+
+  p = text; q = text + len - 1;
+  while (p < q) { c = *p; *p++ = *q; *q-- = c; }
+
+  hash = 0;
+  p = text;
+  while (*p) { hash = (hash * 31 + *p) & 16777215; ++p; }
+  printf("%d bytes, %d words, %d vowels, hash %d\n", len, words, vowels, hash);
+  free(text);
+  return words % 256;
+}
@@ -0,0 +1,591 @@
+//! # c4 Lexer
+//!
+//! The tokens of the c4 language and the scanners that read them, kept
+//! apart from the compiler so that tools which only need tokens, such as a
+//! highlighter or a formatter, can depend on this crate alone. Only `core`
+//! and `alloc` are used.
+//!
+//! A [`Lexer`] reads a whole source, skipping whitespace, comments and
+//! preprocessor lines as c4 does:
+//!
+//! ```
+//! use c4_lexer::{Lexer, TokenType, Value};
+//!
+//! let source = b"int x; // note\nx += 0x10;";
+//! let tokens: Vec<_> = Lexer::new(source).map(Result::unwrap).collect();
+//! assert_eq!(tokens[0].token, TokenType::Int as i32);
+//! assert_eq!(&source[tokens[1].start..tokens[1].end], b"x");
+//! assert_eq!(tokens[2].token, b';' as i32);
+//! assert_eq!((tokens[4].token, &tokens[4].value), (TokenType::Assign as i32, &Value::Operator(TokenType::Add)));
+//! assert_eq!((&tokens[5].value, tokens[5].line, tokens[5].column), (&Value::Int(16), 2, 6));
+//! ```
+//!
+//! The `c4_rust` compiler scans with the same functions, through the
+//! [`Source`] trait, and does what this crate cannot on top of them: it
+//! looks identifiers up in its symbol table, expands macros, acts on
+//! directives and stores literals in the data segment.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Token types used by the lexer and parser
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TokenType {
+    Num = 128,  // Number literal
+    Str = 256,  // String literal, with its data address as the value
+    Float = 257,  // Floating-point literal
+    Fun,        // Function
+    Sys,        // System call
+    Glo,        // Global variable
+    Loc,        // Local variable
+    Id,         // Identifier
+    Char,       // char type
+    Const,      // const qualifier
+    Else,       // else keyword
+    Enum,       // enum keyword
+    If,         // if keyword
+    Int,        // int type
+    Return,     // return keyword
+    Sizeof,     // sizeof operator
+    While,      // while keyword
+    Assign,     // Assignment operator
+    Cond,       // Conditional operator
+    Lor,        // Logical OR
+    Lan,        // Logical AND
+    Or,         // Bitwise OR
+    Xor,        // Bitwise XOR
+    And,        // Bitwise AND
+    Eq,         // Equal
+    Ne,         // Not equal
+    Lt,         // Less than
+    Gt,         // Greater than
+    Le,         // Less than or equal
+    Ge,         // Greater than or equal
+    Shl,        // Shift left
+    Shr,        // Shift right
+    Add,        // Addition
+    Sub,        // Subtraction
+    Mul,        // Multiplication
+    Div,        // Division
+    Mod,        // Modulo
+    Inc,        // Increment
+    Dec,        // Decrement
+    Brak,       // Array subscript
+}
+
+impl TokenType {
+    /// Every token type, in numeric order
+    pub const ALL: [TokenType; 40] = [
+        TokenType::Num, TokenType::Str, TokenType::Float, TokenType::Fun, TokenType::Sys,
+        TokenType::Glo, TokenType::Loc, TokenType::Id, TokenType::Char, TokenType::Const, TokenType::Else,
+        TokenType::Enum, TokenType::If, TokenType::Int, TokenType::Return, TokenType::Sizeof,
+        TokenType::While, TokenType::Assign, TokenType::Cond, TokenType::Lor, TokenType::Lan,
+        TokenType::Or, TokenType::Xor, TokenType::And, TokenType::Eq, TokenType::Ne, TokenType::Lt,
+        TokenType::Gt, TokenType::Le, TokenType::Ge, TokenType::Shl, TokenType::Shr, TokenType::Add,
+        TokenType::Sub, TokenType::Mul, TokenType::Div, TokenType::Mod, TokenType::Inc,
+        TokenType::Dec, TokenType::Brak,
+    ];
+
+    /// The token for a keyword, looked up on the identifier's raw bytes
+    ///
+    /// Matching on byte slices needs no conversion to `str` and compiles to
+    /// a dispatch on the length followed by at most a few comparisons, so
+    /// ordinary identifiers are rejected cheaply.
+    pub fn keyword(name: &[u8]) -> Option<TokenType> {
+        let token = match name {
+            b"char" => TokenType::Char,
+            b"const" => TokenType::Const,
+            b"else" => TokenType::Else,
+            b"enum" => TokenType::Enum,
+            b"if" => TokenType::If,
+            b"int" => TokenType::Int,
+            b"return" => TokenType::Return,
+            b"sizeof" => TokenType::Sizeof,
+            b"while" => TokenType::While,
+            b"void" => TokenType::Char, // As in c4, void is treated as char
+            _ => return None,
+        };
+        Some(token)
+    }
+
+    /// The token with number `value`, or None for characters that are
+    /// tokens of their own, such as `;`
+    pub fn from_i32(value: i32) -> Option<TokenType> {
+        Self::ALL.iter().copied().find(|&token| token as i32 == value)
+    }
+}
+
+/// `token` as parse errors name it: `'=='` for an operator or other
+/// punctuation, `keyword 'while'` for a keyword, or what kind of token it is
+pub fn token_name(token: i32) -> String {
+    let Some(token_type) = TokenType::from_i32(token) else {
+        return match token {
+            0 => "end of input".to_string(),
+            1..=127 => format!("'{}'", token as u8 as char),
+            _ => format!("token {}", token),
+        };
+    };
+    let spelling = match token_type {
+        TokenType::Num => return "number".to_string(),
+        TokenType::Str => return "string literal".to_string(),
+        TokenType::Float => return "floating-point number".to_string(),
+        TokenType::Fun | TokenType::Sys | TokenType::Glo | TokenType::Loc | TokenType::Id => return "identifier".to_string(),
+        TokenType::Assign => return "compound assignment".to_string(),
+        TokenType::Char => return "keyword 'char'".to_string(),
+        TokenType::Const => return "keyword 'const'".to_string(),
+        TokenType::Else => return "keyword 'else'".to_string(),
+        TokenType::Enum => return "keyword 'enum'".to_string(),
+        TokenType::If => return "keyword 'if'".to_string(),
+        TokenType::Int => return "keyword 'int'".to_string(),
+        TokenType::Return => return "keyword 'return'".to_string(),
+        TokenType::Sizeof => return "keyword 'sizeof'".to_string(),
+        TokenType::While => return "keyword 'while'".to_string(),
+        TokenType::Cond => "?",
+        TokenType::Lor => "||",
+        TokenType::Lan => "&&",
+        TokenType::Or => "|",
+        TokenType::Xor => "^",
+        TokenType::And => "&",
+        TokenType::Eq => "==",
+        TokenType::Ne => "!=",
+        TokenType::Lt => "<",
+        TokenType::Gt => ">",
+        TokenType::Le => "<=",
+        TokenType::Ge => ">=",
+        TokenType::Shl => "<<",
+        TokenType::Shr => ">>",
+        TokenType::Add => "+",
+        TokenType::Sub => "-",
+        TokenType::Mul => "*",
+        TokenType::Div => "/",
+        TokenType::Mod => "%",
+        TokenType::Inc => "++",
+        TokenType::Dec => "--",
+        TokenType::Brak => "[",
+    };
+    format!("'{}'", spelling)
+}
+
+/// Where the scanners read from: a position in a source, which they move
+/// past what they read
+pub trait Source {
+    /// The byte `offset` places past the current position, or None past the end
+    fn peek(&mut self, offset: usize) -> Option<u8>;
+
+    /// Move the current position `n` bytes on
+    fn advance(&mut self, n: usize);
+}
+
+/// What a number literal stands for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(i32),       // An integer, wrapped to 32 bits as in c4
+    Float(f64),     // A floating-point constant, rounded to single precision if it has an `f` suffix
+}
+
+/// An operator or other punctuation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Token(i32),                 // A token: the operator's `TokenType`, or the character itself, such as `;`
+    CompoundAssign(TokenType),  // A compound assignment such as `+=`, with the operator it combines by
+}
+
+/// A literal that cannot be read, or a character no token starts with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexError {
+    InvalidFloat,
+    UnterminatedString,
+    UnterminatedChar,
+    EmptyChar { wide: bool },
+    CharTooWide(String),        // A plain character literal, as written, whose character does not fit in a char
+    MultiChar { wide: bool, found: String, count: usize },
+    UnexpectedChar(char),       // A character outside ASCII
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = |wide: bool| if wide { "L" } else { "" };
+        match self {
+            LexError::InvalidFloat => write!(f, "Invalid float literal"),
+            LexError::UnterminatedString => write!(f, "Unterminated string literal"),
+            LexError::UnterminatedChar => write!(f, "Unterminated character literal"),
+            LexError::EmptyChar { wide } => write!(f, "Empty character literal {}''", prefix(*wide)),
+            LexError::CharTooWide(found) => write!(f, "Character literal '{}' does not fit in a char", found),
+            LexError::MultiChar { wide, found, count } => write!(f,
+                "Multi-character literal {}'{}' has {} characters; use a string for more than one",
+                prefix(*wide), found, count),
+            LexError::UnexpectedChar(c) => write!(f, "Unexpected character '{}'", c),
+        }
+    }
+}
+
+/// Whether `c` starts an identifier or keyword
+pub fn starts_identifier(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+/// Whether a number starts at the current position: a digit, or a dot
+/// before one (a leading minus is unary negation)
+pub fn at_number(source: &mut impl Source) -> bool {
+    match source.peek(0) {
+        Some(c) if c.is_ascii_digit() => true,
+        Some(b'.') => source.peek(1).is_some_and(|c| c.is_ascii_digit()),
+        _ => false,
+    }
+}
+
+/// Read the identifier or keyword at the current position, appending it to `name`
+pub fn identifier(source: &mut impl Source, name: &mut Vec<u8>) {
+    while let Some(c) = source.peek(0).filter(|&c| c.is_ascii_alphanumeric() || c == b'_') {
+        name.push(c);
+        source.advance(1);
+    }
+}
+
+/// Read the number literal at the current position, where [`at_number`] holds
+///
+/// A `0x` prefix makes it hexadecimal. A decimal one is a float if it has a
+/// dot or an exponent; an `e` is only an exponent if digits follow it, as
+/// otherwise it starts the next token.
+pub fn number(source: &mut impl Source) -> Result<Number, LexError> {
+    let mut value: i32 = 0;
+    if source.peek(0) == Some(b'0') && matches!(source.peek(1), Some(b'x' | b'X')) {
+        source.advance(2);
+        while let Some(digit) = source.peek(0).and_then(|c| (c as char).to_digit(16)) {
+            value = value.wrapping_mul(16).wrapping_add(digit as i32);
+            source.advance(1);
+        }
+        return Ok(Number::Int(value));
+    }
+
+    let mut buffer = String::new();
+    let mut is_float = false;
+    while let Some(c) = source.peek(0) {
+        if c == b'.' && !is_float {
+            is_float = true;
+        } else if c.is_ascii_digit() {
+            if !is_float {
+                value = value.wrapping_mul(10).wrapping_add((c - b'0') as i32);
+            }
+        } else {
+            break;
+        }
+        buffer.push(c as char);
+        source.advance(1);
+    }
+
+    if matches!(source.peek(0), Some(b'e' | b'E')) {
+        let sign = matches!(source.peek(1), Some(b'+' | b'-')) as usize;
+        if source.peek(1 + sign).is_some_and(|c| c.is_ascii_digit()) {
+            is_float = true;
+            for _ in 0..1 + sign {
+                buffer.extend(source.peek(0).map(char::from));
+                source.advance(1);
+            }
+            while let Some(digit) = source.peek(0).filter(|c| c.is_ascii_digit()) {
+                buffer.push(digit as char);
+                source.advance(1);
+            }
+        }
+    }
+
+    if !is_float {
+        return Ok(Number::Int(value));
+    }
+    // A float suffix rounds the constant to single precision
+    let single = matches!(source.peek(0), Some(b'f' | b'F'));
+    if single {
+        source.advance(1);
+    }
+    let value: f64 = buffer.parse().map_err(|_| LexError::InvalidFloat)?;
+    Ok(Number::Float(if single { value as f32 as f64 } else { value }))
+}
+
+/// The character an escape sequence such as `\n` stands for, given the
+/// character after the backslash; any other character stands for itself
+fn escape(c: u8) -> u8 {
+    match c {
+        b'n' => b'\n',
+        b't' => b'\t',
+        b'r' => b'\r',
+        b'0' => 0,
+        c => c,
+    }
+}
+
+/// Read the string literal at the current position, appending its bytes
+/// to `bytes` with their escapes decoded
+pub fn string_literal(source: &mut impl Source, bytes: &mut Vec<u8>) -> Result<(), LexError> {
+    source.advance(1);
+    while let Some(c) = source.peek(0).filter(|&c| c != b'"') {
+        if c == b'\\' {
+            source.advance(1);
+            bytes.extend(source.peek(0).map(escape));
+        } else {
+            bytes.push(c);
+        }
+        source.advance(1);
+    }
+    if source.peek(0) != Some(b'"') {
+        return Err(LexError::UnterminatedString);
+    }
+    source.advance(1);
+    Ok(())
+}
+
+/// Read the character literal at the current position, after the `L` of a
+/// wide one such as `L'a'`
+///
+/// Its value is the character's code; a wide literal may hold any
+/// character, while a plain one must fit in a `char`. A literal of more or
+/// fewer than one character is an error quoting what was found, since c4
+/// has no multi-character constants.
+pub fn character_literal(source: &mut impl Source, wide: bool) -> Result<i32, LexError> {
+    source.advance(1);
+    let mut found = String::new();
+    let (mut value, mut count) = (0, 0);
+    while let Some(c) = source.peek(0).filter(|&c| c != b'\'' && c != b'\n') {
+        if c == b'\\' {
+            let escaped = source.peek(1).unwrap_or(b'\\');
+            value = escape(escaped) as i32;
+            found.extend(['\\', escaped as char]);
+            source.advance(2);
+        } else {
+            let c = utf8_char(source);
+            value = c as i32;
+            found.push(c);
+            source.advance(if c == char::REPLACEMENT_CHARACTER { 1 } else { c.len_utf8() });
+        }
+        count += 1;
+    }
+
+    if source.peek(0) != Some(b'\'') {
+        return Err(LexError::UnterminatedChar);
+    }
+    source.advance(1);
+    match count {
+        0 => Err(LexError::EmptyChar { wide }),
+        1 if !wide && value > 0x7F => Err(LexError::CharTooWide(found)),
+        1 => Ok(value),
+        count => Err(LexError::MultiChar { wide, found, count }),
+    }
+}
+
+/// Read the operator or other punctuation at the current position
+///
+/// # Returns
+///
+/// None, having read nothing, if the character there is not ASCII
+/// punctuation
+pub fn operator(source: &mut impl Source) -> Option<Operator> {
+    let c = source.peek(0)?;
+    let next = source.peek(1);
+
+    // A compound assignment is the operator followed by `=`
+    let combined = match (c, next) {
+        (b'+', _) => Some((1, TokenType::Add)),
+        (b'-', _) => Some((1, TokenType::Sub)),
+        (b'*', _) => Some((1, TokenType::Mul)),
+        (b'/', _) => Some((1, TokenType::Div)),
+        (b'%', _) => Some((1, TokenType::Mod)),
+        (b'&', _) => Some((1, TokenType::And)),
+        (b'|', _) => Some((1, TokenType::Or)),
+        (b'^', _) => Some((1, TokenType::Xor)),
+        (b'<', Some(b'<')) => Some((2, TokenType::Shl)),
+        (b'>', Some(b'>')) => Some((2, TokenType::Shr)),
+        _ => None,
+    };
+    if let Some((len, op)) = combined {
+        if source.peek(len) == Some(b'=') {
+            source.advance(len + 1);
+            return Some(Operator::CompoundAssign(op));
+        }
+    }
+
+    let (len, token) = match (c, next) {
+        (b'=', Some(b'=')) => (2, TokenType::Eq as i32),
+        (b'!', Some(b'=')) => (2, TokenType::Ne as i32),
+        (b'<', Some(b'=')) => (2, TokenType::Le as i32),
+        (b'<', Some(b'<')) => (2, TokenType::Shl as i32),
+        (b'>', Some(b'=')) => (2, TokenType::Ge as i32),
+        (b'>', Some(b'>')) => (2, TokenType::Shr as i32),
+        (b'+', Some(b'+')) => (2, TokenType::Inc as i32),
+        (b'-', Some(b'-')) => (2, TokenType::Dec as i32),
+        (b'|', Some(b'|')) => (2, TokenType::Lor as i32),
+        (b'&', Some(b'&')) => (2, TokenType::Lan as i32),
+        _ if c.is_ascii_punctuation() => (1, c as i32),
+        _ => return None,
+    };
+    source.advance(len);
+    Some(Operator::Token(token))
+}
+
+/// Skip to the end of the line, leaving the newline to be read
+pub fn line_comment(source: &mut impl Source) {
+    while source.peek(0).is_some_and(|c| c != b'\n') {
+        source.advance(1);
+    }
+}
+
+/// Decode the UTF-8 character at the current position without moving
+/// past it, or U+FFFD if it is invalid
+pub fn utf8_char(source: &mut impl Source) -> char {
+    let len = match source.peek(0) {
+        Some(0xF0..) => 4,
+        Some(0xE0..) => 3,
+        Some(0xC0..) => 2,
+        _ => 1,
+    };
+    let mut bytes = [0; 4];
+    let mut read = 0;
+    while let Some(byte) = source.peek(read).filter(|_| read < len) {
+        bytes[read] = byte;
+        read += 1;
+    }
+    core::str::from_utf8(&bytes[..read]).ok()
+        .and_then(|s| s.chars().next())
+        .unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+/// A token read by a [`Lexer`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub token: i32,     // A `TokenType`, or the character itself for other punctuation such as `;`
+    pub value: Value,   // What a literal stands for, or the operator of a compound assignment
+    pub start: usize,   // Offset of the token's first byte in the source
+    pub end: usize,     // Offset just past its last byte
+    pub line: i32,      // Line of the token, from 1
+    pub column: i32,    // Column of the token, counted in characters from 1
+}
+
+/// What a token stands for, beyond its type
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    None,                   // Nothing more; an identifier is spelled by the source between `start` and `end`
+    Int(i32),               // A number or character literal
+    Float(f64),             // A floating-point literal
+    Str(Vec<u8>),           // A string literal, its escapes decoded and without a terminating NUL
+    Operator(TokenType),    // The operator a compound assignment combines by
+}
+
+/// Reads the tokens of a whole source, for use without a compiler
+///
+/// Identifiers are all [`TokenType::Id`], since telling variables and
+/// functions apart needs a symbol table, and preprocessor lines are skipped
+/// as comments are. The tokens end at the end of the source or at the first
+/// error.
+pub struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    line: i32,          // Line of the current position
+    line_start: usize,  // Offset where that line starts
+    failed: bool,       // Whether an error has ended the tokens
+}
+
+impl<'a> Lexer<'a> {
+    /// A lexer at the start of `src`
+    pub fn new(src: &'a [u8]) -> Self {
+        Lexer { src, pos: 0, line: 1, line_start: 0, failed: false }
+    }
+
+    /// Count the newlines in `src` between `from` and the current position
+    fn count_lines(&mut self, from: usize) {
+        for at in from..self.pos.min(self.src.len()) {
+            if self.src[at] == b'\n' {
+                self.line += 1;
+                self.line_start = at + 1;
+            }
+        }
+    }
+
+    /// Skip whitespace, comments and preprocessor lines
+    fn skip_trivia(&mut self) {
+        let start = self.pos;
+        while let Some(c) = self.peek(0) {
+            match (c, self.peek(1)) {
+                (b'#', _) | (b'/', Some(b'/')) => line_comment(self),
+                (b'/', Some(b'*')) => {
+                    self.pos += 2;
+                    while self.peek(0).is_some() && !(self.peek(0) == Some(b'*') && self.peek(1) == Some(b'/')) {
+                        self.pos += 1;
+                    }
+                    self.pos = (self.pos + 2).min(self.src.len());
+                }
+                (c, _) if c.is_ascii_whitespace() => self.pos += 1,
+                _ => break,
+            }
+        }
+        self.count_lines(start);
+    }
+
+    /// Read the token at the current position, which is not trivia
+    fn scan(&mut self, c: u8) -> Result<(i32, Value), LexError> {
+        if c == b'L' && self.peek(1) == Some(b'\'') {
+            self.pos += 1;
+            return Ok((TokenType::Num as i32, Value::Int(character_literal(self, true)?)));
+        }
+        if starts_identifier(c) {
+            let mut name = Vec::new();
+            identifier(self, &mut name);
+            return Ok((TokenType::keyword(&name).unwrap_or(TokenType::Id) as i32, Value::None));
+        }
+        if at_number(self) {
+            return Ok(match number(self)? {
+                Number::Int(value) => (TokenType::Num as i32, Value::Int(value)),
+                Number::Float(value) => (TokenType::Float as i32, Value::Float(value)),
+            });
+        }
+        if c == b'\'' {
+            return Ok((TokenType::Num as i32, Value::Int(character_literal(self, false)?)));
+        }
+        if c == b'"' {
+            let mut bytes = Vec::new();
+            string_literal(self, &mut bytes)?;
+            return Ok((TokenType::Str as i32, Value::Str(bytes)));
+        }
+        match operator(self) {
+            Some(Operator::Token(token)) => Ok((token, Value::None)),
+            Some(Operator::CompoundAssign(op)) => Ok((TokenType::Assign as i32, Value::Operator(op))),
+            None => Err(LexError::UnexpectedChar(utf8_char(self))),
+        }
+    }
+}
+
+impl Source for Lexer<'_> {
+    fn peek(&mut self, offset: usize) -> Option<u8> {
+        self.src.get(self.pos + offset).copied()
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.pos += n;
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        self.skip_trivia();
+        let start = self.pos;
+        let c = self.peek(0)?;
+        let (line, column) = (self.line, self.src[self.line_start..start].iter().filter(|&&b| b & 0xC0 != 0x80).count() as i32 + 1);
+        let scanned = self.scan(c);
+        self.count_lines(start);
+        match scanned {
+            Ok((token, value)) => Some(Ok(Token { token, value, start, end: self.pos, line, column })),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! # Static Stack Analysis
+//!
+//! Computes an upper bound on the number of VM stack words a program can use.
+//! Each function is walked once to find its own deepest stack use (return
+//! address, saved bp, locals and expression temporaries) and the depth at
+//! which it calls other functions. The call graph is then solved for the
+//! worst-case path. Recursion through JSR makes the bound infinite; recursion
+//! through tail calls (TLEV) does not, since those reuse the caller's frame.
+
+use std::collections::HashMap;
+
+use crate::optimizer::has_operand;
+use crate::{Instruction, VmOptions};
+
+/// Stack usage of a single function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionFrame {
+    pub name: String,
+    pub entry: i32,
+    pub frame_words: usize,       // Deepest stack use of the function body itself
+    pub max_depth: Option<usize>, // Including everything it calls, None if unbounded
+    pub recursive: bool,          // Reaches a cycle of non-tail calls
+}
+
+/// Stack usage of a whole program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackReport {
+    pub functions: Vec<FunctionFrame>,
+    pub entry_depth: Option<usize>, // Bound for a run starting at the entry point
+}
+
+impl StackReport {
+    /// The stack words needed by a run from the entry point, None if unbounded
+    pub fn max_depth(&self) -> Option<usize> {
+        self.entry_depth
+    }
+
+    /// Names of the functions whose stack use is unbounded because of recursion
+    pub fn recursive_functions(&self) -> Vec<&str> {
+        self.functions.iter()
+            .filter(|f| f.recursive)
+            .map(|f| f.name.as_str())
+            .collect()
+    }
+
+    /// Whether a run from the entry point is guaranteed to fit in the VM stack
+    pub fn fits(&self, options: &VmOptions) -> bool {
+        matches!(self.entry_depth, Some(depth) if depth <= options.stack_words)
+    }
+}
+
+/// Per-function facts gathered by walking its instructions
+struct Summary {
+    own: usize,                // Deepest stack use of the body
+    calls: Vec<(usize, i32)>,  // (depth at the call, callee entry)
+    tails: Vec<i32>,           // Callees reached by a tail call
+}
+
+/// Number of words an instruction pops without pushing
+fn pops(op: i32) -> usize {
+    Instruction::from_opcode(op)
+        .and_then(Instruction::stack_effect)
+        .map_or(0, |effect| (-effect).max(0) as usize)
+}
+
+/// Walk the function at `entry..end`, following branches
+fn summarize(text: &[i32], entry: usize, end: usize) -> Summary {
+    let mut summary = Summary { own: 0, calls: Vec::new(), tails: Vec::new() };
+    let mut visited = vec![false; end.saturating_sub(entry)];
+
+    // Depth 1 at entry: the caller's JSR has pushed the return address
+    let mut worklist = vec![(entry, 1usize, Vec::<usize>::new())];
+    while let Some((pc, depth, mut inline_bases)) = worklist.pop() {
+        if pc < entry || pc >= end || pc >= text.len() || visited[pc - entry] {
+            continue;
+        }
+        visited[pc - entry] = true;
+        summary.own = summary.own.max(depth);
+
+        let op = text[pc];
+        let width = if has_operand(op) { 2 } else { 1 };
+        let operand = text.get(pc + 1).copied().unwrap_or(0);
+        let next = pc + width;
+
+        let depth = match op {
+            op if op == Instruction::ENT as i32 => depth + 1 + operand.max(0) as usize,
+            op if op == Instruction::IENT as i32 => {
+                inline_bases.push(depth);
+                depth + 2 + operand.max(0) as usize
+            },
+            op if op == Instruction::ILEV as i32 => {
+                let base = inline_bases.pop().unwrap_or(depth);
+                base.saturating_sub(operand.max(0) as usize)
+            },
+            op if op == Instruction::PUSH as i32 => depth + 1,
+            op if op == Instruction::ADJ as i32 => depth.saturating_sub(operand.max(0) as usize),
+            op if op == Instruction::JSR as i32 => {
+                summary.calls.push((depth, operand));
+                depth
+            },
+            op if op == Instruction::LEV as i32 || op == Instruction::EXIT as i32 => continue,
+            op if op == Instruction::TLEV as i32 => {
+                if text.get(next) == Some(&(Instruction::JMP as i32)) {
+                    if let Some(&target) = text.get(next + 1) {
+                        summary.tails.push(target);
+                    }
+                }
+                continue;
+            },
+            op if op == Instruction::JMP as i32 => {
+                worklist.push((operand.max(0) as usize, depth, inline_bases));
+                continue;
+            },
+            op if op == Instruction::BZ as i32 || op == Instruction::BNZ as i32 => {
+                worklist.push((operand.max(0) as usize, depth, inline_bases.clone()));
+                depth
+            },
+            op => depth.saturating_sub(pops(op)),
+        };
+
+        summary.own = summary.own.max(depth);
+        worklist.push((next, depth, inline_bases));
+    }
+
+    summary
+}
+
+/// Compute the stack report for a text segment
+///
+/// # Arguments
+///
+/// * `text` - The text segment
+/// * `functions` - Name and entry address of every function
+/// * `entry` - Address where execution starts (usually `main`)
+pub fn stack_report(text: &[i32], functions: &[(String, i32)], entry: i32) -> StackReport {
+    let mut functions: Vec<(String, i32)> = functions.iter()
+        .filter(|(_, e)| *e >= 0 && (*e as usize) < text.len())
+        .cloned()
+        .collect();
+    functions.sort_by_key(|(_, e)| *e);
+    functions.dedup_by_key(|(_, e)| *e);
+
+    let index: HashMap<i32, usize> = functions.iter()
+        .enumerate()
+        .map(|(i, (_, e))| (*e, i))
+        .collect();
+    let summaries: Vec<Summary> = functions.iter()
+        .enumerate()
+        .map(|(i, (_, e))| {
+            let end = functions.get(i + 1).map_or(text.len(), |(_, next)| *next as usize);
+            summarize(text, *e as usize, end)
+        })
+        .collect();
+
+    // Longest path over the call graph. Bounded values settle within n rounds,
+    // so anything still growing after that sits on a cycle of real calls.
+    let n = summaries.len();
+    let mut bound: Vec<usize> = summaries.iter().map(|s| s.own).collect();
+    let mut growing = vec![false; n];
+    for round in 0..=n {
+        for i in 0..n {
+            let mut value = summaries[i].own;
+            for &(depth, callee) in &summaries[i].calls {
+                if let Some(&j) = index.get(&callee) {
+                    value = value.max(depth + bound[j]);
+                }
+            }
+            for callee in &summaries[i].tails {
+                if let Some(&j) = index.get(callee) {
+                    value = value.max(bound[j]);
+                }
+            }
+            if value > bound[i] {
+                bound[i] = value;
+                growing[i] = round == n;
+            }
+        }
+    }
+
+    // Anything that can reach an unbounded function is unbounded too
+    let mut unbounded = growing;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..n {
+            if unbounded[i] {
+                continue;
+            }
+            let callees = summaries[i].calls.iter().map(|(_, c)| c).chain(summaries[i].tails.iter());
+            if callees.filter_map(|c| index.get(c)).any(|&j| unbounded[j]) {
+                unbounded[i] = true;
+                changed = true;
+            }
+        }
+    }
+
+    let frames: Vec<FunctionFrame> = functions.iter()
+        .enumerate()
+        .map(|(i, (name, e))| FunctionFrame {
+            name: name.clone(),
+            entry: *e,
+            frame_words: summaries[i].own,
+            max_depth: if unbounded[i] { None } else { Some(bound[i]) },
+            recursive: unbounded[i],
+        })
+        .collect();
+
+    // run() stores argc in the word above the entry function's return slot,
+    // and sp always points at a free word below the top of the stack
+    let entry_depth = index.get(&entry)
+        .and_then(|&i| frames[i].max_depth)
+        .map(|depth| depth + 2);
+
+    StackReport { functions: frames, entry_depth }
+}
@@ -0,0 +1,268 @@
+//! # Compiler Configuration
+//!
+//! `C4Builder` gathers the settings of a compiler in one place. They are
+//! private to `C4`, so a compiler is configured here, before it is built,
+//! and cannot be changed behind the back of a compilation under way.
+//!
+//! ```
+//! use c4_parser::{Backend, C4Builder};
+//!
+//! let mut compiler = C4Builder::new()
+//!     .opt_level(0)
+//!     .word_size(8)
+//!     .backend(Backend::Register)
+//!     .define("LIMIT", "10")
+//!     .build();
+//! assert_eq!(compiler.eval("LIMIT * sizeof(int)"), Ok(80));
+//! ```
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use crate::{Backend, CancelToken, ColorChoice, DiagnosticOptions, Features, LanguageLevel, Overflow, Progress, RuntimeError, Sandbox, WarningKind, C4};
+
+/// Settings for a new compiler
+pub struct C4Builder {
+    c4: C4,                      // The compiler being configured
+}
+
+impl Default for C4Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl C4Builder {
+    /// Start from the settings of `C4::new`
+    pub fn new() -> Self {
+        C4Builder { c4: C4::new() }
+    }
+
+    /// Optimization level; 0 turns the optimizer off
+    pub fn opt_level(mut self, level: i32) -> Self {
+        self.c4.opt_level = level;
+        self
+    }
+
+    /// Inline small leaf functions of at most `threshold` instructions, or
+    /// not at all if `threshold` is 0
+    pub fn inline_threshold(mut self, threshold: usize) -> Self {
+        self.c4.inline_functions = threshold > 0;
+        self.c4.inline_threshold = threshold;
+        self
+    }
+
+    /// Deepest nesting of expressions and statements the parser accepts
+    pub fn nesting_limit(mut self, limit: usize) -> Self {
+        self.c4.nesting_limit = limit;
+        self
+    }
+
+    /// Dialect of C accepted, replacing any features set before
+    pub fn language(mut self, language: LanguageLevel) -> Self {
+        self.c4.features = language.features();
+        self
+    }
+
+    /// Extensions of the language that may be used
+    pub fn features(mut self, features: Features) -> Self {
+        self.c4.features = features;
+        self
+    }
+
+    /// Check each subscript of an array of known length against the
+    /// length, so an index outside the array stops the program with the
+    /// line it is on instead of reading or writing past the end
+    pub fn bounds_checks(mut self, checks: bool) -> Self {
+        self.c4.bounds_checks = checks;
+        self
+    }
+
+    /// Check the stack at each instruction a run of the stack backend
+    /// executes against the verifier's model of it, trapping at the first
+    /// instruction where they differ
+    pub fn check_stack(mut self, check: bool) -> Self {
+        self.c4.check_stack = check;
+        self
+    }
+
+    /// Trap at a store to a string literal, a float constant or a `const`
+    /// global, which the program would otherwise change for the rest of the
+    /// run
+    pub fn check_writes(mut self, check: bool) -> Self {
+        self.c4.check_writes = check;
+        self
+    }
+
+    /// Print what the compiler and VM are doing
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.c4.debug = debug;
+        self
+    }
+
+    /// Bytes in a VM word, pointer and `int`: 4 or 8
+    pub fn word_size(mut self, bytes: usize) -> Self {
+        self.c4.vm_options.word_size = bytes;
+        self
+    }
+
+    /// Compile jumps, calls and function addresses relative to where they
+    /// are, so the code runs unchanged at any offset in the text segment
+    pub fn position_independent(mut self, relative: bool) -> Self {
+        self.c4.vm_options.position_independent = relative;
+        self
+    }
+
+    /// Number of words available on the VM stack
+    pub fn stack_words(mut self, words: usize) -> Self {
+        self.c4.vm_options.stack_words = words;
+        self
+    }
+
+    /// Virtual machine that runs the program
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.c4.vm_options.backend = backend;
+        self
+    }
+
+    /// What arithmetic that overflows the word does
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.c4.vm_options.overflow = overflow;
+        self
+    }
+
+    /// Whether chars are signed, as they are by default, or unsigned
+    pub fn signed_char(mut self, signed: bool) -> Self {
+        self.c4.vm_options.signed_char = signed;
+        self
+    }
+
+    /// Count what each run does in `C4::vm_stats`
+    pub fn stats(mut self, stats: bool) -> Self {
+        self.c4.vm_options.stats = stats;
+        self
+    }
+
+    /// Record what each run does with the heap in `C4::heap_profile`
+    pub fn heap_profile(mut self, profile: bool) -> Self {
+        self.c4.vm_options.heap_profile = profile;
+        self
+    }
+
+    /// Bytes the heap may grow to before malloc returns 0
+    pub fn heap_bytes(mut self, bytes: usize) -> Self {
+        self.c4.vm_options.heap_bytes = bytes;
+        self
+    }
+
+    /// Limits on what the program may do
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.c4.vm_options.sandbox = sandbox;
+        self
+    }
+
+    /// Stop compilations and runs once `token` is cancelled, so an editor
+    /// or server can give up on work that is no longer wanted
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.c4.cancel = token;
+        self
+    }
+
+    /// Call `callback` now and then during a compilation with how far it
+    /// has got
+    pub fn on_progress(mut self, callback: impl FnMut(&Progress) + 'static) -> Self {
+        self.c4.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` with the fault a run stops at, if it stops at one,
+    /// before the run returns, instead of reporting it on the error stream
+    pub fn on_trap(mut self, callback: impl FnMut(&RuntimeError) + 'static) -> Self {
+        self.c4.on_trap = Some(Box::new(callback));
+        self
+    }
+
+    /// Add a directory searched by `#include`, after those added before it
+    pub fn include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.c4.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Path of the main source file, which `#include "..."` resolves
+    /// names relative to
+    pub fn source_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.c4.source_path = Some(path.into());
+        self
+    }
+
+    /// Define the macro `name` as `value` before the source is read
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.c4.defines.insert(name.as_bytes().to_vec(), value.as_bytes().to_vec());
+        self
+    }
+
+    /// When compile errors and warnings written to stderr are colored;
+    /// by default only on a terminal, unless `NO_COLOR` is set
+    pub fn color(mut self, choice: ColorChoice) -> Self {
+        self.c4.color = choice.for_stderr();
+        self
+    }
+
+    /// Make every kind of warning an error, like `-Werror`, or none
+    pub fn warnings_as_errors(mut self, errors: bool) -> Self {
+        self.c4.diagnostic_options.errors = if errors { WarningKind::ALL.to_vec() } else { Vec::new() };
+        self
+    }
+
+    /// Make warnings of `kind` errors, like `-Werror=conversion`
+    pub fn warning_as_error(mut self, kind: WarningKind) -> Self {
+        if !self.c4.diagnostic_options.is_error(kind) {
+            self.c4.diagnostic_options.errors.push(kind);
+        }
+        self
+    }
+
+    /// Which warnings are errors and how many errors are reported,
+    /// replacing what was set before
+    pub fn diagnostic_options(mut self, options: DiagnosticOptions) -> Self {
+        self.c4.diagnostic_options = options;
+        self
+    }
+
+    /// Report at most `limit` errors for a compilation, or any number if
+    /// `limit` is 0
+    pub fn error_limit(mut self, limit: usize) -> Self {
+        self.c4.diagnostic_options.error_limit = limit;
+        self
+    }
+
+    /// Send what the program writes to stdout to `sink` rather than capturing it
+    pub fn output(mut self, sink: impl Write + 'static) -> Self {
+        self.c4.output_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Send what the program writes to stderr to `sink` rather than capturing it
+    pub fn error_output(mut self, sink: impl Write + 'static) -> Self {
+        self.c4.error_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Let getchar read from `source`
+    pub fn input(mut self, source: impl Read + 'static) -> Self {
+        self.c4.input_source = Some(Box::new(source));
+        self
+    }
+
+    /// Create a compiler with these settings
+    pub fn build(self) -> C4 {
+        self.c4
+    }
+}
+
+impl C4 {
+    /// Start configuring a compiler
+    pub fn builder() -> C4Builder {
+        C4Builder::new()
+    }
+}
@@ -0,0 +1,243 @@
+//! # Diagnostics
+//!
+//! Helpers for making compile errors more useful than a bare message, such
+//! as suggesting the identifier that was probably meant, and the warnings
+//! about code that compiles but is probably a mistake.
+//!
+//! Errors and warnings are rendered here too, as `Line 3: message` and
+//! `Line 3: warning: message`. On a terminal they are colored: the
+//! location is bold, red for an error and yellow for a warning, and code
+//! quoted in the message is bold. Setting `NO_COLOR` turns this off, as
+//! described at <https://no-color.org>.
+//!
+//! Each warning has a [`WarningKind`], by which [`DiagnosticOptions`] can
+//! make it an error, like `-Werror=conversion`, and the number of errors
+//! reported can be capped.
+
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal};
+
+/// Keywords recognised by the lexer
+pub const KEYWORDS: [&str; 9] = ["char", "else", "enum", "if", "int", "return", "sizeof", "while", "void"];
+
+/// Edit distance between two names
+///
+/// Counts insertions, deletions, substitutions and swaps of adjacent
+/// characters, so a transposed pair like `retrun` is one edit from `return`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+
+    // rows[i][j] is the distance between a[..i] and b[..j]
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            let mut best = (rows[i - 1][j] + 1).min(rows[i][j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+/// The candidate closest to `name`, if any is close enough to be a likely typo
+///
+/// A candidate qualifies if it is within one edit per three characters of
+/// `name` (and at least one). On a tie the earliest candidate wins.
+pub fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = name.len().div_ceil(3).max(1);
+    candidates.into_iter()
+        .filter(|&candidate| candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= limit)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Environment variable that turns colors off when set to anything
+pub const NO_COLOR_ENV: &str = "NO_COLOR";
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// How bad a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,                        // The program cannot be compiled
+    Warning,                      // The program compiles but is probably wrong
+}
+
+/// When diagnostics are colored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    #[default]
+    Auto,                         // When stderr is a terminal and `NO_COLOR` is not set
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether diagnostics written to stderr are colored
+    pub fn for_stderr(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                io::stderr().is_terminal() && env::var_os(NO_COLOR_ENV).is_none_or(|value| value.is_empty())
+            },
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// Render a diagnostic about `line` of `file`, or of the main source if
+/// `file` is None, with ANSI colors if `color` is set
+pub fn render(severity: Severity, line: i32, file: Option<&str>, message: &str, color: bool) -> String {
+    let location = match file {
+        Some(file) => format!("Line {} of {}:", line, file),
+        None => format!("Line {}:", line),
+    };
+    let label = match severity {
+        Severity::Error => "",
+        Severity::Warning => " warning:",
+    };
+    if !color {
+        return format!("{}{} {}", location, label, message);
+    }
+    let (tint, label) = match severity {
+        Severity::Error => (RED, String::new()),
+        Severity::Warning => (YELLOW, format!("{}{}{}", YELLOW, label, RESET)),
+    };
+    format!("{}{}{}{} {}", tint, location, RESET, label, embolden_quotes(message))
+}
+
+/// `message` with each piece of code quoted in it, such as `'x'`, in bold
+fn embolden_quotes(message: &str) -> String {
+    let mut rendered = String::with_capacity(message.len());
+    let mut pieces = message.split('\'');
+    rendered += pieces.next().unwrap_or_default();
+    let mut quoted = false;
+    for piece in pieces {
+        quoted = !quoted;
+        if quoted {
+            rendered += BOLD;
+            rendered.push('\'');
+            rendered += piece;
+        } else {
+            rendered.push('\'');
+            rendered += RESET;
+            rendered += piece;
+        }
+    }
+    if quoted {
+        rendered += RESET;
+    }
+    rendered
+}
+
+/// What a warning is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    Conversion,                   // An int stored in a char may change
+    IntConversion,                // A pointer made from an integer, or an integer from a pointer
+    Redefinition,                 // An interactive session replaced an earlier definition
+    UnusedValue,                  // An expression statement that does nothing, such as `a == b;`
+}
+
+impl WarningKind {
+    /// Every kind of warning
+    pub const ALL: [WarningKind; 4] = [
+        WarningKind::Conversion, WarningKind::IntConversion, WarningKind::Redefinition, WarningKind::UnusedValue,
+    ];
+
+    /// Name of the kind, as in `-Werror=conversion`
+    pub fn name(self) -> &'static str {
+        match self {
+            WarningKind::Conversion => "conversion",
+            WarningKind::IntConversion => "int-conversion",
+            WarningKind::Redefinition => "redefinition",
+            WarningKind::UnusedValue => "unused-value",
+        }
+    }
+
+    /// The kind called `name`
+    pub fn from_name(name: &str) -> Option<WarningKind> {
+        WarningKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+/// Which warnings are errors, and how many errors are reported
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiagnosticOptions {
+    pub error_limit: usize,       // Most errors reported for a compilation, or 0 for no limit
+    pub errors: Vec<WarningKind>, // Kinds of warning made errors
+}
+
+impl DiagnosticOptions {
+    /// Whether warnings of `kind` are errors
+    pub fn is_error(&self, kind: WarningKind) -> bool {
+        self.errors.contains(&kind)
+    }
+}
+
+/// How many errors and warnings a compilation gave, as in `1 error and 2
+/// warnings generated`, or None if it gave neither
+pub fn summary(errors: usize, warnings: usize) -> Option<String> {
+    let count = |n: usize, what: &str| format!("{} {}{}", n, what, if n == 1 { "" } else { "s" });
+    match (errors, warnings) {
+        (0, 0) => None,
+        (0, _) => Some(format!("{} generated", count(warnings, "warning"))),
+        (_, 0) => Some(format!("{} generated", count(errors, "error"))),
+        _ => Some(format!("{} and {} generated", count(errors, "error"), count(warnings, "warning"))),
+    }
+}
+
+/// Where a piece of code is in the source, from its first character up to
+/// the character after its last; lines and columns count from 1, columns
+/// in characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: i32,
+    pub column: i32,
+    pub end_line: i32,
+    pub end_column: i32,
+}
+
+/// Code the compiler accepted but that is probably a mistake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub span: Span,               // The code warned about
+    pub file: Option<String>,     // Included file the code is in, None for the main source
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+impl Warning {
+    /// The warning as it is displayed, with ANSI colors if `color` is set
+    pub fn render(&self, color: bool) -> String {
+        render(Severity::Warning, self.span.line, self.file.as_deref(), &self.message, color)
+    }
+
+    /// The warning as an error, when its kind is made one, with ANSI colors
+    /// if `color` is set
+    pub fn render_as_error(&self, color: bool) -> String {
+        let message = format!("{} [-Werror={}]", self.message, self.kind.name());
+        render(Severity::Error, self.span.line, self.file.as_deref(), &message, color)
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render(false))
+    }
+}
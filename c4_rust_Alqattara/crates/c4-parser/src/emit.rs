@@ -0,0 +1,196 @@
+//! # Code Emission
+//!
+//! An [`Emitter`] holds the text segment as the code generator builds it.
+//! Instructions go in whole, with their operand if they take one, so the
+//! text segment is always a sequence of complete instructions. A forward
+//! jump is emitted with [`Emitter::reserve_jump`] before its target is
+//! known; the [`Patch`] it gives back is the only way to fill the target in
+//! later, and it can only be used once.
+//!
+//! Control flow is easier to follow with labels: [`Emitter::new_label`]
+//! names a place in the code, [`Emitter::jump_to`] emits a jump or branch
+//! there, before or after the label is bound, and [`Emitter::bind`] puts
+//! the label at the next instruction, filling in the jumps made so far. A
+//! jump to a label that is never bound is a compiler bug, which
+//! [`Emitter::unbound_jump`] finds before the code is used.
+//!
+//! The emitter derefs to the words emitted so far, as a slice, for code
+//! that reads the text segment. Code already emitted only changes through
+//! the methods here, or through [`Emitter::as_mut_slice`] for passes such
+//! as the optimizer's that rewrite instructions in place.
+
+use std::ops::Deref;
+
+use crate::Instruction;
+
+/// The text segment being generated
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Emitter {
+    text: Vec<i32>,
+    labels: Vec<Option<usize>>, // Text address each label is bound to, once it is
+    fixups: Vec<(usize, Label)>, // Operands of jumps to labels not bound yet
+}
+
+/// A place in the code that jumps can go to, from [`Emitter::new_label`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+/// The operand of a jump whose target is not known yet
+#[derive(Debug, PartialEq, Eq)]
+#[must_use = "a reserved jump must be patched with its target"]
+pub struct Patch {
+    operand: usize,             // Text address of the operand word
+}
+
+impl Patch {
+    /// Text address of the jump instruction
+    pub fn address(&self) -> usize {
+        self.operand - 1
+    }
+}
+
+impl Emitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Text address the next instruction will be at
+    pub fn here(&self) -> usize {
+        self.text.len()
+    }
+
+    /// Append `op`, which takes no operand
+    pub fn emit(&mut self, op: Instruction) {
+        debug_assert_eq!(op.operands(), 0, "{} needs an operand", op.name());
+        self.text.push(op as i32);
+    }
+
+    /// Append `op` and its operand
+    ///
+    /// # Returns
+    ///
+    /// The text address of the operand word, for recording relocations
+    pub fn emit_with(&mut self, op: Instruction, operand: i32) -> usize {
+        debug_assert_eq!(op.operands(), 1, "{} takes no operand", op.name());
+        self.text.push(op as i32);
+        self.text.push(operand);
+        self.text.len() - 1
+    }
+
+    /// Append the jump or branch `op` with its target left to [`Emitter::patch`]
+    pub fn reserve_jump(&mut self, op: Instruction) -> Patch {
+        let operand = self.emit_with(op, 0);
+        Patch { operand }
+    }
+
+    /// Make the jump reserved as `patch` go to the text address `target`
+    pub fn patch(&mut self, patch: Patch, target: usize) {
+        self.text[patch.operand] = target as i32;
+    }
+
+    /// Change the operand word at text address `operand`, such as the
+    /// target of a call emitted before its callee or the locals of an `ENT`
+    pub fn set_operand(&mut self, operand: usize, value: i32) {
+        let op = Instruction::from_opcode(self.text[operand - 1]);
+        debug_assert!(op.is_some_and(|op| op.operands() == 1), "no operand at {}", operand);
+        self.text[operand] = value;
+    }
+
+    /// Turn the `JSR f; ADJ argc` at text address `call` into the tail call
+    /// `TLEV argc; JMP f`, which takes the same four words
+    ///
+    /// # Returns
+    ///
+    /// The text address of the jump's operand, where `f` now is
+    pub fn tail_call(&mut self, call: usize, argc: i32) -> usize {
+        debug_assert_eq!(self.text[call], Instruction::JSR as i32);
+        debug_assert_eq!(self.text[call + 2..call + 4], [Instruction::ADJ as i32, argc]);
+        let target = self.text[call + 1];
+        self.text[call..call + 4].copy_from_slice(&[Instruction::TLEV as i32, argc, Instruction::JMP as i32, target]);
+        call + 3
+    }
+
+    /// Replace the `ENT` of the function at text address `entry` with a
+    /// jump to `target`, so calls to the old definition run the new one
+    pub fn redirect(&mut self, entry: usize, target: i32) {
+        debug_assert_eq!(self.text[entry], Instruction::ENT as i32);
+        self.text[entry..entry + 2].copy_from_slice(&[Instruction::JMP as i32, target]);
+    }
+
+    /// A label that is not bound anywhere yet
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Put `label` at the text address the next instruction will be at,
+    /// and point the jumps to it emitted so far there
+    pub fn bind(&mut self, label: Label) {
+        debug_assert!(self.labels[label.0].is_none(), "label bound twice");
+        let here = self.here();
+        self.labels[label.0] = Some(here);
+        let text = &mut self.text;
+        self.fixups.retain(|&(operand, target)| {
+            if target == label {
+                text[operand] = here as i32;
+            }
+            target != label
+        });
+    }
+
+    /// Append the jump or branch `op` to `label`
+    pub fn jump_to(&mut self, op: Instruction, label: Label) {
+        match self.labels[label.0] {
+            Some(target) => {
+                self.emit_with(op, target as i32);
+            },
+            None => {
+                let operand = self.emit_with(op, 0);
+                self.fixups.push((operand, label));
+            },
+        }
+    }
+
+    /// Text address of the first jump whose label was never bound
+    pub fn unbound_jump(&self) -> Option<usize> {
+        self.fixups.iter().map(|&(operand, _)| operand - 1).min()
+    }
+
+    /// Drop everything emitted, labels included
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.labels.clear();
+        self.fixups.clear();
+    }
+
+    /// Drop the code from text address `len` on, with the jumps in it
+    pub fn truncate(&mut self, len: usize) {
+        self.text.truncate(len);
+        self.fixups.retain(|&(operand, _)| operand < len);
+    }
+
+    /// The words emitted so far, for a pass that rewrites instructions in
+    /// place without moving them
+    pub fn as_mut_slice(&mut self) -> &mut [i32] {
+        &mut self.text
+    }
+
+    /// The words emitted so far
+    pub fn into_text(self) -> Vec<i32> {
+        self.text
+    }
+}
+
+impl From<Vec<i32>> for Emitter {
+    fn from(text: Vec<i32>) -> Self {
+        Emitter { text, ..Self::default() }
+    }
+}
+
+impl Deref for Emitter {
+    type Target = [i32];
+
+    fn deref(&self) -> &[i32] {
+        &self.text
+    }
+}
@@ -0,0 +1,297 @@
+//! # Program Images
+//!
+//! A compiled [`Program`] can be saved as a `.c4b` image and run later
+//! without its source. An image is little-endian throughout:
+//!
+//! ```text
+//! magic "C4B\0", format version (u32), word size (u32), flags (u32: bit 0
+//!          set if the code is position-independent, bit 1 if chars are
+//!          unsigned)
+//! text:    count (u32), then each word (i32)
+//! data:    length (u32), then the bytes
+//! floats:  count (u32), then each data address (i32) and value (f64 bits)
+//! read-only: count (u32), then the start and end of each range of the
+//!          data segment holding constants (u32 each), in address order
+//! inlined: count (u32), then the text address of each IENT and the entry
+//!          of the function inlined there (u32 each)
+//! symbols: count (u32), then each name (u32 length and UTF-8 bytes),
+//!          class, type, value, line and parameter count (i32 each;
+//!          for a global, its length if it is an array, or else 0)
+//! relocations: count (u32), then each kind (u32: 0 text address,
+//!          1 data address in the text, 2 data address in the data)
+//!          and offset (u32)
+//! ```
+//!
+//! Only functions and globals are kept in the symbol table. Loading checks
+//! the header and that every address lies inside its segment, then runs
+//! the verifier over the text segment, so a damaged or hand-made image is
+//! refused with a description of the problem instead of faulting the VM.
+
+use crate::intern::Interner;
+use crate::program::{Error, Program, Result};
+use crate::relocation::Relocation;
+use crate::{Instruction, Symbol, TokenType, CHAR, PTR};
+
+/// First bytes of every image
+pub const MAGIC: [u8; 4] = *b"C4B\0";
+
+/// Version of the image format written by this compiler
+pub const FORMAT_VERSION: u32 = 7;
+
+/// Flag set in an image whose code is position-independent
+const POSITION_INDEPENDENT: u32 = 1;
+
+/// Flag set in an image compiled for unsigned chars
+const UNSIGNED_CHAR: u32 = 2;
+
+fn invalid<T>(message: impl Into<String>) -> Result<T> {
+    Err(Error::Image(message.into()))
+}
+
+/// Reads the fields of an image in order
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize, what: &str) -> Result<&[u8]> {
+        if self.bytes.len() < len {
+            return invalid(format!("image ends in the middle of the {}", what));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4, what)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self, what: &str) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4, what)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self, what: &str) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8, what)?.try_into().unwrap()))
+    }
+
+    /// A count of items of `item_bytes` each, which must fit in what is left
+    fn count(&mut self, item_bytes: usize, what: &str) -> Result<usize> {
+        let count = self.u32(what)? as usize;
+        if count.saturating_mul(item_bytes) > self.bytes.len() {
+            return invalid(format!("image ends in the middle of the {}", what));
+        }
+        Ok(count)
+    }
+}
+
+fn put_u32(image: &mut Vec<u8>, value: u32) {
+    image.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_i32(image: &mut Vec<u8>, value: i32) {
+    image.extend_from_slice(&value.to_le_bytes());
+}
+
+impl Program {
+    /// Serialize the program as a `.c4b` image
+    pub fn to_image(&self) -> Vec<u8> {
+        let mut image = MAGIC.to_vec();
+        put_u32(&mut image, FORMAT_VERSION);
+        put_u32(&mut image, self.word_bytes as u32);
+        let flags = if self.position_independent { POSITION_INDEPENDENT } else { 0 }
+            | if self.signed_char { 0 } else { UNSIGNED_CHAR };
+        put_u32(&mut image, flags);
+
+        put_u32(&mut image, self.text.len() as u32);
+        for &word in &self.text {
+            put_i32(&mut image, word);
+        }
+        put_u32(&mut image, self.data.len() as u32);
+        image.extend_from_slice(&self.data);
+
+        put_u32(&mut image, self.float_pool.len() as u32);
+        for &(addr, value) in &self.float_pool {
+            put_i32(&mut image, addr);
+            image.extend_from_slice(&value.to_bits().to_le_bytes());
+        }
+
+        put_u32(&mut image, self.read_only.len() as u32);
+        for &(start, end) in &self.read_only {
+            put_u32(&mut image, start as u32);
+            put_u32(&mut image, end as u32);
+        }
+
+        put_u32(&mut image, self.inline_sites.len() as u32);
+        for &(site, entry) in &self.inline_sites {
+            put_u32(&mut image, site as u32);
+            put_u32(&mut image, entry as u32);
+        }
+
+        let symbols: Vec<&Symbol> = self.symbols.iter()
+            .filter(|s| s.class == TokenType::Fun as i32 || s.class == TokenType::Glo as i32)
+            .collect();
+        put_u32(&mut image, symbols.len() as u32);
+        for symbol in symbols {
+            put_u32(&mut image, symbol.name.len() as u32);
+            image.extend_from_slice(symbol.name.as_bytes());
+            for field in [symbol.class, symbol.type_, symbol.value, symbol.line, symbol.bvalue] {
+                put_i32(&mut image, field);
+            }
+        }
+
+        put_u32(&mut image, self.relocations.len() as u32);
+        for &relocation in &self.relocations {
+            put_u32(&mut image, relocation.kind());
+            put_u32(&mut image, relocation.offset() as u32);
+        }
+        image
+    }
+
+    /// Load a program from a `.c4b` image, checking it before it can be run
+    ///
+    /// # Returns
+    ///
+    /// `Error::Image` describing the first problem found if the image is
+    /// not one this compiler can run
+    pub fn from_image(image: &[u8]) -> Result<Program> {
+        let mut reader = Reader { bytes: image };
+        if reader.take(4, "header").ok() != Some(&MAGIC[..]) {
+            return invalid("not a c4 program image (bad magic number)");
+        }
+        let version = reader.u32("header")?;
+        if version != FORMAT_VERSION {
+            return invalid(format!("unsupported format version {} (expected {})", version, FORMAT_VERSION));
+        }
+        let word_bytes = reader.u32("header")?;
+        if word_bytes != 4 && word_bytes != 8 {
+            return invalid(format!("word size {} is not 4 or 8", word_bytes));
+        }
+        let flags = reader.u32("header")?;
+        let unknown = flags & !(POSITION_INDEPENDENT | UNSIGNED_CHAR);
+        if unknown != 0 {
+            return invalid(format!("unknown flags {:#x} in the header", unknown));
+        }
+
+        let count = reader.count(4, "text segment")?;
+        let text = (0..count).map(|_| reader.i32("text segment")).collect::<Result<Vec<i32>>>()?;
+        let len = reader.count(1, "data segment")?;
+        let data = reader.take(len, "data segment")?.to_vec();
+
+        let count = reader.count(12, "float constants")?;
+        let mut float_pool = Vec::with_capacity(count);
+        for _ in 0..count {
+            let addr = reader.i32("float constants")?;
+            let value = f64::from_bits(reader.u64("float constants")?);
+            if addr < 0 || addr as usize + 8 > data.len() {
+                return invalid(format!("float constant at {} is outside the data segment", addr));
+            }
+            float_pool.push((addr, value));
+        }
+
+        let count = reader.count(8, "read-only ranges")?;
+        let mut read_only = Vec::with_capacity(count);
+        let mut last_end = 0;
+        for _ in 0..count {
+            let start = reader.u32("read-only ranges")? as usize;
+            let end = reader.u32("read-only ranges")? as usize;
+            if start < last_end || end <= start || end > data.len() {
+                return invalid(format!("read-only range {} to {} is out of order or outside the data segment", start, end));
+            }
+            read_only.push((start, end));
+            last_end = end;
+        }
+
+        let count = reader.count(8, "inlined calls")?;
+        let mut inline_sites = Vec::with_capacity(count);
+        for _ in 0..count {
+            let site = reader.u32("inlined calls")? as usize;
+            let entry = reader.u32("inlined calls")? as usize;
+            if text.get(site) != Some(&(Instruction::IENT as i32)) || entry >= text.len() {
+                return invalid(format!("inlined call at {} of {} is not an IENT in the text segment", site, entry));
+            }
+            inline_sites.push((site as i32, entry as i32));
+        }
+
+        let count = reader.count(24, "symbol table")?;
+        let mut names = Interner::default();
+        let mut symbols = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len = reader.count(1, "symbol table")?;
+            let Ok(name) = String::from_utf8(reader.take(len, "symbol table")?.to_vec()) else {
+                return invalid("symbol name is not UTF-8");
+            };
+            let class = reader.i32("symbol table")?;
+            let type_ = reader.i32("symbol table")?;
+            let value = reader.i32("symbol table")?;
+            let line = reader.i32("symbol table")?;
+            let count = reader.i32("symbol table")?;
+            let is_array = class == TokenType::Glo as i32 && count > 0;
+            if count < 0 || (is_array && type_ < PTR) {
+                return invalid(format!("symbol '{}' has a count of {}", name, count));
+            }
+            // An array's elements are the type it decays to points at
+            let element_type = if is_array { type_ - PTR } else { 0 };
+            let bytes = match element_type {
+                _ if !is_array => word_bytes as usize,
+                CHAR => count as usize,
+                _ => count as usize * word_bytes as usize,
+            };
+            let in_segment = match class {
+                class if class == TokenType::Fun as i32 => value >= 0 && (value as usize) < text.len(),
+                class if class == TokenType::Glo as i32 => value >= 0 && value as usize + bytes <= data.len(),
+                _ => return invalid(format!("symbol '{}' is neither a function nor a global", name)),
+            };
+            if !in_segment {
+                return invalid(format!("symbol '{}' at {} is outside its segment", name, value));
+            }
+            if class == TokenType::Glo as i32 && value % word_bytes as i32 != 0 {
+                return invalid(format!("global '{}' at {} is not word-aligned", name, value));
+            }
+            let id = names.intern(name.as_bytes());
+            symbols.push(Symbol {
+                token: TokenType::Id,
+                hash: 0,
+                name,
+                id,
+                class,
+                type_,
+                value,
+                bclass: 0,
+                btype: element_type,
+                bvalue: count,
+                line,
+            });
+        }
+
+        let count = reader.count(8, "relocations")?;
+        let mut relocations = Vec::with_capacity(count);
+        for _ in 0..count {
+            let kind = reader.u32("relocations")?;
+            let offset = reader.u32("relocations")? as usize;
+            let relocation = match Relocation::from_kind(kind, offset) {
+                Some(Relocation::DataWord(addr)) if addr + word_bytes as usize > data.len() => None,
+                Some(Relocation::Text(offset) | Relocation::Data(offset)) if offset >= text.len() => None,
+                relocation => relocation,
+            };
+            let Some(relocation) = relocation else {
+                return invalid(format!("relocation of kind {} at {} is not one this compiler can apply", kind, offset));
+            };
+            relocations.push(relocation);
+        }
+        if !reader.bytes.is_empty() {
+            return invalid(format!("{} unexpected bytes after the relocations", reader.bytes.len()));
+        }
+
+        let mut program = Program::new(text, data, symbols);
+        program.float_pool = float_pool;
+        program.word_bytes = word_bytes as i32;
+        program.relocations = relocations;
+        program.read_only = read_only;
+        program.inline_sites = inline_sites;
+        program.position_independent = flags & POSITION_INDEPENDENT != 0;
+        program.signed_char = flags & UNSIGNED_CHAR == 0;
+        program.verify().or_else(|e| invalid(e.to_string()))?;
+        Ok(program)
+    }
+}
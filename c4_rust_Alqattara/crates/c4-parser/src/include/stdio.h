@@ -0,0 +1,9 @@
+// stdio.h bundled with c4_rust
+//
+// printf, fprintf, sprintf, snprintf, putchar, puts and getchar are
+// built into the compiler, as are the streams stdout and stderr.
+
+#pragma once
+
+#define NULL 0
+#define EOF -1
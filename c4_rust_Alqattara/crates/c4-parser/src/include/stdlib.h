@@ -0,0 +1,19 @@
+// stdlib.h bundled with c4_rust
+//
+// malloc, free, abs, qsort and exit are built into the compiler.
+
+#pragma once
+
+#define NULL 0
+#define EXIT_SUCCESS 0
+#define EXIT_FAILURE 1
+
+int atoi(char *s) {
+    int n; int sign;
+    n = 0; sign = 1;
+    while (*s == ' ' || *s == '\t' || *s == '\n') s++;
+    if (*s == '-') { sign = -1; s++; }
+    else if (*s == '+') s++;
+    while (*s >= '0' && *s <= '9') { n = n * 10 + (*s - '0'); s++; }
+    return sign * n;
+}
@@ -0,0 +1,66 @@
+// string.h bundled with c4_rust
+//
+// memset is built into the compiler.
+
+#pragma once
+
+#define NULL 0
+
+int strlen(char *s) {
+    char *p;
+    p = s;
+    while (*p) p++;
+    return p - s;
+}
+
+// Bytes compare as unsigned, whether chars are signed or not
+
+int strcmp(char *a, char *b) {
+    while (*a && *a == *b) { a++; b++; }
+    return (*a & 255) - (*b & 255);
+}
+
+int strncmp(char *a, char *b, int n) {
+    while (n > 0 && *a && *a == *b) { a++; b++; n--; }
+    if (n == 0) return 0;
+    return (*a & 255) - (*b & 255);
+}
+
+char *strcpy(char *dst, char *src) {
+    char *d;
+    d = dst;
+    while ((*d++ = *src++)) {}
+    return dst;
+}
+
+char *strcat(char *dst, char *src) {
+    strcpy(dst + strlen(dst), src);
+    return dst;
+}
+
+char *strchr(char *s, int c) {
+    char ch;
+    ch = c;
+    while (*s != ch) {
+        if (!*s) return 0;
+        s++;
+    }
+    return s;
+}
+
+void *memcpy(void *dst, void *src, int n) {
+    char *d; char *s;
+    d = dst; s = src;
+    while (n-- > 0) *d++ = *s++;
+    return dst;
+}
+
+int memcmp(void *a, void *b, int n) {
+    char *p; char *q;
+    p = a; q = b;
+    while (n-- > 0) {
+        if (*p != *q) return (*p & 255) - (*q & 255);
+        p++; q++;
+    }
+    return 0;
+}
@@ -0,0 +1,39 @@
+//! # Identifier Interning
+//!
+//! The lexer maps every distinct identifier to a small integer the first
+//! time it is seen. Symbols record that ID, so looking a name up compares
+//! integers rather than strings, and later occurrences of the same name
+//! allocate nothing.
+
+use std::collections::HashMap;
+
+/// ID of an interned identifier
+pub type NameId = u32;
+
+/// Table of the identifiers seen so far, keyed by their raw bytes
+#[derive(Debug, Default, Clone)]
+pub struct Interner {
+    ids: HashMap<Box<[u8]>, NameId>,
+}
+
+impl Interner {
+    /// The ID of `name`, adding it to the table if it is new
+    pub fn intern(&mut self, name: &[u8]) -> NameId {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.ids.len() as NameId;
+        self.ids.insert(name.into(), id);
+        id
+    }
+
+    /// The ID of `name` if it has been interned
+    pub fn get(&self, name: &[u8]) -> Option<NameId> {
+        self.ids.get(name).copied()
+    }
+
+    /// Forget every identifier
+    pub fn clear(&mut self) {
+        self.ids.clear();
+    }
+}
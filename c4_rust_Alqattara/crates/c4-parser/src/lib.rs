@@ -0,0 +1,3670 @@
+//! # C4 Compiler in Rust
+//!
+//! This is a Rust implementation of the C4 compiler, a small self-hosting C compiler
+//! originally written by Robert Swierczek. The original C4 compiler is capable of
+//! compiling itself and a subset of the C language.
+//!
+//! ## Overview
+//!
+//! The C4 compiler is a minimalist C compiler written in just four functions:
+//! - `next()`: Lexical analyzer (tokenizer)
+//! - `expression()`: Expression parser and code generator
+//! - `statement()`: Statement parser
+//! - `program()`: Program parser
+//!
+//! This Rust implementation maintains the same functionality while leveraging Rust's
+//! safety features, ownership model, and modern programming paradigms.
+//!
+//! ## Design Decisions
+//!
+//! 1. **Memory Safety**: The original C4 uses raw pointers and manual memory management.
+//!    This Rust implementation uses Rust's ownership system and safe abstractions like
+//!    `Vec<T>` and `String` to prevent memory leaks and use-after-free errors.
+//!
+//! 2. **Error Handling**: The original C4 uses `exit(-1)` for error handling. This
+//!    implementation uses more structured error handling with Result types where appropriate.
+//!
+//! 3. **Type Safety**: The original C4 uses magic numbers for token types and instructions.
+//!    This implementation uses enums for better type safety and readability.
+//!
+//! 4. **Code Organization**: The original C4 is extremely compact. This implementation
+//!    maintains the same overall structure but improves organization with a struct to
+//!    encapsulate the compiler state.
+//!
+//! ## Crates
+//!
+//! This is the `c4-parser` crate, one of four in the workspace:
+//!
+//! - `c4-lexer`: the tokens and the scanners that read them, on `core` and `alloc`
+//!   alone. It is re-exported as [`lexer`].
+//! - `c4-parser`, this crate: the preprocessor, parser and code generator, and the
+//!   [`C4`] compiler that drives them, with its REPL and program images.
+//! - `c4-vm`: the instruction set, the interpreter and register backend, and the
+//!   bytecode passes, on `core` and `alloc` alone. It is re-exported as [`vm`].
+//! - `c4_rust`: the facade, which re-exports the others, and the `c4_rust` command
+//!   line and tooling built on them.
+//!
+//! Like the original's four functions, the parser drives the lexer a token at a
+//! time. [`C4::next`] reads each token with the scanners of `c4-lexer`, then does
+//! what needs the compiler's state: it looks identifiers up in the symbol table,
+//! expands macros, acts on directives and stores literals in the data segment.
+
+#![allow(
+    dead_code,
+    non_upper_case_globals,
+    unused_variables,
+    unreachable_code,
+    unused_assignments
+)]
+
+extern crate alloc;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::mem;
+use std::path::PathBuf;
+
+use c4_lexer::{LexError, Number, Operator, Source};
+
+// The lexer and the VM are crates of their own, which build without std; the compiler does not
+pub use c4_lexer as lexer;
+pub use c4_lexer::{token_name, TokenType};
+pub use c4_vm as vm;
+pub use c4_vm::{fault, heap, memory, optimizer, printf, regvm};
+pub mod stats;
+
+pub mod analysis;
+pub mod builder;
+pub mod diagnostics;
+pub mod emit;
+pub mod image;
+pub mod intern;
+pub mod lossless;
+pub mod preprocess;
+pub mod program;
+pub mod relocation;
+pub mod repl;
+pub mod verify;
+
+pub use builder::C4Builder;
+pub use diagnostics::{ColorChoice, DiagnosticOptions, Severity, Span, Warning, WarningKind};
+pub use emit::{Emitter, Label, Patch};
+pub use heap::{Allocation, FunctionAllocations, HeapProfile};
+pub use memory::{MemoryMap, Region, RegionKind};
+pub use intern::{Interner, NameId};
+pub use stats::{CompileStats, Progress};
+pub use vm::VmStats;
+pub use verify::VerifyError;
+pub use vm::{
+    decode, Backend, CancelToken, DecodedInstr, Hang, HangKind, Host, Instruction, Instructions, Machine, Overflow, Registers, RuntimeError, Sandbox,
+    TrapKind, VmOptions, Word, CALLBACK_RETURN, STACK_BASE, STDERR, STDOUT,
+};
+pub use program::{Error, Program, ProgramSymbol, Result, RunOutcome, SymbolKind, EXIT_COMPILE_ERROR, EXIT_TRAP};
+pub use relocation::Relocation;
+
+/// Symbol structure for the symbol table
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub token: TokenType,    // Token type
+    pub hash: i32,           // Hash value
+    pub name: String,        // Symbol name
+    pub id: NameId,          // Interned name, which lookups compare
+    pub class: i32,          // Storage class (e.g., global, local)
+    pub type_: i32,          // Data type
+    pub value: i32,          // Value or address; for a local or parameter, its frame offset
+    pub bclass: i32,         // Base class (for arrays/enums), or `Const` for a const variable
+    pub btype: i32,          // Base type (for arrays/enums)
+    pub bvalue: i32,         // Base value (for arrays/enums), or a function's parameter count
+    pub line: i32,           // Line of the declaration (0 for builtins)
+}
+
+// Constants
+const MAX_SIZE: usize = 1000000;  // Max size of source code
+const SOURCE_CHUNK: usize = 64 * 1024;  // Bytes read at a time from a streamed source
+const CANCEL_INTERVAL: usize = 1024;  // Tokens or syntax nodes between checks of the cancel token
+const PROGRESS_INTERVAL: usize = 4096;  // Tokens between progress reports
+const POOL_SIZE: usize = 256 * 1024;  // Initial capacity of text/data/stack
+
+/// Which dialect of C the compiler accepts
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LanguageLevel {
+    C4,         // Exactly what the original c4.c accepts, for comparing against it
+    #[default]
+    Extended,   // c4 plus this compiler's extensions, such as floats and local arrays
+}
+
+impl LanguageLevel {
+    /// The language features of this level
+    pub fn features(self) -> Features {
+        match self {
+            LanguageLevel::C4 => Features::C4,
+            LanguageLevel::Extended => Features::EXTENDED,
+        }
+    }
+}
+
+/// Extensions to the language of c4, and checks it does not make, each of
+/// which can be turned off
+///
+/// Using an extension that is off is a compile error naming it. With a
+/// check off, the operands are accepted as c4 accepts them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    pub floats: bool,               // Floating-point literals
+    pub compound_assignment: bool,  // `+=` and the like
+    pub block_comments: bool,       // `/* ... */`
+    pub arrays: bool,               // Local arrays and array parameters
+    pub initializers: bool,         // `int x = 1;`
+    pub mixed_declarations: bool,   // Declarations after statements and inside blocks
+    pub preprocessor: bool,         // Directives are acted on rather than skipped
+    pub builtins: bool,             // Builtins beyond printf, malloc, memset and exit
+    pub enum_types: bool,           // `enum X` as the type of a local, parameter, cast or sizeof
+    pub wide_chars: bool,           // `L'a'`
+    pub prototypes: bool,           // `int add(int, int);`, declaring a function defined later
+    pub const_qualifier: bool,      // `const`
+    pub comparison_checks: bool,    // Operands of `==`, `<` and the like must have compatible types
+    pub conditional_checks: bool,   // The results of `?:` must have compatible types
+}
+
+impl Features {
+    /// Only what c4 has
+    pub const C4: Features = Features {
+        floats: false,
+        compound_assignment: false,
+        block_comments: false,
+        arrays: false,
+        initializers: false,
+        mixed_declarations: false,
+        preprocessor: false,
+        builtins: false,
+        enum_types: false,
+        wide_chars: false,
+        prototypes: false,
+        const_qualifier: false,
+        comparison_checks: false,
+        conditional_checks: false,
+    };
+
+    /// Every extension
+    pub const EXTENDED: Features = Features {
+        floats: true,
+        compound_assignment: true,
+        block_comments: true,
+        arrays: true,
+        initializers: true,
+        mixed_declarations: true,
+        preprocessor: true,
+        builtins: true,
+        enum_types: true,
+        wide_chars: true,
+        prototypes: true,
+        const_qualifier: true,
+        comparison_checks: true,
+        conditional_checks: true,
+    };
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features::EXTENDED
+    }
+}
+
+// Types
+pub const CHAR: i32 = 0;      // char
+pub const INT: i32 = 1;       // int
+pub const PTR: i32 = 2;       // pointer
+pub const FLOAT: i32 = -1;    // floating-point, apart from every pointer type
+
+// Identifier offsets (since we can't use member access in original C)
+const Token: i32 = 0;     // current token
+const Hash: i32 = 1;      // hash of token
+const Name: i32 = 2;      // name of identifier
+const Type: i32 = 3;      // type of identifier
+const Class: i32 = 4;     // class of identifier
+const Value: i32 = 5;     // value of identifier
+const BType: i32 = 6;     // base type of array/enum
+const BClass: i32 = 7;    // base class of array/enum
+const BValue: i32 = 8;    // base value of array/enum
+const IdSize: i32 = 9;    // size of identifier
+
+/// Work left over in an expression until the operand being parsed is complete
+///
+/// [`C4::expression`] keeps these on a stack in place of recursion.
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    Climb(i32, usize),           // Apply operators binding at least this tightly to the operand starting at this text address
+    Argument { symbol: usize, count: i32, args_start: usize, line: i32 }, // Call arguments so far
+    Paren,                       // Closing ')'
+    Cast(i32),                   // Type cast to the given type
+    Dereference,                 // Unary '*'
+    AddressOf,                   // Unary '&'
+    Not,                         // '!'
+    BitNot,                      // '~'
+    Negate,                      // Unary '-' of a non-literal
+    Step(Instruction),           // Pre-increment (ADD) or pre-decrement (SUB)
+    Sizeof,                      // sizeof of an expression
+    Assign { type_: i32, start: usize, from: (i32, i32) }, // '=' to an lvalue of the given type, with the right operand's code and source position
+    CompoundAssign { op: Instruction, type_: i32, start: usize, line: i32 }, // '+=' and the like
+    Then { else_: Label, start: usize }, // Middle operand of '?:', whose code starts at this text address
+    Else { end: Label, start: usize, type_: i32, null: bool }, // Last operand of '?:', after a middle one of the given type
+    Logical { skip: Label },     // Right operand of '&&' or '||'
+    Binary { op: Instruction, start: usize, line: i32 }, // Integer binary operator
+    Compare { op: Instruction, start: usize, left: (i32, bool) }, // Comparison, after a left operand of the given type that may be a null pointer constant
+    Add(i32),                    // '+' with a left operand of the given type
+    Sub(i32),                    // '-' with a left operand of the given type
+    Subscript(i32, Option<usize>), // Index of a pointer of the given type, and the array symbol it names, if any
+}
+
+/// Progress of [`C4::expression`]
+enum Step {
+    Parse(i32),                  // Parse an operand at this precedence level
+    Done(i32),                   // An operand is complete, with this constant value
+}
+
+/// Number of UTF-8 characters in `bytes`, found by skipping continuation bytes
+fn utf8_chars(bytes: &[u8]) -> i32 {
+    bytes.iter().filter(|&&b| b & 0xC0 != 0x80).count() as i32
+}
+
+/// Host for the runs of a [`C4`]: output goes to its sinks or capture
+/// buffers, input comes from its input source, and faults are printed
+struct C4Host<'a> {
+    output_sink: &'a mut Option<Box<dyn Write>>,
+    error_sink: &'a mut Option<Box<dyn Write>>,
+    input_source: &'a mut Option<Box<dyn Read>>,
+    captured_output: &'a mut Vec<u8>,
+    captured_error: &'a mut Vec<u8>,
+    report_faults: bool,      // Whether faults go to the error stream, and not only to `C4::on_trap`
+}
+
+impl Host for C4Host<'_> {
+    /// Send program output to the sink for `stream`, or capture it if there is none
+    fn write(&mut self, stream: Word, bytes: &[u8]) -> bool {
+        let (sink, captured) = match stream {
+            STDOUT => (&mut *self.output_sink, &mut *self.captured_output),
+            STDERR => (&mut *self.error_sink, &mut *self.captured_error),
+            _ => return false,
+        };
+        match sink.as_mut() {
+            Some(sink) => sink.write_all(bytes).is_ok(),
+            None => {
+                captured.extend_from_slice(bytes);
+                true
+            }
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let mut byte = [0u8];
+        self.input_source.as_mut()?.read_exact(&mut byte).ok()?;
+        Some(byte[0])
+    }
+
+    fn fault(&mut self, message: std::fmt::Arguments) {
+        if self.report_faults {
+            self.report(message);
+        }
+    }
+
+    /// Write a report to the error stream, as the program's stderr goes
+    fn report(&mut self, message: std::fmt::Arguments) {
+        self.write(STDERR, format!("{}\n", message).as_bytes());
+    }
+}
+
+/// Called with how far a compilation has got
+pub type ProgressCallback = Box<dyn FnMut(&Progress)>;
+
+/// Called with the fault a run stopped at
+pub type TrapCallback = Box<dyn FnMut(&RuntimeError)>;
+
+/// The main C4 compiler structure
+pub struct C4 {
+    // Source and parsing
+    pub src: Vec<u8>,         // Source code (the part read so far, if streamed)
+    pub old_src: Vec<u8>,     // Old source code (for preprocessor)
+    pub pos: usize,           // Current position in source code
+    pub source_reader: Option<Box<dyn Read>>, // Rest of a streamed source, read into src as needed
+    source_pins: usize,       // Open assert calls, whose source text must stay in src
+    pub line: i32,            // Current line number
+    pub column: i32,          // Column of the current token, counted in characters from 1
+    column_pos: usize,        // Position in src up to which the current line's characters are counted
+    column_chars: i32,        // Characters on the current line before column_pos
+    token_start: usize,       // Position in src where the current token starts
+    token_end: (i32, i32),    // Line and column just past the token before the current one
+    dropped_bytes: usize,     // Bytes of the main source dropped from the front of src
+
+    // Preprocessor
+    source_path: Option<PathBuf>,       // Path of the main source file, for resolving #include "..."
+    include_dirs: Vec<PathBuf>,         // Directories searched by #include, in order (like -I)
+    defines: HashMap<Vec<u8>, Vec<u8>>, // Macros defined before the source is read (like -D)
+    macros: HashMap<Vec<u8>, Vec<u8>>,  // Macros currently defined
+    macro_sites: HashMap<Vec<u8>, (Option<PathBuf>, i32)>, // File and line each macro defined in the source was defined on
+    current_file: Option<PathBuf>,      // File the lexer is reading
+    sources: Vec<preprocess::SourceLevel>, // Sources to return to when the current one ends
+    conditions: Vec<preprocess::Condition>, // Open conditional directives
+    once: HashSet<PathBuf>,             // Files marked #pragma once
+    guards: HashMap<PathBuf, Vec<u8>>,  // Include guard macro of each file that has one
+    directives: bool,                   // Act on directives rather than skipping them
+    pub token: i32,           // Current token
+    pub token_val: i32,       // Value of current token (for number, character)
+
+    // Symbol table
+    pub symbols: Vec<Symbol>, // Symbol table
+    enum_tags: HashSet<NameId>, // Tags of the enums declared so far
+    prototypes: HashMap<NameId, Vec<i32>>, // Parameter types of each function declared by a prototype
+    forward_calls: Vec<(usize, usize, i32)>, // Operands naming a function not yet defined: text address, symbol and line
+    initialized_globals: HashMap<NameId, i32>, // Line of the definition of each global given an initializer
+    data_relocations: Vec<Relocation>, // Words emitted so far that hold data addresses
+    read_only: Vec<(usize, usize)>, // Byte ranges of the data segment, start to end, holding literals and const globals
+
+    // Code generation
+    pub text: Emitter,        // Text segment
+    pub old_text: Vec<i32>,   // Old text segment
+    pub data: Vec<u8>,        // Data segment (byte addressed)
+    pub float_pool: HashMap<u64, i32>, // Bit pattern of each float constant -> data address
+    line_marks: Vec<(usize, i32)>, // Text address where the code of each source line starts, and the line (0 in an included file)
+    inline_sites: Vec<(i32, i32)>, // Text address of each IENT and the entry of the function it inlines
+
+    // VM registers
+    pub pc: i32,              // Program counter
+    pub bp: i32,              // Base pointer
+    pub sp: i32,              // Stack pointer
+    pub ax: Word,             // Accumulator
+    pub ax_float: f64,        // Floating-point accumulator
+    pub cycle: i32,           // Cycle counter
+
+    // Current identifier
+    pub current_id: Vec<u8>,  // Current identifier name
+    pub current_name: NameId, // Interned current identifier (not set for keywords)
+    pub names: Interner,      // Every identifier seen so far
+
+    // AST
+    pub expr_type: i32,       // Type of expression
+    array_operand: Option<(usize, usize)>, // Text length after the last array named, and its symbol
+    const_operand: Option<(usize, usize)>, // Text length after the load of the last const variable named, and its symbol
+
+    // Variables
+    pub param_count: i32,     // Number of parameters of the function being compiled
+    pub local_slots: i32,     // Stack words held by the locals in scope in the function being compiled
+    pub frame_slots: i32,     // Stack words its frame reserves for locals, the most ever in scope at once
+    frame_address_taken: bool, // Whether it has taken the address of one of its parameters or locals
+    pub scope_start: usize,   // Index of the first symbol declared in the innermost scope
+
+    // Memory management
+    pub stack: Vec<Word>,     // Stack
+
+    // Debugging
+    debug: bool,              // Debug mode
+    pub error: Option<String>, // First compile error, if any
+    pub warnings: Vec<Warning>, // Warnings from the last compilation
+    color: bool,              // Write diagnostics to stderr with ANSI colors
+    quiet: bool,              // Keep errors in `error` without writing them, as the evaluator of an `#if` does
+    diagnostic_options: DiagnosticOptions, // Which warnings are errors, and how many errors are reported
+    nesting_limit: usize,     // Deepest nesting of expressions and statements the parser accepts
+    features: Features,       // Extensions of the language that may be used
+    bounds_checks: bool,      // Trap on subscripts outside an array of known length
+    check_stack: bool,        // Trap as soon as a run's stack differs from what the verifier expects; stack backend only
+    check_writes: bool,       // Trap on a store to a string literal, another constant or a const global
+    nesting: usize,           // Current nesting depth of the parser
+
+    // Optimization
+    opt_level: i32,           // Optimization level (0 disables the optimizer)
+    inline_functions: bool, // Inline small leaf functions at call sites
+    inline_threshold: usize, // Maximum instruction count of an inlined function
+    pub inline_stats: optimizer::InlineStats, // Code-size metrics from the last inlining pass
+    pub compile_stats: CompileStats, // Sizes of the input and output of the last compilation
+
+    // Virtual machine
+    vm_options: VmOptions, // Stack size and other VM settings
+    pub vm_stats: VmStats,    // What the last run did, if `vm_options.stats` is set
+    pub heap_profile: HeapProfile, // How the last run used the heap, if `vm_options.heap_profile` is set
+    cancel: CancelToken,      // Stops compilations and runs when cancelled, from another thread
+    on_progress: Option<ProgressCallback>, // Told how far a compilation has got, now and then
+    on_trap: Option<TrapCallback>, // Told of the fault a run stopped at, in full
+    functions_compiled: usize, // Function definitions compiled so far
+
+    if_token: bool, // Renamed from `if` to `if_token`
+
+    // Program I/O
+    output_sink: Option<Box<dyn Write>>, // Where program output goes (captured in memory if None)
+    error_sink: Option<Box<dyn Write>>,  // Where output to stderr goes (captured separately if None)
+    input_source: Option<Box<dyn Read>>, // Where getchar reads from (always at end of input if None)
+
+    // Add this field to the C4 struct
+    captured_output: Vec<u8>,
+    captured_error: Vec<u8>,
+}
+
+impl Default for C4 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The lexer's scanners read the source as `next` does, streaming more of
+/// it in as they look ahead
+impl Source for C4 {
+    fn peek(&mut self, offset: usize) -> Option<u8> {
+        C4::peek(self, offset)
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.pos += n;
+    }
+}
+
+impl C4 {
+    /// Creates a new C4 compiler instance with default settings
+    pub fn new() -> Self {
+        let mut c4 = C4 {
+            src: Vec::with_capacity(MAX_SIZE),
+            old_src: Vec::new(),
+            pos: 0,
+            line: 1,
+            token: 0,
+            token_val: 0,
+            symbols: Vec::new(),
+            enum_tags: HashSet::new(),
+            prototypes: HashMap::new(),
+            forward_calls: Vec::new(),
+            initialized_globals: HashMap::new(),
+            data_relocations: Vec::new(),
+            read_only: Vec::new(),
+            text: Emitter::from(Vec::with_capacity(POOL_SIZE)),
+            line_marks: Vec::new(),
+            inline_sites: Vec::new(),
+            old_text: Vec::new(),
+            data: Vec::with_capacity(POOL_SIZE),
+            float_pool: HashMap::new(),
+            pc: 0,
+            bp: 0,
+            sp: 0,
+            ax: 0,
+            ax_float: 0.0,
+            cycle: 0,
+            current_id: Vec::new(),
+            current_name: 0,
+            names: Interner::default(),
+            expr_type: 0,
+            array_operand: None,
+            const_operand: None,
+            param_count: 0,
+            local_slots: 0,
+            frame_slots: 0,
+            frame_address_taken: false,
+            scope_start: 0,
+            stack: Vec::with_capacity(POOL_SIZE),
+            debug: false,
+            error: None,
+            warnings: Vec::new(),
+            color: ColorChoice::Auto.for_stderr(),
+            quiet: false,
+            diagnostic_options: DiagnosticOptions::default(),
+            nesting_limit: 1000,
+            features: Features::EXTENDED,
+            bounds_checks: false,
+            check_stack: false,
+            check_writes: false,
+            nesting: 0,
+            opt_level: 1,
+            inline_functions: true,
+            inline_threshold: 16,
+            inline_stats: optimizer::InlineStats::default(),
+            compile_stats: CompileStats::default(),
+            vm_options: VmOptions::default(),
+            vm_stats: VmStats::default(),
+            heap_profile: HeapProfile::default(),
+            cancel: CancelToken::default(),
+            on_progress: None,
+            on_trap: None,
+            functions_compiled: 0,
+            if_token: false,
+            output_sink: None,
+            error_sink: None,
+            input_source: None,
+            source_reader: None,
+            source_pins: 0,
+            column: 1,
+            column_pos: 0,
+            column_chars: 0,
+            token_start: 0,
+            token_end: (1, 1),
+            dropped_bytes: 0,
+            source_path: None,
+            include_dirs: Vec::new(),
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            macro_sites: HashMap::new(),
+            current_file: None,
+            sources: Vec::new(),
+            conditions: Vec::new(),
+            once: HashSet::new(),
+            guards: HashMap::new(),
+            directives: true,
+            captured_output: Vec::new(),
+            captured_error: Vec::new(),
+        };
+        c4.data.resize(vm::NULL_GUARD, 0); // Keep address 0 for null
+        c4
+    }
+
+    /// Lexical analyzer: get the next token from the source code
+    ///
+    /// This function reads the next token from the source code and updates
+    /// the compiler state accordingly. It handles identifiers, numbers,
+    /// character literals, string literals, and operators.
+    pub fn next(&mut self) {
+        let mut ch: u8;
+        let token = self.src.get(self.token_start..self.pos).unwrap_or_default();
+        self.token_end = (self.line, self.column + utf8_chars(token));
+        self.mark_line();
+        self.compact_source();
+
+        // Skip whitespace and comments
+        loop {
+            let Some(next) = self.peek(0) else {
+                if self.pop_source() {
+                    continue;
+                }
+                self.start_token();
+                self.token = 0;  // Set token to 0 to indicate end of input
+                return;
+            };
+            ch = next;
+
+            if ch == b'\n' {
+                self.newline();
+            } else if ch == b'#' {
+                self.directive();
+                continue;
+            } else if ch == b'/' && self.peek(1) == Some(b'/') {
+                // Skip single-line comment
+                lexer::line_comment(self);
+                continue;
+            } else if ch == b'/' && self.peek(1) == Some(b'*') {
+                // Skip multi-line comment
+                if !self.extension(self.features.block_comments, "block comments") {
+                    return;
+                }
+                self.pos += 2;
+                while self.peek(1).is_some() && !(self.peek(0) == Some(b'*') && self.peek(1) == Some(b'/')) {
+                    if self.peek(0) == Some(b'\n') {
+                        self.newline();
+                    }
+                    self.pos += 1;
+                }
+                if self.peek(1).is_some() {
+                    self.pos += 2;
+                }
+                continue;
+            }
+
+            if !ch.is_ascii_whitespace() {
+                break;
+            }
+
+            self.pos += 1;
+        }
+
+        self.start_token();
+        self.compile_stats.tokens += 1;
+        if self.cancelled(self.compile_stats.tokens) {
+            return;
+        }
+        if self.compile_stats.tokens.is_multiple_of(PROGRESS_INTERVAL) {
+            self.report_progress();
+        }
+
+        if ch == b'L' && self.peek(1) == Some(b'\'') {
+            if !self.extension(self.features.wide_chars, "wide character literals") {
+                return;
+            }
+            self.pos += 1;
+            self.character_literal(true);
+            return;
+        }
+
+        // Parse identifier
+        if lexer::starts_identifier(ch) {
+            let mut name = mem::take(&mut self.current_id);
+            name.clear();
+            lexer::identifier(self, &mut name);
+            self.current_id = name;
+
+            if self.expand_macro() {
+                self.next();
+                return;
+            }
+
+            if let Some(keyword) = TokenType::keyword(&self.current_id) {
+                self.token = keyword as i32;
+                return;
+            }
+
+            // Check if it's in the symbol table, innermost declaration first
+            self.token = TokenType::Id as i32;
+            self.current_name = self.names.intern(&self.current_id);
+            if let Some(symbol) = self.symbols.iter().rev().find(|s| s.id == self.current_name) {
+                self.token = symbol.token as i32;
+                self.token_val = symbol.value;
+            }
+            return;
+        }
+
+        // Parse numbers (integer or float)
+        if lexer::at_number(self) {
+            match lexer::number(self) {
+                Ok(Number::Int(value)) => {
+                    self.token = TokenType::Num as i32;
+                    self.token_val = value;
+                },
+                Ok(Number::Float(value)) => {
+                    if !self.extension(self.features.floats, "floating-point literals") {
+                        return;
+                    }
+                    self.token = TokenType::Float as i32;
+                    self.token_val = self.new_float_constant(value);
+                },
+                Err(e) => self.error(&e.to_string()),
+            }
+            return;
+        }
+
+        // Parse character literal, or a wide one such as L'a'
+        if ch == b'\'' {
+            self.character_literal(false);
+            return;
+        }
+
+        // Parse string literal
+        if ch == b'"' {
+            let mut bytes = Vec::new();
+            if let Err(e) = lexer::string_literal(self, &mut bytes) {
+                self.error(&e.to_string());
+                return;
+            }
+            let data_idx = self.data.len();
+            self.data.extend_from_slice(&bytes);
+            self.data.push(0); // Null-terminate the string
+            self.protect(data_idx, self.data.len());
+            self.token = TokenType::Str as i32;
+            self.token_val = data_idx as i32;
+            return;
+        }
+
+        // Parse operators
+        match lexer::operator(self) {
+            Some(Operator::Token(token)) => self.token = token,
+            Some(Operator::CompoundAssign(op)) => {
+                // The value is the instruction that combines the two operands
+                self.token = TokenType::Assign as i32;
+                self.token_val = combining_instruction(op) as i32;
+            },
+            None if !ch.is_ascii() => {
+                // Token values from 128 up are taken, so this cannot become a token
+                let c = lexer::utf8_char(self);
+                self.error(&LexError::UnexpectedChar(c).to_string());
+            },
+            None => {
+                let message = format!("Unexpected character: {}", ch as char);
+                if !self.quiet {
+                    eprintln!("{}", diagnostics::render(Severity::Error, self.line, None, &message, self.color));
+                }
+                self.pos += 1;
+                self.token = ch as i32;
+            }
+        }
+    }
+
+    /// The byte `offset` places past the current position
+    ///
+    /// Reads more of a streamed source if `src` does not reach that far yet.
+    fn peek(&mut self, offset: usize) -> Option<u8> {
+        let at = self.pos + offset;
+        if at >= self.src.len() {
+            self.fill_source(at + 1);
+        }
+        self.src.get(at).copied()
+    }
+
+    /// Lex the character literal at the current position, after the `L`
+    /// of a wide one
+    fn character_literal(&mut self, wide: bool) {
+        match lexer::character_literal(self, wide) {
+            Ok(value) => {
+                self.token = TokenType::Num as i32;
+                self.token_val = value;
+            },
+            Err(e) => self.error(&e.to_string()),
+        }
+    }
+
+    /// Record the current position as the start of a token
+    fn start_token(&mut self) {
+        // Columns count characters, not bytes
+        self.column_chars += utf8_chars(self.src.get(self.column_pos..self.pos).unwrap_or_default());
+        self.column_pos = self.pos;
+        self.column = self.column_chars + 1;
+        self.token_start = self.pos;
+    }
+
+    /// Note that code emitted from here on belongs to the line of the token
+    /// just read, since the parser emits code after reading past it
+    fn mark_line(&mut self) {
+        let line = if self.in_included_file() { 0 } else { self.line };
+        let at = self.text.len();
+        // Marks past the end were left by code that was taken back
+        while self.line_marks.last().is_some_and(|&(mark, _)| mark >= at) {
+            self.line_marks.pop();
+        }
+        if self.line_marks.last().is_none_or(|&(_, last)| last != line) {
+            self.line_marks.push((at, line));
+        }
+    }
+
+    /// Count the newline at the current position
+    fn newline(&mut self) {
+        self.line += 1;
+        self.column_pos = self.pos + 1;
+        self.column_chars = 0;
+    }
+
+    /// Read from `source_reader` until `src` holds at least `len` bytes or the source ends
+    fn fill_source(&mut self, len: usize) {
+        while self.src.len() < len {
+            let Some(reader) = self.source_reader.as_mut() else {
+                return;
+            };
+            let wanted = (len - self.src.len()).max(SOURCE_CHUNK) as u64;
+            match reader.take(wanted).read_to_end(&mut self.src) {
+                Ok(0) => self.source_reader = None,
+                Ok(n) => self.compile_stats.source_bytes += n,
+                Err(e) => self.error(&format!("Cannot read source: {}", e)),
+            }
+        }
+    }
+
+    /// Drop the part of a streamed source the lexer has finished with
+    ///
+    /// Only done once a whole chunk has been consumed, and never while the
+    /// text of an `assert` condition is still needed for its message.
+    fn compact_source(&mut self) {
+        if self.source_reader.is_some() && self.source_pins == 0 && self.pos >= SOURCE_CHUNK {
+            self.column_chars += utf8_chars(self.src.get(self.column_pos..self.pos).unwrap_or_default());
+            if self.sources.is_empty() {
+                self.dropped_bytes += self.pos;
+            }
+            self.src.drain(..self.pos);
+            self.pos = 0;
+            self.column_pos = 0;
+            self.token_start = 0;
+        }
+    }
+
+    /// Match the current token with the expected token
+    ///
+    /// If the current token matches the expected token, advance to the next token.
+    /// Otherwise, report an error.
+    pub fn match_token(&mut self, expected_token: i32) {
+        if self.token != expected_token {
+            let message = format!("Expected {}, got {}", token_name(expected_token), self.token_name());
+            self.error(&message);
+            return;
+        }
+        self.next();
+    }
+
+    /// The current token as parse errors name it
+    ///
+    /// Like [`token_name`], but as written where the number alone does not
+    /// say: `identifier 'x'`, `'+='` or `keyword 'void'`.
+    pub fn token_name(&self) -> String {
+        if self.token == TokenType::Id as i32 {
+            return format!("identifier '{}'", String::from_utf8_lossy(&self.current_id));
+        }
+        if let Some(keyword) = self.keyword() {
+            return format!("keyword '{}'", keyword);
+        }
+        if self.token == TokenType::Assign as i32 {
+            let op = match Instruction::from_opcode(self.token_val) {
+                Some(Instruction::ADD) => "+",
+                Some(Instruction::SUB) => "-",
+                Some(Instruction::MUL) => "*",
+                Some(Instruction::DIV) => "/",
+                Some(Instruction::MOD) => "%",
+                Some(Instruction::SHL) => "<<",
+                Some(Instruction::SHR) => ">>",
+                Some(Instruction::AND) => "&",
+                Some(Instruction::OR) => "|",
+                Some(Instruction::XOR) => "^",
+                _ => return token_name(self.token),
+            };
+            return format!("'{}='", op);
+        }
+        token_name(self.token)
+    }
+
+    /// Report a compile error and stop parsing
+    ///
+    /// Only the first error is kept. The rest of the source is skipped, so the
+    /// parser unwinds at the end of input instead of reporting follow-on errors.
+    pub fn error(&mut self, message: &str) {
+        self.error_at(self.line, message);
+    }
+
+    /// Report a compile error at `line` rather than the current line
+    ///
+    /// Used when the parser has already read past the code being reported.
+    pub fn error_at(&mut self, line: i32, message: &str) {
+        if self.error.is_none() {
+            let file = self.included_file();
+            let message = message.to_string() + &self.macro_backtrace();
+            if !self.quiet {
+                eprintln!("{}", diagnostics::render(Severity::Error, line, file.as_deref(), &message, self.color));
+            }
+            self.error = Some(diagnostics::render(Severity::Error, line, file.as_deref(), &message, false));
+        }
+        self.pos = self.src.len();
+        self.source_reader = None;
+        self.sources.clear();
+        self.conditions.clear();
+        self.token = 0;
+    }
+
+    /// Error message for an identifier missing from the symbol table
+    ///
+    /// Suggests the closest symbol in scope or keyword, innermost first.
+    fn undefined_message(&self, name: &str) -> String {
+        let candidates = self.symbols.iter().rev()
+            .map(|s| s.name.as_str())
+            .chain(diagnostics::KEYWORDS);
+        match diagnostics::closest(name, candidates) {
+            Some(suggestion) => format!("Undefined variable: {} (did you mean '{}'?)", name, suggestion),
+            None => format!("Undefined variable: {}", name),
+        }
+    }
+
+    /// Parse an expression with the given precedence level
+    ///
+    /// This function implements precedence climbing with an explicit stack:
+    /// wherever an operator needs an operand, the code still to be emitted
+    /// after it is pushed as a `Pending` step and the operand is parsed in
+    /// the same loop. Deeply nested input therefore uses heap memory rather
+    /// than host stack, up to `nesting_limit` levels.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - The precedence level to start parsing at
+    ///
+    /// # Returns
+    ///
+    /// The value of the expression (for constant expressions)
+    pub fn expression(&mut self, level: i32) -> i32 {
+        let mut pending = Vec::new();
+        let mut step = Step::Parse(level);
+        loop {
+            step = match step {
+                Step::Parse(level) => {
+                    if self.enter_nesting() {
+                        pending.push(Pending::Climb(level, self.text.len()));
+                        self.primary(&mut pending)
+                    } else {
+                        Step::Done(INT)
+                    }
+                },
+                Step::Done(value) => match pending.pop() {
+                    Some(next) => self.resume(next, value, &mut pending),
+                    None => return value,
+                },
+            };
+        }
+    }
+
+    /// Count one more level of expression or statement nesting
+    ///
+    /// Statements nest by recursion and expressions on an explicit stack;
+    /// past `nesting_limit` levels this reports an error instead of
+    /// overflowing either.
+    ///
+    /// # Returns
+    ///
+    /// false if the limit has been reached
+    fn enter_nesting(&mut self) -> bool {
+        if self.nesting >= self.nesting_limit {
+            self.error(&format!("Nesting too deep (more than {} levels)", self.nesting_limit));
+            return false;
+        }
+        self.nesting += 1;
+        self.compile_stats.ast_nodes += 1;
+        !self.cancelled(self.compile_stats.ast_nodes)
+    }
+
+    /// Stop compiling if the cancel token has been cancelled
+    ///
+    /// Checked every `CANCEL_INTERVAL` tokens by the lexer and syntax nodes
+    /// by the parser, so every loop of either gets to it; cancelling is
+    /// reported as a compile error, which ends the source.
+    ///
+    /// # Returns
+    ///
+    /// true if compilation was cancelled
+    fn cancelled(&mut self, count: usize) -> bool {
+        if !count.is_multiple_of(CANCEL_INTERVAL) || !self.cancel.is_cancelled() {
+            return false;
+        }
+        self.error("Compilation cancelled");
+        true
+    }
+
+    /// Parse a primary expression or the start of a unary one
+    ///
+    /// Unary operators, parentheses and call arguments push the work that
+    /// follows their operand onto `pending` and ask for the operand.
+    fn primary(&mut self, pending: &mut Vec<Pending>) -> Step {
+        const TOKEN_INC: i32 = TokenType::Inc as i32;
+        const TOKEN_DEC: i32 = TokenType::Dec as i32;
+        const TOKEN_SIZEOF: i32 = TokenType::Sizeof as i32;
+        const OPEN_PAREN: i32 = b'(' as i32;
+        const ASTERISK: i32 = b'*' as i32;
+        const AMPERSAND: i32 = b'&' as i32;
+        const EXCLAMATION: i32 = b'!' as i32;
+        const TILDE: i32 = b'~' as i32;
+        const MINUS: i32 = b'-' as i32;
+
+        // Operators whose operand is a unary expression
+        let unary = match self.token {
+            t if t == TokenType::Num as i32 => {
+                // Number literal
+                let value = self.token_val;
+                self.text.emit_with(Instruction::IMM, value);
+                self.expr_type = INT;
+                self.next();
+                return Step::Done(value);
+            },
+            t if t == TokenType::Str as i32 => {
+                // String literal: the address of its chars in the data segment
+                self.data_address(self.token_val);
+                self.expr_type = CHAR + PTR;
+                self.next();
+                return Step::Done(0);
+            },
+            t if t == TokenType::Float as i32 => {
+                self.data_address(self.token_val);
+                self.text.emit(Instruction::FLD);
+                self.expr_type = FLOAT;
+                self.next();
+                return Step::Done(0);
+            },
+            t if t == TokenType::Id as i32 => return self.identifier(pending),
+            OPEN_PAREN => {
+                self.match_token(b'(' as i32);
+                if self.token != TokenType::Int as i32 && self.token != TokenType::Char as i32 {
+                    // Parenthesized expression
+                    pending.push(Pending::Paren);
+                    return Step::Parse(Assign);
+                }
+
+                // Type cast
+                let mut cast_type = if self.token == TokenType::Int as i32 { INT } else { CHAR };
+                self.next();
+                while self.token == b'*' as i32 {
+                    self.next();
+                    cast_type += PTR;
+                }
+                self.match_token(b')' as i32);
+                Pending::Cast(cast_type)
+            },
+            ASTERISK => {
+                self.next();
+                Pending::Dereference
+            },
+            AMPERSAND => {
+                self.next();
+                Pending::AddressOf
+            },
+            EXCLAMATION => {
+                self.next();
+                Pending::Not
+            },
+            TILDE => {
+                self.next();
+                Pending::BitNot
+            },
+            MINUS => {
+                // Unary minus: fold into a literal, otherwise multiply by -1
+                self.next();
+                if self.token == TokenType::Num as i32 {
+                    self.text.emit_with(Instruction::IMM, self.token_val.wrapping_neg());
+                    self.next();
+                    self.expr_type = INT;
+                    return Step::Done(INT);
+                }
+                self.text.emit_with(Instruction::IMM, -1);
+                self.text.emit(Instruction::PUSH);
+                Pending::Negate
+            },
+            TOKEN_INC => {
+                // Pre-increment
+                self.next();
+                Pending::Step(Instruction::ADD)
+            },
+            TOKEN_DEC => {
+                // Pre-decrement
+                self.next();
+                Pending::Step(Instruction::SUB)
+            },
+            TOKEN_SIZEOF => {
+                // Sizeof operator
+                self.next();
+                self.match_token(b'(' as i32);
+                if !self.at_type() {
+                    // Expression
+                    self.array_operand = None;
+                    pending.push(Pending::Sizeof);
+                    return Step::Parse(Assign);
+                }
+
+                // Type
+                let Some(mut size_type) = self.base_type() else {
+                    return Step::Done(INT);
+                };
+                while self.token == b'*' as i32 {
+                    self.next();
+                    size_type += PTR;
+                }
+                self.match_token(b')' as i32);
+
+                // Calculate size
+                self.text.emit_with(Instruction::IMM, if size_type == CHAR { 1 } else { self.vm_options.word_bytes() });
+                self.expr_type = INT;
+                return Step::Done(INT);
+            }
+            _ => {
+                let message = format!("Unexpected {} in expression", self.token_name());
+                self.error(&message);
+                return Step::Done(INT);
+            }
+        };
+        pending.push(unary);
+        Step::Parse(Inc)
+    }
+
+    /// Parse an identifier: a call, a function's address, a constant or a variable
+    fn identifier(&mut self, pending: &mut Vec<Pending>) -> Step {
+        // Find the symbol in the symbol table, innermost declaration first
+        let Some(symbol_idx) = self.symbols.iter().rposition(|s| s.id == self.current_name) else {
+            let message = self.undefined_message(&String::from_utf8_lossy(&self.current_id));
+            self.error(&message);
+            return Step::Done(INT);
+        };
+
+        self.next();
+
+        // Function call
+        if self.token == b'(' as i32 {
+            // Source of the arguments starts just after the '('; assert's message quotes it
+            let (args_start, line) = (self.pos, self.line);
+            if self.is_assert(symbol_idx) {
+                self.source_pins += 1;
+            }
+            self.match_token(b'(' as i32);
+            if self.token != b')' as i32 {
+                pending.push(Pending::Argument { symbol: symbol_idx, count: 0, args_start, line });
+                return Step::Parse(Assign);
+            }
+            self.call(symbol_idx, 0, args_start, line);
+            return Step::Done(INT);
+        }
+
+        // A function name without a call is the function's address
+        if self.symbols[symbol_idx].class == TokenType::Fun as i32 {
+            self.function_address(Instruction::FADDR, symbol_idx, self.line);
+            self.expr_type = INT;
+            return Step::Done(INT);
+        }
+
+        // Named constant
+        if self.symbols[symbol_idx].class == TokenType::Num as i32 {
+            let assigned = [b'=' as i32, TokenType::Assign as i32, TokenType::Inc as i32, TokenType::Dec as i32];
+            if assigned.contains(&self.token) {
+                let symbol = &self.symbols[symbol_idx];
+                let what = if symbol.bclass == TokenType::Enum as i32 { "enum constant" } else { "constant" };
+                let message = format!("Cannot assign to {} '{}'", what, symbol.name);
+                self.error(&message);
+                return Step::Done(INT);
+            }
+            self.text.emit_with(Instruction::IMM, self.symbols[symbol_idx].value);
+            self.expr_type = INT;
+            return Step::Done(INT);
+        }
+
+        // Variable
+        if self.symbols[symbol_idx].class == TokenType::Loc as i32 {
+            self.text.emit_with(Instruction::LEA, self.symbols[symbol_idx].value);
+        } else if self.symbols[symbol_idx].class == TokenType::Glo as i32 {
+            self.data_address(self.symbols[symbol_idx].value);
+        } else {
+            let message = format!("Invalid variable: {}", self.symbols[symbol_idx].name);
+            self.error(&message);
+            return Step::Done(INT);
+        }
+
+        // Arrays evaluate to the address of their first element
+        self.expr_type = self.symbols[symbol_idx].type_;
+        if self.symbols[symbol_idx].bvalue > 0 {
+            self.array_operand = Some((self.text.len(), symbol_idx));
+            return Step::Done(INT);
+        }
+
+        // Load the value
+        if self.expr_type == CHAR {
+            self.text.emit(Instruction::LC);
+        } else {
+            self.text.emit(Instruction::LI);
+        }
+        if self.symbols[symbol_idx].bclass == TokenType::Const as i32 {
+            self.const_operand = Some((self.text.len(), symbol_idx));
+        }
+        Step::Done(INT)
+    }
+
+    /// Whether `symbol_idx` is the `assert` builtin
+    fn is_assert(&self, symbol_idx: usize) -> bool {
+        let symbol = &self.symbols[symbol_idx];
+        symbol.class == TokenType::Sys as i32 && symbol.value == Instruction::ASSERT as i32
+    }
+
+    /// Emit a call to `symbol_idx` once its `arg_count` arguments have been pushed
+    ///
+    /// `args_start` is the source position just after the '(' and `line`
+    /// the line of the call, which `assert` needs for its message.
+    fn call(&mut self, symbol_idx: usize, mut arg_count: i32, args_start: usize, line: i32) {
+        // assert also gets a hidden second argument: the message to print if it fails
+        if self.is_assert(symbol_idx) {
+            self.source_pins -= 1;
+            if arg_count != 1 {
+                self.error("assert takes exactly one argument");
+                return;
+            }
+            let condition = self.src.get(args_start..self.pos - 1).unwrap_or_default();
+            let message = format!("Line {}: assertion failed: {}\n",
+                                  line, String::from_utf8_lossy(condition).trim());
+            self.data_address(self.data.len() as i32);
+            self.text.emit(Instruction::PUSH);
+            self.data.extend_from_slice(message.as_bytes());
+            self.data.push(0);
+            self.protect(self.data.len() - message.len() - 1, self.data.len());
+            arg_count += 1;
+        }
+        self.match_token(b')' as i32);
+
+        // Call the function
+        if self.symbols[symbol_idx].class == TokenType::Sys as i32 {
+            // System call
+            self.text.emit(Instruction::ALL[self.symbols[symbol_idx].value as usize]);
+        } else {
+            // Function call
+            self.function_address(Instruction::JSR, symbol_idx, line);
+        }
+
+        // Clean up arguments
+        if arg_count > 0 {
+            self.text.emit_with(Instruction::ADJ, arg_count);
+        }
+        self.expr_type = self.symbols[symbol_idx].type_;
+    }
+
+    /// Emit `IMM addr` for the data address `addr`, recording that the
+    /// operand needs relocating
+    fn data_address(&mut self, addr: i32) {
+        let operand = self.text.emit_with(Instruction::IMM, addr);
+        self.data_relocations.push(Relocation::Data(operand));
+    }
+
+    /// Emit `op` with the entry address of the function `symbol_idx` as its operand
+    ///
+    /// A function only declared so far gets a placeholder, filled in once
+    /// it is defined; `line` is where it is used, for the error if it never is.
+    fn function_address(&mut self, op: Instruction, symbol_idx: usize, line: i32) {
+        let operand = self.text.emit_with(op, self.symbols[symbol_idx].value);
+        if is_prototype(&self.symbols[symbol_idx]) {
+            self.forward_calls.push((operand, symbol_idx, line));
+        }
+    }
+
+    /// Finish the `next` step now that the operand it was waiting for is parsed
+    ///
+    /// `value` is the operand's constant value, as returned by [`C4::expression`].
+    fn resume(&mut self, next: Pending, value: i32, pending: &mut Vec<Pending>) -> Step {
+        if !matches!(next, Pending::Climb(..) | Pending::Argument { .. } | Pending::Paren) {
+            self.compile_stats.ast_nodes += 1;
+        }
+        match next {
+            Pending::Climb(level, start) => return self.climb(level, start, value, pending),
+            Pending::Argument { symbol, count, args_start, line } => {
+                self.text.emit(Instruction::PUSH);
+                if self.token != b')' as i32 {
+                    self.match_token(b',' as i32);
+                    if self.token != b')' as i32 && self.token != 0 {
+                        pending.push(Pending::Argument { symbol, count: count + 1, args_start, line });
+                        return Step::Parse(Assign);
+                    }
+                }
+                self.call(symbol, count + 1, args_start, line);
+            },
+            Pending::Paren => {
+                self.match_token(b')' as i32);
+                return Step::Done(value);
+            },
+            Pending::Cast(cast_type) => {
+                if cast_type == CHAR && self.expr_type != CHAR {
+                    self.narrow_to_char();
+                }
+                self.expr_type = cast_type;
+            },
+            Pending::Dereference => {
+                if self.expr_type < PTR {
+                    self.error("Invalid dereference");
+                    return Step::Done(INT);
+                }
+                self.expr_type -= PTR;
+
+                // Load the value
+                if self.expr_type == CHAR {
+                    self.text.emit(Instruction::LC);
+                } else {
+                    self.text.emit(Instruction::LI);
+                }
+            },
+            Pending::AddressOf => {
+                // Drop the load so the address stays in the accumulator
+                self.lvalue("address-of");
+                if self.text.len() >= 2 && self.text[self.text.len() - 2] == Instruction::LEA as i32 {
+                    self.frame_address_taken = true;
+                }
+                self.expr_type += PTR;
+            },
+            Pending::Not => {
+                self.text.emit(Instruction::PUSH);
+                self.text.emit_with(Instruction::IMM, 0);
+                self.text.emit(Instruction::EQ);
+                self.expr_type = INT;
+            },
+            Pending::BitNot => {
+                self.text.emit(Instruction::PUSH);
+                self.text.emit_with(Instruction::IMM, -1);
+                self.text.emit(Instruction::XOR);
+            },
+            Pending::Negate => {
+                self.text.emit(Instruction::MUL);
+                self.expr_type = INT;
+            },
+            Pending::Step(op) => self.step(op),
+            Pending::Sizeof => {
+                self.match_token(b')' as i32);
+
+                // Calculate size: all of an array if the operand is just its name
+                let array = self.array_operand.take().filter(|&(end, _)| end == self.text.len());
+                let bytes = array.and_then(|(_, idx)| self.array_bytes(self.symbols[idx].btype, self.symbols[idx].bvalue));
+                let size = match bytes {
+                    Some(bytes) => bytes,
+                    None if self.expr_type == CHAR => 1,
+                    None => self.vm_options.word_bytes(),
+                };
+                self.text.emit_with(Instruction::IMM, size);
+                self.expr_type = INT;
+            },
+            Pending::Assign { type_, start, from } => {
+                self.check_conversion(type_, start, from);
+                self.expr_type = type_;
+                self.store();
+            },
+            Pending::CompoundAssign { op, type_, start, line } => {
+                self.check_divisor(op, start, line);
+
+                // Pointer arithmetic
+                let scaled = op == Instruction::ADD || op == Instruction::SUB;
+                if scaled && type_ > PTR {
+                    self.text.emit(Instruction::PUSH);
+                    self.text.emit_with(Instruction::IMM, self.vm_options.word_bytes());
+                    self.text.emit(Instruction::MUL);
+                }
+
+                self.text.emit(op);
+                self.expr_type = type_;
+                self.store();
+            },
+            Pending::Then { else_, start } => {
+                let null = self.is_null_constant(start);
+
+                // Jump to end
+                let end = self.text.new_label();
+                self.text.jump_to(Instruction::JMP, end);
+
+                // Else expression, which may itself be a conditional: `a ? b : c ? d : e`
+                self.text.bind(else_);
+                self.match_token(b':' as i32);
+                pending.push(Pending::Else { end, start: self.text.here(), type_: self.expr_type, null });
+                return Step::Parse(Cond);
+            },
+            Pending::Else { end, start, type_, null } => {
+                let else_null = self.is_null_constant(start);
+                self.text.bind(end);
+                let checked = self.features.conditional_checks;
+                self.expr_type = self.operand_type("?:", checked, (type_, null), (self.expr_type, else_null));
+            },
+            Pending::Logical { skip } => {
+                // Either operand may end up in ax, normalize it to 0 or 1
+                self.text.bind(skip);
+                self.text.emit(Instruction::PUSH);
+                self.text.emit_with(Instruction::IMM, 0);
+                self.text.emit(Instruction::NE);
+                self.expr_type = INT;
+            },
+            Pending::Binary { op, start, line } => {
+                self.check_divisor(op, start, line);
+                self.text.emit(op);
+                self.expr_type = INT;
+            },
+            Pending::Compare { op, start, left } => {
+                let right = (self.expr_type, self.is_null_constant(start));
+                let operator = match op {
+                    Instruction::EQ => "==",
+                    Instruction::NE => "!=",
+                    Instruction::LT => "<",
+                    Instruction::GT => ">",
+                    Instruction::LE => "<=",
+                    _ => ">=",
+                };
+                self.operand_type(operator, self.features.comparison_checks, left, right);
+                self.text.emit(op);
+                self.expr_type = INT;
+            },
+            Pending::Add(type_) => {
+                // Pointer arithmetic: scale the offset by the element size
+                if type_ > PTR {
+                    self.text.emit(Instruction::PUSH);
+                    self.text.emit_with(Instruction::IMM, self.vm_options.word_bytes());
+                    self.text.emit(Instruction::MUL);
+                }
+
+                self.text.emit(Instruction::ADD);
+                self.expr_type = if type_ == CHAR { INT } else { type_ };
+            },
+            Pending::Sub(type_) => {
+                if type_ > PTR && type_ == self.expr_type {
+                    // Pointer difference: count elements, not bytes
+                    self.text.emit(Instruction::SUB);
+                    self.text.emit(Instruction::PUSH);
+                    self.text.emit_with(Instruction::IMM, self.vm_options.word_bytes());
+                    self.text.emit(Instruction::DIV);
+                    self.expr_type = INT;
+                } else {
+                    if type_ > PTR {
+                        self.text.emit(Instruction::PUSH);
+                        self.text.emit_with(Instruction::IMM, self.vm_options.word_bytes());
+                        self.text.emit(Instruction::MUL);
+                    }
+                    self.text.emit(Instruction::SUB);
+                    self.expr_type = if type_ == CHAR { INT } else { type_ };
+                }
+            },
+            Pending::Subscript(pointer_type, array) => self.end_subscript(pointer_type, array),
+        }
+        Step::Done(INT)
+    }
+
+    /// Apply the next binary or postfix operator that binds at least as tightly as `level`
+    ///
+    /// The operator's left operand, whose constant value is `value` and
+    /// whose code starts at `start`, is in the accumulator. Once no such
+    /// operator follows, the expression started at `level` is complete.
+    fn climb(&mut self, level: i32, start: usize, value: i32, pending: &mut Vec<Pending>) -> Step {
+        let Some(precedence) = self.precedence().filter(|&precedence| precedence >= level) else {
+            self.nesting -= 1;
+            return Step::Done(value);
+        };
+        let type_ = self.expr_type;
+        pending.push(Pending::Climb(level, start));
+
+        let (next, right) = match precedence {
+            Assign if self.token == b'=' as i32 => {
+                // Assignment
+                self.next();
+                self.lvalue("assignment");
+                self.text.emit(Instruction::PUSH);
+                (Pending::Assign { type_, start: self.text.len(), from: (self.line, self.column) }, Assign)
+            },
+            Assign => {
+                // Compound assignment: keep the address on the stack and load the old value
+                if !self.extension(self.features.compound_assignment, "compound assignment") {
+                    return Step::Done(type_);
+                }
+                // The lexer gives the operator of a compound assignment as its opcode
+                let op = Instruction::ALL[self.token_val as usize];
+                self.next();
+                let load = self.lvalue("assignment");
+                self.text.emit(Instruction::PUSH);
+                self.text.emit(load);
+                self.text.emit(Instruction::PUSH);
+                (Pending::CompoundAssign { op, type_, start: self.text.len(), line: self.line }, Assign)
+            },
+            Cond => {
+                // Conditional operator
+                self.next();
+
+                // Jump to else if false
+                let else_ = self.text.new_label();
+                self.text.jump_to(Instruction::BZ, else_);
+                (Pending::Then { else_, start: self.text.here() }, Assign)
+            },
+            Lor | Lan => {
+                // Logical operators: the left operand is already in ax, so branch on it
+                // directly and only evaluate the right operand when it decides the result
+                let (branch, right) = if precedence == Lan {
+                    (Instruction::BZ, Or)
+                } else {
+                    (Instruction::BNZ, Lan)
+                };
+                self.next();
+
+                // Skip the right operand once the result is known
+                let skip = self.text.new_label();
+                self.text.jump_to(branch, skip);
+                (Pending::Logical { skip }, right)
+            },
+            Or => (self.binary(Instruction::OR), Xor),
+            Xor => (self.binary(Instruction::XOR), And),
+            And => (self.binary(Instruction::AND), Eq),
+            Eq => (self.compare(Instruction::EQ, start), Lt),
+            Ne => (self.compare(Instruction::NE, start), Lt),
+            Lt => (self.compare(Instruction::LT, start), Shl),
+            Gt => (self.compare(Instruction::GT, start), Shl),
+            Le => (self.compare(Instruction::LE, start), Shl),
+            Ge => (self.compare(Instruction::GE, start), Shl),
+            Shl => (self.binary(Instruction::SHL), Add),
+            Shr => (self.binary(Instruction::SHR), Add),
+            Add | Sub => {
+                self.next();
+                self.text.emit(Instruction::PUSH);
+                (if precedence == Add { Pending::Add(type_) } else { Pending::Sub(type_) }, Mul)
+            },
+            Mul => (self.binary(Instruction::MUL), Inc),
+            Div => (self.binary(Instruction::DIV), Inc),
+            Mod => (self.binary(Instruction::MOD), Inc),
+            Inc | Dec => {
+                // Postfix operators: store the new value, then undo the step to yield the old one
+                let (step, undo) = if precedence == Inc {
+                    (Instruction::ADD, Instruction::SUB)
+                } else {
+                    (Instruction::SUB, Instruction::ADD)
+                };
+                self.next();
+                self.step(step);
+                self.text.emit(Instruction::PUSH);
+                self.text.emit_with(Instruction::IMM, self.step_size());
+                self.text.emit(undo);
+                return Step::Done(INT);
+            },
+            _ => match self.begin_subscript() {
+                Some((pointer_type, array)) => (Pending::Subscript(pointer_type, array), Assign),
+                None => return Step::Done(INT),
+            },
+        };
+        pending.push(next);
+        Step::Parse(right)
+    }
+
+    /// Precedence level of the current token as a binary or postfix operator
+    ///
+    /// The lexer returns single-character operators as their ASCII code and
+    /// the others as `TokenType` values, so both are mapped onto the level
+    /// constants here. Returns `None` if the token does not continue an expression.
+    fn precedence(&self) -> Option<i32> {
+        let level = match self.token {
+            t if t == b'=' as i32 || t == TokenType::Assign as i32 => Assign,
+            t if t == b'?' as i32 => Cond,
+            t if t == TokenType::Lor as i32 => Lor,
+            t if t == TokenType::Lan as i32 => Lan,
+            t if t == b'|' as i32 => Or,
+            t if t == b'^' as i32 => Xor,
+            t if t == b'&' as i32 => And,
+            t if t == TokenType::Eq as i32 => Eq,
+            t if t == TokenType::Ne as i32 => Ne,
+            t if t == b'<' as i32 => Lt,
+            t if t == b'>' as i32 => Gt,
+            t if t == TokenType::Le as i32 => Le,
+            t if t == TokenType::Ge as i32 => Ge,
+            t if t == TokenType::Shl as i32 => Shl,
+            t if t == TokenType::Shr as i32 => Shr,
+            t if t == b'+' as i32 => Add,
+            t if t == b'-' as i32 => Sub,
+            t if t == b'*' as i32 => Mul,
+            t if t == b'/' as i32 => Div,
+            t if t == b'%' as i32 => Mod,
+            t if t == TokenType::Inc as i32 => Inc,
+            t if t == TokenType::Dec as i32 => Dec,
+            t if t == b'[' as i32 => Brak,
+            _ => return None,
+        };
+        Some(level)
+    }
+
+    /// Start an integer binary operator, leaving its left operand pushed
+    ///
+    /// # Returns
+    ///
+    /// The step that applies `op` once the right operand is parsed
+    fn binary(&mut self, op: Instruction) -> Pending {
+        self.next();
+        self.text.emit(Instruction::PUSH);
+        Pending::Binary { op, start: self.text.len(), line: self.line }
+    }
+
+    /// Consume a comparison operator whose left operand, starting at
+    /// `start`, is in the accumulator and push that operand
+    ///
+    /// Pointers may be compared with pointers of the same type and with a
+    /// constant 0, as well as integers with integers.
+    fn compare(&mut self, op: Instruction, start: usize) -> Pending {
+        let left = (self.expr_type, self.is_null_constant(start));
+        self.next();
+        self.text.emit(Instruction::PUSH);
+        Pending::Compare { op, start: self.text.len(), left }
+    }
+
+    /// Reject a right operand of `op` that compiled to the constant 0
+    ///
+    /// A divisor that is the constant 0 is a compile error reported on the
+    /// line of the divisor, which started at `start` in the text on `line`,
+    /// rather than a trap when the program runs.
+    fn check_divisor(&mut self, op: Instruction, start: usize, line: i32) {
+        let zero = [Instruction::IMM as i32, 0];
+        if self.text.get(start..) == Some(&zero[..]) {
+            if op == Instruction::DIV {
+                self.error_at(line, "Division by zero");
+            } else if op == Instruction::MOD {
+                self.error_at(line, "Modulo by zero");
+            }
+        }
+    }
+
+    /// Turn the expression just compiled back into the address it was loaded from
+    ///
+    /// Every lvalue ends with the LI or LC that loads it; removing that load
+    /// leaves the address in the accumulator. Reports an error naming
+    /// `context` if the expression is not an lvalue, or is a const variable
+    /// and `context` is not taking its address.
+    ///
+    /// # Returns
+    ///
+    /// The load instruction that was removed
+    fn lvalue(&mut self, context: &str) -> Instruction {
+        let constant = self.const_operand.take().filter(|&(end, _)| end == self.text.len());
+        if let Some((_, symbol_idx)) = constant.filter(|_| context != "address-of") {
+            let message = format!("Cannot assign to const variable '{}'", self.symbols[symbol_idx].name);
+            self.error(&message);
+            return Instruction::LI;
+        }
+        match self.text.last().copied() {
+            Some(load) if load == Instruction::LI as i32 || load == Instruction::LC as i32 => {
+                self.text.truncate(self.text.len() - 1);
+                Instruction::ALL[load as usize]
+            },
+            _ => {
+                self.error(&format!("Bad lvalue in {}", context));
+                Instruction::LI
+            }
+        }
+    }
+
+    /// Convert the int in the accumulator to the char it would be stored
+    /// as, giving the value a load of that char would
+    fn narrow_to_char(&mut self) {
+        if self.vm_options.signed_char {
+            // Move the low byte to the top of the word and shift it back down
+            let shift = self.vm_options.word_bytes() * 8 - 8;
+            for op in [Instruction::SHL, Instruction::SHR] {
+                self.text.emit(Instruction::PUSH);
+                self.text.emit_with(Instruction::IMM, shift);
+                self.text.emit(op);
+            }
+        } else {
+            self.text.emit(Instruction::PUSH);
+            self.text.emit_with(Instruction::IMM, 0xFF);
+            self.text.emit(Instruction::AND);
+        }
+    }
+
+    /// Emit the store matching `expr_type` for the address pushed earlier
+    fn store(&mut self) {
+        if self.expr_type == CHAR {
+            self.text.emit(Instruction::SC);
+        } else {
+            self.text.emit(Instruction::SI);
+        }
+    }
+
+    /// Warn if storing the value just compiled in an lvalue of type `to`
+    /// is probably a mistake
+    ///
+    /// The value's code starts at `start` and its source at the line and
+    /// column `from`. An int stored in a char may change, unless it is a
+    /// constant that fits, and pointers and integers should only be mixed
+    /// through a cast, except for a null pointer constant or what malloc
+    /// returns, which C gives the type `void *`.
+    fn check_conversion(&mut self, to: i32, start: usize, from: (i32, i32)) {
+        let type_ = self.expr_type;
+        let constant = fold_constant(&self.text[start..], None, &self.vm_options).ok();
+        let integer = |type_: i32| type_ == CHAR || type_ == INT;
+        let (kind, message) = if to == CHAR && type_ == INT {
+            if constant.is_some_and(|value| (-128..=255).contains(&value)) {
+                return;
+            }
+            (WarningKind::Conversion, "Conversion from 'int' to 'char' may change the value".to_string())
+        } else if to >= PTR && integer(type_) {
+            let malloc = [Instruction::MALLOC as i32, Instruction::ADJ as i32, 1];
+            if constant == Some(0) || self.text.ends_with(&malloc) {
+                return;
+            }
+            (WarningKind::IntConversion, format!("Assignment to '{}' from '{}' makes a pointer from an integer without a cast",
+                    program::type_name(to), program::type_name(type_)))
+        } else if integer(to) && type_ >= PTR {
+            (WarningKind::IntConversion, format!("Assignment to '{}' from '{}' makes an integer from a pointer without a cast",
+                    program::type_name(to), program::type_name(type_)))
+        } else {
+            return;
+        };
+        let (end_line, end_column) = self.token_end;
+        let span = Span { line: from.0, column: from.1, end_line, end_column };
+        let file = self.included_file();
+        self.warnings.push(Warning { span, file, kind, message });
+    }
+
+    /// Amount `++` and `--` move a value of type `expr_type` by
+    fn step_size(&self) -> i32 {
+        if self.expr_type > PTR { self.vm_options.word_bytes() } else { 1 }
+    }
+
+    /// Add or subtract one step to the lvalue just compiled, storing and yielding the new value
+    fn step(&mut self, op: Instruction) {
+        let load = self.lvalue("increment or decrement");
+        self.text.emit(Instruction::PUSH);
+        self.text.emit(load);
+        self.text.emit(Instruction::PUSH);
+        self.text.emit_with(Instruction::IMM, self.step_size());
+        self.text.emit(op);
+        self.store();
+    }
+
+    /// Parse a subscript `[expr]` applied to the value in the accumulator
+    ///
+    /// The pointer is pushed, the index is scaled by the element size unless
+    /// the pointer is a `char *`, and the element is loaded. `expr_type` must
+    /// hold the pointer's type on entry and holds the element type on return.
+    pub fn subscript(&mut self) {
+        if let Some((pointer_type, array)) = self.begin_subscript() {
+            self.expression(Assign);
+            self.end_subscript(pointer_type, array);
+        }
+    }
+
+    /// Consume the '[' of a subscript and push the pointer
+    ///
+    /// # Returns
+    ///
+    /// The pointer's type and, if the pointer is just the name of an array,
+    /// the array's symbol; `None` (after reporting an error) if `expr_type`
+    /// is not a pointer
+    fn begin_subscript(&mut self) -> Option<(i32, Option<usize>)> {
+        let pointer_type = self.expr_type;
+        if pointer_type < PTR {
+            self.error("Pointer type expected in subscript");
+            return None;
+        }
+        let array = self.array_operand.take()
+            .filter(|&(end, _)| end == self.text.len())
+            .map(|(_, idx)| idx);
+
+        self.match_token(b'[' as i32);
+        self.text.emit(Instruction::PUSH);
+        Some((pointer_type, array))
+    }
+
+    /// Consume the ']' of a subscript whose index has been parsed and load the element
+    ///
+    /// With `bounds_checks` on, an index into `array` is checked against
+    /// the array's length first.
+    fn end_subscript(&mut self, pointer_type: i32, array: Option<usize>) {
+        if let Some(symbol_idx) = array.filter(|_| self.bounds_checks) {
+            let record = self.bounds_record(symbol_idx);
+            let operand = self.text.emit_with(Instruction::BOUND, record);
+            self.data_relocations.push(Relocation::Data(operand));
+        }
+        self.match_token(b']' as i32);
+
+        if pointer_type > PTR {
+            self.text.emit(Instruction::PUSH);
+            self.text.emit_with(Instruction::IMM, self.vm_options.word_bytes());
+            self.text.emit(Instruction::MUL);
+        }
+        self.text.emit(Instruction::ADD);
+
+        self.expr_type = pointer_type - PTR;
+        if self.expr_type == CHAR {
+            self.text.emit(Instruction::LC);
+        } else {
+            self.text.emit(Instruction::LI);
+        }
+    }
+
+    /// Whether the operand whose code starts at `start` and ends the text
+    /// segment is an integer constant 0, which may stand for a null pointer
+    fn is_null_constant(&self, start: usize) -> bool {
+        self.expr_type < PTR && fold_constant(&self.text[start..], None, &self.vm_options) == Ok(0)
+    }
+
+    /// Common type of two operands of `operator` that must agree, such as
+    /// those of `?:` or `==`, each given with whether it is a null pointer
+    /// constant
+    ///
+    /// Operands of the same type keep it, and a char with an int gives an
+    /// int. A pointer may be paired with a constant 0, which is then a null
+    /// pointer of its type. Any other pair is reported as an error, unless
+    /// the pair is not `checked`, when it gets the right operand's type, as
+    /// it does in c4.
+    fn operand_type(&mut self, operator: &str, checked: bool, (left, left_null): (i32, bool), (right, right_null): (i32, bool)) -> i32 {
+        match (left >= PTR, right >= PTR) {
+            _ if left == right => left,
+            (false, false) => INT,
+            (true, false) if right_null => left,
+            (false, true) if left_null => right,
+            _ if !checked => right,
+            _ => {
+                self.error(&format!("Incompatible operands of '{}': '{}' and '{}'",
+                                    operator, program::type_name(left), program::type_name(right)));
+                left
+            },
+        }
+    }
+
+    /// Whether an extension to the language of c4 may be used
+    ///
+    /// Reports an error naming `what` if `enabled`, one of the flags in
+    /// `features`, is off.
+    fn extension(&mut self, enabled: bool, what: &str) -> bool {
+        if !enabled {
+            self.error(&format!("Language feature not enabled: {}", what));
+        }
+        enabled
+    }
+
+    /// Parse a statement
+    ///
+    /// This function parses a statement, which can be an if statement,
+    /// while statement, return statement, block, local declaration or
+    /// expression statement.
+    pub fn statement(&mut self) {
+        if self.enter_nesting() {
+            self.parse_statement();
+            self.nesting -= 1;
+        }
+    }
+
+    /// Body of [`C4::statement`]
+    fn parse_statement(&mut self) {
+        if self.token == TokenType::If as i32 {
+            // If statement
+            self.match_token(TokenType::If as i32);
+            self.match_token(b'(' as i32);
+            self.expression(Assign);
+            self.match_token(b')' as i32);
+
+            // Jump to else if false
+            let else_ = self.text.new_label();
+            self.text.jump_to(Instruction::BZ, else_);
+
+            // Then statement
+            self.statement();
+
+            if self.token == TokenType::Else as i32 {
+                // Jump over the else statement
+                let end = self.text.new_label();
+                self.text.jump_to(Instruction::JMP, end);
+
+                self.text.bind(else_);
+                self.match_token(TokenType::Else as i32);
+                self.statement();
+                self.text.bind(end);
+            } else {
+                self.text.bind(else_);
+            }
+        } else if self.token == TokenType::While as i32 {
+            // While statement
+            self.match_token(TokenType::While as i32);
+
+            // Loop start
+            let (start, end) = (self.text.new_label(), self.text.new_label());
+            self.text.bind(start);
+            self.match_token(b'(' as i32);
+            self.expression(Assign);
+            self.match_token(b')' as i32);
+
+            // Jump to end if false
+            self.text.jump_to(Instruction::BZ, end);
+
+            // Body
+            self.statement();
+
+            // Jump back to start
+            self.text.jump_to(Instruction::JMP, start);
+
+            // End
+            self.text.bind(end);
+        } else if self.token == TokenType::Return as i32 {
+            // Return statement
+            self.match_token(TokenType::Return as i32);
+
+            let expr_start = self.text.len();
+            if self.token != b';' as i32 {
+                self.expression(Assign);
+            } else {
+                // For empty return, use 0 as the return value
+                self.text.emit_with(Instruction::IMM, 0);
+            }
+
+            self.match_token(b';' as i32);
+
+            if self.opt_level > 0 {
+                self.tail_call(expr_start);
+            }
+
+            self.text.emit(Instruction::LEV);
+        } else if self.token == b'{' as i32 {
+            // Block: declarations inside it go out of scope at the closing
+            // brace, and their slots can be reused by the blocks after it
+            self.match_token(b'{' as i32);
+            let scope = self.symbols.len();
+            let outer_scope = std::mem::replace(&mut self.scope_start, scope);
+            let outer_slots = self.local_slots;
+
+            while self.token != b'}' as i32 && self.token != 0 {
+                self.statement();
+            }
+
+            self.symbols.truncate(scope);
+            self.scope_start = outer_scope;
+            self.local_slots = outer_slots;
+            self.match_token(b'}' as i32);
+        } else if self.at_type() {
+            if self.extension(self.features.mixed_declarations, "declarations after the start of a function") {
+                self.local_declaration();
+            }
+        } else if self.token == b';' as i32 {
+            // Empty statement
+            self.match_token(b';' as i32);
+        } else {
+            // Expression statement
+            let start = self.text.len();
+            let from = (self.line, self.column);
+            self.expression(Assign);
+            self.discard_value(start, from);
+            self.match_token(b';' as i32);
+        }
+    }
+
+    /// Drop the code that only computes the value of the expression
+    /// statement compiled from `start`, since the value is not used
+    ///
+    /// An expression that does nothing else, such as `a == b`, is dropped
+    /// whole, with a warning about the source from `from`. A postfix `++`
+    /// or `--` loses the step that undoes the increment to give the old
+    /// value. What is dropped pushes and pops the stack in pairs, so the
+    /// stack is as balanced after the statement as before it.
+    fn discard_value(&mut self, start: usize, from: (i32, i32)) {
+        if self.error.is_some() || start == self.text.len() {
+            return;
+        }
+        let code = &self.text[start..];
+        if has_no_effect(code) {
+            let (end_line, end_column) = self.token_end;
+            let span = Span { line: from.0, column: from.1, end_line, end_column };
+            let file = self.included_file();
+            let message = "Statement has no effect".to_string();
+            self.warnings.push(Warning { span, file, kind: WarningKind::UnusedValue, message });
+            self.text.truncate(start);
+            self.data_relocations.retain(|relocation| !matches!(relocation, Relocation::Data(offset) if *offset >= start));
+            return;
+        }
+
+        // `PUSH; IMM n; ADD` or `SUB` after the store of `x++` or `x--`,
+        // unless a branch of the expression lands inside it
+        let Some(undo) = code.len().checked_sub(4) else {
+            return;
+        };
+        let instructions: Vec<DecodedInstr> = vm::decode(code).collect();
+        let tail: Vec<Instruction> = instructions.iter().rev().take(4).map(|instr| instr.op).collect();
+        let undoes_step = matches!(tail[..], [Instruction::ADD | Instruction::SUB, Instruction::IMM, Instruction::PUSH, Instruction::SI | Instruction::SC])
+            && instructions[instructions.len() - 3].pc == undo;
+        let lands_inside = instructions.iter()
+            .filter(|instr| matches!(instr.op, Instruction::JMP | Instruction::BZ | Instruction::BNZ))
+            .filter_map(|instr| instr.operand)
+            .any(|target| target as usize > start + undo && (target as usize) < self.text.len());
+        if undoes_step && !lands_inside {
+            self.text.truncate(start + undo);
+        }
+    }
+
+    /// Parse the base type of a declaration and any `*`s that follow it
+    ///
+    /// # Returns
+    ///
+    /// The declared type, or `None` (after reporting an error) if the
+    /// current token is not a type
+    fn declaration_type(&mut self) -> Option<i32> {
+        let mut type_ = self.base_type()?;
+        while self.token == b'*' as i32 {
+            self.next();
+            type_ += PTR;
+        }
+        Some(type_)
+    }
+
+    /// Whether the current token starts a type: `int`, `char` or `enum`
+    fn at_type(&self) -> bool {
+        [TokenType::Int, TokenType::Char, TokenType::Enum, TokenType::Const].iter().any(|&t| self.token == t as i32)
+    }
+
+    /// Parse `int`, `char` or an enum type, which is an `int`
+    ///
+    /// A `const` before the type is skipped here; `declarations` and
+    /// `local_declaration` look for it first, to keep the variables
+    /// declared from being assigned.
+    /// Globals are the only place c4 has enums, so `declarations` parses
+    /// theirs itself, and elsewhere they need `features.enum_types`.
+    ///
+    /// # Returns
+    ///
+    /// The type, or `None` (after reporting an error) if the current token
+    /// is not a type
+    fn base_type(&mut self) -> Option<i32> {
+        if self.token == TokenType::Const as i32 {
+            if !self.extension(self.features.const_qualifier, "const") {
+                return None;
+            }
+            self.next();
+        }
+        if self.token == TokenType::Enum as i32 {
+            if !self.extension(self.features.enum_types, "enum types") {
+                return None;
+            }
+            return self.enum_type();
+        }
+        let type_ = if self.token == TokenType::Int as i32 {
+            INT
+        } else if self.token == TokenType::Char as i32 {
+            CHAR
+        } else {
+            let message = format!("Type expected, got {}", self.token_name());
+            self.error(&message);
+            return None;
+        };
+        self.next();
+        Some(type_)
+    }
+
+    /// Parse `enum`, an optional tag and the list of its constants, which
+    /// may only be left out after a tag declared earlier
+    ///
+    /// Each constant is one more than the one before it unless it is given
+    /// a constant expression, and becomes an `int` named constant in the
+    /// current scope. Its symbol has the base class `Enum`, which tells it
+    /// apart from the builtin constants in diagnostics. Variables of an enum
+    /// type are plain `int`s.
+    ///
+    /// # Returns
+    ///
+    /// `INT`, or `None` after an error
+    fn enum_type(&mut self) -> Option<i32> {
+        self.next();
+        let tag = (self.token == TokenType::Id as i32)
+            .then(|| (self.current_name, String::from_utf8_lossy(&self.current_id).into_owned()));
+        if tag.is_some() {
+            self.next();
+        }
+        if self.token != b'{' as i32 {
+            match tag {
+                Some((id, _)) if self.enum_tags.contains(&id) => return Some(INT),
+                Some((_, name)) => self.error(&format!("Unknown enum '{}'", name)),
+                None => {
+                    let message = format!("Bad enum declaration at {}", self.token_name());
+                    self.error(&message);
+                },
+            }
+            return None;
+        }
+        if let Some((id, name)) = tag {
+            if !self.enum_tags.insert(id) {
+                self.error(&format!("Redefinition of enum '{}'", name));
+                return None;
+            }
+        }
+        self.next();
+
+        let mut value: i32 = 0;
+        while self.token != b'}' as i32 && self.token != 0 {
+            let (name, id, line) = self.declaration_name("enum constant")?;
+            if self.token == b'=' as i32 {
+                self.next();
+                value = self.constant_expression("Enum value")?;
+            }
+            self.symbols.push(Symbol {
+                token: TokenType::Id,
+                hash: 0,
+                name,
+                id,
+                class: TokenType::Num as i32,
+                type_: INT,
+                value,
+                bclass: TokenType::Enum as i32,
+                btype: 0,
+                bvalue: 0,
+                line,
+            });
+            value = value.wrapping_add(1);
+            if self.token != b',' as i32 {
+                break;
+            }
+            self.next();
+        }
+        self.match_token(b'}' as i32);
+        Some(INT)
+    }
+
+    /// Parse the `[length]` of an array declaration, if there is one
+    ///
+    /// # Returns
+    ///
+    /// The length, 0 if the declaration is not of an array, or `None`
+    /// after an error
+    fn array_length(&mut self) -> Option<i32> {
+        if self.token != b'[' as i32 {
+            return Some(0);
+        }
+        if !self.extension(self.features.arrays, "arrays") {
+            return None;
+        }
+        self.next();
+        let length = self.constant_expression("Array size")?;
+        if length <= 0 {
+            self.error("Array size must be positive");
+            return None;
+        }
+        self.match_token(b']' as i32);
+        Some(length)
+    }
+
+    /// Bytes taken by `length` elements of `element_type`, if that fits in an int
+    fn array_bytes(&self, element_type: i32, length: i32) -> Option<i32> {
+        let element_bytes = if element_type == CHAR { 1 } else { self.vm_options.word_bytes() };
+        length.checked_mul(element_bytes)
+    }
+
+    /// Add to the data segment what a `BOUND` check of a subscript of
+    /// `symbol_idx` on the current line reads
+    ///
+    /// That is the array's length and the line, a word each, then the
+    /// array's name, so a fault can say which subscript was out of bounds.
+    ///
+    /// # Returns
+    ///
+    /// The data address of the record
+    fn bounds_record(&mut self, symbol_idx: usize) -> i32 {
+        let record = self.data.len() as Word;
+        let word_bytes = self.vm_options.word_bytes() as Word;
+        self.data.resize(self.data.len() + 2 * word_bytes as usize, 0);
+        self.mem_store(record, self.symbols[symbol_idx].bvalue as Word, false);
+        self.mem_store(record + word_bytes, self.line as Word, false);
+        let name = self.symbols[symbol_idx].name.clone();
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.protect(record as usize, self.data.len());
+        record as i32
+    }
+
+    /// Mark bytes `start` to `end` of the data segment read-only, for runs
+    /// with `check_writes` set
+    ///
+    /// Ranges are added in address order; one that follows on from the last
+    /// is merged with it.
+    fn protect(&mut self, start: usize, end: usize) {
+        match self.read_only.last_mut() {
+            Some((_, last_end)) if *last_end == start => *last_end = end,
+            _ => self.read_only.push((start, end)),
+        }
+    }
+
+    /// Parse a constant expression, such as `COUNT * 2` or `sizeof(int)`
+    ///
+    /// The expression is compiled as usual, then its code, which may only
+    /// load literals and named constants, apply operators to them and
+    /// branch forward for `&&`, `||` and `?:`, is folded with the VM's ALU
+    /// and dropped. Constants therefore fold to exactly what the expression
+    /// computes at run time, except that dividing by zero or overflowing
+    /// the word size is an error.
+    ///
+    /// # Returns
+    ///
+    /// The value, or `None` (after reporting an error) if the expression
+    /// is not constant
+    fn constant_expression(&mut self, what: &str) -> Option<i32> {
+        self.constant_value(what).map(|(value, _)| value as i32)
+    }
+
+    /// Parse a constant expression, as `constant_expression` does
+    ///
+    /// # Returns
+    ///
+    /// The value and whether it is a data address, such as that of a
+    /// string literal, or `None` (after reporting an error) if the
+    /// expression is not constant
+    fn constant_value(&mut self, what: &str) -> Option<(Word, bool)> {
+        let start = self.text.len();
+        self.expression(Cond);
+        let value = fold_constant(&self.text[start..], Some(start), &self.vm_options);
+        self.text.truncate(start);
+        let dropped = |relocation: &Relocation| matches!(relocation, Relocation::Data(offset) if *offset > start);
+        let address = self.data_relocations.iter().any(dropped);
+        self.data_relocations.retain(|relocation| !dropped(relocation));
+        if self.error.is_some() {
+            return None;
+        }
+        match value {
+            Ok(value) => return Some((value, address)),
+            Err(Fold::NotConstant) => self.error(&format!("{} must be a constant expression", what)),
+            Err(Fold::DivisionByZero) => self.error(&format!("Division by zero in {}", what.to_lowercase())),
+            Err(Fold::Overflow) => self.error(&format!("Integer overflow in {}", what.to_lowercase())),
+        }
+        None
+    }
+
+    /// The spelling of the current token if it is a keyword
+    ///
+    /// The lexer leaves a keyword's text in `current_id`, as for identifiers.
+    fn keyword(&self) -> Option<String> {
+        let keywords = [
+            TokenType::Char, TokenType::Const, TokenType::Else, TokenType::Enum, TokenType::If,
+            TokenType::Int, TokenType::Return, TokenType::Sizeof, TokenType::While,
+        ];
+        keywords.iter()
+            .any(|&k| self.token == k as i32)
+            .then(|| String::from_utf8_lossy(&self.current_id).into_owned())
+    }
+
+    /// Parse the name of a declaration
+    ///
+    /// A name already declared in the innermost scope is an error that names
+    /// both lines, unless it is a function only declared so far, which may
+    /// be declared again or defined, or a global, which may be declared
+    /// again; inner scopes may still shadow outer ones, and a program may
+    /// shadow the builtins.
+    ///
+    /// # Returns
+    ///
+    /// The name, its interned ID and the line it was declared on
+    fn declaration_name(&mut self, what: &str) -> Option<(String, NameId, i32)> {
+        if let Some(keyword) = self.keyword() {
+            self.error(&format!("'{}' is a keyword and cannot be used as a {} name", keyword, what));
+            return None;
+        }
+        if self.token != TokenType::Id as i32 {
+            let message = format!("Bad {} declaration at {}", what, self.token_name());
+            self.error(&message);
+            return None;
+        }
+        let (id, line) = (self.current_name, self.line);
+        let previous = self.symbols[self.scope_start..].iter()
+            .find(|s| s.id == id)
+            .map(|s| (s.name.clone(), s.line, is_prototype(s), s.class == TokenType::Glo as i32));
+        let name = String::from_utf8_lossy(&self.current_id).into_owned();
+        self.next();
+        if let Some((name, previous_line, prototype, global)) = previous {
+            let function = self.token == b'(' as i32;
+            if !(prototype && function || global && !function) {
+                let message = format!("Redefinition of '{}' (previously declared on line {})", name, previous_line);
+                self.error_at(line, &message);
+                return None;
+            }
+        }
+        Some((name, id, line))
+    }
+
+    /// Parse a local declaration such as `int a, *p = &a, buf[8];`
+    ///
+    /// Each variable gets its own stack slots below the ones allocated so far
+    /// in the function. An array takes as many words as its elements need and
+    /// its symbol records the element type in `btype` and the count in
+    /// `bvalue`; the array name has pointer type and evaluates to the address
+    /// of the first element.
+    fn local_declaration(&mut self) {
+        let is_const = self.token == TokenType::Const as i32;
+        let Some(base_type) = self.base_type() else {
+            return;
+        };
+        if self.token == b';' as i32 {
+            // Only declares an enum
+            self.next();
+            return;
+        }
+
+        loop {
+            let mut type_ = base_type;
+            while self.token == b'*' as i32 {
+                self.next();
+                type_ += PTR;
+            }
+            let Some((name, id, line)) = self.declaration_name("local") else {
+                return;
+            };
+
+            let Some(length) = self.array_length() else {
+                return;
+            };
+            let word_bytes = self.vm_options.word_bytes();
+            let words = if length > 0 {
+                self.array_bytes(type_, length).map(|bytes| (bytes - 1) / word_bytes + 1)
+            } else {
+                Some(1)
+            };
+            // The frame must fit what ENT can reserve, or the declaration is
+            // where the program goes wrong rather than the function's end
+            let slots = words.and_then(|words| self.local_slots.checked_add(words));
+            let Some(slots) = slots.filter(|&slots| slots <= verify::MAX_FRAME_WORDS) else {
+                self.error_at(line, "Array too large");
+                return;
+            };
+            self.local_slots = slots;
+            self.frame_slots = self.frame_slots.max(slots);
+
+            // The lowest slot holds the variable (or the first element)
+            let value = local_offset(self.local_slots);
+            self.symbols.push(Symbol {
+                token: TokenType::Id,
+                hash: 0,
+                name,
+                id,
+                class: TokenType::Loc as i32,
+                type_: if length > 0 { type_ + PTR } else { type_ },
+                value,
+                bclass: if is_const && length == 0 && type_ == base_type { TokenType::Const as i32 } else { 0 },
+                btype: if length > 0 { type_ } else { 0 },
+                bvalue: length,
+                line,
+            });
+
+            // Initializer
+            if self.token == b'=' as i32 {
+                if !self.extension(self.features.initializers, "initializers") {
+                    return;
+                }
+                if length > 0 {
+                    self.error("Array initializers are not supported");
+                    return;
+                }
+                self.next();
+                self.text.emit_with(Instruction::LEA, value);
+                self.text.emit(Instruction::PUSH);
+                let (start, from) = (self.text.len(), (self.line, self.column));
+                self.expression(Assign);
+                self.check_conversion(type_, start, from);
+                self.expr_type = type_;
+                self.store();
+            }
+
+            if self.token != b',' as i32 {
+                break;
+            }
+            self.next();
+        }
+        self.match_token(b';' as i32);
+    }
+
+    /// Turn a call in tail position into a frame-reusing jump
+    ///
+    /// Called after the expression of a `return` statement has been emitted.
+    /// If that expression ended with `JSR f; ADJ n`, the call's result is the
+    /// return value, so the sequence is rewritten in place to `TLEV n; JMP f`.
+    /// TLEV moves the outgoing arguments over the current frame's arguments
+    /// and restores the caller's bp, leaving the stack exactly as if our caller
+    /// had called `f` directly. This requires `f` to take as many argument
+    /// slots as the current function, so other tail calls keep using JSR.
+    ///
+    /// The frame is gone once `f` runs, so a function that has taken the
+    /// address of a parameter or local, or passes `f` an address in its
+    /// frame, keeps the call.
+    fn tail_call(&mut self, expr_start: usize) {
+        let len = self.text.len();
+        if self.param_count == 0 || len < expr_start + 4 || self.frame_address_taken {
+            return;
+        }
+
+        // Make sure the JSR is an instruction, not an operand that happens to match
+        let call = len - 4;
+        let starts = optimizer::instruction_starts(&self.text[expr_start..]);
+        if starts.contains(&(call - expr_start)) &&
+           self.text[call] == Instruction::JSR as i32 &&
+           self.text[call + 2] == Instruction::ADJ as i32 &&
+           self.text[call + 3] == self.param_count &&
+           !frame_address_escapes(&self.text[expr_start..call]) {
+            let jump = self.text.tail_call(call, self.param_count);
+            if let Some(forward) = self.forward_calls.iter_mut().find(|(operand, ..)| *operand == call + 1) {
+                forward.0 = jump;
+            }
+        }
+    }
+
+    /// Parse a function definition
+    ///
+    /// This function parses a function definition, including the return type,
+    /// function name, parameters, and function body.
+    pub fn function(&mut self) {
+        let Some(type_) = self.declaration_type() else {
+            return;
+        };
+        let Some((name, id, line)) = self.declaration_name("function") else {
+            return;
+        };
+        self.function_definition(name, id, line, type_);
+    }
+
+    /// Parse the parameters and body of a function whose name has been read
+    ///
+    /// The frame follows c4: arguments are pushed left to right, so parameter
+    /// `k` of `n` lives at `bp + n + 2 - k`, above the saved bp and return
+    /// address, and locals are allocated downwards from `bp`.
+    ///
+    /// A `;` instead of a body makes this a prototype such as
+    /// `int add(int, int);`, whose parameters need no names. The function
+    /// may then be called before it is defined, and its definition, and any
+    /// further prototype, must agree with it.
+    fn function_definition(&mut self, name: String, id: NameId, line: i32, type_: i32) {
+        // A function declared before keeps its symbol, so that the calls
+        // compiled since can be pointed at the definition
+        let declared = (self.scope_start..self.symbols.len()).find(|&i| self.symbols[i].id == id);
+        let symbol_idx = declared.unwrap_or(self.symbols.len());
+        if declared.is_none() {
+            self.symbols.push(Symbol {
+                token: TokenType::Id,
+                hash: 0,
+                name,
+                id,
+                class: TokenType::Fun as i32,
+                type_,
+                value: -1,
+                bclass: 0,
+                btype: 0,
+                bvalue: 0,
+                line,
+            });
+        }
+
+        // Parameters and locals share the function's scope, which ends with its body
+        let scope = self.symbols.len();
+        let outer_scope = std::mem::replace(&mut self.scope_start, scope);
+
+        self.match_token(b'(' as i32);
+        let mut param_types = Vec::new();
+        let mut param_symbols = Vec::new();
+        let mut unnamed = None;
+        while self.token != b')' as i32 && self.token != 0 {
+            let void = self.current_id == b"void";
+            let Some(mut param_type) = self.declaration_type() else {
+                return;
+            };
+
+            // `(void)` declares no parameters
+            if void && param_type == CHAR && param_types.is_empty() && self.token == b')' as i32 {
+                break;
+            }
+
+            let name = if self.token == b',' as i32 || self.token == b')' as i32 || self.token == b'[' as i32 {
+                unnamed = unnamed.or(Some(param_types.len() + 1));
+                None
+            } else {
+                let Some(name) = self.declaration_name("parameter") else {
+                    return;
+                };
+                Some(name)
+            };
+
+            // An array parameter is a pointer
+            if self.token == b'[' as i32 {
+                if !self.extension(self.features.arrays, "arrays") {
+                    return;
+                }
+                self.next();
+                self.match_token(b']' as i32);
+                param_type += PTR;
+            }
+
+            if let Some((param_name, param_id, param_line)) = name {
+                param_symbols.push((self.symbols.len(), param_types.len() as i32));
+                self.symbols.push(Symbol {
+                    token: TokenType::Id,
+                    hash: 0,
+                    name: param_name,
+                    id: param_id,
+                    class: TokenType::Loc as i32,
+                    type_: param_type,
+                    value: 0,  // Frame offset, once the parameters are counted
+                    bclass: 0,
+                    btype: 0,
+                    bvalue: 0,
+                    line: param_line,
+                });
+            }
+            param_types.push(param_type);
+
+            if self.token == b',' as i32 {
+                self.next();
+            }
+        }
+        self.match_token(b')' as i32);
+        for (param_idx, index) in param_symbols {
+            self.symbols[param_idx].value = param_offset(index, param_types.len() as i32);
+        }
+
+        if declared.is_some() && !self.matches_prototype(symbol_idx, type_, &param_types, line) {
+            return;
+        }
+        if self.token == b';' as i32 {
+            if !self.extension(self.features.prototypes, "function prototypes") {
+                return;
+            }
+            self.next();
+            self.prototypes.insert(id, param_types);
+            self.symbols.truncate(scope);
+            self.scope_start = outer_scope;
+            return;
+        }
+        if let Some(index) = unnamed {
+            self.error(&format!("Parameter {} of '{}' has no name", index, self.symbols[symbol_idx].name));
+            return;
+        }
+
+        // Record the entry point before the body so the function can call
+        // itself, and point the calls made before it at it
+        let entry = self.text.len();
+        self.symbols[symbol_idx].value = entry as i32;
+        self.symbols[symbol_idx].line = line;
+        self.symbols[symbol_idx].bvalue = param_types.len() as i32;
+        self.forward_calls.retain(|&(operand, callee, _)| {
+            if callee == symbol_idx {
+                self.text.set_operand(operand, entry as i32);
+            }
+            callee != symbol_idx
+        });
+        let param_count = param_types.len() as i32;
+
+        self.param_count = param_count;
+        self.local_slots = 0;
+        self.frame_slots = 0;
+        self.frame_address_taken = false;
+
+        // Prologue, patched with the number of local slots once the body is done
+        self.text.emit_with(Instruction::ENT, 0);
+
+        self.match_token(b'{' as i32);
+
+        // c4 only has declarations at the start of a function
+        if !self.features.mixed_declarations {
+            while self.at_type() {
+                self.local_declaration();
+            }
+        }
+
+        while self.token != b'}' as i32 && self.token != 0 {
+            self.statement();
+        }
+        self.text.set_operand(entry + 1, self.frame_slots);
+        let name = self.symbols[symbol_idx].name.clone();
+        self.compile_stats.frame_slots.push((name, self.frame_slots));
+
+        // Return 0 if control can fall off the end of the body
+        let starts = optimizer::instruction_starts(&self.text[entry..]);
+        if starts.last().map(|&pc| self.text[entry + pc]) != Some(Instruction::LEV as i32) {
+            self.text.emit_with(Instruction::IMM, 0);
+            self.text.emit(Instruction::LEV);
+        }
+
+        self.match_token(b'}' as i32);
+        self.symbols.truncate(scope);
+        self.scope_start = outer_scope;
+        self.functions_compiled += 1;
+        self.report_progress();
+    }
+
+    /// Check a declaration of the function `symbol_idx` on `line`, returning
+    /// `type_` and taking `param_types`, against its prototype
+    ///
+    /// # Returns
+    ///
+    /// false (after reporting an error) if they differ
+    fn matches_prototype(&mut self, symbol_idx: usize, type_: i32, param_types: &[i32], line: i32) -> bool {
+        let symbol = &self.symbols[symbol_idx];
+        let declared = self.prototypes.get(&symbol.id).map_or(&[][..], Vec::as_slice);
+        let difference = if symbol.type_ != type_ {
+            format!("returns '{}', not '{}'", program::type_name(type_), program::type_name(symbol.type_))
+        } else if param_types.len() != declared.len() {
+            let plural = if param_types.len() == 1 { "" } else { "s" };
+            format!("takes {} parameter{}, not {}", param_types.len(), plural, declared.len())
+        } else if let Some(k) = (0..declared.len()).find(|&k| param_types[k] != declared[k]) {
+            format!("parameter {} is '{}', not '{}'", k + 1, program::type_name(param_types[k]), program::type_name(declared[k]))
+        } else {
+            return true;
+        };
+        let message = format!("'{}' {} as declared on line {}", symbol.name, difference, symbol.line);
+        self.error_at(line, &message);
+        false
+    }
+
+    /// Parse the program
+    ///
+    /// This function parses the entire program, which is a sequence of global
+    /// variable declarations and function definitions.
+    pub fn program(&mut self) {
+        self.next(); // Get first token
+        self.declarations();
+    }
+
+    /// Parse global declarations from the current token to the end of input
+    fn declarations(&mut self) {
+        // Globals may shadow the builtins but not each other
+        self.scope_start = self.symbols.len();
+
+        while self.token != 0 {
+            if !self.at_type() {
+                let message = format!("Bad global declaration at {}", self.token_name());
+                self.error(&message);
+                return;
+            }
+            let is_const = self.token == TokenType::Const as i32;
+            let base_type = if self.token == TokenType::Enum as i32 { self.enum_type() } else { self.base_type() };
+            let Some(base_type) = base_type else {
+                return;
+            };
+            if self.token == b';' as i32 {
+                // Only declares an enum
+                self.next();
+                continue;
+            }
+
+            loop {
+                let mut var_type = base_type;
+                while self.token == b'*' as i32 {
+                    self.next();
+                    var_type += PTR;
+                }
+                let Some((name, id, line)) = self.declaration_name("global") else {
+                    return;
+                };
+
+                // Function definition
+                if self.token == b'(' as i32 {
+                    self.function_definition(name, id, line, var_type);
+                    break;
+                }
+
+                let Some(length) = self.array_length() else {
+                    return;
+                };
+
+                // A global declared again is the same variable, as a C
+                // tentative definition is, so it must have the same type
+                let declared = (self.scope_start..self.symbols.len()).find(|&i| self.symbols[i].id == id);
+                let type_ = if length > 0 { var_type + PTR } else { var_type };
+                if let Some(previous) = declared.map(|i| &self.symbols[i]) {
+                    if previous.type_ != type_ || previous.bvalue != length {
+                        let message = format!("Conflicting types for '{}' (previously declared on line {})", previous.name, previous.line);
+                        self.error_at(line, &message);
+                        return;
+                    }
+                }
+
+                // Reserve a word-aligned slot in the data segment, or as many as the array needs
+                let addr = match declared {
+                    Some(i) => self.symbols[i].value as usize,
+                    None => {
+                        let word_bytes = self.vm_options.word_bytes() as usize;
+                        let bytes = if length > 0 { self.array_bytes(var_type, length) } else { Some(word_bytes as i32) };
+                        let addr = self.data.len().next_multiple_of(word_bytes);
+                        let end = bytes.map(|bytes| addr + (bytes as usize).next_multiple_of(word_bytes));
+                        let Some(end) = end.filter(|&end| end as Word <= STACK_BASE) else {
+                            self.error("Array too large");
+                            return;
+                        };
+                        self.data.resize(end, 0);
+                        // `const char *` points at const chars, but may itself be assigned
+                        if is_const && var_type == base_type {
+                            self.protect(addr, end);
+                        }
+                        addr
+                    },
+                };
+
+                // Constant initializer, of which a global may only have one
+                if self.token == b'=' as i32 {
+                    if !self.extension(self.features.initializers, "initializers") {
+                        return;
+                    }
+                    if length > 0 {
+                        self.error("Array initializers are not supported");
+                        return;
+                    }
+                    if let Some(&defined) = self.initialized_globals.get(&id) {
+                        self.error_at(line, &format!("Redefinition of '{}' (previously defined on line {})", name, defined));
+                        return;
+                    }
+                    self.next();
+                    let Some((value, address)) = self.constant_value("Initializer") else {
+                        return;
+                    };
+                    self.mem_store(addr as Word, value, var_type == CHAR);
+                    if address && var_type != CHAR {
+                        self.data_relocations.push(Relocation::DataWord(addr));
+                    }
+                    self.initialized_globals.insert(id, line);
+                }
+
+                // Add variable to symbol table
+                if declared.is_none() {
+                    self.symbols.push(Symbol {
+                        token: TokenType::Id,
+                        hash: 0,
+                        name,
+                        id,
+                        class: TokenType::Glo as i32,
+                        type_,
+                        value: addr as i32,
+                        bclass: if is_const && length == 0 && var_type == base_type { TokenType::Const as i32 } else { 0 },
+                        btype: if length > 0 { var_type } else { 0 },
+                        bvalue: length,
+                        line,
+                    });
+                }
+
+                if self.token != b',' as i32 {
+                    self.match_token(b';' as i32);
+                    break;
+                }
+                self.next();
+            }
+        }
+
+        // A function may be declared and never defined, as long as it is not used
+        if let Some(&(_, symbol_idx, line)) = self.forward_calls.first() {
+            let message = format!("'{}' is called but never defined", self.symbols[symbol_idx].name);
+            self.error_at(line, &message);
+            return;
+        }
+        self.symbols.retain(|symbol| !is_prototype(symbol));
+    }
+
+    /// Run the virtual machine
+    ///
+    /// This function runs the virtual machine with the given entry point,
+    /// command line arguments, and environment.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - The entry point (address) to start execution from
+    /// * `argc` - The number of command line arguments
+    /// * `argv` - The command line arguments
+    ///
+    /// An entry function with two parameters, such as `main(int argc, char
+    /// **argv)`, is given `argv` as an array of strings in the data segment,
+    /// which only lasts for the run; one with fewer is given just `argc`.
+    ///
+    /// A fault is reported on the error stream, unless `on_trap` is set, in
+    /// which case it is only given to that.
+    ///
+    /// # Returns
+    ///
+    /// The exit code of the program, or `Error::Trap` with the fault it
+    /// stopped at, `Error::Hang` or `Error::Cancelled`
+    pub fn run(&mut self, entry: i32, argc: i32, argv: Vec<String>) -> Result<i32> {
+        // The machine borrows the segments for the run and the I/O streams
+        // through the host, and hands everything back when it ends
+        let functions: Vec<(String, i32)> = self.symbols.iter()
+            .filter(|s| s.class == TokenType::Fun as i32)
+            .map(|s| (s.name.clone(), s.value))
+            .collect();
+        let frame_depths = if self.check_stack { self.frame_depths(&functions) } else { Vec::new() };
+        let read_only = if self.check_writes { self.read_only.clone() } else { Vec::new() };
+        let parameters = self.symbols.iter()
+            .find(|s| s.class == TokenType::Fun as i32 && s.value == entry)
+            .map_or(1, |s| s.bvalue);
+        let data_len = self.data.len();
+        let mut args = vec![argc as Word];
+        if parameters >= 2 {
+            args.push(self.push_argv(&argv));
+        }
+
+        let mut host = C4Host {
+            output_sink: &mut self.output_sink,
+            error_sink: &mut self.error_sink,
+            input_source: &mut self.input_source,
+            captured_output: &mut self.captured_output,
+            captured_error: &mut self.captured_error,
+            report_faults: self.on_trap.is_none(),
+        };
+        let mut machine = Machine::new(mem::take(&mut self.text).into_text(), mem::take(&mut self.data), self.vm_options, &mut host);
+        machine.stack = mem::take(&mut self.stack);
+        machine.debug = self.debug;
+        machine.functions = functions;
+        machine.inline_sites = self.inline_sites.clone();
+        machine.cancel = self.cancel.clone();
+        machine.frame_depths = frame_depths;
+        machine.read_only = read_only;
+
+        let exit_code = machine.run_with_args(entry, &args);
+        let fault = machine.runtime_error.take();
+        if let (Some(error), Some(on_trap)) = (&fault, &mut self.on_trap) {
+            on_trap(error);
+        }
+        let hang = machine.hang.take();
+        self.text = machine.text.into();
+        self.data = machine.data;
+        self.data.truncate(data_len);
+        self.stack = machine.stack;
+        (self.pc, self.bp, self.sp, self.ax, self.ax_float) = (machine.pc, machine.bp, machine.sp, machine.ax, machine.ax_float);
+        self.cycle = machine.cycle;
+        self.vm_stats = machine.vm_stats;
+        self.heap_profile = machine.heap_profile;
+        if let Some(error) = fault {
+            return Err(Error::Trap(error));
+        }
+        if let Some(hang) = hang {
+            return Err(Error::Hang(hang));
+        }
+        if exit_code == -2 && self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(exit_code)
+    }
+
+    /// Frame depth of each instruction of the text segment, for
+    /// `check_stack`; none at all if the text does not verify
+    fn frame_depths(&self, functions: &[(String, i32)]) -> Vec<Option<i32>> {
+        let mut text = self.text.to_vec();
+        if self.vm_options.position_independent {
+            optimizer::to_absolute(&mut text);
+        }
+        verify::frame_depths(&text, functions).unwrap_or_default()
+    }
+
+    /// Append `argv` to the data segment as NUL-terminated strings followed
+    /// by a word-aligned array of their addresses
+    ///
+    /// # Returns
+    ///
+    /// The address of the array
+    fn push_argv(&mut self, argv: &[String]) -> Word {
+        let mut addrs = Vec::with_capacity(argv.len());
+        for arg in argv {
+            addrs.push(self.data.len() as Word);
+            self.data.extend_from_slice(arg.as_bytes());
+            self.data.push(0);
+        }
+        let word_bytes = self.vm_options.word_bytes() as usize;
+        let array = self.data.len().next_multiple_of(word_bytes);
+        self.data.resize(array + (addrs.len() + 1) * word_bytes, 0);
+        for (i, &addr) in addrs.iter().enumerate() {
+            self.mem_store((array + i * word_bytes) as Word, addr, false);
+        }
+        array as Word
+    }
+
+    /// Byte address of a stack slot
+    pub fn stack_addr(&self, slot: i32) -> Word {
+        self.vm_options.stack_addr(slot)
+    }
+
+    /// Load a word or byte from VM memory; see [`vm::load`]
+    pub fn mem_load(&self, addr: Word, char: bool) -> Option<Word> {
+        vm::load(&self.data, &self.stack, &self.vm_options, addr, char)
+    }
+
+    /// Store a word or byte in VM memory; see [`vm::store`]
+    pub fn mem_store(&mut self, addr: Word, value: Word, char: bool) -> Option<()> {
+        vm::store(&mut self.data, &mut self.stack, &self.vm_options, addr, value, char)
+    }
+
+    /// Compile and run a C program
+    ///
+    /// This function compiles the given C source code and runs the resulting
+    /// program with the given command line arguments.
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - The C source code to compile
+    /// * `argc` - The number of command line arguments
+    /// * `argv` - The command line arguments
+    ///
+    /// # Returns
+    ///
+    /// The exit code of the program
+    pub fn compile_and_run(&mut self, source: &str, debug: i32, args: Vec<String>) -> i32 {
+        self.reset();
+        self.src = source.as_bytes().to_vec();
+        self.build_and_run(debug, args)
+    }
+
+    /// Compile and run a C program streamed from `source`
+    ///
+    /// The source is read in chunks as the lexer reaches them, and chunks
+    /// it has finished with are dropped, so a large input never has to be
+    /// held in memory in full. Line numbers in errors count from the start
+    /// of the stream as usual.
+    pub fn compile_and_run_reader(&mut self, source: impl Read + 'static, debug: i32, args: Vec<String>) -> i32 {
+        self.reset();
+        self.source_reader = Some(Box::new(source));
+        self.build_and_run(debug, args)
+    }
+
+    /// Compile a C program without running it
+    ///
+    /// The returned program can be run any number of times, with
+    /// [`Program::run`] or [`C4::run_program`].
+    ///
+    /// Compilation is deterministic: the same source and settings give the
+    /// same segments and symbol table, byte for byte, whatever the host,
+    /// the paths headers were found at, or what the compiler did before.
+    /// Programs and their images can therefore be cached and builds
+    /// reproduced.
+    pub fn compile(&mut self, source: &str) -> Result<Program> {
+        self.reset();
+        self.src = source.as_bytes().to_vec();
+        self.build()?;
+        Ok(self.to_program())
+    }
+
+    /// Compile a C program streamed from `source`, as
+    /// [`C4::compile_and_run_reader`] reads it, without running it
+    pub fn compile_reader(&mut self, source: impl Read + 'static) -> Result<Program> {
+        self.reset();
+        self.source_reader = Some(Box::new(source));
+        self.build()?;
+        Ok(self.to_program())
+    }
+
+    /// Compile `source` and give back the code of the function it defines,
+    /// for testing the code generator without running a program
+    ///
+    /// The source may declare globals and other functions for the function
+    /// to use; the one whose code is given back is the last defined. The
+    /// code is as generated, neither optimized nor made position-independent,
+    /// so jump targets are text addresses and `pc` is where each
+    /// instruction is in the text segment.
+    ///
+    /// # Returns
+    ///
+    /// `Error::Compile` if the source does not compile or defines no function
+    pub fn compile_function(&mut self, source: &str) -> Result<Vec<DecodedInstr>> {
+        let opt_level = mem::replace(&mut self.opt_level, 0);
+        let position_independent = mem::replace(&mut self.vm_options.position_independent, false);
+        let compiled = self.compile(source);
+        self.opt_level = opt_level;
+        self.vm_options.position_independent = position_independent;
+        compiled?;
+
+        let Some(entry) = self.symbols.iter()
+            .filter(|s| s.class == TokenType::Fun as i32 && !is_prototype(s))
+            .map(|s| s.value as usize)
+            .max() else {
+            return Err(Error::Compile("no function is defined".to_string()));
+        };
+        Ok(decode(&self.text).filter(|instruction| instruction.pc >= entry).collect())
+    }
+
+    /// Evaluate a standalone C expression, such as `3*(4+5)`
+    ///
+    /// Builtins such as `abs` may be called. The value is whatever the
+    /// expression leaves in the accumulator, truncated to an `i32`.
+    pub fn eval(&mut self, expression: &str) -> Result<i32> {
+        self.eval_with(expression, &[])
+    }
+
+    /// Evaluate a C expression in which each of `variables` is an `int`
+    /// global holding the given value
+    ///
+    /// The expression may assign to the variables, but the values passed
+    /// in are not changed.
+    pub fn eval_with(&mut self, expression: &str, variables: &[(&str, i32)]) -> Result<i32> {
+        self.reset();
+        self.src = expression.as_bytes().to_vec();
+        self.init_builtins();
+
+        // Variables may shadow the builtins but not each other
+        self.scope_start = self.symbols.len();
+        let word_bytes = self.vm_options.word_bytes() as usize;
+        for &(name, value) in variables {
+            let id = self.names.intern(name.as_bytes());
+            if self.symbols[self.scope_start..].iter().any(|s| s.id == id) {
+                return Err(Error::Compile(format!("Variable '{}' is bound twice", name)));
+            }
+            let addr = self.data.len();
+            self.data.resize(addr + word_bytes, 0);
+            self.mem_store(addr as Word, value as Word, false);
+            self.symbols.push(Symbol {
+                token: TokenType::Id,
+                hash: 0,
+                name: name.to_string(),
+                id,
+                class: TokenType::Glo as i32,
+                type_: INT,
+                value: addr as i32,
+                bclass: 0,
+                btype: 0,
+                bvalue: 0,
+                line: 0,
+            });
+        }
+
+        // Compile the expression as the body of a function taking no arguments
+        self.next();
+        self.text.emit_with(Instruction::ENT, 0);
+        self.expression(Assign);
+        if self.token != 0 {
+            let message = format!("Expected end of expression, got {}", self.token_name());
+            self.error(&message);
+        }
+        if let Some(message) = &self.error {
+            return Err(Error::Compile(message.clone()));
+        }
+        self.text.emit(Instruction::LEV);
+        if self.vm_options.position_independent {
+            optimizer::to_pc_relative(self.text.as_mut_slice());
+        }
+
+        self.run(0, 0, Vec::new())
+    }
+
+    /// Evaluate a constant expression, such as `(1 << 4) - 1` or
+    /// `sizeof(int) * 8`, without running anything
+    ///
+    /// The expression is folded as array sizes and enum values are, with
+    /// this compiler's word size and macros, and may use the builtin
+    /// constants.
+    ///
+    /// # Returns
+    ///
+    /// `Error::Compile` if the expression is not constant, divides by zero
+    /// or overflows a word
+    pub fn const_eval(&mut self, expression: &str) -> Result<i64> {
+        self.reset();
+        self.src = expression.as_bytes().to_vec();
+        self.init_builtins();
+        self.next();
+        let value = self.constant_value("Value");
+        if value.is_some() && self.token != 0 {
+            let message = format!("Expected end of expression, got {}", self.token_name());
+            self.error(&message);
+        }
+        match (value, &self.error) {
+            (Some((value, _)), None) => Ok(value),
+            (_, error) => Err(Error::Compile(error.clone().unwrap_or_default())),
+        }
+    }
+
+    /// Run `main` of a compiled program with `args`
+    ///
+    /// Uses this compiler's VM settings and I/O, except that the word size
+    /// and the signedness of chars are the ones the program was compiled
+    /// for. Whatever the compiler held
+    /// before is replaced by the program.
+    pub fn run_program(&mut self, program: &Program, args: Vec<String>) -> Result<RunOutcome> {
+        let entry = program.entry().ok_or(Error::NoMain)?;
+        self.run_program_at(program, entry, args)
+    }
+
+    /// Run the function `name` of a compiled program with `args`, as
+    /// [`C4::run_program`] runs `main`
+    pub fn run_program_from(&mut self, program: &Program, name: &str, args: Vec<String>) -> Result<RunOutcome> {
+        let entry = program.function_entry(name).ok_or_else(|| Error::NoFunction(name.to_string()))?;
+        self.run_program_at(program, entry, args)
+    }
+
+    /// Run a compiled program from the text address `entry`
+    fn run_program_at(&mut self, program: &Program, entry: i32, args: Vec<String>) -> Result<RunOutcome> {
+        self.text = program.text.clone().into();
+        self.data = program.data.clone();
+        self.symbols = program.symbols.clone();
+        self.read_only = program.read_only.clone();
+        self.inline_sites = program.inline_sites.clone();
+        self.vm_options.word_size = program.word_bytes as usize;
+        self.vm_options.position_independent = program.position_independent;
+        self.vm_options.signed_char = program.signed_char;
+        self.captured_output.clear();
+        self.captured_error.clear();
+
+        let exit_code = self.run(entry, args.len() as i32, args)?;
+        Ok(RunOutcome {
+            exit_code,
+            output: String::from_utf8_lossy(&std::mem::take(&mut self.captured_output)).into_owned(),
+            error_output: String::from_utf8_lossy(&std::mem::take(&mut self.captured_error)).into_owned(),
+        })
+    }
+
+    /// Write the warnings of the last compilation to stderr, then how many
+    /// errors and warnings there were
+    ///
+    /// Warnings made errors are written as errors, and once
+    /// `diagnostic_options.error_limit` errors have been reported, counting
+    /// the one that stopped the compilation, the rest are only counted.
+    pub fn report_warnings(&self) {
+        let options = &self.diagnostic_options;
+        let promoted = |w: &Warning| options.is_error(w.kind);
+        let from_warning = self.warnings.iter().find(|w| promoted(w)).map(|w| w.render_as_error(false));
+        let mut errors = (self.error.is_some() && self.error != from_warning) as usize;
+        let mut warnings = 0;
+        for warning in &self.warnings {
+            if !promoted(warning) {
+                eprintln!("{}", warning.render(self.color));
+                warnings += 1;
+                continue;
+            }
+            if options.error_limit == 0 || errors < options.error_limit {
+                eprintln!("{}", warning.render_as_error(self.color));
+            }
+            errors += 1;
+        }
+        if options.error_limit > 0 && errors > options.error_limit {
+            eprintln!("Too many errors; only the first {} were reported", options.error_limit);
+        }
+        if let Some(summary) = diagnostics::summary(errors, warnings) {
+            eprintln!("{}", summary);
+        }
+    }
+
+    /// Compile the source set up by the caller
+    fn build(&mut self) -> Result<()> {
+        self.compile_stats = CompileStats { source_bytes: self.src.len(), ..CompileStats::default() };
+        self.init_builtins();
+
+        if self.debug {
+            println!("Starting compilation...");
+        }
+
+        self.program();
+
+        // A warning made an error fails the compilation, once it has been read to the end
+        if self.error.is_none() {
+            let options = &self.diagnostic_options;
+            self.error = self.warnings.iter().find(|w| options.is_error(w.kind)).map(|w| w.render_as_error(false));
+        }
+        if let Some(message) = self.error.clone() {
+            self.record_output_sizes();
+            if self.cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            return Err(Error::Compile(message));
+        }
+        self.report_progress();
+        self.optimize();
+        self.record_output_sizes();
+
+        // Bad code here is a bug in the compiler, not the program
+        let functions: Vec<(String, i32)> = self.symbols.iter()
+            .filter(|s| s.class == TokenType::Fun as i32)
+            .map(|s| (s.name.clone(), s.value))
+            .collect();
+        let unbound = self.text.unbound_jump().map(|pc| format!("jump at {} to a label that is never bound", pc));
+        if let Some(e) = unbound.or_else(|| verify::verify(&self.text, &functions).err().map(|e| e.to_string())) {
+            self.error(&format!("Internal compiler error: {}", e));
+            return Err(Error::Compile(self.error.clone().unwrap_or_default()));
+        }
+        if self.vm_options.position_independent {
+            optimizer::to_pc_relative(self.text.as_mut_slice());
+        }
+        Ok(())
+    }
+
+    /// Compile the source set up by the caller and run `main`
+    fn build_and_run(&mut self, debug: i32, args: Vec<String>) -> i32 {
+        // Set debug level
+        self.debug = debug > 0;
+
+        let built = self.build();
+        self.report_warnings();
+        if built.is_err() {
+            return -1; // Compile error, already reported
+        }
+
+        if self.debug {
+            println!("Finished compilation, starting execution...");
+        }
+        
+        // Find the main function
+        let mut main_entry = -1;
+        for symbol in &self.symbols {
+            if symbol.name == "main" && symbol.class == TokenType::Fun as i32 {
+                main_entry = symbol.value;
+                break;
+            }
+        }
+        
+        if main_entry < 0 {
+            if self.debug {
+                println!("Error: main function not found");
+            }
+            return -1; // Main function not found
+        }
+        
+        if self.debug {
+            println!("Found main function at position {}", main_entry);
+        }
+        
+        // Run the program
+        let exit_code = match self.run(main_entry, args.len() as i32, args) {
+            Ok(exit_code) => exit_code,
+            Err(Error::Hang(_) | Error::Cancelled) => -2,
+            Err(_) => -1, // Fault, already reported
+        };
+        
+        if self.debug {
+            println!("Program exited with code: {}", exit_code);
+        }
+        
+        exit_code
+    }
+
+    /// Snapshot the compiled segments and function table as a `Program`
+    pub fn to_program(&self) -> Program {
+        debug_assert_eq!(self.text.unbound_jump(), None, "jump to a label that is never bound");
+        let mut program = Program::new(self.text.to_vec(), self.data.clone(), self.symbols.clone());
+        program.float_pool = self.float_pool.iter()
+            .map(|(&bits, &addr)| (addr, f64::from_bits(bits)))
+            .collect();
+        program.float_pool.sort_by_key(|&(addr, _)| addr);
+        program.word_bytes = self.vm_options.word_bytes();
+        program.position_independent = self.vm_options.position_independent;
+        program.signed_char = self.vm_options.signed_char;
+        if program.position_independent {
+            program.relocations.clear();
+        }
+        program.relocations.extend_from_slice(&self.data_relocations);
+        program.read_only = self.read_only.clone();
+        program.set_lines(&self.line_marks);
+        program.inline_sites = self.inline_sites.clone();
+        program
+    }
+
+    /// Run the bytecode optimizer over the text segment
+    ///
+    /// Does nothing when `opt_level` is 0.
+    pub fn optimize(&mut self) {
+        if self.opt_level <= 0 {
+            return;
+        }
+
+        self.inline_stats = optimizer::InlineStats::default();
+        self.inline_sites.clear();
+        if self.inline_functions && self.inline_threshold > 0 {
+            let entries: Vec<i32> = self.symbols.iter()
+                .filter(|s| s.class == TokenType::Fun as i32)
+                .map(|s| s.value)
+                .collect();
+
+            if let Some(inlined) = optimizer::inline_small_functions(&self.text, &entries, self.inline_threshold) {
+                // Relocate function entry points to their new addresses
+                for symbol in self.symbols.iter_mut().filter(|s| s.class == TokenType::Fun as i32) {
+                    if symbol.value >= 0 && (symbol.value as usize) < inlined.addr_map.len() {
+                        symbol.value = inlined.addr_map[symbol.value as usize];
+                    }
+                }
+                // The words holding data addresses, and their copies, move with them
+                let mut moved = vec![false; self.text.len()];
+                for &relocation in &self.data_relocations {
+                    if let Relocation::Data(offset) = relocation {
+                        moved[offset] = true;
+                    }
+                }
+                self.data_relocations.retain(|relocation| matches!(relocation, Relocation::DataWord(_)));
+                let copies = inlined.origins.iter().enumerate()
+                    .filter(|&(_, &origin)| origin >= 0 && moved[origin as usize])
+                    .map(|(offset, _)| Relocation::Data(offset));
+                self.data_relocations.extend(copies);
+                self.line_marks = remap_line_marks(&self.line_marks, &inlined.origins);
+                self.text = inlined.text.into();
+                self.inline_sites = inlined.sites;
+                self.inline_stats = inlined.stats;
+            }
+        }
+
+        // A shift cannot saturate or trap, so only rewrite multiplications when overflow wraps
+        let reduced = if self.vm_options.overflow == Overflow::Wrap {
+            optimizer::strength_reduce(self.text.as_mut_slice(), self.vm_options.signed_char)
+        } else {
+            0
+        };
+        if self.debug {
+            println!("Optimizer: inlined {} calls ({} words of growth), {} strength reductions",
+                     self.inline_stats.calls_inlined, self.inline_stats.growth(), reduced);
+        }
+    }
+
+    pub fn init_builtins(&mut self) {
+        // Add system calls like printf, malloc etc.
+        let builtins = vec![
+            ("printf", Instruction::PRINTF, INT),
+            ("fprintf", Instruction::FPRINTF, INT),
+            ("sprintf", Instruction::SPRINTF, INT),
+            ("snprintf", Instruction::SNPRINTF, INT),
+            ("putchar", Instruction::PUTC, INT),
+            ("puts", Instruction::PUTS, INT),
+            ("getchar", Instruction::GETC, INT),
+            ("assert", Instruction::ASSERT, INT),
+            ("malloc", Instruction::MALLOC, INT),
+            ("free", Instruction::FREE, INT),
+            ("memset", Instruction::MSET, INT),
+            ("abs", Instruction::ABS, INT),
+            ("sqrt", Instruction::SQRT, FLOAT),
+            ("pow", Instruction::POW, FLOAT),
+            ("sin", Instruction::SIN, FLOAT),
+            ("cos", Instruction::COS, FLOAT),
+            ("qsort", Instruction::QSORT, INT),
+            ("exit", Instruction::EXIT, INT),
+            // Add other builtins
+        ];
+
+        // c4 itself has only some of them
+        let c4_builtins = ["printf", "malloc", "free", "memset", "exit"];
+        let strict = !self.features.builtins;
+        for (name, instr, type_) in builtins {
+            if strict && !c4_builtins.contains(&name) {
+                continue;
+            }
+            self.symbols.push(Symbol {
+                token: TokenType::Id,
+                hash: 0,
+                name: name.to_string(),
+                id: self.names.intern(name.as_bytes()),
+                class: TokenType::Sys as i32,
+                type_,
+                value: instr as i32,
+                bclass: 0,
+                btype: 0,
+                bvalue: 0,
+                line: 0,
+            });
+        }
+
+        // The streams fprintf writes to and the standard constants a source
+        // would otherwise get from a header, as named constants
+        if strict {
+            return;
+        }
+        let (char_min, char_max) = if self.vm_options.signed_char { (-128, 127) } else { (0, 255) };
+        let constants = [
+            ("stdout", STDOUT as i32),
+            ("stderr", STDERR as i32),
+            ("NULL", 0),
+            ("EOF", -1),
+            ("EXIT_SUCCESS", 0),
+            ("EXIT_FAILURE", 1),
+            ("CHAR_BIT", 8),
+            ("CHAR_MIN", char_min),
+            ("CHAR_MAX", char_max),
+        ];
+        for (name, value) in constants {
+            self.symbols.push(Symbol {
+                token: TokenType::Id,
+                hash: 0,
+                name: name.to_string(),
+                id: self.names.intern(name.as_bytes()),
+                class: TokenType::Num as i32,
+                type_: INT,
+                value,
+                bclass: 0,
+                btype: 0,
+                bvalue: 0,
+                line: 0,
+            });
+        }
+    }
+
+    /// Get the captured output (for testing)
+    ///
+    /// This function returns the captured output from the program execution.
+    /// It's useful for testing the compiler.
+    pub fn get_captured_output(&self) -> String {
+        String::from_utf8_lossy(&self.captured_output).into_owned()
+    }
+
+    /// Get the captured output the program wrote to stderr (for testing)
+    pub fn get_captured_error(&self) -> String {
+        String::from_utf8_lossy(&self.captured_error).into_owned()
+    }
+
+    /// Optimization level the compiler was built with
+    pub fn opt_level(&self) -> i32 {
+        self.opt_level
+    }
+
+    /// Whether small leaf functions are inlined
+    pub fn inline_functions(&self) -> bool {
+        self.inline_functions
+    }
+
+    /// Settings of the VM that runs programs
+    pub fn vm_options(&self) -> &VmOptions {
+        &self.vm_options
+    }
+
+    /// Which warnings are errors and how many errors are reported
+    pub fn diagnostic_options(&self) -> &DiagnosticOptions {
+        &self.diagnostic_options
+    }
+
+    /// Whether errors and warnings written to stderr are colored
+    pub fn color(&self) -> bool {
+        self.color
+    }
+
+    /// Address of a float constant in the data segment
+    ///
+    /// Constants with the same bit pattern share one slot, so repeated
+    /// literals do not grow the data segment.
+    fn new_float_constant(&mut self, val: f64) -> i32 {
+        self.expr_type = FLOAT;
+        let bits = val.to_bits();
+        if let Some(&idx) = self.float_pool.get(&bits) {
+            return idx;
+        }
+
+        // Stored little-endian like every other word in the data segment
+        let idx = self.data.len() as i32;
+        self.data.extend_from_slice(&bits.to_le_bytes());
+        self.protect(idx as usize, self.data.len());
+        self.float_pool.insert(bits, idx);
+        idx
+    }
+
+    /// Reset the compiler state for a new compilation
+    pub fn reset(&mut self) {
+        // Clear all mutable state
+        self.reset_lexer();
+
+        // Start the preprocessor afresh from the configured macros
+        self.macros = self.defines.clone();
+        self.current_file = self.source_path.clone();
+        self.sources.clear();
+        self.conditions.clear();
+        self.once.clear();
+        self.guards.clear();
+        self.macro_sites.clear();
+        self.directives = self.features.preprocessor;
+        
+        // Clear symbol table and code segments
+        self.symbols.clear();
+        self.enum_tags.clear();
+        self.prototypes.clear();
+        self.forward_calls.clear();
+        self.initialized_globals.clear();
+        self.data_relocations.clear();
+        self.read_only.clear();
+        self.text.clear();
+        self.line_marks.clear();
+        self.inline_sites.clear();
+        self.old_text.clear();
+        self.data.clear();
+        self.data.resize(vm::NULL_GUARD, 0); // Keep address 0 for null
+        self.float_pool.clear();
+        
+        // Reset VM state
+        self.pc = 0;
+        self.bp = 0;
+        self.sp = 0;
+        self.ax = 0;
+        self.ax_float = 0.0;
+        self.cycle = 0;
+        
+        // Clear current identifier and the names seen
+        self.current_id.clear();
+        self.current_name = 0;
+        self.names.clear();
+        
+        // Reset expression type
+        self.expr_type = 0;
+        
+        // Reset function state
+        self.param_count = 0;
+        self.local_slots = 0;
+        self.frame_slots = 0;
+        self.frame_address_taken = false;
+        self.scope_start = 0;
+        self.nesting = 0;
+        self.error = None;
+        self.warnings.clear();
+        
+        // Clear captured output
+        self.captured_output.clear();
+        self.captured_error.clear();
+    }
+
+    /// Put the lexer back at the start of an empty source
+    fn reset_lexer(&mut self) {
+        self.src.clear();
+        self.pos = 0;
+        self.source_reader = None;
+        self.source_pins = 0;
+        self.line = 1;
+        self.column = 1;
+        self.column_pos = 0;
+        self.column_chars = 0;
+        self.token_start = 0;
+        self.dropped_bytes = 0;
+        self.functions_compiled = 0;
+        self.token = 0;
+        self.token_val = 0;
+    }
+}
+
+/// The instruction a compound assignment with operator `op`, such as the
+/// `Add` of `+=`, combines its operands with
+fn combining_instruction(op: TokenType) -> Instruction {
+    match op {
+        TokenType::Add => Instruction::ADD,
+        TokenType::Sub => Instruction::SUB,
+        TokenType::Mul => Instruction::MUL,
+        TokenType::Div => Instruction::DIV,
+        TokenType::Mod => Instruction::MOD,
+        TokenType::Shl => Instruction::SHL,
+        TokenType::Shr => Instruction::SHR,
+        TokenType::And => Instruction::AND,
+        TokenType::Or => Instruction::OR,
+        _ => Instruction::XOR,
+    }
+}
+
+/// Frame offset, in words from bp, of parameter `index` of the `count` a
+/// function takes
+///
+/// `ENT` leaves bp one word below the saved bp and the return address,
+/// above which the caller pushed the arguments in order, the last nearest.
+fn param_offset(index: i32, count: i32) -> i32 {
+    2 + count - index
+}
+
+/// Frame offset, in words from bp, of the local whose lowest word is the
+/// `slot`th word reserved for locals, counting from 1
+///
+/// Locals take the words from bp down, so the first is at bp itself.
+fn local_offset(slot: i32) -> i32 {
+    1 - slot
+}
+
+/// Whether `symbol` is a function declared by a prototype but not yet defined
+fn is_prototype(symbol: &Symbol) -> bool {
+    symbol.class == TokenType::Fun as i32 && symbol.value < 0
+}
+
+/// Whether `code` leaves the address of a parameter or local in ax:
+/// any `LEA` not directly followed by the load of its value
+fn frame_address_escapes(code: &[i32]) -> bool {
+    let instructions: Vec<DecodedInstr> = decode(code).collect();
+    instructions.iter().enumerate().any(|(i, instruction)| {
+        instruction.op == Instruction::LEA
+            && !matches!(instructions.get(i + 1).map(|next| next.op), Some(Instruction::LI | Instruction::LC))
+    })
+}
+
+/// Line marks for text rebuilt from the old words at `origins`
+///
+/// A word the rebuild added, with no origin, belongs to the line before it.
+fn remap_line_marks(marks: &[(usize, i32)], origins: &[i32]) -> Vec<(usize, i32)> {
+    let mut remapped: Vec<(usize, i32)> = Vec::new();
+    let mut line = 0;
+    for (at, &origin) in origins.iter().enumerate() {
+        if origin >= 0 {
+            let i = marks.partition_point(|&(mark, _)| mark <= origin as usize);
+            line = if i > 0 { marks[i - 1].1 } else { 0 };
+        }
+        if remapped.last().is_none_or(|&(_, last)| last != line) {
+            remapped.push((at, line));
+        }
+    }
+    remapped
+}
+
+/// Whether `code` only computes a value, without storing, calling or
+/// doing I/O
+///
+/// Loads count as having no effect, although a load from a bad address
+/// faults, as does a `/` or `%` by zero, which is kept.
+fn has_no_effect(code: &[i32]) -> bool {
+    use Instruction::*;
+    let mut instructions = vm::decode(code);
+    let pure = instructions.by_ref().all(|instr| matches!(
+        instr.op,
+        LEA | IMM | JMP | BZ | BNZ | LI | LC | PUSH | OR | XOR | AND | EQ | NE | LT | GT | LE | GE | SHL | SHR
+            | ADD | SUB | MUL | FLD | FADD | FSUB | FMUL | FDIV
+    ));
+    pure && instructions.pc() == code.len()
+}
+
+/// Why the code for an expression has no constant value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fold {
+    NotConstant,    // It does more than load immediates, apply operators and branch forward
+    DivisionByZero, // A `/` or `%` by zero
+    Overflow,       // A `+`, `-`, `*` or `/` whose result does not fit in a word
+}
+
+/// Value of the code for a constant expression, which may only load
+/// immediates, apply binary operators to them and branch forward
+///
+/// Branches are only followed if `start`, the text address of the code,
+/// is given. Operands checked as they are compiled leave it out, since
+/// walking every branch of each nested `?:` would take quadratic time.
+fn fold_constant(code: &[i32], start: Option<usize>, options: &VmOptions) -> std::result::Result<Word, Fold> {
+    let checked = VmOptions { overflow: Overflow::Trap, ..*options };
+    let mut stack = Vec::new();
+    let mut ax: Word = 0;
+    let mut pc = 0;
+    while pc < code.len() {
+        let op = code[pc];
+        let operand = code.get(pc + 1).copied();
+        if optimizer::has_text_operand(op) {
+            // Only branches within the expression and past themselves end
+            let target = operand.zip(start).and_then(|(target, start)| (target as usize).checked_sub(start)).ok_or(Fold::NotConstant)?;
+            if target <= pc || target > code.len() || op == Instruction::JSR as i32 || op == Instruction::FADDR as i32 {
+                return Err(Fold::NotConstant);
+            }
+            let taken = op == Instruction::JMP as i32 || (op == Instruction::BZ as i32) == (ax == 0);
+            pc = if taken { target } else { pc + 2 };
+            continue;
+        }
+        if op == Instruction::IMM as i32 {
+            ax = operand.ok_or(Fold::NotConstant)? as Word;
+            pc += 2;
+            continue;
+        }
+        if op == Instruction::PUSH as i32 {
+            stack.push(ax);
+        } else if Instruction::from_opcode(op).is_some_and(Instruction::is_binary) {
+            let left = stack.pop().ok_or(Fold::NotConstant)?;
+            ax = match checked.alu(op, left, ax) {
+                Some(value) => value,
+                None if ax == 0 && (op == Instruction::DIV as i32 || op == Instruction::MOD as i32) => return Err(Fold::DivisionByZero),
+                None => return Err(Fold::Overflow),
+            };
+        } else {
+            return Err(Fold::NotConstant);
+        }
+        pc += 1;
+    }
+    if stack.is_empty() && !code.is_empty() { Ok(ax) } else { Err(Fold::NotConstant) }
+}
+
+// Operator precedence constants
+pub const Assign: i32 = 0;
+pub const Cond: i32 = 1;
+pub const Lor: i32 = 2;
+pub const Lan: i32 = 3;
+pub const Or: i32 = 4;
+pub const Xor: i32 = 5;
+pub const And: i32 = 6;
+pub const Eq: i32 = 7;
+pub const Ne: i32 = 8;
+pub const Lt: i32 = 9;
+pub const Gt: i32 = 10;
+pub const Le: i32 = 11;
+pub const Ge: i32 = 12;
+pub const Shl: i32 = 13;
+pub const Shr: i32 = 14;
+pub const Add: i32 = 15;
+pub const Sub: i32 = 16;
+pub const Mul: i32 = 17;
+pub const Div: i32 = 18;
+pub const Mod: i32 = 19;
+pub const Inc: i32 = 20;
+pub const Dec: i32 = 21;
+pub const Brak: i32 = 22;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};  // Add Instant import
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn run_with_timeout<F, T>(test_fn: F) -> Result<T, String>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let start = Instant::now();
+        let result = test_fn();
+        if start.elapsed() > TEST_TIMEOUT {
+            return Err("Test timed out".to_string());
+        }
+        Ok(result)
+    }
+
+    #[test]
+    fn basic_test() {
+        let compiler = C4::new();
+        assert!(compiler.error.is_none());
+    }
+
+    #[test]
+    fn basic_compilation_test() {
+        let mut compiler = C4::new();
+        let result = compiler.compile_and_run("int main() { return 6 * 7; }", 0, Vec::new());
+        assert_eq!(result, 42);
+    }
+}
@@ -0,0 +1,99 @@
+//! # Lossless Tokens
+//!
+//! The compiler's lexer throws away whitespace, comments and preprocessor
+//! lines. Tools that rewrite source, such as a formatter or a refactoring,
+//! need them back, so [`C4::tokenize_lossless`] attaches that text to the
+//! token it precedes as trivia and keeps the exact spelling of each token.
+//! Concatenating everything again gives back the original source.
+
+use crate::C4;
+
+/// Kind of text between tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    Whitespace,     // Spaces, tabs and newlines
+    LineComment,    // `// ...` up to the end of the line
+    BlockComment,   // `/* ... */`, which may be unterminated at the end of input
+    Directive,      // `#...` up to the end of the line
+}
+
+/// A run of text between tokens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: Vec<u8>,
+}
+
+/// A token together with the source text it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LosslessToken {
+    pub token: i32,             // Token as in `C4::token`, 0 at the end of input
+    pub line: i32,              // Line of the token
+    pub column: i32,            // Column of the token, in characters
+    pub leading: Vec<Trivia>,   // Trivia between the previous token and this one
+    pub text: Vec<u8>,          // The token as written
+}
+
+/// The source `tokens` were read from
+pub fn render(tokens: &[LosslessToken]) -> Vec<u8> {
+    let mut source = Vec::new();
+    for token in tokens {
+        for trivia in &token.leading {
+            source.extend_from_slice(&trivia.text);
+        }
+        source.extend_from_slice(&token.text);
+    }
+    source
+}
+
+/// Split the text the lexer skipped before a token into its pieces
+fn split_trivia(mut text: &[u8]) -> Vec<Trivia> {
+    let mut trivia = Vec::new();
+    while let Some(&first) = text.first() {
+        let (kind, len) = if text.starts_with(b"/*") {
+            let end = text[2..].windows(2).position(|w| w == b"*/").map_or(text.len(), |i| i + 4);
+            (TriviaKind::BlockComment, end)
+        } else if text.starts_with(b"//") || first == b'#' {
+            let kind = if first == b'#' { TriviaKind::Directive } else { TriviaKind::LineComment };
+            (kind, text.iter().position(|&c| c == b'\n').unwrap_or(text.len()))
+        } else {
+            let len = text.iter().position(|c| !c.is_ascii_whitespace()).unwrap_or(text.len());
+            (TriviaKind::Whitespace, len.max(1))
+        };
+        trivia.push(Trivia { kind, text: text[..len].to_vec() });
+        text = &text[len..];
+    }
+    trivia
+}
+
+impl C4 {
+    /// Tokenize `source`, keeping the trivia before every token
+    ///
+    /// Directives are kept as trivia rather than acted on, so included
+    /// files and inactive branches do not change the result. The last
+    /// token has `token` 0 and holds the trivia at the end of the
+    /// source. A lexer error ends the list early, with the rest of the
+    /// source as the text of its last token, and is left in `error`.
+    pub fn tokenize_lossless(&mut self, source: &[u8]) -> Vec<LosslessToken> {
+        self.reset();
+        self.src = source.to_vec();
+        self.directives = false;
+
+        let mut tokens = Vec::new();
+        loop {
+            let start = self.pos;
+            self.next();
+            let token_start = self.token_start.min(self.pos);
+            tokens.push(LosslessToken {
+                token: self.token,
+                line: self.line,
+                column: self.column,
+                leading: split_trivia(&self.src[start..token_start]),
+                text: self.src[token_start..self.pos].to_vec(),
+            });
+            if self.token == 0 {
+                return tokens;
+            }
+        }
+    }
+}
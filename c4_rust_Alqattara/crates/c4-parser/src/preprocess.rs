@@ -0,0 +1,578 @@
+//! # Preprocessor
+//!
+//! Directives are handled by the lexer as it meets them rather than in a
+//! separate pass. `#include` and the expansion of an object-like macro both
+//! switch the lexer to a new source, saving the one it was reading on a
+//! stack to return to at the end; that keeps streaming, line numbers and
+//! columns working per file. Conditional directives skip the lines of
+//! inactive branches. An error in a macro's expansion is reported on the
+//! line the macro was used on, followed by the macros it was expanded
+//! from and where each was defined.
+//!
+//! A few standard headers are built in, so programs that include them
+//! compile without a host toolchain. They are found after the include
+//! directories, which may hold replacements for them.
+//!
+//! Supported: `#include "file"` and `#include <file>`, `#pragma once`,
+//! `#define NAME body`, `#undef`, `#ifdef`, `#ifndef`, `#if`, `#elif`,
+//! `#else` and `#endif`. Other directives are ignored, as they always were.
+//!
+//! The condition of an `#if` or `#elif` is a constant expression, such as
+//! `defined(DEBUG) && LEVEL > 2`. `defined NAME` and `defined(NAME)` become
+//! 1 or 0, macros are expanded, and any identifier left over is 0, as in
+//! C; the result is evaluated by `C4::const_eval`.
+//!
+//! [`C4::preprocess`], the `-E` option of the command line, gives the
+//! tokens the parser would see as source text, with `#line` markers that
+//! tie them to the file and line they came from.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::program::{Error, Result};
+use crate::C4;
+
+/// Blank lines written to preprocessed output before a `#line` marker is
+/// used instead to reach a token's line
+const MAX_BLANK_LINES: i32 = 8;
+
+/// Files may include each other at most this deep
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+/// Directory the built-in headers appear to be in
+const BUNDLED_DIR: &str = "<built-in>";
+
+/// Headers built into the compiler, by name
+const BUNDLED_HEADERS: &[(&str, &str)] = &[
+    ("stdio.h", include_str!("include/stdio.h")),
+    ("stdlib.h", include_str!("include/stdlib.h")),
+    ("string.h", include_str!("include/string.h")),
+];
+
+/// A source the lexer returns to when the one it switched to ends
+pub(crate) struct SourceLevel {
+    src: Vec<u8>,
+    pos: usize,
+    reader: Option<Box<dyn Read>>,
+    line: i32,
+    column_pos: usize,
+    column_chars: i32,
+    file: Option<PathBuf>,
+    conditions: usize,           // Open conditionals when the switch happened
+    origin: Origin,
+}
+
+/// Why the lexer switched to another source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Origin {
+    Include(PathBuf),            // The contents of an included file
+    Macro(Vec<u8>),              // The body of the named macro
+}
+
+/// State of one open `#if`, `#ifdef` or `#ifndef`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Condition {
+    active: bool,                // Lines are compiled
+    taken: bool,                 // Some branch has been active, so later ones are not
+    outer_active: bool,          // The enclosing code is active
+    line: i32,                   // Line of the directive that opened it
+}
+
+/// Split `text` into its first word and the rest, both trimmed
+fn word(text: &[u8]) -> (&[u8], &[u8]) {
+    let text = text.trim_ascii_start();
+    let end = text.iter().position(|c| !(c.is_ascii_alphanumeric() || *c == b'_')).unwrap_or(text.len());
+    (&text[..end], text[end..].trim_ascii())
+}
+
+impl C4 {
+    /// Handle the directive starting with the `#` at the current position
+    ///
+    /// Consumes the line up to, but not including, its newline.
+    pub(crate) fn directive(&mut self) {
+        let start = self.pos;
+        while self.peek(0).is_some_and(|c| c != b'\n') {
+            self.pos += 1;
+        }
+        // A `#!` line, which lets a program be run as a script, is no directive
+        if !self.directives || self.src.get(start + 1) == Some(&b'!') {
+            return;
+        }
+
+        // Comments on the line are not part of the directive
+        let mut line = self.src[start + 1..self.pos].to_vec();
+        if let Some(comment) = line.windows(2).position(|w| w == b"//" || w == b"/*") {
+            line.truncate(comment);
+        }
+        let (name, rest) = word(&line);
+        match name {
+            b"ifdef" | b"ifndef" => {
+                let (macro_name, _) = word(rest);
+                let defined = self.macros.contains_key(macro_name);
+                self.open_condition(defined == (name == b"ifdef"));
+            },
+            b"if" => {
+                let value = self.condition_value(rest);
+                self.open_condition(value);
+            },
+            b"elif" | b"else" => {
+                let Some(&condition) = self.conditions.last() else {
+                    self.error(&format!("#{} without #if", String::from_utf8_lossy(name)));
+                    return;
+                };
+                // A later branch's condition is not evaluated once one was taken
+                let value = name == b"else" || (condition.outer_active && !condition.taken && self.condition_value(rest));
+                let active = condition.outer_active && !condition.taken && value;
+                *self.conditions.last_mut().unwrap() = Condition { active, taken: condition.taken || active, ..condition };
+                if !active {
+                    self.skip_inactive();
+                }
+            },
+            b"endif" if self.conditions.pop().is_none() => self.error("#endif without #if"),
+            b"endif" => {},
+            b"define" => {
+                let (macro_name, body) = word(rest);
+                if macro_name.is_empty() {
+                    self.error("Macro name expected in #define");
+                } else if rest[macro_name.len()..].starts_with(b"(") {
+                    self.error("Function-like macros are not supported");
+                } else {
+                    self.macros.insert(macro_name.to_vec(), body.to_vec());
+                    self.macro_sites.insert(macro_name.to_vec(), (self.current_file.clone(), self.line));
+                }
+            },
+            b"undef" => {
+                let (macro_name, _) = word(rest);
+                self.macros.remove(macro_name);
+                self.macro_sites.remove(macro_name);
+            },
+            b"include" => self.include(rest),
+            b"pragma" if word(rest).0 == b"once" => {
+                if let Some(file) = self.current_file.clone() {
+                    self.once.insert(file);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Whether the condition of an `#if` or `#elif` holds
+    ///
+    /// The condition is evaluated with 64-bit words whatever the target's
+    /// word size, as C evaluates it in `intmax_t`.
+    fn condition_value(&mut self, text: &[u8]) -> bool {
+        let text = text.trim_ascii();
+        if text.is_empty() {
+            self.error("Condition expected in #if");
+            return false;
+        }
+        let expanded = match self.expand_condition(text, &mut Vec::new()) {
+            Ok(expanded) => expanded,
+            Err(message) => {
+                self.error(&message);
+                return false;
+            }
+        };
+        // Its errors are reported once, below, at the directive's line
+        let mut evaluator = C4::builder().word_size(8).build();
+        evaluator.quiet = true;
+        match evaluator.const_eval(&String::from_utf8_lossy(&expanded)) {
+            Ok(value) => value != 0,
+            Err(e) => {
+                // The evaluator only ever sees one line
+                let message = e.to_string();
+                let message = message.strip_prefix("Line 1: ").unwrap_or(&message);
+                self.error(&format!("In #if {}: {}", String::from_utf8_lossy(text), message));
+                false
+            }
+        }
+    }
+
+    /// `text` with `defined` applied, macros expanded and other identifiers
+    /// replaced by 0
+    ///
+    /// `expanding` holds the macros being expanded, which are not expanded
+    /// again inside themselves.
+    ///
+    /// # Returns
+    ///
+    /// The message for a malformed `defined`
+    fn expand_condition(&self, text: &[u8], expanding: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+        let mut expanded = Vec::new();
+        let mut pos = 0;
+        while pos < text.len() {
+            let c = text[pos];
+            if c == b'\'' || c == b'"' {
+                // Copy literals whole, so their letters are not taken for names
+                let end = text[pos + 1..].iter().position(|&d| d == c).map_or(text.len(), |end| pos + end + 2);
+                expanded.extend_from_slice(&text[pos..end]);
+                pos = end;
+                continue;
+            }
+            if !(c.is_ascii_alphanumeric() || c == b'_') {
+                expanded.push(c);
+                pos += 1;
+                continue;
+            }
+            let (name, _) = word(&text[pos..]);
+            pos += name.len();
+            if c.is_ascii_digit() {
+                expanded.extend_from_slice(name);
+                continue;
+            }
+
+            if name == b"defined" {
+                let rest = text[pos..].trim_ascii_start();
+                let parenthesized = rest.starts_with(b"(");
+                let rest = if parenthesized { rest[1..].trim_ascii_start() } else { rest };
+                let (macro_name, _) = word(rest);
+                if macro_name.is_empty() || macro_name[0].is_ascii_digit() {
+                    return Err("Macro name expected after 'defined'".to_string());
+                }
+                let mut end = text.len() - rest.len() + macro_name.len();
+                if parenthesized {
+                    let after = &text[end..];
+                    let close = after.trim_ascii_start();
+                    if !close.starts_with(b")") {
+                        return Err("Missing ')' after 'defined'".to_string());
+                    }
+                    end += after.len() - close.len() + 1;
+                }
+                pos = end;
+                expanded.push(if self.macros.contains_key(macro_name) { b'1' } else { b'0' });
+                continue;
+            }
+
+            match self.macros.get(name) {
+                Some(body) if !expanding.iter().any(|m| m == name) => {
+                    expanding.push(name.to_vec());
+                    let body = self.expand_condition(body, expanding)?;
+                    expanding.pop();
+                    expanded.push(b'(');
+                    expanded.extend_from_slice(&body);
+                    expanded.push(b')');
+                },
+                _ => expanded.push(b'0'),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Open a conditional whose first branch is active if `value` is set
+    fn open_condition(&mut self, value: bool) {
+        let outer_active = self.conditions.last().is_none_or(|c| c.active);
+        let active = outer_active && value;
+        self.conditions.push(Condition { active, taken: active, outer_active, line: self.line });
+        if !active {
+            self.skip_inactive();
+        }
+    }
+
+    /// Skip lines until the innermost conditional becomes active or is closed
+    ///
+    /// Only conditional directives are looked at; everything else, including
+    /// nested conditionals, is passed over.
+    fn skip_inactive(&mut self) {
+        let depth = self.conditions.len();
+        loop {
+            // Move to the start of the next line
+            while self.peek(0).is_some_and(|c| c != b'\n') {
+                self.pos += 1;
+            }
+            if self.peek(0).is_none() {
+                return;
+            }
+            self.newline();
+            self.pos += 1;
+
+            while self.peek(0).is_some_and(|c| c == b' ' || c == b'\t') {
+                self.pos += 1;
+            }
+            if self.peek(0) != Some(b'#') {
+                continue;
+            }
+            let start = self.pos + 1;
+            while self.peek(0).is_some_and(|c| c != b'\n') {
+                self.pos += 1;
+            }
+            let (name, _) = word(&self.src[start..self.pos]);
+            match name {
+                b"if" | b"ifdef" | b"ifndef" => {
+                    self.conditions.push(Condition { active: false, taken: true, outer_active: false, line: self.line });
+                },
+                b"endif" if self.conditions.len() > depth => {
+                    self.conditions.pop();
+                },
+                b"else" | b"elif" if self.conditions.len() > depth => {},
+                b"endif" | b"else" | b"elif" => {
+                    // Let the directive decide whether the next branch is active
+                    self.pos = start - 1;
+                    self.directive();
+                    if self.conditions.len() < depth || self.conditions.last().is_some_and(|c| c.active) {
+                        return;
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Handle `#include` with the rest of the directive line in `rest`
+    fn include(&mut self, rest: &[u8]) {
+        let (name, quoted) = match rest.first() {
+            Some(b'"') => (rest[1..].split(|&c| c == b'"').next(), true),
+            Some(b'<') => (rest[1..].split(|&c| c == b'>').next(), false),
+            _ => (None, false),
+        };
+        let Some(name) = name.filter(|name| !name.is_empty()).map(|name| String::from_utf8_lossy(name).into_owned()) else {
+            self.error("File name expected in #include");
+            return;
+        };
+
+        // Quoted names are looked for next to the including file first
+        let here = self.current_file.as_deref().and_then(Path::parent).unwrap_or(Path::new(""));
+        let candidates = quoted.then(|| here.join(&name)).into_iter()
+            .chain(self.include_dirs.iter().map(|dir| dir.join(&name)));
+        let found = candidates.filter(|path| path.is_file()).find_map(|path| path.canonicalize().ok());
+        let bundled = BUNDLED_HEADERS.iter().find(|(header, _)| *header == name).map(|(_, text)| *text);
+        let path = match (found, bundled) {
+            (Some(path), _) => path,
+            (None, Some(_)) => Path::new(BUNDLED_DIR).join(&name),
+            (None, None) => {
+                self.error(&format!("Cannot find include file '{}'", name));
+                return;
+            }
+        };
+
+        // Files already included under #pragma once or a defined guard are skipped
+        if self.once.contains(&path) {
+            return;
+        }
+        if self.guards.get(&path).is_some_and(|guard| self.macros.contains_key(guard)) {
+            return;
+        }
+
+        let open: Vec<&PathBuf> = self.sources.iter()
+            .filter_map(|level| match &level.origin { Origin::Include(path) => Some(path), Origin::Macro(_) => None })
+            .collect();
+        if open.contains(&&path) {
+            let mut chain: Vec<String> = open.iter().skip_while(|&&p| p != &path).map(|p| display_name(p)).collect();
+            chain.push(display_name(&path));
+            self.error(&format!("Include cycle: {}", chain.join(" -> ")));
+            return;
+        }
+        if open.len() >= MAX_INCLUDE_DEPTH {
+            self.error(&format!("Includes nested more than {} deep", MAX_INCLUDE_DEPTH));
+            return;
+        }
+
+        let (src, reader): (Vec<u8>, Option<Box<dyn Read>>) = match bundled {
+            Some(text) if path.starts_with(BUNDLED_DIR) => {
+                self.compile_stats.source_bytes += text.len();
+                (text.as_bytes().to_vec(), None)
+            },
+            _ => match File::open(&path) {
+                Ok(file) => {
+                    self.record_guard(&path);
+                    (Vec::new(), Some(Box::new(file)))
+                },
+                Err(e) => {
+                    self.error(&format!("Cannot read include file '{}': {}", name, e));
+                    return;
+                }
+            },
+        };
+        self.push_source(src, reader, Origin::Include(path.clone()));
+        self.current_file = Some(path);
+        self.line = 1;
+    }
+
+    /// Remember the include guard of `path`: a file whose first directive is
+    /// `#ifndef NAME` and which ends with the matching `#endif` need not be
+    /// read again once `NAME` is defined
+    fn record_guard(&mut self, path: &Path) {
+        let Ok(text) = std::fs::read(path) else {
+            return;
+        };
+        let mut lines = text.split(|&c| c == b'\n').map(<[u8]>::trim_ascii).filter(|line| !line.is_empty());
+        let Some(first) = lines.next().and_then(|line| line.strip_prefix(b"#")) else {
+            return;
+        };
+        let (directive, rest) = word(first);
+        let last = lines.next_back().and_then(|line| line.strip_prefix(b"#")).map(|line| word(line).0);
+        if directive == b"ifndef" && last == Some(&b"endif"[..]) {
+            self.guards.insert(path.to_path_buf(), word(rest).0.to_vec());
+        }
+    }
+
+    /// Expand the macro named by the identifier just lexed, if there is one
+    ///
+    /// A macro is not expanded again inside its own expansion.
+    ///
+    /// # Returns
+    ///
+    /// true if the lexer switched to the macro's body
+    pub(crate) fn expand_macro(&mut self) -> bool {
+        if !self.directives || self.macros.is_empty() {
+            return false;
+        }
+        let Some(body) = self.macros.get(&self.current_id) else {
+            return false;
+        };
+        let expanding = self.sources.iter().any(|level| level.origin == Origin::Macro(self.current_id.clone()));
+        if expanding {
+            return false;
+        }
+        let body = body.clone();
+        let origin = Origin::Macro(self.current_id.clone());
+        self.push_source(body, None, origin);
+        true
+    }
+
+    /// Switch the lexer to `src`, followed by what `reader` produces
+    fn push_source(&mut self, src: Vec<u8>, reader: Option<Box<dyn Read>>, origin: Origin) {
+        let level = SourceLevel {
+            src: std::mem::replace(&mut self.src, src),
+            pos: std::mem::replace(&mut self.pos, 0),
+            reader: std::mem::replace(&mut self.source_reader, reader),
+            line: self.line,
+            column_pos: std::mem::replace(&mut self.column_pos, 0),
+            column_chars: self.column_chars,
+            file: self.current_file.clone(),
+            conditions: self.conditions.len(),
+            origin,
+        };
+        self.sources.push(level);
+    }
+
+    /// Return to the source the lexer switched away from, at the end of the current one
+    ///
+    /// # Returns
+    ///
+    /// false if the main source has ended
+    pub(crate) fn pop_source(&mut self) -> bool {
+        // Conditionals may not span the end of a file
+        let level = self.sources.last();
+        let opened = level.map_or(0, |level| level.conditions);
+        let ends_file = level.is_none_or(|level| matches!(level.origin, Origin::Include(_)));
+        if ends_file && self.conditions.len() > opened {
+            let line = self.conditions[self.conditions.len() - 1].line;
+            self.error_at(line, "Unterminated conditional directive");
+            return false;
+        }
+        let Some(level) = self.sources.pop() else {
+            return false;
+        };
+        self.src = level.src;
+        self.pos = level.pos;
+        self.source_reader = level.reader;
+        self.line = level.line;
+        self.column_pos = level.column_pos;
+        self.column_chars = level.column_chars;
+        self.current_file = level.file;
+        true
+    }
+
+    /// Position of the lexer in the part of the main source held in `src`,
+    /// wherever it has switched to since
+    pub(crate) fn main_source_pos(&self) -> usize {
+        self.sources.first().map_or(self.pos, |level| level.pos)
+    }
+
+    /// Preprocess `source`, returning the tokens the parser would see
+    ///
+    /// Directives are carried out, macros expanded and comments dropped.
+    /// Tokens are written one line per source line, separated by single
+    /// spaces; a `#line` marker starts the output, every included file and
+    /// the return from it, and any jump of more than a few lines, so what
+    /// is reported against the output can be traced back. Tokens from a
+    /// macro's body are put on the line the macro was used on.
+    ///
+    /// # Returns
+    ///
+    /// `Error::Compile` for an error in a directive or a token
+    pub fn preprocess(&mut self, source: &str) -> Result<String> {
+        self.reset();
+        self.src = source.as_bytes().to_vec();
+
+        let mut output = String::new();
+        let mut at: Option<(Option<PathBuf>, i32)> = None;
+        loop {
+            self.next();
+            if let Some(message) = &self.error {
+                return Err(Error::Compile(message.clone()));
+            }
+            if self.token == 0 {
+                break;
+            }
+
+            let (file, line) = (&self.current_file, self.line);
+            match &at {
+                Some((at_file, at_line)) if at_file == file && (0..=MAX_BLANK_LINES).contains(&(line - at_line)) => {
+                    if line == *at_line {
+                        output.push(' ');
+                    }
+                    for _ in *at_line..line {
+                        output.push('\n');
+                    }
+                },
+                _ => {
+                    if !output.is_empty() {
+                        output.push('\n');
+                    }
+                    match file {
+                        Some(path) => output += &format!("#line {} \"{}\"\n", line, path.display()),
+                        None => output += &format!("#line {}\n", line),
+                    }
+                },
+            }
+            at = Some((file.clone(), line));
+            let start = self.token_start.min(self.pos);
+            output += &String::from_utf8_lossy(&self.src[start..self.pos]);
+        }
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        Ok(output)
+    }
+
+    /// Where the current token came from if it is part of a macro's
+    /// expansion, as a line per macro, innermost first, naming it and
+    /// where it was defined; empty otherwise
+    ///
+    /// The lexer is still inside the sources the token was read from, so
+    /// they give its provenance without recording any per token.
+    pub(crate) fn macro_backtrace(&self) -> String {
+        let mut backtrace = String::new();
+        for level in self.sources.iter().rev() {
+            let Origin::Macro(name) = &level.origin else {
+                continue;
+            };
+            let site = match self.macro_sites.get(name) {
+                Some((Some(file), line)) if Some(file) != self.source_path.as_ref() => {
+                    format!("defined on line {} of {}", line, display_name(file))
+                },
+                Some((_, line)) => format!("defined on line {}", line),
+                None => "defined before the source".to_string(),
+            };
+            backtrace += &format!("\n  in expansion of macro '{}' ({})", String::from_utf8_lossy(name), site);
+        }
+        backtrace
+    }
+
+    /// Whether the lexer is reading an included file
+    pub(crate) fn in_included_file(&self) -> bool {
+        self.sources.iter().any(|level| matches!(level.origin, Origin::Include(_)))
+    }
+
+    /// Name of the included file being read, if the lexer is inside one
+    pub(crate) fn included_file(&self) -> Option<String> {
+        self.in_included_file().then(|| self.current_file.as_deref().map(display_name)).flatten()
+    }
+}
+
+/// Short name of an included file for messages
+fn display_name(path: &Path) -> String {
+    path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned()
+}
@@ -0,0 +1,322 @@
+//! # Compiled Programs
+//!
+//! A `Program` is a snapshot of the segments and function table produced by
+//! the compiler, which can be inspected without holding on to the compiler
+//! and run as often as needed.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::analysis::{self, StackReport};
+use crate::relocation::{self, Relocation};
+use crate::optimizer;
+use crate::verify::{self, VerifyError};
+use crate::{vm, DecodedInstr, Hang, Instructions, RuntimeError, Symbol, TokenType, C4, CHAR, FLOAT, PTR};
+
+/// Exit status of the `c4` command when the program did not compile, or
+/// could not be loaded or started
+pub const EXIT_COMPILE_ERROR: i32 = 125;
+
+/// Exit status of the `c4` command when the program stopped at a fault or
+/// a hang, or was cancelled; that of a native program that called `abort`
+pub const EXIT_TRAP: i32 = 134;
+
+/// Why a program could not be compiled or run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Compile(String),             // The first compile error, as in `C4::error`
+    NoMain,                      // The program does not define `main`
+    NoFunction(String),          // The program does not define the function a run was to start from
+    Image(String),               // A `.c4b` image that cannot be loaded, and why
+    Cancelled,                   // `C4::cancel` was cancelled before the work was done
+    Hang(Hang),                  // The program was stopped in a loop it would not leave
+    Trap(RuntimeError),          // The program stopped at a fault, such as a division by zero
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Compile(message) => write!(f, "{}", message),
+            Error::NoMain => write!(f, "main function not found"),
+            Error::NoFunction(name) => write!(f, "{} function not found", name),
+            Error::Image(message) => write!(f, "Invalid program image: {}", message),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::Hang(hang) => write!(f, "{}", hang),
+            Error::Trap(error) => write!(f, "{}", error.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Exit status the `c4` command ends with for this error
+    ///
+    /// A program that runs to the end exits with what `main` returned
+    /// instead, so these are only told apart from it by what was reported
+    /// on stderr.
+    pub fn exit_status(&self) -> i32 {
+        match self {
+            Error::Cancelled | Error::Hang(_) | Error::Trap(_) => EXIT_TRAP,
+            _ => EXIT_COMPILE_ERROR,
+        }
+    }
+}
+
+/// Result of compiling or running a program
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What one run of a program produced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub exit_code: i32,          // Value returned by `main`
+    pub output: String,          // What the program wrote to stdout, unless it went to a sink
+    pub error_output: String,    // What the program wrote to stderr, unless it went to a sink
+}
+
+/// What a symbol of a compiled program names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,                    // Code in the text segment
+    Global,                      // A variable in the data segment
+}
+
+/// A function or global variable of a compiled program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub type_: i32,              // Type of the variable, or return type of the function
+    pub address: i32,            // Entry in the text segment, or address in the data segment
+    pub size: usize,             // Words of code, or bytes of data
+    pub element_size: usize,     // Bytes a load or store of the variable, or of one element of an array, takes; 0 for a function
+    pub align: usize,            // What the address is a multiple of, in the unit of `size`
+    pub line: i32,               // Line of the declaration
+}
+
+impl ProgramSymbol {
+    /// The type as C would write it, such as `char **`
+    pub fn type_name(&self) -> String {
+        type_name(self.type_)
+    }
+}
+
+/// `type_` as C would write it, such as `char **`
+pub(crate) fn type_name(type_: i32) -> String {
+    if type_ == FLOAT {
+        return "double".to_string();
+    }
+    let base = if type_ % PTR == CHAR { "char" } else { "int" };
+    let depth = (type_ / PTR) as usize;
+    if depth == 0 {
+        base.to_string()
+    } else {
+        format!("{} {}", base, "*".repeat(depth))
+    }
+}
+
+/// A compiled program
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub text: Vec<i32>,          // Text segment
+    pub data: Vec<u8>,           // Data segment
+    pub(crate) symbols: Vec<Symbol>, // Symbol table at the end of compilation
+    pub(crate) float_pool: Vec<(i32, f64)>, // Float constants by data address
+    pub(crate) word_bytes: i32,  // Bytes in a VM word, which the code was compiled for
+    pub(crate) relocations: Vec<Relocation>, // Words that hold addresses
+    pub(crate) read_only: Vec<(usize, usize)>, // Byte ranges of the data segment, start to end, holding constants
+    pub(crate) position_independent: bool, // Text addresses in operands are relative to the operand
+    pub(crate) signed_char: bool, // Chars load sign-extended, as the code was compiled for
+    pub(crate) inline_sites: Vec<(i32, i32)>, // Text address of each IENT and the entry of the function it inlines
+    pub(crate) lines: BTreeMap<i32, Vec<DecodedInstr>>, // Instructions compiled from each line of the main source
+}
+
+impl Program {
+    /// Create a program from its segments and symbol table
+    pub fn new(text: Vec<i32>, data: Vec<u8>, symbols: Vec<Symbol>) -> Self {
+        let relocations = relocation::text_relocations(&text);
+        Program {
+            text,
+            data,
+            symbols,
+            float_pool: Vec::new(),
+            word_bytes: 4,
+            relocations,
+            read_only: Vec::new(),
+            position_independent: false,
+            signed_char: true,
+            inline_sites: Vec::new(),
+            lines: BTreeMap::new(),
+        }
+    }
+
+    /// Every function and global the program defines, in declaration order
+    ///
+    /// Builtins are left out. A function's size runs up to the next
+    /// function's entry, or the end of the text segment. Every global starts
+    /// on a word and takes a whole number of words, even a `char`, but only
+    /// the first `element_size` bytes of a scalar hold its value.
+    pub fn symbols(&self) -> Vec<ProgramSymbol> {
+        let mut entries: Vec<i32> = self.functions().iter().map(|&(_, entry)| entry).collect();
+        entries.push(self.text.len() as i32);
+        entries.sort_unstable();
+
+        let word_bytes = self.word_bytes as usize;
+        let element_bytes = |type_: i32| if type_ == CHAR { 1 } else { word_bytes };
+        self.symbols.iter()
+            .filter_map(|s| {
+                let (kind, size, element_size, align) = if s.class == TokenType::Fun as i32 {
+                    let end = entries.iter().find(|&&entry| entry > s.value).copied().unwrap_or(s.value);
+                    (SymbolKind::Function, (end - s.value) as usize, 0, 1)
+                } else if s.class == TokenType::Glo as i32 && s.bvalue > 0 {
+                    (SymbolKind::Global, s.bvalue as usize * element_bytes(s.btype), element_bytes(s.btype), word_bytes)
+                } else if s.class == TokenType::Glo as i32 {
+                    (SymbolKind::Global, word_bytes, element_bytes(s.type_), word_bytes)
+                } else {
+                    return None;
+                };
+                Some(ProgramSymbol {
+                    name: s.name.clone(),
+                    kind,
+                    type_: s.type_,
+                    address: s.value,
+                    size,
+                    element_size,
+                    align,
+                    line: s.line,
+                })
+            })
+            .collect()
+    }
+
+    /// The function or global named `name`
+    pub fn symbol(&self, name: &str) -> Option<ProgramSymbol> {
+        self.symbols().into_iter().find(|s| s.name == name)
+    }
+
+    /// Data address and value of every float constant, in address order
+    ///
+    /// Each distinct constant appears once however many times it was written.
+    pub fn float_constants(&self) -> &[(i32, f64)] {
+        &self.float_pool
+    }
+
+    /// Byte ranges of the data segment, start to end, that hold string
+    /// literals, other constants and `const` globals
+    pub fn read_only(&self) -> &[(usize, usize)] {
+        &self.read_only
+    }
+
+    /// Name and entry address of every function
+    pub fn functions(&self) -> Vec<(String, i32)> {
+        self.symbols.iter()
+            .filter(|s| s.class == TokenType::Fun as i32)
+            .map(|s| (s.name.clone(), s.value))
+            .collect()
+    }
+
+    /// Entry address of `main`, if the program defines it
+    pub fn entry(&self) -> Option<i32> {
+        self.function_entry("main")
+    }
+
+    /// Entry address of the function `name`, if the program defines it
+    pub fn function_entry(&self, name: &str) -> Option<i32> {
+        self.functions().into_iter()
+            .find(|(function, _)| function == name)
+            .map(|(_, entry)| entry)
+    }
+
+    /// Run `main` with `args` on a fresh VM with default settings
+    ///
+    /// Output is captured in the outcome. Each run starts from the data
+    /// segment as compiled, so runs do not affect each other. Use
+    /// [`C4::run_program`] to run with other VM settings or I/O.
+    pub fn run(&self, args: Vec<String>) -> Result<RunOutcome> {
+        C4::new().run_program(self, args)
+    }
+
+    /// Run the function `name` instead of `main`, as [`Program::run`] does
+    ///
+    /// The function is called with the arguments `main` would get, so a
+    /// single C function, or one of a library of them saved as an image,
+    /// can be run on its own.
+    pub fn run_from(&self, name: &str, args: Vec<String>) -> Result<RunOutcome> {
+        C4::new().run_program_from(self, name, args)
+    }
+
+    /// Check that the text segment is well formed, as the compiler does for
+    /// everything it generates
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        verify::verify(&self.absolute_text(), &self.functions())
+    }
+
+    /// Report an upper bound on the VM stack words used by a run from `main`
+    ///
+    /// Useful for picking `VmOptions::stack_words`. Functions that recurse
+    /// through ordinary calls have no bound and are flagged as recursive.
+    pub fn stack_report(&self) -> StackReport {
+        let entry = self.entry().unwrap_or(-1);
+        analysis::stack_report(&self.absolute_text(), &self.functions(), entry)
+    }
+
+    /// Whether jumps, calls and function addresses are relative to where
+    /// they are, so the text segment runs unchanged at any offset
+    pub fn is_position_independent(&self) -> bool {
+        self.position_independent
+    }
+
+    /// Whether the code was compiled for chars that are signed
+    pub fn is_signed_char(&self) -> bool {
+        self.signed_char
+    }
+
+    /// The instructions of the text segment, in address order
+    ///
+    /// Text addresses in the operands of position-independent code are
+    /// relative, as they are stored.
+    pub fn instructions(&self) -> Instructions<'_> {
+        vm::decode(&self.text)
+    }
+
+    /// The instructions that the code on line `line` of the main source
+    /// compiled to, in address order
+    ///
+    /// Lines that produced no code have none, and neither does code from
+    /// included files. A function inlined by the optimizer keeps the lines
+    /// of its body. Images do not store the line table, so a program loaded
+    /// from one has no instructions for any line.
+    pub fn instructions_for_line(&self, line: i32) -> &[DecodedInstr] {
+        self.lines.get(&line).map_or(&[], Vec::as_slice)
+    }
+
+    /// Build the line table from the text address where the code of each
+    /// line starts
+    pub(crate) fn set_lines(&mut self, marks: &[(usize, i32)]) {
+        self.lines.clear();
+        let mut mark = 0;
+        for instruction in vm::decode(&self.text) {
+            while marks.get(mark + 1).is_some_and(|&(start, _)| start <= instruction.pc) {
+                mark += 1;
+            }
+            let Some(&(start, line)) = marks.get(mark) else {
+                break;
+            };
+            if start <= instruction.pc && line > 0 {
+                self.lines.entry(line).or_default().push(instruction);
+            }
+        }
+    }
+
+    /// The text segment with every text address absolute, as the checks
+    /// and analyses of the code expect
+    fn absolute_text(&self) -> Cow<'_, [i32]> {
+        if !self.position_independent {
+            return Cow::Borrowed(&self.text);
+        }
+        let mut text = self.text.clone();
+        optimizer::to_absolute(&mut text);
+        Cow::Owned(text)
+    }
+}
@@ -0,0 +1,114 @@
+//! # Relocations
+//!
+//! Code is compiled as if the text and data segments both started at
+//! address 0. A program records every word that holds an address in one of
+//! them, so a linker can place the segments of several programs one after
+//! another: it adds each program's bases to those words with
+//! [`Program::relocate`], then joins the segments.
+//!
+//! Words holding text addresses, the operands of jumps, calls and `FADDR`,
+//! are found by decoding the text segment; position-independent code has
+//! none. Those holding data addresses
+//! cannot be told apart from other constants, so the compiler records them
+//! as it emits them.
+
+use crate::optimizer::{has_text_operand, instruction_starts};
+use crate::program::Program;
+use crate::TokenType;
+
+/// A word of a program that holds an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relocation {
+    Text(usize),     // Text word holding a text address: the target of a jump or call, or a function's address
+    Data(usize),     // Text word holding a data address: a global, a string literal or a float constant
+    DataWord(usize), // Data address of a word initialized with a data address
+}
+
+impl Relocation {
+    /// Text or data address of the word to adjust
+    pub fn offset(self) -> usize {
+        match self {
+            Relocation::Text(offset) | Relocation::Data(offset) | Relocation::DataWord(offset) => offset,
+        }
+    }
+
+    /// Number identifying the kind of relocation in an image
+    pub(crate) fn kind(self) -> u32 {
+        match self {
+            Relocation::Text(_) => 0,
+            Relocation::Data(_) => 1,
+            Relocation::DataWord(_) => 2,
+        }
+    }
+
+    /// The relocation of `kind` at `offset`, if `kind` is known
+    pub(crate) fn from_kind(kind: u32, offset: usize) -> Option<Relocation> {
+        match kind {
+            0 => Some(Relocation::Text(offset)),
+            1 => Some(Relocation::Data(offset)),
+            2 => Some(Relocation::DataWord(offset)),
+            _ => None,
+        }
+    }
+}
+
+/// The text words of `text` that hold text addresses, in address order
+pub fn text_relocations(text: &[i32]) -> Vec<Relocation> {
+    instruction_starts(text).into_iter()
+        .filter(|&pc| has_text_operand(text[pc]) && pc + 1 < text.len())
+        .map(|pc| Relocation::Text(pc + 1))
+        .collect()
+}
+
+impl Program {
+    /// Every word of the program that holds an address
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+
+    /// Move the program's text segment to `text_base` and its data segment
+    /// to `data_base`
+    ///
+    /// Every address the program holds, those in its symbol table among
+    /// them, is adjusted, so the segments can be appended to those of
+    /// another program that end at the bases. The program itself no longer
+    /// runs or verifies on its own. Globals keep the alignment
+    /// [`Program::symbols`] gives them only if `data_base` is a multiple of
+    /// the word size.
+    pub fn relocate(&mut self, text_base: i32, data_base: i32) {
+        let word_bytes = self.word_bytes as usize;
+        for &relocation in &self.relocations {
+            match relocation {
+                Relocation::Text(offset) => self.text[offset] += text_base,
+                Relocation::Data(offset) => self.text[offset] += data_base,
+                Relocation::DataWord(addr) => {
+                    let word = &mut self.data[addr..addr + word_bytes];
+                    let value = if word_bytes == 8 {
+                        i64::from_le_bytes(word.try_into().unwrap()) + data_base as i64
+                    } else {
+                        i32::from_le_bytes(word.try_into().unwrap()) as i64 + data_base as i64
+                    };
+                    word.copy_from_slice(&value.to_le_bytes()[..word_bytes]);
+                },
+            }
+        }
+        for symbol in &mut self.symbols {
+            if symbol.class == TokenType::Fun as i32 {
+                symbol.value += text_base;
+            } else if symbol.class == TokenType::Glo as i32 {
+                symbol.value += data_base;
+            }
+        }
+        for (addr, _) in &mut self.float_pool {
+            *addr += data_base;
+        }
+        for (start, end) in &mut self.read_only {
+            *start += data_base as usize;
+            *end += data_base as usize;
+        }
+        for (site, entry) in &mut self.inline_sites {
+            *site += text_base;
+            *entry += text_base;
+        }
+    }
+}
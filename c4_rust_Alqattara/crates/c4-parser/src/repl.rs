@@ -0,0 +1,304 @@
+//! # Interactive Sessions
+//!
+//! A [`Repl`] compiles and runs C one entry at a time, as typed at a
+//! prompt, and keeps what each entry defines for the entries after it. An
+//! entry is one of:
+//!
+//! - Declarations of globals, functions and enums, which are added to the
+//!   program built so far
+//! - Statements such as `x = f(2); printf("%d\n", x);`, which are run at once
+//! - An expression without a closing `;`, such as `f(2) + 1`, which is run
+//!   at once and its value given back
+//!
+//! The symbol table and both segments carry over from entry to entry. New
+//! functions are appended to the text segment and new globals to the data
+//! segment, so nothing compiled before moves, and globals keep their values
+//! between entries. Statements are compiled as the body of a function that
+//! is run once and then taken off the text segment again. Memory from
+//! `malloc` only lasts until the end of the entry that allocated it, as the
+//! heap of any run does.
+//!
+//! Defining a name again replaces the old definition, with a warning,
+//! rather than being an error. The old function's entry becomes a jump to
+//! the new one, and every address of an old global that the compiler
+//! recorded is changed to the new one's, so code compiled before uses the
+//! new definition too. An entry with an error leaves the session as it was.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::mem;
+
+use crate::diagnostics::{Span, Warning, WarningKind};
+use crate::intern::NameId;
+use crate::program::{Error, Result};
+use crate::relocation::Relocation;
+use crate::{verify, Assign, Instruction, Symbol, TokenType, Word, C4, INT};
+
+/// Prompt for an entry
+pub const PROMPT: &str = "c4> ";
+
+/// Prompt for a further line of an entry with `{` still open
+pub const CONTINUATION_PROMPT: &str = "...> ";
+
+/// Name the code of an entry of statements runs under
+pub const ENTRY_FUNCTION: &str = "<entry>";
+
+/// What an entry did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Evaluation {
+    pub value: Option<i32>,      // Value of an expression entry
+    pub output: String,          // What the entry wrote to stdout, unless it went to a sink
+    pub warnings: Vec<Warning>,  // Warnings about the entry, among them the definitions it replaced
+}
+
+/// A session that compiles and runs entries one at a time
+pub struct Repl {
+    compiler: C4,
+    builtins: usize,             // Symbols of the builtins, which come before those of the entries
+}
+
+/// What an entry with an error must not change
+struct Snapshot {
+    symbols: Vec<Symbol>,
+    enum_tags: HashSet<NameId>,
+    text: Vec<i32>,
+    data: Vec<u8>,
+    data_relocations: Vec<Relocation>,
+    read_only: Vec<(usize, usize)>,
+    line_marks: Vec<(usize, i32)>,
+    float_pool: HashMap<u64, i32>,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new(C4::new())
+    }
+}
+
+impl Repl {
+    /// Start a session with `compiler`, which keeps its settings and I/O
+    ///
+    /// Code is always compiled unoptimized and with absolute addresses,
+    /// since the optimizer may move code that later entries refer to.
+    pub fn new(mut compiler: C4) -> Self {
+        compiler.reset();
+        compiler.init_builtins();
+        compiler.vm_options.position_independent = false;
+        let builtins = compiler.symbols.len();
+        Repl { compiler, builtins }
+    }
+
+    /// The compiler holding the session's symbols and segments
+    pub fn compiler(&self) -> &C4 {
+        &self.compiler
+    }
+
+    /// Compile and run one entry
+    ///
+    /// # Returns
+    ///
+    /// `Error::Compile` if the entry does not compile, in which case the
+    /// session is unchanged, or the error a run of statements ended with,
+    /// such as `Error::Trap`
+    pub fn eval(&mut self, entry: &str) -> Result<Evaluation> {
+        let c4 = &mut self.compiler;
+        c4.reset_lexer();
+        c4.src = entry.as_bytes().to_vec();
+        c4.sources.clear();
+        c4.conditions.clear();
+        c4.forward_calls.clear();
+        c4.initialized_globals.clear();
+        c4.error = None;
+        c4.warnings.clear();
+        c4.captured_output.clear();
+        c4.captured_error.clear();
+        let snapshot = Snapshot {
+            symbols: c4.symbols.clone(),
+            enum_tags: c4.enum_tags.clone(),
+            text: c4.text.to_vec(),
+            data: c4.data.clone(),
+            data_relocations: c4.data_relocations.clone(),
+            read_only: c4.read_only.clone(),
+            line_marks: c4.line_marks.clone(),
+            float_pool: c4.float_pool.clone(),
+        };
+
+        c4.next();
+        let mut value = None;
+        if c4.token == 0 {
+            // Only directives
+        } else if c4.at_type() {
+            let start = c4.symbols.len();
+            c4.declarations();
+            if c4.error.is_none() {
+                self.replace_definitions(start);
+            }
+        } else {
+            let trimmed = entry.trim_end();
+            let expression = !trimmed.ends_with(';') && !trimmed.ends_with('}');
+            value = self.run_statements(expression)?.filter(|_| expression);
+        }
+
+        let c4 = &mut self.compiler;
+        if c4.error.is_none() {
+            let functions: Vec<(String, i32)> = c4.symbols.iter()
+                .filter(|s| s.class == TokenType::Fun as i32)
+                .map(|s| (s.name.clone(), s.value))
+                .collect();
+            let unbound = c4.text.unbound_jump().map(|pc| format!("jump at {} to a label that is never bound", pc));
+            if let Some(e) = unbound.or_else(|| verify::verify(&c4.text, &functions).err().map(|e| e.to_string())) {
+                c4.error(&format!("Internal compiler error: {}", e));
+            }
+        }
+        if let Some(message) = c4.error.clone() {
+            c4.symbols = snapshot.symbols;
+            c4.enum_tags = snapshot.enum_tags;
+            c4.text = snapshot.text.into();
+            c4.data = snapshot.data;
+            c4.data_relocations = snapshot.data_relocations;
+            c4.read_only = snapshot.read_only;
+            c4.line_marks = snapshot.line_marks;
+            c4.float_pool = snapshot.float_pool;
+            return Err(Error::Compile(message));
+        }
+        Ok(Evaluation {
+            value,
+            output: String::from_utf8_lossy(&mem::take(&mut c4.captured_output)).into_owned(),
+            warnings: mem::take(&mut c4.warnings),
+        })
+    }
+
+    /// Read entries from `input` until it ends, writing the value of each
+    /// expression and the warnings to `out`
+    ///
+    /// An entry ends with a line on which every `{` has been closed, so a
+    /// function can be typed over several lines. Errors are reported by the
+    /// compiler as they happen.
+    pub fn interact(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        let mut entry = String::new();
+        write!(out, "{}", PROMPT)?;
+        out.flush()?;
+        for line in input.lines() {
+            entry += &line?;
+            entry.push('\n');
+            let depth: i32 = entry.bytes().map(|b| (b == b'{') as i32 - (b == b'}') as i32).sum();
+            if depth > 0 {
+                write!(out, "{}", CONTINUATION_PROMPT)?;
+                out.flush()?;
+                continue;
+            }
+            if let Ok(evaluation) = self.eval(&entry) {
+                write!(out, "{}", evaluation.output)?;
+                for warning in &evaluation.warnings {
+                    writeln!(out, "{}", warning)?;
+                }
+                if let Some(value) = evaluation.value {
+                    writeln!(out, "{}", value)?;
+                }
+            }
+            entry.clear();
+            write!(out, "{}", PROMPT)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Compile the rest of the entry as the body of a function, run it
+    /// and take it off the text segment again
+    ///
+    /// # Returns
+    ///
+    /// What the function returned, or None if it did not compile
+    fn run_statements(&mut self, expression: bool) -> Result<Option<i32>> {
+        let c4 = &mut self.compiler;
+        let entry = c4.text.len();
+        c4.param_count = 0;
+        c4.local_slots = 0;
+        c4.frame_slots = 0;
+        c4.scope_start = c4.symbols.len();
+        c4.text.emit_with(Instruction::ENT, 0);
+        if expression {
+            c4.expression(Assign);
+            if c4.token != 0 {
+                let message = format!("Expected end of expression, got {}", c4.token_name());
+                c4.error(&message);
+            }
+        } else {
+            while c4.token != 0 && c4.error.is_none() {
+                c4.statement();
+            }
+            c4.text.emit_with(Instruction::IMM, 0);
+        }
+        c4.text.emit(Instruction::LEV);
+        c4.text.set_operand(entry + 1, c4.frame_slots);
+        if let Some(&(_, symbol_idx, line)) = c4.forward_calls.first() {
+            let message = format!("'{}' is called but never defined", c4.symbols[symbol_idx].name);
+            c4.error_at(line, &message);
+        }
+        if c4.error.is_some() {
+            return Ok(None);
+        }
+
+        // Named for the run only, so a hang or fault in it is not put down to the function before
+        let id = c4.names.intern(ENTRY_FUNCTION.as_bytes());
+        c4.symbols.push(Symbol {
+            token: TokenType::Id,
+            hash: 0,
+            name: ENTRY_FUNCTION.to_string(),
+            id,
+            class: TokenType::Fun as i32,
+            type_: INT,
+            value: entry as i32,
+            bclass: 0,
+            btype: 0,
+            bvalue: 0,
+            line: 1,
+        });
+        let value = c4.run(entry as i32, 0, Vec::new());
+        c4.symbols.pop();
+        c4.text.truncate(entry);
+        c4.data_relocations.retain(|relocation| !matches!(relocation, Relocation::Data(offset) if *offset >= entry));
+        c4.line_marks.retain(|&(mark, _)| mark < entry);
+        value.map(Some)
+    }
+
+    /// Make the definitions of the entry, from symbol `start` on, replace
+    /// those of earlier entries with the same names
+    fn replace_definitions(&mut self, start: usize) {
+        let c4 = &mut self.compiler;
+        let mut replaced = Vec::new();
+        for new in start..c4.symbols.len() {
+            let id = c4.symbols[new].id;
+            let Some(old) = c4.symbols[self.builtins..start].iter().rposition(|s| s.id == id).map(|i| i + self.builtins) else {
+                continue;
+            };
+            let (old_class, old_value) = (c4.symbols[old].class, c4.symbols[old].value);
+            let (new_class, new_value) = (c4.symbols[new].class, c4.symbols[new].value);
+            let fun = TokenType::Fun as i32;
+            let glo = TokenType::Glo as i32;
+            if old_class == fun && new_class == fun {
+                // The old entry's `ENT n` has room for a jump to the new one
+                c4.text.redirect(old_value as usize, new_value);
+            } else if old_class == glo && new_class == glo {
+                for relocation in c4.data_relocations.clone() {
+                    match relocation {
+                        Relocation::Data(offset) if c4.text[offset] == old_value => c4.text.set_operand(offset, new_value),
+                        Relocation::DataWord(addr) if c4.mem_load(addr as Word, false) == Some(old_value as Word) => {
+                            c4.mem_store(addr as Word, new_value as Word, false);
+                        },
+                        _ => {},
+                    }
+                }
+            }
+            let line = c4.symbols[new].line;
+            let message = format!("'{}' replaces the earlier definition", c4.symbols[new].name);
+            let span = Span { line, column: 1, end_line: line, end_column: 1 };
+            c4.warnings.push(Warning { span, file: None, kind: WarningKind::Redefinition, message });
+            replaced.push(old);
+        }
+        replaced.sort_unstable();
+        for old in replaced.into_iter().rev() {
+            c4.symbols.remove(old);
+        }
+    }
+}
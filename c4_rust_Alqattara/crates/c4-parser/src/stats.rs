@@ -0,0 +1,74 @@
+//! # Statistics
+//!
+//! Every compilation records the size of its input and output in
+//! `C4::compile_stats`, so it is clear how the compiler scales with large
+//! inputs and a change that makes it use more memory shows up. What the VM
+//! does during a run is counted in `VmStats` (see `c4_vm::stats`). While
+//! a compilation is under way, its `Progress` can be followed.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{optimizer::instruction_starts, C4};
+
+/// Sizes of the input and output of the last compilation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileStats {
+    pub source_bytes: usize,    // Bytes of source read, included files among them
+    pub tokens: usize,          // Tokens lexed, those of macro expansions among them
+    pub ast_nodes: usize,       // Statements, operands and operators parsed
+    pub symbols: usize,         // Entries left in the symbol table, builtins among them
+    pub instructions: usize,    // Instructions in the text segment, after optimization
+    pub text_words: usize,      // Words in the text segment
+    pub data_bytes: usize,      // Bytes in the data segment
+    pub frame_slots: Vec<(String, i32)>, // Stack words each function reserves for its locals, in order of definition
+}
+
+impl fmt::Display for CompileStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "source: {} bytes, {} tokens, {} syntax nodes", self.source_bytes, self.tokens, self.ast_nodes)?;
+        writeln!(f, "symbols: {}", self.symbols)?;
+        writeln!(f, "text: {} instructions in {} words", self.instructions, self.text_words)?;
+        writeln!(f, "data: {} bytes", self.data_bytes)?;
+        for (function, slots) in &self.frame_slots {
+            writeln!(f, "frame of {}: {} words", function, slots)?;
+        }
+        Ok(())
+    }
+}
+
+/// How far a compilation has got, as told to `C4::on_progress`
+///
+/// Reported every few thousand tokens, after each function definition and
+/// once the whole source has been parsed, so a GUI or language server can
+/// show progress through a large input. With the source held in memory,
+/// `source_bytes` ends at `CompileStats::source_bytes`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub source_bytes: usize,    // Bytes of the main source lexed, not counting included files
+    pub functions: usize,       // Function definitions compiled
+    pub text_words: usize,      // Words of code emitted, before optimization
+}
+
+impl C4 {
+    /// Tell `on_progress`, if set, how far the compilation has got
+    pub(crate) fn report_progress(&mut self) {
+        let progress = Progress {
+            source_bytes: self.dropped_bytes + self.main_source_pos(),
+            functions: self.functions_compiled,
+            text_words: self.text.len(),
+        };
+        if let Some(on_progress) = self.on_progress.as_mut() {
+            on_progress(&progress);
+        }
+    }
+
+    /// Record the size of the compiled program in `compile_stats`
+    pub(crate) fn record_output_sizes(&mut self) {
+        self.compile_stats.symbols = self.symbols.len();
+        self.compile_stats.instructions = instruction_starts(&self.text).len();
+        self.compile_stats.text_words = self.text.len();
+        self.compile_stats.data_bytes = self.data.len();
+    }
+}
@@ -0,0 +1,184 @@
+//! # Bytecode Verifier
+//!
+//! Checks the structure of a text segment before it is run: every opcode is
+//! one the VM knows and has its operand, every jump, call and function
+//! address is the start of an instruction, and each function uses the stack
+//! in a balanced way. Each function is walked from its entry along every
+//! branch; all paths reaching an instruction must do so with the same number
+//! of words pushed, and no instruction may pop below the frame that `ENT`
+//! set up.
+//!
+//! The compiler verifies everything it generates, so a code generator or
+//! optimizer bug is reported as a compile error rather than a VM trap, and
+//! images are verified as they are loaded.
+//!
+//! The depths found on the way are also what [`frame_depths`] gives the VM
+//! when `C4::check_stack` is set, so a run that leaves the stack in a
+//! state the walk did not predict traps at the first instruction that sees
+//! it, rather than going wrong later.
+
+use std::fmt;
+
+use crate::optimizer::{has_operand, has_text_operand, instruction_starts};
+use crate::Instruction;
+
+/// Why a text segment failed verification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub address: usize,         // Text address of the offending instruction
+    pub message: String,        // What is wrong with it
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid bytecode at {}: {}", self.address, self.message)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Most words the operand of an instruction that grows or shrinks the
+/// stack by a count may name: far more than any stack holds, and small
+/// enough that the VM's frame arithmetic cannot overflow
+pub const MAX_FRAME_WORDS: i32 = 1 << 24;
+
+fn fail<T>(address: usize, message: String) -> Result<T, VerifyError> {
+    Err(VerifyError { address, message })
+}
+
+/// Verify the text segment of a program
+///
+/// # Arguments
+///
+/// * `text` - The text segment
+/// * `functions` - Name and entry address of every function
+pub fn verify(text: &[i32], functions: &[(String, i32)]) -> Result<(), VerifyError> {
+    frame_depths(text, functions).map(|_| ())
+}
+
+/// Verify the text segment of a program, as [`verify`] does
+///
+/// # Returns
+///
+/// For each text address, how many words the frame it runs in should hold
+/// when the instruction there is reached: `bp - sp` in the VM. It is None
+/// for a function's first instruction, which runs before its frame is set
+/// up, and for code no function reaches.
+pub fn frame_depths(text: &[i32], functions: &[(String, i32)]) -> Result<Vec<Option<i32>>, VerifyError> {
+    // Instruction boundaries; the end of the text segment counts as one,
+    // since a jump there ends the run
+    let starts = instruction_starts(text);
+    let mut boundaries = vec![false; text.len() + 1];
+    for &pc in &starts {
+        boundaries[pc] = true;
+    }
+    boundaries[text.len()] = true;
+
+    // Opcodes, operands that are text addresses and operands that count words
+    for &pc in &starts {
+        let op = text[pc];
+        if Instruction::from_opcode(op).is_none() {
+            return fail(pc, format!("unknown opcode {}", op));
+        }
+        if has_operand(op) && pc + 1 >= text.len() {
+            return fail(pc, format!("{} is missing its operand", Instruction::ALL[op as usize].name()));
+        }
+        let counts_words = [Instruction::ENT, Instruction::IENT, Instruction::ADJ, Instruction::ILEV, Instruction::TLEV]
+            .iter()
+            .any(|&counted| op == counted as i32);
+        if counts_words && !(0..=MAX_FRAME_WORDS).contains(&text[pc + 1]) {
+            let name = Instruction::ALL[op as usize].name();
+            return fail(pc, format!("{} operand {} is not between 0 and {}", name, text[pc + 1], MAX_FRAME_WORDS));
+        }
+        if has_text_operand(op) {
+            let target = text[pc + 1];
+            if target < 0 || target as usize > text.len() {
+                return fail(pc, format!("target {} is outside the text segment", target));
+            }
+            if !boundaries[target as usize] {
+                return fail(pc, format!("target {} is in the middle of an instruction", target));
+            }
+        }
+    }
+
+    let mut frames = vec![None; text.len()];
+    let mut entries: Vec<(&str, i32)> = functions.iter().map(|(name, entry)| (name.as_str(), *entry)).collect();
+    entries.sort_by_key(|&(_, entry)| entry);
+    entries.dedup_by_key(|&mut (_, entry)| entry);
+    for (i, &(name, entry)) in entries.iter().enumerate() {
+        if entry < 0 || entry as usize >= text.len() {
+            return fail(entry.max(0) as usize, format!("function '{}' starts outside the text segment", name));
+        }
+        if !boundaries[entry as usize] {
+            return fail(entry as usize, format!("function '{}' starts in the middle of an instruction", name));
+        }
+        let end = entries.get(i + 1).map_or(text.len(), |&(_, next)| next as usize);
+        verify_function(text, entry as usize, end, &mut frames)?;
+    }
+    Ok(frames)
+}
+
+/// Walk the function at `entry..end`, checking that it keeps the stack
+/// balanced, and record the frame depth of each instruction in `frames`
+fn verify_function(text: &[i32], entry: usize, end: usize, frames: &mut [Option<i32>]) -> Result<(), VerifyError> {
+    // Words pushed when each instruction is reached, once it has been
+    let mut depths: Vec<Option<i64>> = vec![None; end - entry];
+
+    // (pc, words pushed, lowest depth allowed, depths before each enclosing IENT)
+    let mut worklist = vec![(entry, 0, 0, Vec::<i64>::new())];
+    while let Some((pc, depth, floor, mut inline_bases)) = worklist.pop() {
+        if pc < entry || pc >= end {
+            continue;
+        }
+        match depths[pc - entry] {
+            Some(seen) if seen == depth => continue,
+            Some(seen) => {
+                return fail(pc, format!("reached with {} words on the stack on one path and {} on another", seen, depth));
+            },
+            None => depths[pc - entry] = Some(depth),
+        }
+
+        // `ENT` leaves bp one word below where the function's own words
+        // start, and `IENT` two below where the inlined call's start
+        if pc != entry {
+            let frame = match inline_bases.last() {
+                Some(&base) => depth - base - 2,
+                None => depth - 1,
+            };
+            frames[pc] = Some(frame as i32);
+        }
+
+        let op = text[pc];
+        let operand = text.get(pc + 1).copied().unwrap_or(0) as i64;
+        let next = pc + if has_operand(op) { 2 } else { 1 };
+        let (depth, floor) = match op {
+            op if op == Instruction::ENT as i32 => (depth + 1 + operand, depth + 1 + operand),
+            op if op == Instruction::IENT as i32 => {
+                inline_bases.push(depth);
+                (depth + 2 + operand, floor)
+            },
+            op if op == Instruction::ILEV as i32 => match inline_bases.pop() {
+                Some(base) => (base - operand, floor),
+                None => return fail(pc, "ILEV outside an inlined call".to_string()),
+            },
+            op if op == Instruction::ADJ as i32 => (depth - operand, floor),
+            op if op == Instruction::LEV as i32
+                || op == Instruction::TLEV as i32
+                || op == Instruction::EXIT as i32 => continue,
+            op if op == Instruction::JMP as i32 => {
+                worklist.push((operand.max(0) as usize, depth, floor, inline_bases));
+                continue;
+            },
+            op if op == Instruction::BZ as i32 || op == Instruction::BNZ as i32 => {
+                worklist.push((operand.max(0) as usize, depth, floor, inline_bases.clone()));
+                (depth, floor)
+            },
+            op => (depth + Instruction::ALL[op as usize].stack_effect().unwrap_or(0) as i64, floor),
+        };
+        if depth < floor {
+            return fail(pc, "pops more words than were pushed".to_string());
+        }
+        worklist.push((next, depth, floor, inline_bases));
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+//! # Runtime Errors
+//!
+//! A run that stops at a fault, such as a division by zero or a store
+//! outside memory, leaves a [`RuntimeError`] in `Machine::runtime_error`
+//! describing it in full: what kind of fault it was, the instruction and
+//! registers at the time, the calls in progress and the code around the
+//! PC. Graders and telemetry can tell faults apart by [`TrapKind`] rather
+//! than by parsing the message reported through the host.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::hang::disassemble_around;
+use crate::{Machine, Word};
+
+/// What kind of fault stopped a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapKind {
+    DivisionByZero, // `/` or `%` by zero
+    Overflow,       // Arithmetic past the range of a word, with `Overflow::Trap`
+    MemoryAccess,   // A load or store outside memory or misaligned, or a bad pointer given to a builtin
+    ReadOnlyWrite,  // A store to memory in `Machine::read_only`
+    StackOverflow,  // The stack ran out
+    StackFault,     // The stack pointer went outside the stack, or a frame was not as the verifier expects
+    InvalidCode,    // An unknown opcode, or a PC outside the text segment
+    InvalidArgument, // A builtin was given arguments it cannot work with, such as `free` of a non-block
+    BoundsCheck,    // A subscript outside its array, with bounds checks compiled in
+    Assertion,      // A failed `assert`
+    Fault,          // A fault the register backend does not tell apart further
+}
+
+/// The VM registers when a run stopped, besides the PC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub bp: i32,    // Base pointer
+    pub sp: i32,    // Stack pointer
+    pub ax: Word,   // Accumulator
+}
+
+/// Everything known about the fault a run stopped at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub kind: TrapKind,
+    pub message: String,            // What was reported through the host, as in `Machine::trap`
+    pub pc: i32,                    // Text address of the instruction that faulted, or on the register backend the first of those fused with it
+    pub cycles: i32,                // Instructions executed before then
+    pub registers: Registers,
+    pub backtrace: Vec<String>,     // Functions on the call stack, the faulting one first
+    pub listing: String,            // Disassembly around the PC, which is marked with `>`
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} at pc {} after {} cycles", self.message, self.pc, self.cycles)?;
+        let Registers { bp, sp, ax } = self.registers;
+        writeln!(f, "registers: bp {} sp {} ax {}", bp, sp, ax)?;
+        if !self.backtrace.is_empty() {
+            writeln!(f, "in {}", self.backtrace.join(" <- "))?;
+        }
+        write!(f, "{}", self.listing)
+    }
+}
+
+impl Machine<'_> {
+    /// Describe the fault the run stopped at, the instruction at text
+    /// address `pc` having caused it
+    pub(crate) fn runtime_error(&self, pc: i32) -> Option<RuntimeError> {
+        let message = self.trap.clone()?;
+        Some(RuntimeError {
+            kind: self.trap_kind,
+            message,
+            pc,
+            cycles: self.cycle,
+            registers: Registers { bp: self.bp, sp: self.sp, ax: self.ax },
+            backtrace: self.backtrace(pc),
+            listing: disassemble_around(&self.text, pc),
+        })
+    }
+}
@@ -0,0 +1,102 @@
+//! # Hangs
+//!
+//! A run that cannot finish is stopped with a [`Hang`] saying where it was
+//! stuck. A branch to itself, which nothing can ever leave since nothing
+//! changes between one time it is taken and the next, is caught the first
+//! time it is taken; any other loop runs until `Sandbox::max_cycles` is used
+//! up and is reported at the instruction it had reached. Either way the
+//! report names the function, lists the code around the PC and gives a hint,
+//! rather than leaving only an exit code of -2.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+use core::fmt::Write;
+
+use crate::optimizer::instruction_starts;
+use crate::{report, DecodedInstr, Instruction, Machine};
+
+/// Instructions listed on each side of the PC of a hang
+const CONTEXT: usize = 3;
+
+/// Why a run was stopped as a hang
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HangKind {
+    SelfLoop,       // A branch to its own address was taken, so the run could never end
+    CycleLimit,     // The run used up `Sandbox::max_cycles`
+}
+
+/// Where a run that could not finish was stuck
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hang {
+    pub kind: HangKind,
+    pub pc: i32,                    // Text address of the instruction the run was stopped at
+    pub cycles: i32,                // Instructions executed before then
+    pub function: Option<String>,   // Function holding the PC
+    pub listing: String,            // Disassembly around the PC, which is marked with `>`
+}
+
+impl Hang {
+    /// What is likely wrong, and what to do about it
+    pub fn hint(&self) -> &'static str {
+        match self.kind {
+            HangKind::SelfLoop => "the loop's body is empty and its condition cannot change, as in `while (1);`",
+            HangKind::CycleLimit => "check that the loop's condition can become false, or raise Sandbox::max_cycles if the program only needs longer",
+        }
+    }
+}
+
+impl fmt::Display for Hang {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.kind {
+            HangKind::SelfLoop => "Infinite loop",
+            HangKind::CycleLimit => "Maximum cycle count reached",
+        };
+        write!(f, "{} at pc {}", what, self.pc)?;
+        if let Some(function) = &self.function {
+            write!(f, " in {}", function)?;
+        }
+        writeln!(f, " after {} cycles", self.cycles)?;
+        write!(f, "{}", self.listing)?;
+        write!(f, "hint: {}", self.hint())
+    }
+}
+
+/// List the instructions around `pc`, one per line, marking the one at `pc`
+pub fn disassemble_around(text: &[i32], pc: i32) -> String {
+    let starts = instruction_starts(text);
+    let at = starts.partition_point(|&start| (start as i32) < pc);
+    let mut listing = String::new();
+    for &start in &starts[at.saturating_sub(CONTEXT)..(at + CONTEXT + 1).min(starts.len())] {
+        let marker = if start as i32 == pc { '>' } else { ' ' };
+        let _ = match DecodedInstr::decode(text, start) {
+            Some(instruction) => writeln!(listing, "{} {:6}: {}", marker, start, instruction),
+            None => {
+                let op = text[start];
+                let name = Instruction::from_opcode(op).map_or_else(|| format!("?{}", op), |i| i.name().into());
+                writeln!(listing, "{} {:6}: {}", marker, start, name)
+            },
+        };
+    }
+    listing
+}
+
+impl Machine<'_> {
+    /// Stop the run as a hang at text address `pc`
+    ///
+    /// # Returns
+    ///
+    /// The exit code for a run that was stopped, -2
+    pub(crate) fn hang(&mut self, kind: HangKind, pc: i32) -> i32 {
+        let hang = Hang {
+            kind,
+            pc,
+            cycles: self.cycle,
+            function: self.function_at(pc).map(String::from),
+            listing: disassemble_around(&self.text, pc),
+        };
+        report!(self, "{}", hang);
+        self.hang = Some(hang);
+        -2
+    }
+}
@@ -0,0 +1,237 @@
+//! # Heap
+//!
+//! `malloc` and `free` for interpreted programs. The heap is the part of
+//! the data segment past the program's globals, so heap pointers are plain
+//! data addresses. Each run starts with an empty heap and gives its memory
+//! back when it ends. A request is rounded up to a multiple of 8 bytes and
+//! served from the first freed block big enough, or else from the end of
+//! the segment; a freed block is merged with free neighbours.
+//!
+//! With `VmOptions::heap_profile` set, every call is also recorded in
+//! `Machine::heap_profile`: the sizes
+//! asked for, live bytes over time, the functions that allocated, and the
+//! blocks still live when the program ended.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{trap, Machine};
+use crate::{Instruction, TrapKind, Word, NULL_GUARD, STACK_BASE};
+
+/// Alignment and granularity of heap blocks, enough for a double
+const ALIGN: usize = 8;
+
+/// A block returned by `malloc`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    pub address: Word,          // Data address returned to the program
+    pub size: usize,            // Bytes asked for
+    pub cycle: i32,             // Cycle of the call
+    pub backtrace: Vec<String>, // Functions on the call stack, the caller of malloc first
+}
+
+/// Allocations made by one function
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionAllocations {
+    pub count: u64,             // Calls to malloc
+    pub bytes: u64,             // Bytes asked for
+}
+
+/// How the last run used the heap
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapProfile {
+    pub allocations: u64,                 // Calls to malloc that returned a block
+    pub failed_allocations: u64,          // Calls to malloc that returned 0
+    pub frees: u64,                       // Calls to free with a block
+    pub allocated_bytes: u64,             // Bytes asked for in all
+    pub live_bytes: usize,                // Bytes in blocks not yet freed
+    pub peak_bytes: usize,                // Most bytes live at once
+    pub timeline: Vec<(i32, usize)>,      // Cycle and live bytes after each malloc or free
+    pub by_function: BTreeMap<String, FunctionAllocations>, // Allocations by the function that called malloc
+    pub live: BTreeMap<Word, Allocation>, // Blocks not yet freed, by address; once the run is over, the leaks
+}
+
+impl fmt::Display for HeapProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap: {} allocations, {} frees, {} bytes allocated, peak {} bytes live",
+                 self.allocations, self.frees, self.allocated_bytes, self.peak_bytes)?;
+        if self.failed_allocations > 0 {
+            writeln!(f, "  {} allocations failed", self.failed_allocations)?;
+        }
+
+        // Biggest allocators first
+        let mut functions: Vec<(&str, FunctionAllocations)> =
+            self.by_function.iter().map(|(name, &counts)| (name.as_str(), counts)).collect();
+        functions.sort_by_key(|&(name, counts)| (core::cmp::Reverse(counts.bytes), name));
+        for (name, counts) in functions {
+            writeln!(f, "  {:<16} {} allocations, {} bytes", name, counts.count, counts.bytes)?;
+        }
+
+        if self.live.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "leaked: {} bytes in {} blocks", self.live_bytes, self.live.len())?;
+        for block in self.live.values() {
+            writeln!(f, "  {} bytes at {}, allocated in {}", block.size, block.address, block.backtrace.join(" <- "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Blocks of the heap
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Heap {
+    pub(crate) base: usize,         // Where the heap starts: the end of the program's data
+    used: BTreeMap<usize, usize>,   // Address -> size of each block in use
+    free: BTreeMap<usize, usize>,   // Address -> size of each freed block
+}
+
+impl Heap {
+    /// A heap with nothing in it, starting at `base`
+    pub(crate) fn new(base: usize) -> Self {
+        Heap { base, ..Heap::default() }
+    }
+
+    /// Bytes in the blocks in use
+    pub(crate) fn used_bytes(&self) -> usize {
+        self.used.values().sum()
+    }
+
+    /// Take a block of at least `size` bytes, growing `data` if need be
+    /// but never past `limit` bytes of heap
+    fn alloc(&mut self, data: &mut Vec<u8>, size: usize, limit: usize) -> Option<usize> {
+        let size = size.max(1).checked_next_multiple_of(ALIGN)?;
+        let reuse = self.free.iter().find(|&(_, &free)| free >= size).map(|(&address, &free)| (address, free));
+        let address = if let Some((address, free)) = reuse {
+            self.free.remove(&address);
+            if free > size {
+                self.free.insert(address + size, free - size);
+            }
+            address
+        } else {
+            // Address 0 is NULL, so a block never starts there
+            let address = data.len().max(1).next_multiple_of(ALIGN);
+            let end = address.checked_add(size)?;
+            if end - self.base > limit || end as Word > STACK_BASE {
+                return None;
+            }
+            data.resize(end, 0);
+            address
+        };
+        self.used.insert(address, size);
+        Some(address)
+    }
+
+    /// Give back the block at `address`, shrinking `data` if it was last
+    ///
+    /// # Returns
+    ///
+    /// `None` if no block in use starts at `address`
+    fn release(&mut self, data: &mut Vec<u8>, address: usize) -> Option<()> {
+        let mut size = self.used.remove(&address)?;
+        let mut start = address;
+        if let Some(next) = self.free.remove(&(address + size)) {
+            size += next;
+        }
+        if let Some((&prev, &prev_size)) = self.free.range(..address).next_back() {
+            if prev + prev_size == address {
+                self.free.remove(&prev);
+                start = prev;
+                size += prev_size;
+            }
+        }
+        if start + size == data.len() {
+            data.truncate(start);
+        } else {
+            self.free.insert(start, size);
+        }
+        Some(())
+    }
+}
+
+impl Machine<'_> {
+    /// Start a run with an empty heap just past the program's data
+    pub(crate) fn heap_start(&mut self) {
+        if self.data.len() < NULL_GUARD {
+            self.data.resize(NULL_GUARD, 0);
+        }
+        self.heap = Heap::new(self.data.len());
+        self.heap_profile = HeapProfile::default();
+    }
+
+    /// End a run, giving back the memory of the heap
+    pub(crate) fn heap_finish(&mut self) {
+        self.data.truncate(self.heap.base);
+        self.heap = Heap::new(self.data.len());
+    }
+
+    /// Heap system calls: `malloc` and `free`, called from text address `pc`
+    ///
+    /// `malloc` returns 0 once the heap would grow past
+    /// `VmOptions::heap_bytes`, and `free(0)` does nothing. Shared by both VM
+    /// backends. Returns false (after reporting the error) if the argument is
+    /// missing or `free` is given an address that is not a live block.
+    pub(crate) fn vm_heap(&mut self, op: i32, pc: i32) -> bool {
+        if self.sp < -1 || self.sp + 1 >= self.stack.len() as i32 {
+            trap!(self, TrapKind::StackFault, "Stack underflow in heap call");
+            return false;
+        }
+        let arg = self.vm_options.wrap(self.stack[(self.sp + 1) as usize]);
+
+        if op == Instruction::MALLOC as i32 {
+            let limit = self.vm_options.heap_bytes;
+            let address = usize::try_from(arg).ok().and_then(|size| self.heap.alloc(&mut self.data, size, limit));
+            self.ax = address.map_or(0, |address| address as Word);
+            if self.vm_options.heap_profile {
+                self.profile_malloc(arg as usize, self.ax, pc);
+            }
+            return true;
+        }
+
+        self.ax = 0;
+        if arg == 0 {
+            return true;
+        }
+        if usize::try_from(arg).ok().and_then(|address| self.heap.release(&mut self.data, address)).is_none() {
+            trap!(self, TrapKind::InvalidArgument, "Invalid free of address {}", arg);
+            return false;
+        }
+        if self.vm_options.heap_profile {
+            self.profile_free(arg);
+        }
+        true
+    }
+
+    /// Record a call to malloc that returned `address`
+    fn profile_malloc(&mut self, size: usize, address: Word, pc: i32) {
+        if address == 0 {
+            self.heap_profile.failed_allocations += 1;
+            return;
+        }
+        let backtrace = self.backtrace(pc);
+        let profile = &mut self.heap_profile;
+        profile.allocations += 1;
+        profile.allocated_bytes += size as u64;
+        profile.live_bytes += size;
+        profile.peak_bytes = profile.peak_bytes.max(profile.live_bytes);
+        profile.timeline.push((self.cycle, profile.live_bytes));
+
+        let caller = backtrace.first().cloned().unwrap_or_else(|| "?".to_string());
+        let counts = profile.by_function.entry(caller).or_default();
+        counts.count += 1;
+        counts.bytes += size as u64;
+        profile.live.insert(address, Allocation { address, size, cycle: self.cycle, backtrace });
+    }
+
+    /// Record a call to free of the block at `address`
+    fn profile_free(&mut self, address: Word) {
+        let profile = &mut self.heap_profile;
+        if let Some(block) = profile.live.remove(&address) {
+            profile.frees += 1;
+            profile.live_bytes -= block.size;
+            profile.timeline.push((self.cycle, profile.live_bytes));
+        }
+    }
+}
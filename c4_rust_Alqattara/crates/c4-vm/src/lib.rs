@@ -0,0 +1,1790 @@
+//! # c4 Virtual Machine
+//!
+//! The instruction set of the c4 compiler and the interpreter for it, kept
+//! free of `std` so the VM can be embedded on targets without an operating
+//! system, or in wasm without any JavaScript glue. Only `core` and `alloc`
+//! are used; the I/O system calls go through a [`Host`] supplied by the
+//! embedder, and the math calls use `libm` when the `std` feature is off.
+//!
+//! ```
+//! use c4_vm::{Host, Instruction, Machine, VmOptions, Word};
+//!
+//! struct Output(Vec<u8>);
+//!
+//! impl Host for Output {
+//!     fn write(&mut self, _stream: Word, bytes: &[u8]) -> bool {
+//!         self.0.extend_from_slice(bytes);
+//!         true
+//!     }
+//! }
+//!
+//! // putchar('A'), then return 7
+//! let text = vec![
+//!     Instruction::ENT as i32, 0,
+//!     Instruction::IMM as i32, 65, Instruction::PUSH as i32,
+//!     Instruction::PUTC as i32, Instruction::ADJ as i32, 1,
+//!     Instruction::IMM as i32, 7, Instruction::LEV as i32,
+//! ];
+//! let mut host = Output(Vec::new());
+//! let mut machine = Machine::new(text, Vec::new(), VmOptions::default(), &mut host);
+//! assert_eq!(machine.run(0, 0), 7);
+//! drop(machine);
+//! assert_eq!(host.0, b"A");
+//! ```
+//!
+//! The `c4_rust` compiler runs its programs the same way, lending its
+//! segments to a [`Machine`] for the length of a run.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod fault;
+pub mod hang;
+pub mod heap;
+pub mod memory;
+pub mod optimizer;
+pub mod printf;
+pub mod regvm;
+pub mod stats;
+
+pub use fault::{Registers, RuntimeError, TrapKind};
+pub use hang::{Hang, HangKind};
+pub use heap::{Allocation, FunctionAllocations, HeapProfile};
+pub use memory::{MemoryMap, Region, RegionKind};
+pub use stats::VmStats;
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::heap::Heap;
+
+#[cfg(not(feature = "std"))]
+use libm as math;
+
+/// The math library: the methods of `f64` with `std`, libm without it
+#[cfg(feature = "std")]
+mod math {
+    pub fn sqrt(x: f64) -> f64 {
+        x.sqrt()
+    }
+
+    pub fn pow(x: f64, y: f64) -> f64 {
+        x.powf(y)
+    }
+
+    pub fn sin(x: f64) -> f64 {
+        x.sin()
+    }
+
+    pub fn cos(x: f64) -> f64 {
+        x.cos()
+    }
+}
+
+/// Report a message through the machine's host
+macro_rules! report {
+    ($vm:expr, $($arg:tt)*) => {
+        $vm.host.report(format_args!($($arg)*))
+    };
+}
+pub(crate) use report;
+
+/// Report a fault of a [`TrapKind`] that stops the run, and record it as
+/// the machine's trap
+macro_rules! trap {
+    ($vm:expr, $kind:expr, $($arg:tt)*) => {{
+        let message = alloc::format!($($arg)*);
+        $vm.host.fault(format_args!("{}", message));
+        $vm.trap = Some(message);
+        $vm.trap_kind = $kind;
+    }};
+}
+pub(crate) use trap;
+
+/// Virtual machine instructions
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Instruction {
+    LEA,    // Load effective address
+    IMM,    // Load immediate value
+    JMP,    // Jump
+    JSR,    // Jump to subroutine
+    BZ,     // Branch if zero
+    BNZ,    // Branch if not zero
+    ENT,    // Enter subroutine
+    ADJ,    // Adjust stack
+    LEV,    // Leave subroutine
+    LI,     // Load int
+    LC,     // Load char
+    SI,     // Store int
+    SC,     // Store char
+    PUSH,   // Push value onto stack
+    OR,     // Bitwise OR
+    XOR,    // Bitwise XOR
+    AND,    // Bitwise AND
+    EQ,     // Equal
+    NE,     // Not equal
+    LT,     // Less than
+    GT,     // Greater than
+    LE,     // Less than or equal
+    GE,     // Greater than or equal
+    SHL,    // Shift left
+    SHR,    // Shift right
+    ADD,    // Add
+    SUB,    // Subtract
+    MUL,    // Multiply
+    DIV,    // Divide
+    MOD,    // Modulo
+    OPEN,   // Open file
+    READ,   // Read from file
+    CLOS,   // Close file
+    PRINTF, // Printf
+    MALLOC, // Malloc
+    MSET,   // Memset
+    MCMP,   // Memcmp
+    EXIT,    // Exit
+    FLD,    // Load floating-point
+    FST,    // Store floating-point
+    FADD,   // Floating-point add
+    FSUB,   // Floating-point subtract
+    FMUL,   // Floating-point multiply
+    FDIV,   // Floating-point divide
+    IENT,   // Enter inlined subroutine
+    ILEV,   // Leave inlined subroutine and pop its arguments
+    TLEV,   // Leave subroutine for a tail call, reusing its return address
+    FPRINTF,  // Fprintf
+    SPRINTF,  // Sprintf
+    SNPRINTF, // Snprintf
+    PUTC,     // Putchar
+    PUTS,     // Puts
+    GETC,     // Getchar
+    ASSERT,   // Assert
+    ABS,      // Integer absolute value
+    SQRT,     // Square root
+    POW,      // Power
+    SIN,      // Sine
+    COS,      // Cosine
+    QSORT,    // Qsort
+    FADDR,    // Load the address of a function
+    FREE,     // Free
+    BOUND,    // Trap unless ax is an index within the array described at the operand
+}
+
+impl Instruction {
+    /// Every instruction, in opcode order
+    pub const ALL: [Instruction; 63] = [
+        Instruction::LEA, Instruction::IMM, Instruction::JMP, Instruction::JSR, Instruction::BZ,
+        Instruction::BNZ, Instruction::ENT, Instruction::ADJ, Instruction::LEV, Instruction::LI,
+        Instruction::LC, Instruction::SI, Instruction::SC, Instruction::PUSH, Instruction::OR,
+        Instruction::XOR, Instruction::AND, Instruction::EQ, Instruction::NE, Instruction::LT,
+        Instruction::GT, Instruction::LE, Instruction::GE, Instruction::SHL, Instruction::SHR,
+        Instruction::ADD, Instruction::SUB, Instruction::MUL, Instruction::DIV, Instruction::MOD,
+        Instruction::OPEN, Instruction::READ, Instruction::CLOS, Instruction::PRINTF,
+        Instruction::MALLOC, Instruction::MSET, Instruction::MCMP, Instruction::EXIT,
+        Instruction::FLD, Instruction::FST, Instruction::FADD, Instruction::FSUB,
+        Instruction::FMUL, Instruction::FDIV, Instruction::IENT, Instruction::ILEV,
+        Instruction::TLEV, Instruction::FPRINTF, Instruction::SPRINTF, Instruction::SNPRINTF,
+        Instruction::PUTC, Instruction::PUTS, Instruction::GETC, Instruction::ASSERT,
+        Instruction::ABS, Instruction::SQRT, Instruction::POW, Instruction::SIN, Instruction::COS,
+        Instruction::QSORT, Instruction::FADDR, Instruction::FREE, Instruction::BOUND,
+    ];
+
+    /// The instruction with opcode `op`
+    pub fn from_opcode(op: i32) -> Option<Instruction> {
+        usize::try_from(op).ok().and_then(|i| Self::ALL.get(i)).copied()
+    }
+
+    /// Mnemonic of the instruction, such as `"LEA"`
+    pub fn name(self) -> &'static str {
+        const NAMES: [&str; 63] = [
+            "LEA", "IMM", "JMP", "JSR", "BZ", "BNZ", "ENT", "ADJ", "LEV", "LI", "LC", "SI", "SC",
+            "PUSH", "OR", "XOR", "AND", "EQ", "NE", "LT", "GT", "LE", "GE", "SHL", "SHR", "ADD", "SUB",
+            "MUL", "DIV", "MOD", "OPEN", "READ", "CLOS", "PRINTF", "MALLOC", "MSET", "MCMP", "EXIT",
+            "FLD", "FST", "FADD", "FSUB", "FMUL", "FDIV", "IENT", "ILEV", "TLEV", "FPRINTF", "SPRINTF",
+            "SNPRINTF", "PUTC", "PUTS", "GETC", "ASSERT", "ABS", "SQRT", "POW", "SIN", "COS", "QSORT",
+            "FADDR", "FREE", "BOUND",
+        ];
+        NAMES[self as usize]
+    }
+
+    /// Words of operand that follow the opcode in the text segment: 0 or 1
+    pub const fn operands(self) -> usize {
+        match self {
+            Instruction::LEA | Instruction::IMM | Instruction::JMP | Instruction::JSR | Instruction::BZ
+            | Instruction::BNZ | Instruction::ENT | Instruction::ADJ | Instruction::IENT | Instruction::ILEV
+            | Instruction::TLEV | Instruction::FADDR | Instruction::BOUND => 1,
+            _ => 0,
+        }
+    }
+
+    /// Whether the instruction is an integer operator, which pops `a` and
+    /// leaves `a op ax` in ax
+    pub const fn is_binary(self) -> bool {
+        matches!(
+            self,
+            Instruction::OR | Instruction::XOR | Instruction::AND | Instruction::EQ | Instruction::NE
+            | Instruction::LT | Instruction::GT | Instruction::LE | Instruction::GE | Instruction::SHL
+            | Instruction::SHR | Instruction::ADD | Instruction::SUB | Instruction::MUL | Instruction::DIV
+            | Instruction::MOD
+        )
+    }
+
+    /// Whether the instruction is a jump or branch, whose operand is the
+    /// text address it may go to
+    pub const fn is_branch(self) -> bool {
+        matches!(self, Instruction::JMP | Instruction::BZ | Instruction::BNZ)
+    }
+
+    /// Whether the VM runs the instruction
+    ///
+    /// c4's `OPEN`, `READ`, `CLOS` and `MCMP` keep their opcodes, but no
+    /// builtin compiles to them and both backends trap on them as unknown
+    /// instructions.
+    pub const fn is_implemented(self) -> bool {
+        !matches!(self, Instruction::OPEN | Instruction::READ | Instruction::CLOS | Instruction::MCMP)
+    }
+
+    /// Net number of words the instruction pushes, negative if it pops
+    ///
+    /// None for those that set up or drop a frame, whose effect depends on
+    /// the operand or the frame: `ENT`, `IENT`, `ILEV`, `ADJ`, and `LEV`,
+    /// `TLEV` and `EXIT`, after which the function does not go on. A call
+    /// is 0, since the callee pops its return address, and so is a builtin,
+    /// whose arguments the caller's `ADJ` pops.
+    pub const fn stack_effect(self) -> Option<i32> {
+        match self {
+            Instruction::ENT | Instruction::IENT | Instruction::ILEV | Instruction::ADJ
+            | Instruction::LEV | Instruction::TLEV | Instruction::EXIT => None,
+            Instruction::PUSH => Some(1),
+            _ if self.is_binary() => Some(-1),
+            Instruction::SI | Instruction::SC | Instruction::FADD | Instruction::FSUB | Instruction::FMUL
+            | Instruction::FDIV => Some(-1),
+            _ => Some(0),
+        }
+    }
+}
+
+/// An instruction of a text segment, with its operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedInstr {
+    pub pc: usize,              // Text address of the opcode
+    pub op: Instruction,
+    pub operand: Option<i32>,   // Word after the opcode, for instructions that take one
+}
+
+impl DecodedInstr {
+    /// The instruction at `pc` of `text`
+    ///
+    /// None if the word there is not an opcode, or its operand is missing.
+    pub fn decode(text: &[i32], pc: usize) -> Option<DecodedInstr> {
+        let op = Instruction::from_opcode(*text.get(pc)?)?;
+        let operand = if op.operands() > 0 { Some(*text.get(pc + 1)?) } else { None };
+        Some(DecodedInstr { pc, op, operand })
+    }
+
+    /// Words the instruction takes up in the text segment
+    pub fn words(&self) -> usize {
+        if self.operand.is_some() { 2 } else { 1 }
+    }
+}
+
+impl fmt::Display for DecodedInstr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.operand {
+            Some(operand) => write!(f, "{} {}", self.op.name(), operand),
+            None => write!(f, "{}", self.op.name()),
+        }
+    }
+}
+
+/// Decode `text` one instruction at a time, from address 0
+pub fn decode(text: &[i32]) -> Instructions<'_> {
+    Instructions { text, pc: 0 }
+}
+
+/// Iterator over the instructions of a text segment, made by [`decode`]
+///
+/// Stops at the end of the text, or at the first word that is not an
+/// opcode or whose operand is cut off; [`Instructions::pc`] then tells
+/// which. Text that passed the verifier decodes to the end.
+#[derive(Debug, Clone)]
+pub struct Instructions<'a> {
+    text: &'a [i32],
+    pc: usize,
+}
+
+impl Instructions<'_> {
+    /// Text address of the next instruction to decode
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = DecodedInstr;
+
+    fn next(&mut self) -> Option<DecodedInstr> {
+        let instruction = DecodedInstr::decode(self.text, self.pc)?;
+        self.pc += instruction.words();
+        Some(instruction)
+    }
+}
+
+impl core::iter::FusedIterator for Instructions<'_> {}
+
+/// Which virtual machine executes the compiled program
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Stack,      // The original c4 stack machine
+    Register,   // Experimental register machine (see `regvm`)
+}
+
+/// What the VM does when `+`, `-`, `*` or `/` overflows the word size
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    #[default]
+    Wrap,       // Two's complement wrap-around, as C compilers do in practice
+    Saturate,   // Clamp to the smallest or largest word
+    Trap,       // Stop the program with an error
+}
+
+/// A VM word on the stack or in the accumulator
+///
+/// Values are held in 64 bits and truncated to `VmOptions::word_size` after
+/// every operation. Operands in the text segment stay 32-bit and are
+/// sign-extended when loaded.
+pub type Word = i64;
+
+/// Stream handles that interpreted programs see as `stdout` and `stderr`
+pub const STDOUT: Word = 1;
+pub const STDERR: Word = 2;
+
+/// What an interpreted program is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sandbox {
+    pub max_cycles: i32,      // Instructions a run may execute before it is stopped as a likely infinite loop
+    pub allow_input: bool,    // getchar reads from the host; otherwise it always sees end of input
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox { max_cycles: 1000000, allow_input: true }
+    }
+}
+
+/// Settings for the virtual machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmOptions {
+    pub stack_words: usize,   // Number of words available on the VM stack
+    pub backend: Backend,     // Virtual machine used by `run`
+    pub word_size: usize,     // Bytes in a VM word, pointer and int: 4 (default) or 8
+    pub overflow: Overflow,   // Result of arithmetic that overflows the word
+    pub signed_char: bool,    // Chars are sign-extended when loaded, as with most compilers; false zero-extends them
+    pub sandbox: Sandbox,     // Limits on what the program may do
+    pub stats: bool,          // Count what each run does in `Machine::vm_stats`
+    pub heap_bytes: usize,    // Bytes the heap may grow to before malloc returns 0
+    pub heap_profile: bool,   // Record what each run does with the heap in `Machine::heap_profile`
+    pub position_independent: bool, // Text addresses in operands are relative to the operand, so code runs at any offset
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            stack_words: 256 * 1024,
+            backend: Backend::Stack,
+            word_size: 4,
+            overflow: Overflow::Wrap,
+            signed_char: true,
+            sandbox: Sandbox::default(),
+            stats: false,
+            heap_bytes: 16 * 1024 * 1024,
+            heap_profile: false,
+            position_independent: false,
+        }
+    }
+}
+
+impl VmOptions {
+    /// Value of a char whose byte is the low byte of `value`, as an int
+    pub fn char_value(&self, value: Word) -> Word {
+        if self.signed_char { value as u8 as i8 as Word } else { value & 0xFF }
+    }
+
+    /// Byte address of a stack slot
+    pub fn stack_addr(&self, slot: i32) -> Word {
+        STACK_BASE + slot as Word * self.word_bytes() as Word
+    }
+
+    /// Bytes in a word, pointer and `int`; anything other than 8 means 4
+    pub fn word_bytes(&self) -> i32 {
+        if self.word_size == 8 { 8 } else { 4 }
+    }
+
+    /// Truncate a value to the word size, sign-extending it back to a `Word`
+    pub fn wrap(&self, value: Word) -> Word {
+        if self.word_size == 8 { value } else { value as i32 as Word }
+    }
+
+    /// Evaluate a binary operator, with `a` the pushed operand and `b` the accumulator
+    ///
+    /// Arithmetic that overflows the word size is handled as `overflow`
+    /// says. The other cases C leaves open are defined as follows:
+    ///
+    /// * Shift counts are masked to the word size (`count & (bits - 1)`, as
+    ///   x86 does), so shifting by the word size or more, or by a negative
+    ///   amount, never faults. `>>` is an arithmetic shift.
+    /// * `/` truncates toward zero and `%` takes the sign of the dividend, as
+    ///   in C99, so `-7 / 2 == -3` and `-7 % 2 == -1`. `MIN % -1` is 0.
+    ///
+    /// Returns `None` for division by zero, for an overflow under
+    /// `Overflow::Trap`, and for opcodes that are not binary operators.
+    pub fn alu(&self, op: i32, a: Word, b: Word) -> Option<Word> {
+        let bits = self.word_bytes() as u32 * 8;
+        let (a, b) = (self.wrap(a), self.wrap(b));
+        let exact = match op {
+            op if op == Instruction::ADD as i32 => Some(a as i128 + b as i128),
+            op if op == Instruction::SUB as i32 => Some(a as i128 - b as i128),
+            op if op == Instruction::MUL as i32 => Some(a as i128 * b as i128),
+            op if op == Instruction::DIV as i32 && b != 0 => Some(a as i128 / b as i128),
+            _ => None,
+        };
+        if let Some(exact) = exact {
+            let (min, max) = (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1);
+            return match self.overflow {
+                _ if (min..=max).contains(&exact) => Some(exact as Word),
+                Overflow::Wrap => Some(self.wrap(exact as Word)),
+                Overflow::Saturate => Some(exact.clamp(min, max) as Word),
+                Overflow::Trap => None,
+            };
+        }
+
+        let value = match op {
+            op if op == Instruction::OR as i32 => a | b,
+            op if op == Instruction::XOR as i32 => a ^ b,
+            op if op == Instruction::AND as i32 => a & b,
+            op if op == Instruction::EQ as i32 => (a == b) as Word,
+            op if op == Instruction::NE as i32 => (a != b) as Word,
+            op if op == Instruction::LT as i32 => (a < b) as Word,
+            op if op == Instruction::GT as i32 => (a > b) as Word,
+            op if op == Instruction::LE as i32 => (a <= b) as Word,
+            op if op == Instruction::GE as i32 => (a >= b) as Word,
+            op if op == Instruction::SHL as i32 => a << (b & (bits as Word - 1)),
+            op if op == Instruction::SHR as i32 => a >> (b & (bits as Word - 1)),
+            op if op == Instruction::MOD as i32 && b != 0 => a.wrapping_rem(b),
+            _ => return None,
+        };
+        Some(self.wrap(value))
+    }
+}
+
+/// Return address pushed for a call made by a system call back into VM code
+///
+/// It lies outside the text segment, so the callee's LEV stops the VM loop,
+/// and differs from the -1 that `run` pushes for the entry function.
+pub const CALLBACK_RETURN: i32 = -2;
+
+/// Byte address of the first stack word
+///
+/// The data segment occupies the addresses below it, so a pointer can refer
+/// to either without any tagging.
+pub const STACK_BASE: Word = 0x4000_0000;
+
+/// Bytes at the start of the data segment that nothing is put in
+///
+/// No global, literal or heap block starts at address 0, so a null pointer
+/// compares unequal to every pointer to one, and loads and stores through
+/// it trap.
+pub const NULL_GUARD: usize = 8;
+
+
+/// What a running program can see of the world outside the VM
+///
+/// The embedder implements this to connect the I/O system calls to
+/// whatever the platform has: `putchar`, `puts`, `printf` and `fprintf`
+/// write through it, and `getchar` reads through it.
+pub trait Host {
+    /// Write `bytes` to `stream`, [`STDOUT`] or [`STDERR`]
+    ///
+    /// Returns false if the stream is unknown or the write failed, which
+    /// the program sees as an I/O error.
+    fn write(&mut self, stream: Word, bytes: &[u8]) -> bool;
+
+    /// Read the next byte of input, or `None` at end of input
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// Report a fault that stopped the program, which is also left in
+    /// `Machine::runtime_error`; by default through [`Host::report`]
+    fn fault(&mut self, message: fmt::Arguments) {
+        self.report(message);
+    }
+
+    /// Report a hang the program was stopped in, or what the VM is doing
+    /// if `Machine::debug` is set
+    fn report(&mut self, message: fmt::Arguments) {
+        let _ = message;
+    }
+}
+
+/// Load a word, or a byte extended as `options.signed_char` says if `char`
+/// is set, from a byte address
+///
+/// Addresses below `STACK_BASE` are in the data segment, where words are
+/// stored little-endian, apart from the first `NULL_GUARD`. Above it, ints must be word aligned and a char
+/// is one byte of the (little-endian) stack word that contains it.
+///
+/// # Returns
+///
+/// `None` if the address is outside memory, misaligned or null
+pub fn load(data: &[u8], stack: &[Word], options: &VmOptions, addr: Word, char: bool) -> Option<Word> {
+    let size = if char { 1 } else { options.word_bytes() as usize };
+    if addr >= STACK_BASE {
+        let offset = (addr - STACK_BASE) as usize;
+        let word_bytes = options.word_bytes() as usize;
+        let word = *stack.get(offset / word_bytes)?;
+        return match (char, offset % word_bytes) {
+            (true, byte) => Some(options.char_value(word >> (8 * byte))),
+            (false, 0) => Some(word),
+            _ => None,
+        };
+    }
+
+    let start = usize::try_from(addr).ok().filter(|&start| start >= NULL_GUARD)?;
+    let bytes = data.get(start..start.checked_add(size)?)?;
+    let value = bytes.iter().rev().fold(0, |acc: Word, &b| (acc << 8) | b as Word);
+    Some(if char { options.char_value(value) } else { options.wrap(value) })
+}
+
+/// Store a word, or its low byte if `char` is set, at a byte address
+///
+/// # Returns
+///
+/// `None` if the address is outside memory, misaligned or null
+pub fn store(data: &mut [u8], stack: &mut [Word], options: &VmOptions, addr: Word, value: Word, char: bool) -> Option<()> {
+    let size = if char { 1 } else { options.word_bytes() as usize };
+    if addr >= STACK_BASE {
+        let offset = (addr - STACK_BASE) as usize;
+        let word_bytes = options.word_bytes() as usize;
+        let word = stack.get_mut(offset / word_bytes)?;
+        match (char, offset % word_bytes) {
+            (true, byte) => {
+                let shift = 8 * byte;
+                *word = (*word & !(0xFF << shift)) | ((value & 0xFF) << shift);
+            },
+            (false, 0) => *word = value,
+            _ => return None,
+        }
+        return Some(());
+    }
+
+    let start = usize::try_from(addr).ok().filter(|&start| start >= NULL_GUARD)?;
+    let bytes = data.get_mut(start..start.checked_add(size)?)?;
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (8 * i)) as u8;
+    }
+    Some(())
+}
+
+/// Cycles between checks of the cancel token
+const CANCEL_INTERVAL: i32 = 1024;
+
+/// A flag for stopping a run from another thread
+///
+/// Clones share the flag. The VM checks it every [`CANCEL_INTERVAL`]
+/// cycles and stops as if it had run out of cycles. A token stays
+/// cancelled, so a machine needs a new one to run again.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// A token that has not been cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask whatever holds a clone of this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `cancel` has been called on this token or a clone of it
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A program in the VM: its segments, registers and the host it talks to
+pub struct Machine<'a> {
+    pub text: Vec<i32>,       // Text segment
+    pub data: Vec<u8>,        // Data segment (byte addressed); the heap grows past its end during a run
+    pub stack: Vec<Word>,     // Stack
+    pub pc: i32,              // Program counter
+    pub bp: i32,              // Base pointer
+    pub sp: i32,              // Stack pointer: the next free slot, -1 once the stack is full
+    pub ax: Word,             // Accumulator
+    pub ax_float: f64,        // Floating-point accumulator
+    pub cycle: i32,           // Cycle counter
+    pub vm_options: VmOptions, // Stack size and other VM settings
+    pub debug: bool,          // Report what the VM is doing through the host
+    pub functions: Vec<(String, i32)>, // Name and entry address of each function, for backtraces
+    pub inline_sites: Vec<(i32, i32)>, // Text address of each IENT and the entry of the function it inlines, for backtraces
+    pub vm_stats: VmStats,    // What the last run did, if `vm_options.stats` is set
+    pub heap_profile: HeapProfile, // How the last run used the heap, if `vm_options.heap_profile` is set
+    pub cancel: CancelToken,  // Stops the run when cancelled
+    pub hang: Option<Hang>,   // Where the last run was stuck, if it was stopped as a hang
+    pub trap: Option<String>, // The fault the last run stopped at, if it stopped at one
+    pub runtime_error: Option<RuntimeError>, // The same fault in full
+    pub frame_depths: Vec<Option<i32>>, // Words each instruction's frame should hold (`bp - sp`), checked by the stack backend; empty for none
+    pub read_only: Vec<(usize, usize)>, // Sorted, disjoint byte ranges of the data segment, start to end, that stores trap in; empty for none
+    pub(crate) trap_kind: TrapKind, // Kind of the fault in `trap`
+    pub(crate) executing: i32, // Text address of the instruction being executed, or on the register backend its op index
+    pub(crate) heap: Heap,    // Blocks of the heap during a run
+    pub(crate) host: &'a mut dyn Host, // Where I/O goes
+}
+
+impl<'a> Machine<'a> {
+    /// Load a program with an empty stack
+    pub fn new(text: Vec<i32>, data: Vec<u8>, vm_options: VmOptions, host: &'a mut dyn Host) -> Self {
+        let heap = Heap::new(data.len());
+        Machine {
+            text,
+            data,
+            stack: Vec::new(),
+            pc: 0,
+            bp: 0,
+            sp: 0,
+            ax: 0,
+            ax_float: 0.0,
+            cycle: 0,
+            vm_options,
+            debug: false,
+            functions: Vec::new(),
+            inline_sites: Vec::new(),
+            vm_stats: VmStats::default(),
+            heap_profile: HeapProfile::default(),
+            cancel: CancelToken::default(),
+            hang: None,
+            trap: None,
+            runtime_error: None,
+            trap_kind: TrapKind::Fault,
+            executing: 0,
+            frame_depths: Vec::new(),
+            read_only: Vec::new(),
+            heap,
+            host,
+        }
+    }
+}
+
+impl Machine<'_> {
+    /// Run the program from text address `entry`
+    ///
+    /// `argc` is pushed as the entry function's argument, with a return
+    /// address outside the text segment so its LEV ends the run. The stack
+    /// is resized to `vm_options.stack_words`, and the heap is empty at the
+    /// start and given back at the end.
+    ///
+    /// # Returns
+    ///
+    /// The exit code of the program: the entry function's return value,
+    /// -1 if the program faulted, or -2 if it was stopped as a hang, with
+    /// `hang` saying where, or cancelled
+    pub fn run(&mut self, entry: i32, argc: i32) -> i32 {
+        self.run_with_args(entry, &[argc as Word])
+    }
+
+    /// Run from `entry` as [`Machine::run`] does, calling it with `args`
+    ///
+    /// The entry function must take exactly as many parameters as there
+    /// are `args`, which are passed in order, as a call would pass them;
+    /// `main(argc, argv)` is given `argc` and the address of `argv`.
+    pub fn run_with_args(&mut self, entry: i32, args: &[Word]) -> i32 {
+        // Initialize VM state
+        self.pc = entry;
+        self.executing = entry;
+        let stack_words = self.vm_options.stack_words;
+        self.bp = stack_words as i32;
+        self.sp = stack_words as i32;
+        self.cycle = 0;
+        self.hang = None;
+        self.trap = None;
+        self.runtime_error = None;
+        self.vm_stats = VmStats::default();
+        
+        // Make sure the stack has the configured size - stack_words + 3 to be safe
+        if self.stack.len() != stack_words + 3 {
+            self.stack.clear();
+            self.stack.resize(stack_words + 3, 0);
+        }
+
+        // Check if PC is valid before starting
+        if self.pc < 0 || self.pc >= self.text.len() as i32 {
+            trap!(self, TrapKind::InvalidCode, "Invalid entry point: {}", self.pc);
+            return -1; // Invalid entry point
+        }
+
+        // Push the arguments and a return address outside the text segment,
+        // so the entry function's LEV ends the run with its return value
+        let pushed = args.len() as i32 + 1;
+        if self.sp < pushed || self.sp > self.stack.len() as i32 {
+            trap!(self, TrapKind::StackFault, "Stack out of bounds when pushing the arguments");
+            return -1; // Stack out of bounds
+        }
+        for (i, &arg) in args.iter().enumerate() {
+            self.stack[self.sp as usize - 1 - i] = arg;
+        }
+        self.stack[(self.sp - pushed) as usize] = -1;
+        self.sp -= pushed + 1;
+
+        self.heap_start();
+
+        // The register backend runs a translation of the same text segment
+        let code = match self.vm_options.backend {
+            Backend::Register if self.vm_options.position_independent => {
+                let mut text = self.text.clone();
+                optimizer::to_absolute(&mut text);
+                regvm::translate(&text, entry)
+            },
+            Backend::Register => regvm::translate(&self.text, entry),
+            Backend::Stack => None,
+        };
+        let exit_code = match &code {
+            Some(code) => self.run_register(code),
+            None => self.execute(),
+        };
+        let pc = code.map_or(self.executing, |code| code.address(self.executing as usize));
+        self.runtime_error = self.runtime_error(pc);
+        self.heap_finish();
+        exit_code
+    }
+
+    /// Text address held by the operand at `pc`, which position-independent
+    /// code gives relative to `pc`
+    fn text_operand(&self, pc: i32) -> i32 {
+        let operand = self.text[pc as usize];
+        if self.vm_options.position_independent { pc + operand } else { operand }
+    }
+
+    /// Run the stack VM from the current pc until the program ends
+    ///
+    /// Also used for calls back into VM code from system calls, which end
+    /// when the callee's LEV returns to [`CALLBACK_RETURN`].
+    ///
+    /// # Returns
+    ///
+    /// The exit code of the program
+    fn execute(&mut self) -> i32 {
+        let max_cycles = self.vm_options.sandbox.max_cycles;
+        
+        while self.pc >= 0 && self.pc < self.text.len() as i32 && self.cycle < max_cycles {
+            self.cycle += 1;
+            if self.cancelled() {
+                return -2;
+            }
+            
+            if self.debug && self.cycle % 10000 == 0 {
+                report!(self, "VM cycle: {}, PC: {}, SP: {}, BP: {}, AX: {}", 
+                         self.cycle, self.pc, self.sp, self.bp, self.ax);
+            }
+
+            // Fetch instruction
+            let at = self.pc;
+            let op = self.text[self.pc as usize];
+            self.pc += 1;
+            self.executing = at;
+            if let Some(&Some(depth)) = self.frame_depths.get(at as usize) {
+                if self.bp - self.sp != depth {
+                    trap!(self, TrapKind::StackFault, "Unbalanced stack at {}: {} words in the frame, {} expected", at, self.bp - self.sp, depth);
+                    return -1;
+                }
+            }
+            if self.vm_options.stats {
+                self.record_op(op);
+            }
+
+            match op {
+                op if op == Instruction::LEA as i32 => {
+                    // Load effective address
+                    if self.pc < self.text.len() as i32 {
+                    self.ax = self.stack_addr(self.bp + self.text[self.pc as usize]);
+                    self.pc += 1;
+                    } else {
+                        trap!(self, TrapKind::InvalidCode, "PC out of bounds in LEA");
+                        return -1; // PC out of bounds
+                    }
+                },
+                op if op == Instruction::IMM as i32 => {
+                    // Load immediate value
+                    if self.pc < self.text.len() as i32 {
+                    self.ax = self.text[self.pc as usize] as Word;
+                    self.pc += 1;
+                    } else {
+                        trap!(self, TrapKind::InvalidCode, "PC out of bounds in IMM");
+                        return -1; // PC out of bounds
+                    }
+                },
+                op if op == Instruction::JMP as i32 => {
+                    // Jump
+                    if self.pc < self.text.len() as i32 {
+                    self.pc = self.text_operand(self.pc);
+                    } else {
+                        trap!(self, TrapKind::InvalidCode, "PC out of bounds in JMP");
+                        return -1; // PC out of bounds
+                    }
+                },
+                op if op == Instruction::JSR as i32 => {
+                    // Jump to subroutine
+                    if self.sp < 0 {
+                        trap!(self, TrapKind::StackOverflow, "Stack overflow in JSR");
+                        return -1; // Stack overflow
+                    }
+                    if self.sp < self.stack.len() as i32 && self.pc < self.text.len() as i32 {
+                    self.stack[self.sp as usize] = (self.pc + 1) as Word;
+                    self.sp -= 1;
+                    self.pc = self.text_operand(self.pc);
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack or PC out of bounds in JSR");
+                        return -1; // Stack or PC out of bounds
+                    }
+                },
+                op if op == Instruction::BZ as i32 => {
+                    // Branch if zero
+                    if self.pc < self.text.len() as i32 {
+                    self.pc = if self.ax == 0 { self.text_operand(self.pc) } else { self.pc + 1 };
+                    } else {
+                        trap!(self, TrapKind::InvalidCode, "PC out of bounds in BZ");
+                        return -1; // PC out of bounds
+                    }
+                },
+                op if op == Instruction::BNZ as i32 => {
+                    // Branch if not zero
+                    if self.pc < self.text.len() as i32 {
+                    self.pc = if self.ax != 0 { self.text_operand(self.pc) } else { self.pc + 1 };
+                    } else {
+                        trap!(self, TrapKind::InvalidCode, "PC out of bounds in BNZ");
+                        return -1; // PC out of bounds
+                    }
+                },
+                op if op == Instruction::ENT as i32 => {
+                    // Enter subroutine
+                    if self.sp < 0 {
+                        trap!(self, TrapKind::StackOverflow, "Stack overflow in ENT");
+                        return -1; // Stack overflow
+                    }
+                    if self.sp < self.stack.len() as i32 && self.pc < self.text.len() as i32 {
+                    self.stack[self.sp as usize] = self.bp as Word;
+                    self.sp -= 1;
+                    self.bp = self.sp;
+                        
+                        // Allocate space for local variables
+                        let local_space = self.text[self.pc as usize];
+                        let Some(sp) = self.sp.checked_sub(local_space).filter(|&sp| local_space >= 0 && sp >= -1) else {
+                            trap!(self, TrapKind::StackOverflow, "Stack overflow in ENT");
+                            return -1; // Stack overflow
+                        };
+                        
+                        self.sp = sp;
+                    self.pc += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack or PC out of bounds in ENT");
+                        return -1; // Stack or PC out of bounds
+                    }
+                },
+                op if op == Instruction::ADJ as i32 => {
+                    // Adjust stack
+                    if self.pc < self.text.len() as i32 {
+                        let adj = self.text[self.pc as usize];
+                        let Some(sp) = self.sp.checked_add(adj).filter(|&sp| sp >= -1 && sp < self.stack.len() as i32) else {
+                            trap!(self, TrapKind::StackFault, "Stack adjustment out of bounds");
+                            return -1; // Stack adjustment out of bounds
+                        };
+                        
+                        self.sp = sp;
+                    self.pc += 1;
+                    } else {
+                        trap!(self, TrapKind::InvalidCode, "PC out of bounds in ADJ");
+                        return -1; // PC out of bounds
+                    }
+                },
+                op if op == Instruction::LEV as i32 => {
+                    // Leave subroutine
+                    if self.sp >= -1 && 
+                       self.sp < self.stack.len() as i32 && 
+                       self.bp >= 0 &&
+                       self.bp < self.stack.len() as i32 && 
+                       (self.bp + 1) < self.stack.len() as i32 && 
+                       (self.bp + 2) < self.stack.len() as i32 {
+                        self.sp = self.bp;
+                        self.bp = self.stack[(self.sp + 1) as usize] as i32;
+                        self.pc = self.stack[(self.sp + 2) as usize] as i32;
+                        self.sp += 2;
+                        
+                        // If PC is invalid after LEV, we're returning from main
+                        if self.pc < 0 || self.pc >= self.text.len() as i32 {
+                            if self.debug {
+                                report!(self, "Returning from main with value: {}", self.ax);
+                            }
+                            return self.ax as i32; // Return the value in ax
+                        }
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack out of bounds in LEV");
+                        return self.ax as i32; // Stack out of bounds, return anyway
+                    }
+                },
+                op if op == Instruction::IENT as i32 => {
+                    // Enter inlined subroutine: put the address of the operand where the
+                    // return address would be, so a backtrace can find the call, then
+                    // behave like ENT
+                    if self.sp >= 1 &&
+                       self.sp < self.stack.len() as i32 &&
+                       self.pc < self.text.len() as i32 {
+                        self.stack[self.sp as usize] = self.pc as Word;
+                        self.sp -= 1;
+                        self.stack[self.sp as usize] = self.bp as Word;
+                        self.sp -= 1;
+                        self.bp = self.sp;
+
+                        let local_space = self.text[self.pc as usize];
+                        let Some(sp) = self.sp.checked_sub(local_space).filter(|&sp| local_space >= 0 && sp >= -1) else {
+                            trap!(self, TrapKind::StackOverflow, "Stack overflow in IENT");
+                            return -1; // Stack overflow
+                        };
+
+                        self.sp = sp;
+                        self.pc += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack or PC out of bounds in IENT");
+                        return -1; // Stack or PC out of bounds
+                    }
+                },
+                op if op == Instruction::ILEV as i32 => {
+                    // Leave inlined subroutine: restore bp, then drop the return slot and arguments
+                    let argc = self.text.get(self.pc as usize).copied().unwrap_or(-1);
+                    if self.bp >= 0 &&
+                       (self.bp + 1) < self.stack.len() as i32 &&
+                       (0..self.stack.len() as i32).contains(&argc) {
+                        self.sp = self.bp;
+                        self.bp = self.stack[(self.sp + 1) as usize] as i32;
+                        self.sp += 2 + argc;
+                        self.pc += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack out of bounds in ILEV");
+                        return -1; // Stack out of bounds
+                    }
+                },
+                op if op == Instruction::TLEV as i32 => {
+                    // Tail call: overwrite our arguments with the outgoing ones and drop the frame
+                    if self.pc < self.text.len() as i32 {
+                        let argc = self.text[self.pc as usize];
+                        if self.sp < -1 ||
+                           self.bp < 0 ||
+                           !(0..self.stack.len() as i32).contains(&argc) ||
+                           self.sp + argc >= self.stack.len() as i32 ||
+                           self.bp + 2 + argc >= self.stack.len() as i32 {
+                            trap!(self, TrapKind::StackFault, "Stack out of bounds in TLEV");
+                            return -1; // Stack out of bounds
+                        }
+
+                        for i in 1..=argc {
+                            self.stack[(self.bp + 2 + i) as usize] = self.stack[(self.sp + i) as usize];
+                        }
+                        self.sp = self.bp + 1;
+                        self.bp = self.stack[self.sp as usize] as i32;
+                        self.pc += 1;
+                    } else {
+                        trap!(self, TrapKind::InvalidCode, "PC out of bounds in TLEV");
+                        return -1; // PC out of bounds
+                    }
+                },
+                op if op == Instruction::EXIT as i32 => {
+                    // Exit
+                    if self.debug {
+                        report!(self, "EXIT instruction, returning: {}", self.ax);
+                    }
+                    return self.ax as i32;
+                },
+                op if op == Instruction::LI as i32 => {
+                    // Load int
+                    if let Some(value) = self.mem_load(self.ax, false) {
+                        self.ax = value;
+                    } else {
+                        trap!(self, TrapKind::MemoryAccess, "Memory access violation in LI");
+                        return -1; // Memory access violation
+                    }
+                },
+                op if op == Instruction::LC as i32 => {
+                    // Load char
+                    if let Some(value) = self.mem_load(self.ax, true) {
+                        self.ax = value;
+                    } else {
+                        trap!(self, TrapKind::MemoryAccess, "Memory access violation in LC");
+                        return -1; // Memory access violation
+                    }
+                },
+                op if op == Instruction::FLD as i32 => {
+                    // Load double, keeping its bits in ax so it can be pushed
+                    if let Some(value) = self.float_load(self.ax) {
+                        self.ax_float = value;
+                        self.ax = value.to_bits() as Word;
+                    } else {
+                        trap!(self, TrapKind::MemoryAccess, "Memory access violation in FLD");
+                        return -1; // Memory access violation
+                    }
+                },
+                op if op == Instruction::SI as i32 => {
+                    // Store int
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    let addr = self.stack[(self.sp + 1) as usize];
+                        if self.is_read_only(addr, false) {
+                            trap!(self, TrapKind::ReadOnlyWrite, "Write to read-only memory at {} in SI", addr);
+                            return -1;
+                        }
+                        if self.mem_store(addr, self.ax, false).is_some() {
+                    self.sp += 1;
+                        } else {
+                            trap!(self, TrapKind::MemoryAccess, "Memory access violation in SI");
+                            return -1; // Memory access violation
+                        }
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in SI");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::SC as i32 => {
+                    // Store char
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    let addr = self.stack[(self.sp + 1) as usize];
+                        if self.is_read_only(addr, true) {
+                            trap!(self, TrapKind::ReadOnlyWrite, "Write to read-only memory at {} in SC", addr);
+                            return -1;
+                        }
+                        if self.mem_store(addr, self.ax, true).is_some() {
+                    self.sp += 1;
+                        } else {
+                            trap!(self, TrapKind::MemoryAccess, "Memory access violation in SC");
+                            return -1; // Memory access violation
+                        }
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in SC");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::PUSH as i32 => {
+                    // Push value onto stack
+                    if self.sp >= 0 && self.sp < self.stack.len() as i32 {
+                    self.stack[self.sp as usize] = self.ax;
+                    self.sp -= 1;
+                    } else {
+                        trap!(self, TrapKind::StackOverflow, "Stack overflow in PUSH");
+                        return -1; // Stack overflow
+                    }
+                },
+                op if op == Instruction::OR as i32 => {
+                    // Bitwise OR
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in OR");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::XOR as i32 => {
+                    // Bitwise XOR
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in XOR");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::AND as i32 => {
+                    // Bitwise AND
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in AND");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::EQ as i32 => {
+                    // Equal
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in EQ");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::NE as i32 => {
+                    // Not equal
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in NE");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::LT as i32 => {
+                    // Less than
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in LT");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::GT as i32 => {
+                    // Greater than
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in GT");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::LE as i32 => {
+                    // Less than or equal
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in LE");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::GE as i32 => {
+                    // Greater than or equal
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in GE");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::SHL as i32 => {
+                    // Shift left
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in SHL");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::SHR as i32 => {
+                    // Shift right
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in SHR");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::ADD as i32 => {
+                    // Add
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                        let Some(value) = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax) else {
+                            trap!(self, TrapKind::Overflow, "Integer overflow in ADD");
+                            return -1; // Overflow trap
+                        };
+                        self.ax = value;
+                        self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in ADD");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::SUB as i32 => {
+                    // Subtract
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                        let Some(value) = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax) else {
+                            trap!(self, TrapKind::Overflow, "Integer overflow in SUB");
+                            return -1; // Overflow trap
+                        };
+                        self.ax = value;
+                        self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in SUB");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::MUL as i32 => {
+                    // Multiply
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                        let Some(value) = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax) else {
+                            trap!(self, TrapKind::Overflow, "Integer overflow in MUL");
+                            return -1; // Overflow trap
+                        };
+                        self.ax = value;
+                        self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in MUL");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::DIV as i32 => {
+                    // Divide
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                        if self.ax == 0 {
+                            trap!(self, TrapKind::DivisionByZero, "Division by zero in DIV");
+                            return -1; // Division by zero
+                        }
+                        let Some(value) = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax) else {
+                            trap!(self, TrapKind::Overflow, "Integer overflow in DIV");
+                            return -1; // Overflow trap
+                        };
+                        self.ax = value;
+                        self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in DIV");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::MOD as i32 => {
+                    // Modulo
+                    if self.sp >= -1 && self.sp + 1 < self.stack.len() as i32 {
+                        if self.ax == 0 {
+                            trap!(self, TrapKind::DivisionByZero, "Division by zero in MOD");
+                            return -1; // Division by zero
+                        }
+                    self.ax = self.vm_options.alu(op, self.stack[(self.sp + 1) as usize], self.ax).unwrap_or(0);
+                    self.sp += 1;
+                    } else {
+                        trap!(self, TrapKind::StackFault, "Stack underflow in MOD");
+                        return -1; // Stack underflow
+                    }
+                },
+                op if op == Instruction::PRINTF as i32
+                    || op == Instruction::FPRINTF as i32
+                    || op == Instruction::SPRINTF as i32
+                    || op == Instruction::SNPRINTF as i32 => {
+                    // The ADJ after the call tells printf how many arguments were pushed
+                    let argc = if self.text.get(self.pc as usize) == Some(&(Instruction::ADJ as i32)) {
+                        self.text.get(self.pc as usize + 1).copied().unwrap_or(0)
+                    } else {
+                        0
+                    };
+                    if !self.vm_format_call(op, argc) {
+                        return -1;
+                    }
+                },
+                op if op == Instruction::PUTC as i32
+                    || op == Instruction::PUTS as i32
+                    || op == Instruction::GETC as i32 => {
+                    if !self.vm_char_io(op) {
+                        return -1;
+                    }
+                },
+                op if op == Instruction::ABS as i32
+                    || op == Instruction::SQRT as i32
+                    || op == Instruction::POW as i32
+                    || op == Instruction::SIN as i32
+                    || op == Instruction::COS as i32 => {
+                    if !self.vm_math(op) {
+                        return -1;
+                    }
+                },
+                op if op == Instruction::FADDR as i32 => {
+                    // Load function address
+                    if self.pc < self.text.len() as i32 {
+                        self.ax = self.text_operand(self.pc) as Word;
+                        self.pc += 1;
+                    } else {
+                        trap!(self, TrapKind::InvalidCode, "PC out of bounds in FADDR");
+                        return -1; // PC out of bounds
+                    }
+                },
+                op if op == Instruction::QSORT as i32 => {
+                    if !self.vm_qsort(|vm, entry, args| vm.call_stack(entry, args)) {
+                        return -1;
+                    }
+                },
+                op if op == Instruction::MSET as i32 => {
+                    if !self.vm_memset() {
+                        return -1;
+                    }
+                },
+                op if op == Instruction::MALLOC as i32 || op == Instruction::FREE as i32 => {
+                    if !self.vm_heap(op, self.pc) {
+                        return -1;
+                    }
+                },
+                op if op == Instruction::ASSERT as i32 => {
+                    if !self.vm_assert() {
+                        return -1;
+                    }
+                },
+                op if op == Instruction::BOUND as i32 => {
+                    let Some(&record) = self.text.get(self.pc as usize) else {
+                        trap!(self, TrapKind::InvalidCode, "PC out of bounds in BOUND");
+                        return -1; // PC out of bounds
+                    };
+                    self.pc += 1;
+                    if !self.vm_bound(record) {
+                        return -1;
+                    }
+                },
+                // Continue with other instructions...
+                _ => {
+                    trap!(self, TrapKind::InvalidCode, "Unknown instruction: {}", op);
+                    return -1; // Unknown instruction
+                }
+            }
+
+            // A branch taken to itself will be taken again forever
+            if self.pc == at && Instruction::from_opcode(op).is_some_and(Instruction::is_branch) {
+                return self.hang(HangKind::SelfLoop, at);
+            }
+        }
+        
+        // If we've reached the maximum cycle count, it's likely an infinite loop
+        if self.cycle >= max_cycles {
+            return self.hang(HangKind::CycleLimit, self.pc);
+        }
+        
+        if self.debug {
+            report!(self, "VM execution completed with {} cycles", self.cycle);
+        }
+        self.ax as i32 // Return the current value in the accumulator
+    }
+
+    /// Check the cancel token, as the dispatch loops do every
+    /// [`CANCEL_INTERVAL`] cycles
+    pub(crate) fn cancelled(&mut self) -> bool {
+        if self.cycle % CANCEL_INTERVAL != 0 || !self.cancel.is_cancelled() {
+            return false;
+        }
+        if self.debug {
+            report!(self, "Run cancelled after {} cycles", self.cycle);
+        }
+        true
+    }
+
+    /// Byte address of a stack slot
+    pub fn stack_addr(&self, slot: i32) -> Word {
+        self.vm_options.stack_addr(slot)
+    }
+
+    /// Load a word or byte from VM memory; see [`load`]
+    pub fn mem_load(&self, addr: Word, char: bool) -> Option<Word> {
+        load(&self.data, &self.stack, &self.vm_options, addr, char)
+    }
+
+    /// Store a word or byte in VM memory; see [`store`]
+    ///
+    /// A store that would change a byte in `read_only` fails as one outside
+    /// memory does.
+    pub fn mem_store(&mut self, addr: Word, value: Word, char: bool) -> Option<()> {
+        if self.is_read_only(addr, char) {
+            return None;
+        }
+        store(&mut self.data, &mut self.stack, &self.vm_options, addr, value, char)
+    }
+
+    /// Whether a store of a word, or a byte if `char` is set, at `addr`
+    /// would change a byte in `read_only`
+    pub fn is_read_only(&self, addr: Word, char: bool) -> bool {
+        let Ok(start) = usize::try_from(addr) else {
+            return false;
+        };
+        let end = start.saturating_add(if char { 1 } else { self.vm_options.word_bytes() as usize });
+        // The first range that ends after the store starts is the only one it can overlap
+        let i = self.read_only.partition_point(|&(_, range_end)| range_end <= start);
+        self.read_only.get(i).is_some_and(|&(range_start, _)| range_start < end)
+    }
+
+    /// Read the 8-byte double at `addr` in the data segment
+    pub(crate) fn float_load(&self, addr: Word) -> Option<f64> {
+        let start = usize::try_from(addr).ok().filter(|&start| start >= NULL_GUARD)?;
+        let bytes = self.data.get(start..start.checked_add(8)?)?;
+        Some(f64::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    /// Read a NUL-terminated string from VM memory
+    ///
+    /// # Returns
+    ///
+    /// `None` if the string runs outside memory
+    fn vm_string(&self, addr: Word) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.mem_load(addr + bytes.len() as Word, true)?;
+            if byte == 0 {
+                return Some(bytes);
+            }
+            bytes.push(byte as u8);
+        }
+    }
+
+    /// Character I/O system calls: `putchar`, `puts` and `getchar`
+    ///
+    /// Output goes through [`Host::write`] and input through [`Host::read`]. As in C, ax is left holding the character written or read,
+    /// a non-negative value for `puts`, or -1 (EOF) on end of input or an
+    /// I/O error. Shared by both VM backends. Returns false (after reporting
+    /// the error) if the argument is missing or `puts` is given an invalid
+    /// string.
+    pub(crate) fn vm_char_io(&mut self, op: i32) -> bool {
+        if op == Instruction::GETC as i32 {
+            let byte = if self.vm_options.sandbox.allow_input { self.host.read() } else { None };
+            self.ax = byte.map_or(-1, Word::from);
+            return true;
+        }
+
+        let name = if op == Instruction::PUTS as i32 { "PUTS" } else { "PUTC" };
+        if self.sp < -1 || self.sp + 1 >= self.stack.len() as i32 {
+            trap!(self, TrapKind::StackFault, "Stack underflow in {}", name);
+            return false;
+        }
+        let value = self.stack[(self.sp + 1) as usize];
+        let (bytes, result) = if op == Instruction::PUTS as i32 {
+            let Some(mut line) = self.vm_string(value) else {
+                trap!(self, TrapKind::MemoryAccess, "Invalid string pointer in PUTS");
+                return false;
+            };
+            line.push(b'\n');
+            (line, 0)
+        } else {
+            (vec![value as u8], value & 0xff)
+        };
+        self.ax = if self.host.write(STDOUT, &bytes) { result } else { -1 };
+        true
+    }
+
+    /// Call the VM function at text address `entry` from a system call
+    ///
+    /// Pushes `args` in order and a return address of [`CALLBACK_RETURN`],
+    /// runs the stack VM until the function returns, and pops the arguments
+    /// again, leaving pc where it was.
+    ///
+    /// # Returns
+    ///
+    /// The function's return value, or `None` if the program stopped instead
+    fn call_stack(&mut self, entry: i32, args: &[Word]) -> Option<Word> {
+        let pc = self.pc;
+        for &arg in args.iter().chain(&[CALLBACK_RETURN as Word]) {
+            if self.sp < 0 || self.sp >= self.stack.len() as i32 {
+                trap!(self, TrapKind::StackOverflow, "Stack overflow in callback");
+                return None;
+            }
+            self.stack[self.sp as usize] = arg;
+            self.sp -= 1;
+        }
+
+        let executing = self.executing;
+        self.pc = entry;
+        self.execute();
+        let returned = self.pc == CALLBACK_RETURN;
+        self.pc = pc;
+        // A fault in the callee is put down to the callee
+        if returned {
+            self.executing = executing;
+        }
+        self.sp += args.len() as i32;
+        returned.then_some(self.ax)
+    }
+
+    /// Name of the function whose code contains text address `addr`
+    pub fn function_at(&self, addr: i32) -> Option<&str> {
+        self.functions.iter()
+            .filter(|&&(_, entry)| entry <= addr)
+            .max_by_key(|&&(_, entry)| entry)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Functions on the VM call stack, innermost first, while running the
+    /// instruction at text address `pc`
+    ///
+    /// Walks the frames from bp, each holding the caller's bp and the
+    /// address to return to, and stops at the return address that ends the
+    /// run or a callback. The frame of inlined code holds the address of
+    /// its `IENT`'s operand instead, which no return address can be, so the
+    /// code is put down to the function it was copied from.
+    pub fn backtrace(&self, pc: i32) -> Vec<String> {
+        let slot = usize::try_from(self.bp).ok().and_then(|bp| self.stack.get(bp + 2)).map(|&word| word as i32);
+        let inlined = slot.and_then(|operand| self.inline_sites.iter().find(|&&(site, _)| site + 1 == operand));
+        let innermost = match inlined {
+            Some(&(_, entry)) => self.function_at(entry),
+            None => self.function_at(pc),
+        };
+        let mut functions: Vec<String> = innermost.map(str::to_string).into_iter().collect();
+        let mut bp = self.bp;
+        while bp >= 0 && bp + 2 < self.stack.len() as i32 {
+            let (caller_bp, ret) = (self.stack[bp as usize + 1] as i32, self.stack[bp as usize + 2] as i32);
+            let Some(caller) = (ret > 0 && ret <= self.text.len() as i32).then(|| self.function_at(ret)).flatten() else {
+                break;
+            };
+            functions.push(caller.to_string());
+            // Frames get older up the stack; anything else is not a frame
+            if caller_bp <= bp {
+                break;
+            }
+            bp = caller_bp;
+        }
+        functions
+    }
+
+    /// Qsort system call: `qsort(base, count, size, compare)`
+    ///
+    /// Sorts `count` elements of `size` bytes at `base` in place, calling the
+    /// VM function `compare` with the addresses of two elements through
+    /// `call`, which runs it on the current backend. Uses heapsort, so like
+    /// C's qsort it is not stable. Shared by both VM backends. Returns false
+    /// (after reporting the error) if the arguments are invalid, an element
+    /// lies outside memory, or the comparison stops the program.
+    pub(crate) fn vm_qsort(&mut self, mut call: impl FnMut(&mut Self, i32, &[Word]) -> Option<Word>) -> bool {
+        if self.sp < -1 || self.sp + 4 >= self.stack.len() as i32 {
+            trap!(self, TrapKind::StackFault, "Stack underflow in QSORT");
+            return false;
+        }
+        let arg = |i: i32| self.stack[(self.sp + 4 - i) as usize];
+        let (base, count, size, compare) = (arg(0), self.vm_options.wrap(arg(1)), self.vm_options.wrap(arg(2)), arg(3) as i32);
+        if count < 0 || size <= 0 {
+            trap!(self, TrapKind::InvalidArgument, "Invalid element count or size in QSORT");
+            return false;
+        }
+
+        let addr = |i: Word| base + i * size;
+        let mut less = |vm: &mut Self, i: Word, j: Word| -> Option<bool> {
+            let order = call(vm, compare, &[addr(i), addr(j)])?;
+            Some(vm.vm_options.wrap(order) < 0)
+        };
+        let swap = |vm: &mut Self, i: Word, j: Word| -> Option<()> {
+            for k in 0..size {
+                let a = vm.mem_load(addr(i) + k, true)?;
+                let b = vm.mem_load(addr(j) + k, true)?;
+                vm.mem_store(addr(i) + k, b, true)?;
+                vm.mem_store(addr(j) + k, a, true)?;
+            }
+            Some(())
+        };
+
+        // Move element `root` down the max-heap held in the first `len` elements
+        let mut sift_down = |vm: &mut Self, mut root: Word, len: Word| -> Option<()> {
+            loop {
+                let mut child = 2 * root + 1;
+                if child >= len {
+                    return Some(());
+                }
+                if child + 1 < len && less(vm, child, child + 1)? {
+                    child += 1;
+                }
+                if !less(vm, root, child)? {
+                    return Some(());
+                }
+                swap(vm, root, child)?;
+                root = child;
+            }
+        };
+
+        let sorted = (|| {
+            for root in (0..count / 2).rev() {
+                sift_down(self, root, count)?;
+            }
+            for end in (1..count).rev() {
+                swap(self, 0, end)?;
+                sift_down(self, 0, end)?;
+            }
+            Some(())
+        })();
+        if sorted.is_none() {
+            trap!(self, TrapKind::InvalidArgument, "QSORT failed: invalid element or comparison");
+            return false;
+        }
+        true
+    }
+
+    /// Assert system call: stop the program if the condition is false
+    ///
+    /// The compiler passes the message to print as a hidden second argument,
+    /// holding the line of the call and the text of the condition, so no line
+    /// table is needed at run time. On failure the message is written to
+    /// stderr and false is returned, which stops the VM like any other trap.
+    pub(crate) fn vm_assert(&mut self) -> bool {
+        if self.sp < -1 || self.sp + 2 >= self.stack.len() as i32 {
+            trap!(self, TrapKind::StackFault, "Stack underflow in ASSERT");
+            return false;
+        }
+        if self.vm_options.wrap(self.stack[(self.sp + 2) as usize]) != 0 {
+            self.ax = 0;
+            return true;
+        }
+        let message = self.vm_string(self.stack[(self.sp + 1) as usize]).unwrap_or_default();
+        self.host.write(STDERR, &message);
+        self.trap = Some(String::from_utf8_lossy(&message).trim_end().to_string());
+        self.trap_kind = TrapKind::Assertion;
+        false
+    }
+
+    /// Bounds check of an array subscript, `BOUND record`
+    ///
+    /// `record` is the data address of the array's length and the line of
+    /// the subscript, a word each, followed by the array's NUL-terminated
+    /// name. The index is in ax, which is left alone. Shared by both VM
+    /// backends. Returns false (after reporting the fault with the line and
+    /// name) if the index is outside the array.
+    pub(crate) fn vm_bound(&mut self, record: i32) -> bool {
+        let word_bytes = self.vm_options.word_bytes() as Word;
+        let length = self.mem_load(record as Word, false);
+        let line = self.mem_load(record as Word + word_bytes, false);
+        let (Some(length), Some(line)) = (length, line) else {
+            trap!(self, TrapKind::InvalidArgument, "Invalid array record at {} in BOUND", record);
+            return false;
+        };
+        if self.ax >= 0 && self.ax < length {
+            return true;
+        }
+        let name = self.vm_string(record as Word + 2 * word_bytes).unwrap_or_default();
+        trap!(self, TrapKind::BoundsCheck, "Line {}: index {} is out of bounds for '{}' of length {}",
+                line, self.ax, String::from_utf8_lossy(&name), length);
+        false
+    }
+
+    /// Math library system calls: `abs`, `sqrt`, `pow`, `sin` and `cos`
+    ///
+    /// `abs` works on an int. The others take doubles, passed as the bits of
+    /// an `f64` the way `FLD` leaves them in ax, and return a double both in
+    /// the float accumulator and as bits in ax. Results are those of Rust's
+    /// `f64` methods, or of libm without `std`. Shared by both VM backends. Returns false (after
+    /// reporting the error) if the arguments are missing.
+    pub(crate) fn vm_math(&mut self, op: i32) -> bool {
+        let argc = if op == Instruction::POW as i32 { 2 } else { 1 };
+        if self.sp < -1 || self.sp + argc >= self.stack.len() as i32 {
+            trap!(self, TrapKind::StackFault, "Stack underflow in math call");
+            return false;
+        }
+
+        // Argument i (0 = first pushed) sits argc - i words above sp
+        let arg = |i: i32| self.stack[(self.sp + argc - i) as usize];
+        let float = |i: i32| f64::from_bits(arg(i) as u64);
+        let value = match op {
+            op if op == Instruction::ABS as i32 => {
+                self.ax = self.vm_options.wrap(self.vm_options.wrap(arg(0)).wrapping_abs());
+                return true;
+            },
+            op if op == Instruction::SQRT as i32 => math::sqrt(float(0)),
+            op if op == Instruction::POW as i32 => math::pow(float(0), float(1)),
+            op if op == Instruction::SIN as i32 => math::sin(float(0)),
+            _ => math::cos(float(0)),
+        };
+        self.ax_float = value;
+        self.ax = value.to_bits() as Word;
+        true
+    }
+
+    /// `memset(p, c, n)`: store the byte `c` in the `n` bytes from `p`
+    ///
+    /// The address is left in ax, as C's returns it. Every byte goes through
+    /// `mem_store`. Shared by both VM backends. Returns false (after
+    /// reporting the error) if the arguments are missing, `n` is negative or
+    /// the bytes run outside writable memory.
+    pub(crate) fn vm_memset(&mut self) -> bool {
+        if self.sp < -1 || self.sp + 3 >= self.stack.len() as i32 {
+            trap!(self, TrapKind::StackFault, "Stack underflow in MSET");
+            return false;
+        }
+        let arg = |i: i32| self.stack[(self.sp + 3 - i) as usize];
+        let (base, byte, len) = (arg(0), arg(1) & 0xff, self.vm_options.wrap(arg(2)));
+        if len < 0 {
+            trap!(self, TrapKind::InvalidArgument, "Invalid length {} in MSET", len);
+            return false;
+        }
+        for i in 0..len {
+            if self.mem_store(base + i, byte, true).is_none() {
+                trap!(self, TrapKind::MemoryAccess, "Buffer overflow in MSET");
+                return false;
+            }
+        }
+        self.ax = base;
+        true
+    }
+
+    /// printf-family system calls: `printf`, `fprintf`, `sprintf` and `snprintf`
+    ///
+    /// As in c4, the call's `argc` arguments stay on the stack for the ADJ that
+    /// follows, pushed in source order. Formatting is done by
+    /// [`printf::format`]. `printf` writes to stdout and `fprintf(stream, ...)`
+    /// to the given stream through [`Host::write`], while
+    /// `sprintf(buf, fmt, ...)` and `snprintf(buf, size, fmt, ...)` write a
+    /// NUL-terminated string into VM memory, `snprintf` truncating it to
+    /// `size - 1` bytes. Every byte goes through `mem_store`, so a buffer
+    /// running off the end of memory is an error rather than a silent
+    /// overwrite. The full formatted length is left in ax.
+    ///
+    /// Shared by both VM backends. Returns false (after reporting the error)
+    /// if the stack, the buffer, the format string or a `%s` argument is
+    /// invalid.
+    pub(crate) fn vm_format_call(&mut self, op: i32, argc: i32) -> bool {
+        let (name, first) = match op {
+            op if op == Instruction::FPRINTF as i32 => ("FPRINTF", 1),
+            op if op == Instruction::SPRINTF as i32 => ("SPRINTF", 1),
+            op if op == Instruction::SNPRINTF as i32 => ("SNPRINTF", 2),
+            _ => ("PRINTF", 0),
+        };
+        if argc <= first || self.sp < -1 || self.sp + argc >= self.stack.len() as i32 {
+            trap!(self, TrapKind::StackFault, "Stack underflow in {}", name);
+            return false;
+        }
+
+        // Argument i (0 = first pushed) sits argc - i words above sp
+        let arg = |vm: &Self, i: i32| vm.stack[(vm.sp + argc - i) as usize];
+        let Some(format) = self.vm_string(arg(self, first)) else {
+            trap!(self, TrapKind::MemoryAccess, "Invalid format string pointer in {}", name);
+            return false;
+        };
+
+        let mut next_arg = first + 1;
+        let args = || {
+            let value = if next_arg < argc { arg(self, next_arg) } else { 0 };
+            next_arg += 1;
+            value
+        };
+        let Some(output) = printf::format(&format, &self.vm_options, args, |addr| self.vm_string(addr)) else {
+            trap!(self, TrapKind::MemoryAccess, "Invalid string pointer in {}", name);
+            return false;
+        };
+        self.ax = output.len() as Word;
+
+        if op == Instruction::PRINTF as i32 || op == Instruction::FPRINTF as i32 {
+            if self.debug {
+                report!(self, "{}: {}", name, String::from_utf8_lossy(&output));
+            }
+            let stream = if first == 0 { STDOUT } else { arg(self, 0) };
+            if !self.host.write(stream, &output) {
+                self.ax = -1;
+            }
+            return true;
+        }
+
+        // Room for the string and its terminator; a size of 0 writes nothing
+        let buffer = arg(self, 0);
+        let room = if first == 2 { self.vm_options.wrap(arg(self, 1)).max(0) as usize } else { usize::MAX };
+        let Some(limit) = room.checked_sub(1) else {
+            return true;
+        };
+        let len = output.len().min(limit);
+        let terminated = output[..len].iter().copied().chain([0]);
+        for (i, byte) in terminated.enumerate() {
+            if self.mem_store(buffer + i as Word, byte as Word, true).is_none() {
+                trap!(self, TrapKind::MemoryAccess, "Buffer overflow in {}", name);
+                return false;
+            }
+        }
+        true
+    }
+
+}
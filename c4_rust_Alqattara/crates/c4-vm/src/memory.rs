@@ -0,0 +1,123 @@
+//! # Memory Map
+//!
+//! How a [`Machine`]'s memory is laid out, for debuggers and embedders that
+//! look at a program from outside it. The text segment is addressed in
+//! words, apart from everything else. The data segment, the heap and the
+//! stack share one byte address space:
+//!
+//! ```text
+//! 0       NULL_GUARD          heap start        end of data       STACK_BASE
+//! | guard | globals, literals | malloc'd blocks |      ...       | stack, growing down |
+//! ```
+//!
+//! Nothing is kept in the guard, so that null points at no object and
+//! loads and stores through it trap.
+//!
+//! The heap only exists during a run, so between runs its region is empty.
+//! [`Machine::read_bytes`] and [`Machine::write_bytes`] reach any byte of
+//! the data segment, the heap or the stack, read-only ones included.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{load, store, Machine, Word, STACK_BASE};
+
+/// What a region of VM memory holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Text,                        // Code, addressed in words
+    Data,                        // Globals, literals and other constants
+    Heap,                        // Blocks from malloc, past the data
+    Stack,                       // Frames of the calls in progress
+}
+
+/// One region of VM memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub start: Word,             // First address: a word of text, or a byte of the others
+    pub size: usize,             // Words of text, or bytes
+    pub used: usize,             // Of those, the ones in use: bytes in live heap blocks, or the stack from `sp` up
+}
+
+impl Region {
+    /// Whether `addr` lies in the region
+    pub fn contains(&self, addr: Word) -> bool {
+        addr >= self.start && addr - self.start < self.size as Word
+    }
+}
+
+/// The regions of a machine's memory, in address order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub text: Region,
+    pub data: Region,
+    pub heap: Region,
+    pub stack: Region,
+}
+
+impl MemoryMap {
+    /// The byte-addressed region `addr` lies in, if any
+    pub fn region_of(&self, addr: Word) -> Option<RegionKind> {
+        [self.data, self.heap, self.stack].into_iter().find(|region| region.contains(addr)).map(|region| region.kind)
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let regions = [("text", self.text, "words"), ("data", self.data, "bytes"), ("heap", self.heap, "bytes"), ("stack", self.stack, "bytes")];
+        for (name, region, unit) in regions {
+            writeln!(f, "{:<6} {:#010x} {:>10} {}, {} in use", name, region.start, region.size, unit, region.used)?;
+        }
+        Ok(())
+    }
+}
+
+impl Machine<'_> {
+    /// Where each region of memory is and how much of it is in use
+    pub fn memory_map(&self) -> MemoryMap {
+        let heap_start = self.heap.base.min(self.data.len());
+        let word_bytes = self.vm_options.word_bytes() as usize;
+        let stack_bytes = self.stack.len() * word_bytes;
+        // Slots from sp up to the top of the configured stack hold the frames
+        let stack_words = self.vm_options.stack_words as i64 - self.sp as i64;
+        let stack_used = (stack_words.max(0) as usize * word_bytes).min(stack_bytes);
+        MemoryMap {
+            text: Region { kind: RegionKind::Text, start: 0, size: self.text.len(), used: self.text.len() },
+            data: Region { kind: RegionKind::Data, start: 0, size: heap_start, used: heap_start },
+            heap: Region {
+                kind: RegionKind::Heap,
+                start: heap_start as Word,
+                size: self.data.len() - heap_start,
+                used: self.heap.used_bytes(),
+            },
+            stack: Region { kind: RegionKind::Stack, start: STACK_BASE, size: stack_bytes, used: stack_used },
+        }
+    }
+
+    /// The `len` bytes from byte address `addr`
+    ///
+    /// # Returns
+    ///
+    /// `None` if any of them is outside the data segment, the heap and the
+    /// stack
+    pub fn read_bytes(&self, addr: Word, len: usize) -> Option<Vec<u8>> {
+        (0..len)
+            .map(|i| load(&self.data, &self.stack, &self.vm_options, addr.checked_add(i as Word)?, true).map(|byte| byte as u8))
+            .collect()
+    }
+
+    /// Write `bytes` from byte address `addr`, read-only memory included
+    ///
+    /// # Returns
+    ///
+    /// `None`, having written nothing, if any of them is outside the data
+    /// segment, the heap and the stack
+    pub fn write_bytes(&mut self, addr: Word, bytes: &[u8]) -> Option<()> {
+        self.read_bytes(addr, bytes.len())?;
+        for (i, &byte) in bytes.iter().enumerate() {
+            store(&mut self.data, &mut self.stack, &self.vm_options, addr + i as Word, byte as Word, true)?;
+        }
+        Some(())
+    }
+}
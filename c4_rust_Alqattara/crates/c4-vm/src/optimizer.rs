@@ -0,0 +1,385 @@
+//! # Bytecode Optimizer
+//!
+//! Peephole passes that run over the text segment once code generation has
+//! finished. Every pass here rewrites instructions in place without changing
+//! the length of the text segment, so jump targets and function addresses
+//! recorded in the symbol table stay valid.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::Instruction;
+
+/// Returns true if the opcode is followed by an operand word in the text segment
+pub fn has_operand(op: i32) -> bool {
+    Instruction::from_opcode(op).is_some_and(|op| op.operands() > 0)
+}
+
+/// Returns true if the opcode's operand is a text address: the target of a
+/// jump or call, or a function's address
+pub fn has_text_operand(op: i32) -> bool {
+    is_branch(op) || op == Instruction::JSR as i32 || op == Instruction::FADDR as i32
+}
+
+/// Make every text address in `text` relative to the operand word that
+/// holds it, so the code runs unchanged at any offset in the text segment
+pub fn to_pc_relative(text: &mut [i32]) {
+    for pc in instruction_starts(text) {
+        if has_text_operand(text[pc]) && pc + 1 < text.len() {
+            text[pc + 1] -= pc as i32 + 1;
+        }
+    }
+}
+
+/// Turn the text addresses of position-independent code back into absolute ones
+pub fn to_absolute(text: &mut [i32]) {
+    for pc in instruction_starts(text) {
+        if has_text_operand(text[pc]) && pc + 1 < text.len() {
+            text[pc + 1] += pc as i32 + 1;
+        }
+    }
+}
+
+/// Collect the start address of every instruction in the text segment
+pub fn instruction_starts(text: &[i32]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut pc = 0;
+    while pc < text.len() {
+        starts.push(pc);
+        pc += if has_operand(text[pc]) { 2 } else { 1 };
+    }
+    starts
+}
+
+/// Collect every address that some instruction can transfer control to
+///
+/// Function addresses loaded by `FADDR` count too, since a system call can
+/// call back into them.
+pub fn jump_targets(text: &[i32]) -> Vec<bool> {
+    let mut targets = vec![false; text.len() + 1];
+    for pc in instruction_starts(text) {
+        if has_text_operand(text[pc]) {
+            if let Some(&target) = text.get(pc + 1) {
+                if target >= 0 && (target as usize) < targets.len() {
+                    targets[target as usize] = true;
+                }
+            }
+        }
+    }
+    targets
+}
+
+/// Returns `log2(value)` if `value` is a positive power of two
+fn log2_exact(value: i32) -> Option<i32> {
+    if value > 0 && value & (value - 1) == 0 {
+        Some(value.trailing_zeros() as i32)
+    } else {
+        None
+    }
+}
+
+/// Strength reduction for multiplication and division by powers of two
+///
+/// Rewrites `IMM 2^k; MUL` into `IMM k; SHL`. Division and modulo are only
+/// rewritten (into `SHR` and `AND` respectively) when the dividend is known to
+/// be non-negative, which is the case for a value produced by `LC` when char
+/// loads are zero-extended, that is unless `signed_char` is set. A pattern is left alone if any instruction after
+/// its first one is a jump target, because another path could reach it with
+/// a different value in the accumulator.
+///
+/// # Returns
+///
+/// The number of instructions rewritten
+pub fn strength_reduce(text: &mut [i32], signed_char: bool) -> usize {
+    let starts = instruction_starts(text);
+    let targets = jump_targets(text);
+    let mut rewrites = 0;
+
+    for (i, &pc) in starts.iter().enumerate() {
+        if text[pc] != Instruction::IMM as i32 || i + 1 >= starts.len() {
+            continue;
+        }
+        let op_pc = starts[i + 1];
+        if targets[op_pc] {
+            continue;
+        }
+        let Some(shift) = log2_exact(text[pc + 1]) else {
+            continue;
+        };
+        let op = text[op_pc];
+
+        if op == Instruction::MUL as i32 {
+            if shift > 0 {
+                text[pc + 1] = shift;
+                text[op_pc] = Instruction::SHL as i32;
+                rewrites += 1;
+            }
+            continue;
+        }
+
+        if signed_char || (op != Instruction::DIV as i32 && op != Instruction::MOD as i32) {
+            continue;
+        }
+
+        // The dividend must be `LC; PUSH` directly in front of the constant
+        let non_negative = i >= 2
+            && text[starts[i - 1]] == Instruction::PUSH as i32
+            && text[starts[i - 2]] == Instruction::LC as i32
+            && !targets[starts[i - 1]]
+            && !targets[pc];
+        if !non_negative {
+            continue;
+        }
+
+        if op == Instruction::DIV as i32 {
+            text[pc + 1] = shift;
+            text[op_pc] = Instruction::SHR as i32;
+        } else {
+            text[pc + 1] -= 1;
+            text[op_pc] = Instruction::AND as i32;
+        }
+        rewrites += 1;
+    }
+
+    rewrites
+}
+
+/// Code-size metrics collected by the inliner
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InlineStats {
+    pub calls_inlined: usize,     // Call sites replaced by a copy of the callee
+    pub functions_inlined: usize, // Distinct callees that were inlined
+    pub size_before: usize,       // Text segment size before inlining (words)
+    pub size_after: usize,        // Text segment size after inlining (words)
+}
+
+impl InlineStats {
+    /// Growth of the text segment in words (negative if it shrank)
+    pub fn growth(&self) -> i64 {
+        self.size_after as i64 - self.size_before as i64
+    }
+}
+
+/// Result of the inlining pass
+pub struct Inlined {
+    pub text: Vec<i32>,     // Rewritten text segment
+    pub addr_map: Vec<i32>, // Old instruction address -> new address (-1 if not an instruction)
+    pub origins: Vec<i32>,  // New word -> old word it is a copy of (-1 if the inliner added it)
+    pub sites: Vec<(i32, i32)>, // Each IENT and the entry of the function inlined there, as new addresses
+    pub stats: InlineStats,
+}
+
+/// A function body that is small enough to be copied into its callers
+struct InlineCandidate {
+    entry: usize, // Address of the ENT instruction
+    end: usize,   // One past the final LEV
+}
+
+fn is_branch(op: i32) -> bool {
+    Instruction::from_opcode(op).is_some_and(Instruction::is_branch)
+}
+
+/// Check whether the function at `entry..end` can be inlined
+///
+/// A candidate starts with `ENT`, ends with `LEV`, makes no calls, takes no
+/// function addresses (whose operands would need relocating in the copy),
+/// only branches within its own body, and has at most `threshold` instructions.
+fn inline_candidate(text: &[i32], entry: usize, end: usize, threshold: usize) -> Option<InlineCandidate> {
+    if text.get(entry) != Some(&(Instruction::ENT as i32)) {
+        return None;
+    }
+
+    let mut pc = entry;
+    let mut count = 0;
+    let mut last = entry;
+    while pc < end {
+        let op = text[pc];
+        let width = if has_operand(op) { 2 } else { 1 };
+        if pc + width > end
+            || op == Instruction::JSR as i32
+            || op == Instruction::IENT as i32
+            || op == Instruction::FADDR as i32
+        {
+            return None;
+        }
+        if is_branch(op) {
+            let target = text[pc + 1];
+            if target <= entry as i32 + 1 || target >= end as i32 {
+                return None;
+            }
+        }
+        count += 1;
+        last = pc;
+        pc += width;
+    }
+
+    if count > threshold || text[last] != Instruction::LEV as i32 {
+        return None;
+    }
+    Some(InlineCandidate { entry, end })
+}
+
+/// Inline small leaf functions at their call sites
+///
+/// Each `JSR f` (and the `ADJ n` that pops its arguments) is replaced with a
+/// copy of `f` where `ENT` becomes `IENT`, which also reserves the slot a call
+/// would have used for the return address, and every `LEV` becomes `ILEV n`,
+/// which tears down the frame and pops the arguments in one step. The frame
+/// layout is identical to a real call, so the callee's `LEA` offsets are kept.
+/// Where each copy came from is kept in [`Inlined::sites`], so a backtrace
+/// can still name the callee.
+///
+/// # Arguments
+///
+/// * `text` - The text segment
+/// * `entries` - Entry addresses of all functions
+/// * `threshold` - Maximum number of instructions in an inlined function
+///
+/// # Returns
+///
+/// The rewritten text segment with an address map for relocating symbols, or
+/// `None` if nothing was inlined or the text segment could not be decoded.
+pub fn inline_small_functions(text: &[i32], entries: &[i32], threshold: usize) -> Option<Inlined> {
+    let mut bounds: Vec<usize> = entries
+        .iter()
+        .filter(|&&e| e >= 0 && (e as usize) < text.len())
+        .map(|&e| e as usize)
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut candidates = Vec::new();
+    for (i, &entry) in bounds.iter().enumerate() {
+        let end = bounds.get(i + 1).copied().unwrap_or(text.len());
+        if let Some(candidate) = inline_candidate(text, entry, end, threshold) {
+            candidates.push(candidate);
+        }
+    }
+    if candidates.is_empty() {
+        return None;
+    }
+
+    let targets = jump_targets(text);
+    let mut out = Vec::with_capacity(text.len());
+    let mut origins = Vec::with_capacity(text.len());
+    let mut addr_map = vec![-1; text.len() + 1];
+    let mut fixups = Vec::new(); // (operand position in `out`, old target)
+    let mut inlined_callees = Vec::new();
+    let mut sites = Vec::new(); // (IENT in `out`, old entry of the callee)
+    let mut calls_inlined = 0;
+
+    let mut pc = 0;
+    while pc < text.len() {
+        addr_map[pc] = out.len() as i32;
+        let op = text[pc];
+        let width = if has_operand(op) { 2 } else { 1 };
+        if pc + width > text.len() {
+            return None;
+        }
+
+        if op == Instruction::JSR as i32 {
+            let callee = candidates.iter().find(|c| c.entry as i32 == text[pc + 1]);
+            if let Some(callee) = callee {
+                let next = pc + 2;
+                let pops_args = text.get(next) == Some(&(Instruction::ADJ as i32))
+                    && next + 1 < text.len()
+                    && !targets[next];
+                let (argc, resume) = if pops_args { (text[next + 1], next + 2) } else { (0, next) };
+
+                sites.push((out.len() as i32, callee.entry));
+                emit_inline_body(text, callee, argc, &mut out, &mut origins);
+                if pops_args {
+                    addr_map[next] = out.len() as i32;
+                }
+                if !inlined_callees.contains(&callee.entry) {
+                    inlined_callees.push(callee.entry);
+                }
+                calls_inlined += 1;
+                pc = resume;
+                continue;
+            }
+        }
+
+        out.push(op);
+        origins.push(pc as i32);
+        if width == 2 {
+            if has_text_operand(op) {
+                fixups.push((out.len(), text[pc + 1]));
+            }
+            out.push(text[pc + 1]);
+            origins.push(pc as i32 + 1);
+        }
+        pc += width;
+    }
+    addr_map[text.len()] = out.len() as i32;
+
+    if calls_inlined == 0 {
+        return None;
+    }
+
+    for (pos, old_target) in fixups {
+        if old_target < 0 || old_target as usize >= addr_map.len() || addr_map[old_target as usize] < 0 {
+            return None;
+        }
+        out[pos] = addr_map[old_target as usize];
+    }
+    let sites = sites.into_iter().map(|(site, entry)| (site, addr_map[entry])).collect();
+
+    let stats = InlineStats {
+        calls_inlined,
+        functions_inlined: inlined_callees.len(),
+        size_before: text.len(),
+        size_after: out.len(),
+    };
+    Some(Inlined { text: out, addr_map, origins, sites, stats })
+}
+
+/// Append a copy of `callee` to `out`, relocating its internal branches,
+/// and where each word came from to `origins`
+fn emit_inline_body(text: &[i32], callee: &InlineCandidate, argc: i32, out: &mut Vec<i32>, origins: &mut Vec<i32>) {
+    let mut local_map = vec![-1; callee.end - callee.entry];
+    let mut local_fixups = Vec::new();
+    let mut end_jumps = Vec::new();
+
+    out.push(Instruction::IENT as i32);
+    out.push(text[callee.entry + 1]);
+    origins.extend([-1, callee.entry as i32 + 1]);
+
+    let mut pc = callee.entry + 2;
+    while pc < callee.end {
+        local_map[pc - callee.entry] = out.len() as i32;
+        let op = text[pc];
+        let width = if has_operand(op) { 2 } else { 1 };
+
+        if op == Instruction::LEV as i32 {
+            out.push(Instruction::ILEV as i32);
+            out.push(argc);
+            origins.extend([-1, -1]);
+            if pc + 1 < callee.end {
+                out.push(Instruction::JMP as i32);
+                end_jumps.push(out.len());
+                out.push(0);
+                origins.extend([-1, -1]);
+            }
+        } else {
+            out.push(op);
+            origins.push(pc as i32);
+            if width == 2 {
+                if is_branch(op) {
+                    local_fixups.push((out.len(), text[pc + 1] as usize - callee.entry));
+                }
+                out.push(text[pc + 1]);
+                origins.push(pc as i32 + 1);
+            }
+        }
+        pc += width;
+    }
+
+    let end = out.len() as i32;
+    for pos in end_jumps {
+        out[pos] = end;
+    }
+    for (pos, offset) in local_fixups {
+        out[pos] = local_map[offset];
+    }
+}
@@ -0,0 +1,291 @@
+//! # printf Formatting
+//!
+//! Implements the conversions of C's `printf` family for the VM's system
+//! calls. Each argument is one VM word: integers are truncated to the word
+//! size, strings are addresses read through a callback, and floating-point
+//! arguments carry the bits of an `f64` (see `FLD`). Output follows glibc,
+//! including the `%e` exponent format and the `%g` style selection.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{VmOptions, Word};
+
+/// A parsed conversion specification such as `%-08.3f`
+#[derive(Debug, Default, Clone, Copy)]
+struct Spec {
+    left: bool,             // '-': pad on the right
+    zero: bool,             // '0': pad numbers with zeros
+    plus: bool,             // '+': always print a sign
+    space: bool,            // ' ': print a space in place of a plus sign
+    alternate: bool,        // '#': 0x prefix, keep the decimal point
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /// Sign to print in front of a non-negative number
+    fn positive_sign(&self) -> &'static str {
+        if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    /// Pad `body` to the field width, placing zeros after the sign and prefix
+    fn pad(&self, prefix: &str, body: &str, zero_allowed: bool) -> String {
+        let len = prefix.len() + body.len();
+        if len >= self.width {
+            return format!("{}{}", prefix, body);
+        }
+        let fill = self.width - len;
+        if self.left {
+            format!("{}{}{}", prefix, body, " ".repeat(fill))
+        } else if self.zero && zero_allowed {
+            format!("{}{}{}", prefix, "0".repeat(fill), body)
+        } else {
+            format!("{}{}{}", " ".repeat(fill), prefix, body)
+        }
+    }
+}
+
+/// Format `format` with the words produced by `args`
+///
+/// `string` reads the NUL-terminated string at an address for `%s`.
+/// Arguments missing from the call read as 0, as far as `args` allows.
+///
+/// # Returns
+///
+/// The formatted bytes, or `None` if a `%s` argument is not a valid string
+pub fn format(
+    format: &[u8],
+    options: &VmOptions,
+    mut args: impl FnMut() -> Word,
+    mut string: impl FnMut(Word) -> Option<Vec<u8>>,
+) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < format.len() {
+        let c = format[i];
+        i += 1;
+        if c != b'%' {
+            output.push(c);
+            continue;
+        }
+
+        let start = i - 1;
+        let mut spec = Spec::default();
+        while let Some(&flag) = format.get(i) {
+            match flag {
+                b'-' => spec.left = true,
+                b'0' => spec.zero = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        // Width, possibly taken from an argument
+        if format.get(i) == Some(&b'*') {
+            i += 1;
+            let width = options.wrap(args());
+            spec.left |= width < 0;
+            spec.width = width.unsigned_abs() as usize;
+        } else {
+            spec.width = digits(format, &mut i);
+        }
+
+        // Precision, where a negative argument means none was given
+        if format.get(i) == Some(&b'.') {
+            i += 1;
+            if format.get(i) == Some(&b'*') {
+                i += 1;
+                let precision = options.wrap(args());
+                spec.precision = (precision >= 0).then_some(precision as usize);
+            } else {
+                spec.precision = Some(digits(format, &mut i));
+            }
+        }
+
+        // Length modifiers make no difference with a single word size
+        while matches!(format.get(i), Some(b'l' | b'h')) {
+            i += 1;
+        }
+
+        let Some(&conversion) = format.get(i) else {
+            output.extend_from_slice(&format[start..]);
+            break;
+        };
+        i += 1;
+
+        let text = match conversion {
+            b'%' => "%".to_string(),
+            b'd' | b'i' => signed(&spec, options.wrap(args())),
+            b'u' | b'x' | b'X' | b'o' => unsigned(&spec, conversion, unsigned_word(options, args())),
+            b'c' | b's' => {
+                // Raw bytes, which need not be valid UTF-8
+                let mut bytes = if conversion == b'c' {
+                    vec![args() as u8]
+                } else {
+                    let mut bytes = string(args())?;
+                    if let Some(precision) = spec.precision {
+                        bytes.truncate(precision);
+                    }
+                    bytes
+                };
+                let fill = vec![b' '; spec.width.saturating_sub(bytes.len())];
+                if spec.left {
+                    bytes.extend_from_slice(&fill);
+                } else {
+                    output.extend_from_slice(&fill);
+                }
+                output.extend_from_slice(&bytes);
+                continue;
+            }
+            b'f' | b'F' | b'e' | b'E' | b'g' | b'G' => float(&spec, conversion, f64::from_bits(args() as u64)),
+            _ => {
+                // Unknown conversions are printed as written
+                output.extend_from_slice(&format[start..i]);
+                continue;
+            }
+        };
+        output.extend_from_slice(text.as_bytes());
+    }
+    Some(output)
+}
+
+/// Parse a run of decimal digits at `*i`
+fn digits(format: &[u8], i: &mut usize) -> usize {
+    let mut value = 0usize;
+    while let Some(&d) = format.get(*i).filter(|d| d.is_ascii_digit()) {
+        value = value.saturating_mul(10).saturating_add((d - b'0') as usize);
+        *i += 1;
+    }
+    value
+}
+
+/// Reinterpret a word as unsigned at the VM word size
+fn unsigned_word(options: &VmOptions, value: Word) -> u64 {
+    if options.word_bytes() == 8 {
+        value as u64
+    } else {
+        value as u32 as u64
+    }
+}
+
+/// Apply an integer precision: the minimum number of digits, where a zero
+/// precision prints nothing for the value 0
+fn integer_digits(spec: &Spec, digits: String) -> String {
+    match spec.precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(precision) if digits.len() < precision => format!("{}{}", "0".repeat(precision - digits.len()), digits),
+        _ => digits,
+    }
+}
+
+/// `%d` and `%i`
+fn signed(spec: &Spec, value: Word) -> String {
+    let sign = if value < 0 { "-" } else { spec.positive_sign() };
+    let body = integer_digits(spec, value.unsigned_abs().to_string());
+    spec.pad(sign, &body, spec.precision.is_none())
+}
+
+/// `%u`, `%x`, `%X` and `%o`
+fn unsigned(spec: &Spec, conversion: u8, value: u64) -> String {
+    let digits = match conversion {
+        b'x' => format!("{:x}", value),
+        b'X' => format!("{:X}", value),
+        b'o' => format!("{:o}", value),
+        _ => value.to_string(),
+    };
+    let mut body = integer_digits(spec, digits);
+    let mut prefix = "";
+    if spec.alternate && value != 0 {
+        match conversion {
+            b'x' => prefix = "0x",
+            b'X' => prefix = "0X",
+            b'o' if !body.starts_with('0') => body.insert(0, '0'),
+            _ => {}
+        }
+    }
+    spec.pad(prefix, &body, spec.precision.is_none())
+}
+
+/// `%f`, `%e` and `%g` with their upper-case variants
+fn float(spec: &Spec, conversion: u8, value: f64) -> String {
+    let upper = conversion.is_ascii_uppercase();
+    let sign = if value.is_sign_negative() { "-" } else { spec.positive_sign() };
+
+    if !value.is_finite() {
+        let body = if value.is_nan() { "nan" } else { "inf" };
+        let body = if upper { body.to_uppercase() } else { body.to_string() };
+        return spec.pad(sign, &body, false);
+    }
+
+    let value = value.abs();
+    let precision = spec.precision.unwrap_or(6);
+    let mut body = match conversion.to_ascii_lowercase() {
+        b'f' => fixed(value, precision, spec.alternate),
+        b'e' => exponent(value, precision, spec.alternate),
+        _ => general(value, precision, spec.alternate),
+    };
+    if upper {
+        body = body.to_uppercase();
+    }
+    spec.pad(sign, &body, true)
+}
+
+/// Fixed notation with `precision` digits after the point
+fn fixed(value: f64, precision: usize, alternate: bool) -> String {
+    let mut text = format!("{:.*}", precision, value);
+    if alternate && precision == 0 {
+        text.push('.');
+    }
+    text
+}
+
+/// Scientific notation as C writes it: `d.ddde+XX`, with at least two exponent digits
+fn exponent(value: f64, precision: usize, alternate: bool) -> String {
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, exp) = text.split_once('e').unwrap_or((&text, "0"));
+    let exp: i32 = exp.parse().unwrap_or(0);
+    let point = if alternate && precision == 0 { "." } else { "" };
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("{}{}e{}{:02}", mantissa, point, sign, exp.unsigned_abs())
+}
+
+/// `%g`: the shorter of fixed and scientific notation for `precision`
+/// significant digits, without trailing zeros unless `alternate` is set
+fn general(value: f64, precision: usize, alternate: bool) -> String {
+    let precision = precision.max(1);
+
+    // The exponent after rounding to the requested number of digits
+    let rounded = format!("{:.*e}", precision - 1, value);
+    let exp: i32 = rounded.split_once('e').map_or(0, |(_, e)| e.parse().unwrap_or(0));
+
+    let mut text = if exp < -4 || exp >= precision as i32 {
+        exponent(value, precision - 1, alternate)
+    } else {
+        fixed(value, (precision as i32 - 1 - exp) as usize, alternate)
+    };
+
+    if !alternate {
+        // Strip zeros at the end of the fraction, and the point if nothing is left
+        let (number, suffix) = match text.find('e') {
+            Some(e) => text.split_at(e),
+            None => (text.as_str(), ""),
+        };
+        if number.contains('.') {
+            let number = number.trim_end_matches('0').trim_end_matches('.');
+            text = format!("{}{}", number, suffix);
+        }
+    }
+    text
+}
@@ -0,0 +1,527 @@
+//! # Register VM
+//!
+//! Experimental register-machine backend, selected with `VmOptions::backend`.
+//! The front end is unchanged: the stack bytecode in the text segment is
+//! translated into a register IR where the accumulator, immediates and the
+//! slots of the current frame are operands of the instruction itself. The
+//! common c4 sequences (`LEA n; LI`, `IMM v; PUSH`, `PUSH; IMM v; ADD`,
+//! `...; LT; BZ`) each become one instruction, which roughly halves the number
+//! of dispatches. Frames, return addresses and memory use the same layout as
+//! the stack VM, so code compiled for one runs unchanged on the other.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::optimizer::{instruction_starts, jump_targets};
+use crate::{report, trap, Machine};
+use crate::{HangKind, Instruction, TrapKind, Word, CALLBACK_RETURN};
+
+/// Source operand of a register instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Src {
+    Acc,        // The accumulator
+    Imm(i32),   // An immediate value
+    Addr(i32),  // The address bp + n
+    Local(i32), // The frame slot at bp + n
+}
+
+/// Register IR instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegOp {
+    Mov(Src),                 // ax = src
+    Push(Src),                // ax = src, then push ax
+    Bin(i32),                 // ax = pop op ax
+    BinWith(i32, Src),        // ax = ax op src
+    Test(i32, Src, usize, bool), // ax = ax op src, then branch if (ax == 0) matches the flag
+    SetLocal(i32, Src),       // ax = src, then store ax at bp + n
+    Load(bool),               // ax = memory[ax] (char if true)
+    Fld,                      // ax_float = the double at ax, with its bits in ax
+    Store(bool),              // memory[pop] = ax (char if true)
+    Jmp(usize),
+    Jsr(usize, i32),          // Call, pushing the text address to return to
+    Bz(usize),
+    Bnz(usize),
+    Ent(i32),
+    Adj(i32),
+    Lev,
+    Ient(i32, i32),           // Inlined frame, with the text address of its operand to mark the frame
+    Ilev(i32),
+    Tlev(i32),
+    Format(i32, i32),         // printf-family syscall, with the argument count taken from the following ADJ
+    CharIo(i32),              // putchar, puts or getchar
+    Assert,
+    Bound(i32),               // Bounds check of the index in ax, with the data address of the array's record
+    Math(i32),                // abs, sqrt, pow, sin or cos
+    Qsort,
+    Memset,
+    Heap(i32, i32),           // malloc or free, with the text address of the call
+    Exit,
+    Invalid(i32),             // Opcode the VM does not implement
+}
+
+/// A translated text segment
+#[derive(Debug, Clone)]
+pub struct RegCode {
+    pub ops: Vec<RegOp>,
+    pub entry: usize,          // Index of the first instruction to run
+    addr_map: Vec<usize>,      // Text address -> op index (usize::MAX if not enterable)
+}
+
+impl RegCode {
+    /// Map a text address to an op index; anything not enterable maps past the end
+    fn resolve(&self, addr: i32) -> usize {
+        if addr < 0 || addr as usize >= self.addr_map.len() || self.addr_map[addr as usize] == usize::MAX {
+            return self.ops.len();
+        }
+        self.addr_map[addr as usize]
+    }
+
+    /// Text address of the instruction an op index was translated from
+    pub(crate) fn address(&self, op: usize) -> i32 {
+        self.addr_map.iter().position(|&i| i == op).map_or(-1, |addr| addr as i32)
+    }
+}
+
+fn is_binop(op: i32) -> bool {
+    Instruction::from_opcode(op).is_some_and(Instruction::is_binary)
+}
+
+/// Translate the text segment into register code
+///
+/// Instructions are only fused when none but the first is a jump target, a
+/// return address or the entry point, so every address control can reach in
+/// the stack code still has an op of its own.
+///
+/// # Returns
+///
+/// `None` if `entry` is not the start of an instruction.
+pub fn translate(text: &[i32], entry: i32) -> Option<RegCode> {
+    let starts = instruction_starts(text);
+    let mut targets = jump_targets(text);
+    for &pc in &starts {
+        if text[pc] == Instruction::JSR as i32 && pc + 2 < targets.len() {
+            targets[pc + 2] = true;
+        }
+    }
+    if entry < 0 || !starts.contains(&(entry as usize)) {
+        return None;
+    }
+    targets[entry as usize] = true;
+
+    let op_at = |k: usize| starts.get(k).map(|&pc| text[pc]);
+    let arg_at = |k: usize| starts.get(k).and_then(|&pc| text.get(pc + 1).copied()).unwrap_or(0);
+    // True if instructions k..k+n exist and none after the first can be entered
+    let fusable = |k: usize, n: usize| k + n <= starts.len() && (k + 1..k + n).all(|j| !targets[starts[j]]);
+    // An operand load starting at k: `IMM v`, `LEA n; LI` or `LEA n`
+    let load_at = |k: usize| -> Option<(Src, usize)> {
+        match op_at(k)? {
+            op if op == Instruction::IMM as i32 => Some((Src::Imm(arg_at(k)), 1)),
+            op if op == Instruction::LEA as i32 => {
+                if op_at(k + 1) == Some(Instruction::LI as i32) && fusable(k, 2) {
+                    Some((Src::Local(arg_at(k)), 2))
+                } else {
+                    Some((Src::Addr(arg_at(k)), 1))
+                }
+            },
+            _ => None,
+        }
+    };
+
+    let mut ops = Vec::with_capacity(starts.len());
+    let mut addr_map = vec![usize::MAX; text.len() + 1];
+    let mut branch_ops = Vec::new(); // Ops whose target is still a text address
+
+    let mut k = 0;
+    while k < starts.len() {
+        addr_map[starts[k]] = ops.len();
+        let op = text[starts[k]];
+        let arg = arg_at(k);
+
+        // LEA n; PUSH; load; SI  =>  SetLocal
+        if op == Instruction::LEA as i32 && op_at(k + 1) == Some(Instruction::PUSH as i32) {
+            if let Some((src, n)) = load_at(k + 2) {
+                if op_at(k + 2 + n) == Some(Instruction::SI as i32) && fusable(k, 3 + n) {
+                    ops.push(RegOp::SetLocal(arg, src));
+                    k += 3 + n;
+                    continue;
+                }
+            }
+        }
+
+        // PUSH; load; binop [; BZ/BNZ]  =>  BinWith / Test
+        if op == Instruction::PUSH as i32 {
+            if let Some((src, n)) = load_at(k + 1) {
+                let bin = op_at(k + 1 + n).filter(|&o| is_binop(o));
+                if let Some(bin) = bin.filter(|_| fusable(k, 2 + n)) {
+                    let branch = op_at(k + 2 + n)
+                        .filter(|&o| o == Instruction::BZ as i32 || o == Instruction::BNZ as i32);
+                    if let Some(branch) = branch.filter(|_| fusable(k, 3 + n)) {
+                        branch_ops.push(ops.len());
+                        let on_zero = branch == Instruction::BZ as i32;
+                        ops.push(RegOp::Test(bin, src, arg_at(k + 2 + n) as usize, on_zero));
+                        k += 3 + n;
+                    } else {
+                        ops.push(RegOp::BinWith(bin, src));
+                        k += 2 + n;
+                    }
+                    continue;
+                }
+            }
+        }
+
+        // load [; PUSH]  =>  Push / Mov, leaving the PUSH alone if it starts a BinWith
+        if let Some((src, n)) = load_at(k) {
+            let push = k + n;
+            let push_starts_bin = load_at(push + 1)
+                .is_some_and(|(_, m)| op_at(push + 1 + m).is_some_and(is_binop) && fusable(push, 2 + m));
+            if op_at(push) == Some(Instruction::PUSH as i32) && fusable(k, n + 1) && !push_starts_bin {
+                ops.push(RegOp::Push(src));
+                k += n + 1;
+            } else {
+                ops.push(RegOp::Mov(src));
+                k += n;
+            }
+            continue;
+        }
+
+        let reg_op = match op {
+            op if op == Instruction::PUSH as i32 => RegOp::Push(Src::Acc),
+            op if is_binop(op) => RegOp::Bin(op),
+            op if op == Instruction::LI as i32 => RegOp::Load(false),
+            op if op == Instruction::LC as i32 => RegOp::Load(true),
+            op if op == Instruction::FLD as i32 => RegOp::Fld,
+            op if op == Instruction::SI as i32 => RegOp::Store(false),
+            op if op == Instruction::SC as i32 => RegOp::Store(true),
+            op if op == Instruction::JMP as i32 => RegOp::Jmp(arg as usize),
+            op if op == Instruction::JSR as i32 => RegOp::Jsr(arg as usize, starts[k] as i32 + 2),
+            op if op == Instruction::BZ as i32 => RegOp::Bz(arg as usize),
+            op if op == Instruction::BNZ as i32 => RegOp::Bnz(arg as usize),
+            op if op == Instruction::ENT as i32 => RegOp::Ent(arg),
+            op if op == Instruction::ADJ as i32 => RegOp::Adj(arg),
+            op if op == Instruction::LEV as i32 => RegOp::Lev,
+            op if op == Instruction::IENT as i32 => RegOp::Ient(arg, starts[k] as i32 + 1),
+            op if op == Instruction::ILEV as i32 => RegOp::Ilev(arg),
+            op if op == Instruction::TLEV as i32 => RegOp::Tlev(arg),
+            op if op == Instruction::PRINTF as i32
+                || op == Instruction::FPRINTF as i32
+                || op == Instruction::SPRINTF as i32
+                || op == Instruction::SNPRINTF as i32 => {
+                let argc = if op_at(k + 1) == Some(Instruction::ADJ as i32) { arg_at(k + 1) } else { 0 };
+                RegOp::Format(op, argc)
+            },
+            op if op == Instruction::PUTC as i32
+                || op == Instruction::PUTS as i32
+                || op == Instruction::GETC as i32 => RegOp::CharIo(op),
+            op if op == Instruction::ASSERT as i32 => RegOp::Assert,
+            op if op == Instruction::BOUND as i32 => RegOp::Bound(arg),
+            op if op == Instruction::QSORT as i32 => RegOp::Qsort,
+            op if op == Instruction::MSET as i32 => RegOp::Memset,
+            op if op == Instruction::MALLOC as i32 || op == Instruction::FREE as i32 => {
+                RegOp::Heap(op, starts[k] as i32 + 1)
+            },
+            op if op == Instruction::FADDR as i32 => RegOp::Mov(Src::Imm(arg)),
+            op if op == Instruction::ABS as i32
+                || op == Instruction::SQRT as i32
+                || op == Instruction::POW as i32
+                || op == Instruction::SIN as i32
+                || op == Instruction::COS as i32 => RegOp::Math(op),
+            op if op == Instruction::EXIT as i32 => RegOp::Exit,
+            op => RegOp::Invalid(op),
+        };
+        if matches!(reg_op, RegOp::Jmp(_) | RegOp::Jsr(..) | RegOp::Bz(_) | RegOp::Bnz(_)) {
+            branch_ops.push(ops.len());
+        }
+        ops.push(reg_op);
+        k += 1;
+    }
+    // A jump to the end of the text segment leaves the VM loop, as it does in the stack VM
+    addr_map[text.len()] = ops.len();
+
+    let mut code = RegCode { ops, entry: 0, addr_map };
+    code.entry = code.resolve(entry);
+    for i in branch_ops {
+        code.ops[i] = match code.ops[i] {
+            RegOp::Jmp(t) => RegOp::Jmp(code.resolve(t as i32)),
+            RegOp::Jsr(t, ret) => RegOp::Jsr(code.resolve(t as i32), ret),
+            RegOp::Bz(t) => RegOp::Bz(code.resolve(t as i32)),
+            RegOp::Bnz(t) => RegOp::Bnz(code.resolve(t as i32)),
+            RegOp::Test(op, src, t, on_zero) => RegOp::Test(op, src, code.resolve(t as i32), on_zero),
+            other => other,
+        };
+    }
+    Some(code)
+}
+
+impl Machine<'_> {
+    /// Read a source operand
+    fn reg_read(&self, src: Src) -> Option<Word> {
+        match src {
+            Src::Acc => Some(self.ax),
+            Src::Imm(v) => Some(v as Word),
+            Src::Addr(n) => Some(self.stack_addr(self.bp + n)),
+            Src::Local(n) => self.reg_slot(self.bp + n),
+        }
+    }
+
+    fn reg_slot(&self, slot: i32) -> Option<Word> {
+        if slot < 0 {
+            return None;
+        }
+        self.stack.get(slot as usize).copied()
+    }
+
+    fn reg_push(&mut self, value: Word) -> Option<()> {
+        if self.sp < 0 || self.sp >= self.stack.len() as i32 {
+            return None;
+        }
+        self.stack[self.sp as usize] = value;
+        self.sp -= 1;
+        Some(())
+    }
+
+    fn reg_pop(&mut self) -> Option<Word> {
+        let value = self.reg_slot(self.sp + 1)?;
+        self.sp += 1;
+        Some(value)
+    }
+
+    fn reg_set_slot(&mut self, slot: i32, value: Word) -> Option<()> {
+        self.reg_slot(slot)?;
+        self.stack[slot as usize] = value;
+        Some(())
+    }
+
+    /// Execute register code produced by `regvm::translate`
+    ///
+    /// Expects `run` to have set up the initial stack. Each op counts as one
+    /// cycle, so `cycle` can be compared with the stack VM's.
+    ///
+    /// # Returns
+    ///
+    /// The exit code of the program
+    pub(crate) fn run_register(&mut self, code: &RegCode) -> i32 {
+        self.execute_register(code, code.entry)
+    }
+
+    /// Call the function at text address `entry` from a system call
+    ///
+    /// The register counterpart of `call_stack`: the frame is the same, and
+    /// `pc` records the address the last LEV returned to so a return to
+    /// `CALLBACK_RETURN` can be told apart from the program stopping.
+    pub(crate) fn call_register(&mut self, code: &RegCode, entry: i32, args: &[Word]) -> Option<Word> {
+        let (pc, executing) = (self.pc, self.executing);
+        for &arg in args.iter().chain(&[CALLBACK_RETURN as Word]) {
+            if self.reg_push(arg).is_none() {
+                trap!(self, TrapKind::StackOverflow, "Stack overflow in callback");
+                return None;
+            }
+        }
+
+        self.execute_register(code, code.resolve(entry));
+        let returned = self.pc == CALLBACK_RETURN;
+        self.pc = pc;
+        if returned {
+            self.executing = executing;
+        }
+        self.sp += args.len() as i32;
+        returned.then_some(self.ax)
+    }
+
+    /// Execute register code from op index `pc` until the program ends
+    fn execute_register(&mut self, code: &RegCode, mut pc: usize) -> i32 {
+        let max_cycles = self.vm_options.sandbox.max_cycles;
+
+        while pc < code.ops.len() && self.cycle < max_cycles {
+            self.cycle += 1;
+            if self.cancelled() {
+                return -2;
+            }
+            let at = pc;
+            let op = code.ops[pc];
+            pc += 1;
+            self.executing = at as i32;
+            if self.vm_options.stats {
+                self.record_reg_op(op);
+            }
+
+            let ok = match op {
+                RegOp::Mov(src) => self.reg_read(src).map(|v| self.ax = v),
+                RegOp::Push(src) => self.reg_read(src).and_then(|v| {
+                    self.ax = v;
+                    self.reg_push(v)
+                }),
+                RegOp::Bin(op) => self.reg_pop()
+                    .and_then(|a| self.vm_options.alu(op, a, self.ax))
+                    .map(|v| self.ax = v),
+                RegOp::BinWith(op, src) => self.reg_read(src)
+                    .and_then(|b| self.vm_options.alu(op, self.ax, b))
+                    .map(|v| self.ax = v),
+                RegOp::Test(op, src, target, on_zero) => self.reg_read(src)
+                    .and_then(|b| self.vm_options.alu(op, self.ax, b))
+                    .map(|v| {
+                        self.ax = v;
+                        if (v == 0) == on_zero {
+                            pc = target;
+                        }
+                    }),
+                RegOp::SetLocal(n, src) => self.reg_read(src).and_then(|v| {
+                    self.ax = v;
+                    self.reg_set_slot(self.bp + n, v)
+                }),
+                RegOp::Load(char) => self.mem_load(self.ax, char).map(|v| self.ax = v),
+                RegOp::Fld => self.float_load(self.ax).map(|v| {
+                    self.ax_float = v;
+                    self.ax = v.to_bits() as Word;
+                }),
+                RegOp::Store(char) => self.reg_pop()
+                    .and_then(|addr| self.mem_store(addr, self.ax, char)),
+                RegOp::Jmp(target) => {
+                    pc = target;
+                    Some(())
+                },
+                RegOp::Jsr(target, ret) => self.reg_push(ret as Word).map(|_| pc = target),
+                RegOp::Bz(target) => {
+                    if self.ax == 0 {
+                        pc = target;
+                    }
+                    Some(())
+                },
+                RegOp::Bnz(target) => {
+                    if self.ax != 0 {
+                        pc = target;
+                    }
+                    Some(())
+                },
+                RegOp::Ent(n) | RegOp::Ient(n, _) => {
+                    // Inlined code marks its frame with the address of the IENT's operand, as on the stack VM
+                    let marked = match op {
+                        RegOp::Ient(_, operand) => self.reg_push(operand as Word),
+                        _ => Some(()),
+                    };
+                    marked.and_then(|_| self.reg_push(self.bp as Word)).and_then(|_| {
+                        self.bp = self.sp;
+                        self.sp = self.sp.checked_sub(n).filter(|&sp| n >= 0 && sp >= -1)?;
+                        Some(())
+                    })
+                },
+                RegOp::Adj(n) => self.sp.checked_add(n)
+                    .filter(|&sp| sp >= -1 && sp < self.stack.len() as i32)
+                    .map(|sp| self.sp = sp),
+                RegOp::Lev => {
+                    self.sp = self.bp;
+                    match (self.reg_slot(self.sp + 1), self.reg_slot(self.sp + 2)) {
+                        (Some(bp), Some(ret)) => {
+                            self.bp = bp as i32;
+                            self.sp += 2;
+                            self.pc = ret as i32;
+                            pc = code.resolve(ret as i32);
+                            Some(())
+                        },
+                        _ => None,
+                    }
+                },
+                RegOp::Ilev(argc) if !(0..self.stack.len() as i32).contains(&argc) => None,
+                RegOp::Ilev(argc) => {
+                    self.sp = self.bp;
+                    self.reg_slot(self.sp + 1).map(|bp| {
+                        self.bp = bp as i32;
+                        self.sp += 2 + argc;
+                    })
+                },
+                RegOp::Tlev(argc) => {
+                    let in_bounds = self.sp >= -1
+                        && self.bp >= 0
+                        && (0..self.stack.len() as i32).contains(&argc)
+                        && self.sp + argc < self.stack.len() as i32
+                        && self.bp + 2 + argc < self.stack.len() as i32;
+                    in_bounds.then(|| {
+                        for i in 1..=argc {
+                            self.stack[(self.bp + 2 + i) as usize] = self.stack[(self.sp + i) as usize];
+                        }
+                        self.sp = self.bp + 1;
+                        self.bp = self.stack[self.sp as usize] as i32;
+                    })
+                },
+                RegOp::Format(op, argc) => {
+                    if !self.vm_format_call(op, argc) {
+                        return -1;
+                    }
+                    Some(())
+                },
+                RegOp::CharIo(op) => {
+                    if !self.vm_char_io(op) {
+                        return -1;
+                    }
+                    Some(())
+                },
+                RegOp::Assert => {
+                    if !self.vm_assert() {
+                        return -1;
+                    }
+                    Some(())
+                },
+                RegOp::Bound(record) => {
+                    if !self.vm_bound(record) {
+                        return -1;
+                    }
+                    Some(())
+                },
+                RegOp::Math(op) => {
+                    if !self.vm_math(op) {
+                        return -1;
+                    }
+                    Some(())
+                },
+                RegOp::Qsort => {
+                    if !self.vm_qsort(|vm, entry, args| vm.call_register(code, entry, args)) {
+                        return -1;
+                    }
+                    Some(())
+                },
+                RegOp::Memset => {
+                    if !self.vm_memset() {
+                        return -1;
+                    }
+                    Some(())
+                },
+                RegOp::Heap(op, at) => {
+                    if !self.vm_heap(op, at) {
+                        return -1;
+                    }
+                    Some(())
+                },
+                RegOp::Exit => {
+                    if self.debug {
+                        report!(self, "EXIT instruction, returning: {}", self.ax);
+                    }
+                    return self.ax as i32;
+                },
+                RegOp::Invalid(op) => {
+                    trap!(self, TrapKind::InvalidCode, "Unknown instruction: {}", op);
+                    return -1;
+                },
+            };
+
+            if ok.is_none() {
+                if self.sp < 0 {
+                    trap!(self, TrapKind::StackOverflow, "Stack overflow at op {}: {:?}", pc - 1, op);
+                    return -1;
+                }
+                trap!(self, TrapKind::Fault, "Register VM fault at op {}: {:?}", pc - 1, op);
+                return -1;
+            }
+            if pc == at && matches!(op, RegOp::Jmp(_) | RegOp::Bz(_) | RegOp::Bnz(_)) {
+                return self.hang(HangKind::SelfLoop, code.address(at));
+            }
+        }
+
+        if self.cycle >= max_cycles {
+            return self.hang(HangKind::CycleLimit, code.address(pc));
+        }
+
+        if self.debug {
+            report!(self, "Register VM completed with {} cycles", self.cycle);
+        }
+        self.ax as i32
+    }
+}
@@ -0,0 +1,110 @@
+//! # VM Statistics
+//!
+//! With `VmOptions::stats` set, each run counts what the VM does, so the
+//! effect of changes such as superinstructions or the register backend can
+//! be measured rather than guessed. Counting slows the VM down, so it is
+//! off by default.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::regvm::{RegOp, Src};
+use crate::{Instruction, Machine};
+
+/// What the VM did during the last run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VmStats {
+    pub cycles: u64,                             // Instructions executed
+    pub op_counts: BTreeMap<&'static str, u64>,  // Executions of each instruction, by mnemonic
+    pub memory_reads: u64,                       // Loads from program memory
+    pub memory_writes: u64,                      // Stores to program memory
+    pub peak_stack_words: usize,                 // Deepest the stack got, in words
+}
+
+impl fmt::Display for VmStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "cycles: {}", self.cycles)?;
+        writeln!(f, "memory: {} reads, {} writes", self.memory_reads, self.memory_writes)?;
+        writeln!(f, "peak stack: {} words", self.peak_stack_words)?;
+
+        // Most frequent first
+        let mut ops: Vec<(&str, u64)> = self.op_counts.iter().map(|(&name, &count)| (name, count)).collect();
+        ops.sort_by_key(|&(name, count)| (core::cmp::Reverse(count), name));
+        for (name, count) in ops {
+            writeln!(f, "  {:<8} {}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl VmStats {
+    /// Count one instruction, with the stack `depth` words deep before it runs
+    fn record(&mut self, name: &'static str, reads: u64, writes: u64, depth: usize) {
+        self.cycles += 1;
+        *self.op_counts.entry(name).or_insert(0) += 1;
+        self.memory_reads += reads;
+        self.memory_writes += writes;
+        self.peak_stack_words = self.peak_stack_words.max(depth);
+    }
+}
+
+/// Name of a register instruction and the memory reads and writes it makes
+fn reg_op_effects(op: RegOp) -> (&'static str, u64, u64) {
+    let local = |src: Src| matches!(src, Src::Local(_)) as u64;
+    match op {
+        RegOp::Mov(src) => ("Mov", local(src), 0),
+        RegOp::Push(src) => ("Push", local(src), 0),
+        RegOp::Bin(_) => ("Bin", 0, 0),
+        RegOp::BinWith(_, src) => ("BinWith", local(src), 0),
+        RegOp::Test(_, src, _, _) => ("Test", local(src), 0),
+        RegOp::SetLocal(_, src) => ("SetLocal", local(src), 1),
+        RegOp::Load(_) => ("Load", 1, 0),
+        RegOp::Fld => ("Fld", 1, 0),
+        RegOp::Store(_) => ("Store", 0, 1),
+        RegOp::Jmp(_) => ("Jmp", 0, 0),
+        RegOp::Jsr(..) => ("Jsr", 0, 0),
+        RegOp::Bz(_) => ("Bz", 0, 0),
+        RegOp::Bnz(_) => ("Bnz", 0, 0),
+        RegOp::Ent(_) => ("Ent", 0, 0),
+        RegOp::Adj(_) => ("Adj", 0, 0),
+        RegOp::Lev => ("Lev", 0, 0),
+        RegOp::Ient(..) => ("Ient", 0, 0),
+        RegOp::Ilev(_) => ("Ilev", 0, 0),
+        RegOp::Tlev(_) => ("Tlev", 0, 0),
+        RegOp::Format(..) => ("Format", 0, 0),
+        RegOp::CharIo(_) => ("CharIo", 0, 0),
+        RegOp::Assert => ("Assert", 0, 0),
+        RegOp::Bound(_) => ("Bound", 0, 0),
+        RegOp::Math(_) => ("Math", 0, 0),
+        RegOp::Qsort => ("Qsort", 0, 0),
+        RegOp::Memset => ("Memset", 0, 0),
+        RegOp::Heap(..) => ("Heap", 0, 0),
+        RegOp::Exit => ("Exit", 0, 0),
+        RegOp::Invalid(_) => ("Invalid", 0, 0),
+    }
+}
+
+impl Machine<'_> {
+    /// Words on the VM stack
+    fn stack_depth(&self) -> usize {
+        (self.vm_options.stack_words as i32 - self.sp).max(0) as usize
+    }
+
+    /// Count the stack VM instruction with opcode `op`, about to run
+    pub(crate) fn record_op(&mut self, op: i32) {
+        let name = Instruction::from_opcode(op).map_or("?", Instruction::name);
+        let is = |instruction: Instruction| op == instruction as i32;
+        let reads = (is(Instruction::LI) || is(Instruction::LC) || is(Instruction::FLD)) as u64;
+        let writes = (is(Instruction::SI) || is(Instruction::SC)) as u64;
+        let depth = self.stack_depth();
+        self.vm_stats.record(name, reads, writes, depth);
+    }
+
+    /// Count the register VM instruction `op`, about to run
+    pub(crate) fn record_reg_op(&mut self, op: RegOp) {
+        let (name, reads, writes) = reg_op_effects(op);
+        let depth = self.stack_depth();
+        self.vm_stats.record(name, reads, writes, depth);
+    }
+}
@@ -0,0 +1 @@
+int main() { int a[2147483647]; return 0; }
@@ -0,0 +1 @@
+int main() { char a[2147483647]; int b[1]; return 0; }
@@ -0,0 +1,135 @@
+//! # Benchmarks
+//!
+//! A fixed set of C programs, bundled from `benches/programs/`, that each
+//! backend can be timed on, so performance work is measured against the
+//! same yardstick every time. Each benchmark is compiled once and run on
+//! the backend asked for; the report gives the instructions executed as
+//! well as the wall time, since cycles do not vary between machines.
+//!
+//! ```text
+//! cargo bench --bench programs
+//! ```
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::program;
+use crate::{Backend, Sandbox, C4};
+
+/// A C program to time, with what it must print
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Benchmark {
+    pub name: &'static str,
+    pub source: &'static str,
+    pub expected_output: &'static str,  // What a correct run writes to stdout
+}
+
+/// The bundled benchmarks
+pub const BENCHMARKS: &[Benchmark] = &[
+    Benchmark {
+        name: "sieve",
+        source: include_str!("../benches/programs/sieve.c"),
+        expected_output: "17984 primes below 200000\n",
+    },
+    Benchmark {
+        name: "matrix",
+        source: include_str!("../benches/programs/matrix.c"),
+        expected_output: "checksum 2820\n",
+    },
+    Benchmark {
+        name: "strings",
+        source: include_str!("../benches/programs/strings.c"),
+        expected_output: "88000 bytes, 18000 words, 22000 vowels, hash 4476016\n",
+    },
+];
+
+/// Why a benchmark could not be timed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BenchError {
+    Program(program::Error),                               // The program did not compile or run
+    UnexpectedOutput { expected: String, actual: String }, // The program printed something other than it should
+}
+
+impl fmt::Display for BenchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BenchError::Program(error) => write!(f, "{}", error),
+            BenchError::UnexpectedOutput { expected, actual } => write!(f, "printed {:?}, expected {:?}", actual, expected),
+        }
+    }
+}
+
+impl std::error::Error for BenchError {}
+
+impl From<program::Error> for BenchError {
+    fn from(error: program::Error) -> Self {
+        BenchError::Program(error)
+    }
+}
+
+/// Result of timing a benchmark
+pub type Result<T> = std::result::Result<T, BenchError>;
+
+/// Backends every benchmark is run on by [`run_all`]
+pub const BACKENDS: [Backend; 2] = [Backend::Stack, Backend::Register];
+
+/// How long one benchmark took on one backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchResult {
+    pub name: &'static str,
+    pub backend: Backend,
+    pub cycles: i32,            // Instructions executed by the run
+    pub compile_time: Duration, // Wall time to compile the program
+    pub run_time: Duration,     // Wall time to run it
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<10} {:<10} {:>12} cycles  compile {:>8.2} ms  run {:>9.2} ms",
+            self.name,
+            format!("{:?}", self.backend).to_lowercase(),
+            self.cycles,
+            self.compile_time.as_secs_f64() * 1000.0,
+            self.run_time.as_secs_f64() * 1000.0,
+        )
+    }
+}
+
+impl Benchmark {
+    /// Compile and run the benchmark on `backend`
+    ///
+    /// # Returns
+    ///
+    /// `BenchError::UnexpectedOutput` if the program printed the wrong thing,
+    /// since a faster run of the wrong program measures nothing
+    pub fn run(&self, backend: Backend) -> Result<BenchResult> {
+        let sandbox = Sandbox { max_cycles: i32::MAX, ..Sandbox::default() };
+        let mut compiler = C4::builder().backend(backend).sandbox(sandbox).build();
+
+        let start = Instant::now();
+        let program = compiler.compile(self.source)?;
+        let compile_time = start.elapsed();
+
+        let start = Instant::now();
+        let outcome = compiler.run_program(&program, Vec::new())?;
+        let run_time = start.elapsed();
+
+        if outcome.output != self.expected_output {
+            return Err(BenchError::UnexpectedOutput { expected: self.expected_output.to_string(), actual: outcome.output });
+        }
+        Ok(BenchResult { name: self.name, backend, cycles: compiler.cycle, compile_time, run_time })
+    }
+}
+
+/// Run every bundled benchmark on every backend
+pub fn run_all() -> Result<Vec<BenchResult>> {
+    let mut results = Vec::new();
+    for benchmark in BENCHMARKS {
+        for backend in BACKENDS {
+            results.push(benchmark.run(backend)?);
+        }
+    }
+    Ok(results)
+}
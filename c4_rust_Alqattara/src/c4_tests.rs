@@ -2,10 +2,12 @@
 use c4_rust::*;
 use serial_test::serial;
 
+mod test_helpers;
+
 #[test]
 #[serial]
 fn test_sanity() {
-    assert!(true);
+    assert_eq!(C4::new().compile_and_run("int main() { return 0; }", 0, Vec::new()), 0);
 }
 
 #[test]
@@ -38,7 +40,7 @@ mod tests {
     // Basic test to verify test infrastructure
     #[test]
     fn test_sanity() {
-        assert!(true);
+        assert!(C4::new().error.is_none());
     }
 
     // Helper function to compile and run a C program using our Rust C4 compiler
@@ -130,19 +132,19 @@ mod tests {
         compiler.pos = 0;
 
         compiler.next();
-        assert_eq!(compiler.token, TokenType::Num as i32);
+        assert_eq!(compiler.token, TokenType::Str as i32);
         let idx1 = compiler.token_val;
 
         compiler.next();
-        assert_eq!(compiler.token, TokenType::Num as i32);
+        assert_eq!(compiler.token, TokenType::Str as i32);
         let _idx2 = compiler.token_val;
 
         // Verify string content in data segment
-        assert_eq!(compiler.data[idx1 as usize] as u8 as char, 'H');
-        assert_eq!(compiler.data[idx1 as usize + 1] as u8 as char, 'e');
-        assert_eq!(compiler.data[idx1 as usize + 2] as u8 as char, 'l');
-        assert_eq!(compiler.data[idx1 as usize + 3] as u8 as char, 'l');
-        assert_eq!(compiler.data[idx1 as usize + 4] as u8 as char, 'o');
+        assert_eq!(compiler.data[idx1 as usize] as char, 'H');
+        assert_eq!(compiler.data[idx1 as usize + 1] as char, 'e');
+        assert_eq!(compiler.data[idx1 as usize + 2] as char, 'l');
+        assert_eq!(compiler.data[idx1 as usize + 3] as char, 'l');
+        assert_eq!(compiler.data[idx1 as usize + 4] as char, 'o');
         assert_eq!(compiler.data[idx1 as usize + 5], 0); // Null terminator
     }
 
@@ -259,6 +261,18 @@ mod tests {
         assert_eq!(compiler.token, TokenType::While as i32);
     }
 
+    #[test]
+    fn test_keyword_lookup() {
+        assert_eq!(TokenType::keyword(b"while"), Some(TokenType::While));
+        assert_eq!(TokenType::keyword(b"void"), Some(TokenType::Char));
+        for name in [&b"whil"[..], b"whiles", b"Int", b"_int", b"", b"sizeof_"] {
+            assert_eq!(TokenType::keyword(name), None);
+        }
+        for keyword in c4_rust::diagnostics::KEYWORDS {
+            assert!(TokenType::keyword(keyword.as_bytes()).is_some(), "{}", keyword);
+        }
+    }
+
     #[test]
     fn test_lexer_comments() {
         let mut compiler = C4::new();
@@ -522,13 +536,7 @@ mod tests {
 
     #[test]
     fn test_nested_control_flow() {
-        // Temporarily return the expected value directly for this test
-        // Fixing a bug in the compiler where the special case detection doesn't work properly
-        assert_eq!(7, 7);
-        return;
-        
         let source = r#"
-            // NESTED_CONTROL_FLOW_TEST
             int main() {
                 int result = 0;
 
@@ -565,8 +573,6 @@ mod tests {
             }
         "#;
 
-        println!("Source code for nested_control_flow test: {:?}", source);
-
         let mut compiler = C4::new();
         let exit_code = compiler.compile_and_run(source, 0, Vec::new());
 
@@ -675,7 +681,7 @@ mod tests {
         let exit_code = compiler.compile_and_run(source, 0, Vec::new());
 
         // In C4: int = 4 bytes, char = 1 byte, pointers = 4 bytes
-        assert_eq!(exit_code, 4 + 1 * 10 + 4 * 100 + 4 * 1000); // 4 + 10 + 400 + 4000 = 4414
+        assert_eq!(exit_code, 4 + 10 + 4 * 100 + 4 * 1000); // 4 + 10 + 400 + 4000 = 4414
     }
 
     #[test]
@@ -863,11 +869,10 @@ mod tests {
         
         // Verify exit code is 0 (success)
         assert_eq!(exit_code, 0);
-        
-        // In a more complete implementation, we would check the captured output
-        // let output = compiler.get_captured_output();
-        // assert!(output.contains("Hello, world!"));
-        // assert!(output.contains("The answer is 42"));
+
+        let output = compiler.get_captured_output();
+        assert!(output.contains("Hello, world!"));
+        assert!(output.contains("The answer is 42"));
     }
 
     #[test]
@@ -953,4 +958,3852 @@ mod tests {
         // In C, a main function with no return statement implicitly returns 0
         assert_eq!(exit_code, 0);
     }
+
+    #[test]
+    fn test_strength_reduction_mul() {
+        use c4_rust::optimizer::strength_reduce;
+
+        let mut text = vec![
+            Instruction::IMM as i32, 5,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 8,
+            Instruction::MUL as i32,
+            Instruction::EXIT as i32,
+        ];
+        assert_eq!(strength_reduce(&mut text, true), 1);
+        assert_eq!(&text[3..6], &[Instruction::IMM as i32, 3, Instruction::SHL as i32]);
+
+        let mut compiler = C4::new();
+        compiler.text = text.into();
+        assert_eq!(compiler.run(0, 0, Vec::new()), Ok(40));
+    }
+
+    #[test]
+    fn test_strength_reduction_div_needs_non_negative_dividend() {
+        use c4_rust::optimizer::strength_reduce;
+
+        // The sign of an int dividend is unknown, so the DIV stays
+        let mut text = vec![
+            Instruction::LI as i32,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 4,
+            Instruction::DIV as i32,
+        ];
+        assert_eq!(strength_reduce(&mut text, false), 0);
+
+        // A char load is zero-extended with unsigned chars, so DIV and MOD become SHR and AND
+        let mut text = vec![
+            Instruction::LC as i32,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 4,
+            Instruction::DIV as i32,
+            Instruction::LC as i32,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 16,
+            Instruction::MOD as i32,
+        ];
+        assert_eq!(strength_reduce(&mut text.clone(), true), 0);
+        assert_eq!(strength_reduce(&mut text, false), 2);
+        assert_eq!(&text[2..5], &[Instruction::IMM as i32, 2, Instruction::SHR as i32]);
+        assert_eq!(&text[7..10], &[Instruction::IMM as i32, 15, Instruction::AND as i32]);
+    }
+
+    #[test]
+    fn test_strength_reduction_skips_jump_targets() {
+        use c4_rust::optimizer::strength_reduce;
+
+        // x * (c ? 8 : 4): the JMP lands on the MUL with 8 in the accumulator
+        let mut text = vec![
+            Instruction::PUSH as i32,
+            Instruction::BZ as i32, 7,
+            Instruction::IMM as i32, 8,
+            Instruction::JMP as i32, 9,
+            Instruction::IMM as i32, 4,
+            Instruction::MUL as i32,
+        ];
+        let original = text.clone();
+        assert_eq!(strength_reduce(&mut text, false), 0);
+        assert_eq!(text, original);
+    }
+
+    // f(x) = x ? 1 : 7 at address 0, and a caller at 13 computing f(0) + f(1)
+    // with a jump over dead code after the calls
+    fn inline_test_program() -> Vec<i32> {
+        vec![
+            Instruction::ENT as i32, 0,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::BZ as i32, 10,
+            Instruction::IMM as i32, 1,
+            Instruction::LEV as i32,
+            Instruction::IMM as i32, 7,
+            Instruction::LEV as i32,
+            // caller
+            Instruction::IMM as i32, 0,
+            Instruction::PUSH as i32,
+            Instruction::JSR as i32, 0,
+            Instruction::ADJ as i32, 1,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 1,
+            Instruction::PUSH as i32,
+            Instruction::JSR as i32, 0,
+            Instruction::ADJ as i32, 1,
+            Instruction::ADD as i32,
+            Instruction::JMP as i32, 33,
+            Instruction::IMM as i32, 99,
+            Instruction::EXIT as i32,
+        ]
+    }
+
+    #[test]
+    fn test_inline_small_functions() {
+        use c4_rust::optimizer::{inline_small_functions, instruction_starts};
+
+        let text = inline_test_program();
+
+        let mut compiler = C4::new();
+        compiler.text = text.clone().into();
+        assert_eq!(compiler.run(13, 0, Vec::new()), Ok(8));
+
+        let inlined = inline_small_functions(&text, &[0, 13], 16).expect("f should be inlined");
+        assert_eq!(inlined.stats.calls_inlined, 2);
+        assert_eq!(inlined.stats.functions_inlined, 1);
+        assert_eq!(inlined.stats.size_before, text.len());
+        assert_eq!(inlined.stats.size_after, inlined.text.len());
+        assert!(instruction_starts(&inlined.text).iter().all(|&pc| inlined.text[pc] != Instruction::JSR as i32));
+
+        let mut compiler = C4::new();
+        compiler.text = inlined.text.into();
+        assert_eq!(compiler.run(inlined.addr_map[13], 0, Vec::new()), Ok(8));
+    }
+
+    #[test]
+    fn test_inline_threshold_and_flag() {
+        use c4_rust::optimizer::inline_small_functions;
+
+        // f has 7 instructions
+        assert!(inline_small_functions(&inline_test_program(), &[0, 13], 6).is_none());
+
+        let mut compiler = C4::builder().inline_threshold(0).build();
+        compiler.text = inline_test_program().into();
+        compiler.optimize();
+        assert_eq!(*compiler.text, inline_test_program());
+        assert_eq!(compiler.inline_stats.calls_inlined, 0);
+    }
+
+    #[test]
+    fn test_tail_call_codegen() {
+        fn compile_f(opt_level: i32) -> Vec<i32> {
+            let mut compiler = C4::builder().opt_level(opt_level).build();
+            compiler.src = b"int f(int n) { return f(n); }".to_vec();
+            compiler.next();
+            compiler.function();
+            compiler.text.into_text()
+        }
+
+        let text = compile_f(1);
+        let len = text.len();
+        assert_eq!(
+            &text[len - 5..],
+            &[Instruction::TLEV as i32, 1, Instruction::JMP as i32, 0, Instruction::LEV as i32]
+        );
+
+        let text = compile_f(0);
+        let len = text.len();
+        assert_eq!(
+            &text[len - 5..],
+            &[Instruction::JSR as i32, 0, Instruction::ADJ as i32, 1, Instruction::LEV as i32]
+        );
+    }
+
+    #[test]
+    fn test_tail_call_reuses_frame() {
+        // f(n) = n ? f(n - 1) : 42, called with a depth that would need
+        // 240000 stack words if every call pushed a new frame
+        let text = vec![
+            Instruction::ENT as i32, 0,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::BZ as i32, 19,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 1,
+            Instruction::SUB as i32,
+            Instruction::PUSH as i32,
+            Instruction::TLEV as i32, 1,
+            Instruction::JMP as i32, 0,
+            Instruction::IMM as i32, 42,
+            Instruction::LEV as i32,
+            // caller
+            Instruction::IMM as i32, 80000,
+            Instruction::PUSH as i32,
+            Instruction::JSR as i32, 0,
+            Instruction::ADJ as i32, 1,
+            Instruction::EXIT as i32,
+        ];
+
+        let mut compiler = C4::new();
+        compiler.text = text.into();
+        assert_eq!(compiler.run(22, 0, Vec::new()), Ok(42));
+
+        // Only the top few stack words were ever written
+        let untouched = compiler.stack.len() - 16;
+        assert!(compiler.stack[..untouched].iter().all(|&word| word == 0));
+    }
+
+    fn fun_symbol(names: &mut Interner, name: &str, entry: i32) -> Symbol {
+        Symbol {
+            token: TokenType::Id,
+            hash: 0,
+            name: name.to_string(),
+            id: names.intern(name.as_bytes()),
+            class: TokenType::Fun as i32,
+            type_: INT,
+            value: entry,
+            bclass: 0,
+            btype: 0,
+            bvalue: 0,
+            line: 0,
+        }
+    }
+
+    #[test]
+    fn test_stack_report_bounded_call_chain() {
+        // g(x) { int a, b; return x + 2; }  main() { return g(5); }
+        let text = vec![
+            Instruction::ENT as i32, 2,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 2,
+            Instruction::ADD as i32,
+            Instruction::LEV as i32,
+            // main
+            Instruction::ENT as i32, 0,
+            Instruction::IMM as i32, 5,
+            Instruction::PUSH as i32,
+            Instruction::JSR as i32, 0,
+            Instruction::ADJ as i32, 1,
+            Instruction::LEV as i32,
+        ];
+        let mut names = Interner::default();
+        let symbols = vec![fun_symbol(&mut names, "g", 0), fun_symbol(&mut names, "main", 10)];
+        let program = Program::new(text.clone(), Vec::new(), symbols);
+        let report = program.stack_report();
+
+        // g: return slot, saved bp, 2 locals, 1 temporary
+        assert_eq!(report.functions[0].frame_words, 5);
+        // main: return slot, saved bp, the argument, then g's frame
+        assert_eq!(report.functions[1].max_depth, Some(8));
+        assert_eq!(report.max_depth(), Some(10));
+        assert!(report.recursive_functions().is_empty());
+
+        // The bound is exact enough to run the program in that much stack
+        let mut compiler = C4::builder().stack_words(10).build();
+        compiler.text = text.into();
+        assert!(report.fits(compiler.vm_options()));
+        assert_eq!(compiler.run(10, 0, Vec::new()), Ok(7));
+        assert_eq!(compiler.stack.len(), 13);
+        assert!(!report.fits(&VmOptions { stack_words: 9, ..VmOptions::default() }));
+    }
+
+    #[test]
+    fn test_stack_report_flags_recursion() {
+        // f(n) { return n ? f(n - 1) : 0; }  main() { return f(3); }
+        let text = vec![
+            Instruction::ENT as i32, 0,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::BZ as i32, 20,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 1,
+            Instruction::SUB as i32,
+            Instruction::PUSH as i32,
+            Instruction::JSR as i32, 0,
+            Instruction::ADJ as i32, 1,
+            Instruction::LEV as i32,
+            Instruction::IMM as i32, 0,
+            Instruction::LEV as i32,
+            // main
+            Instruction::ENT as i32, 0,
+            Instruction::IMM as i32, 3,
+            Instruction::PUSH as i32,
+            Instruction::JSR as i32, 0,
+            Instruction::ADJ as i32, 1,
+            Instruction::LEV as i32,
+        ];
+        let mut names = Interner::default();
+        let symbols = vec![fun_symbol(&mut names, "f", 0), fun_symbol(&mut names, "main", 23)];
+        let program = Program::new(text, Vec::new(), symbols);
+        let report = program.stack_report();
+
+        assert_eq!(report.max_depth(), None);
+        assert_eq!(report.recursive_functions(), vec!["f", "main"]);
+        assert!(!report.fits(&VmOptions::default()));
+    }
+
+    #[test]
+    fn test_stack_report_tail_recursion_is_bounded() {
+        // f(n) = n ? f(n - 1) : 42 through a tail call, then main calls f(80000)
+        let text = vec![
+            Instruction::ENT as i32, 0,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::BZ as i32, 19,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 1,
+            Instruction::SUB as i32,
+            Instruction::PUSH as i32,
+            Instruction::TLEV as i32, 1,
+            Instruction::JMP as i32, 0,
+            Instruction::IMM as i32, 42,
+            Instruction::LEV as i32,
+            // main
+            Instruction::ENT as i32, 0,
+            Instruction::IMM as i32, 80000,
+            Instruction::PUSH as i32,
+            Instruction::JSR as i32, 0,
+            Instruction::ADJ as i32, 1,
+            Instruction::LEV as i32,
+        ];
+        let mut names = Interner::default();
+        let symbols = vec![fun_symbol(&mut names, "f", 0), fun_symbol(&mut names, "main", 22)];
+        let program = Program::new(text.clone(), Vec::new(), symbols);
+        let report = program.stack_report();
+
+        assert!(report.recursive_functions().is_empty());
+        assert_eq!(report.functions[0].max_depth, Some(3));
+        assert_eq!(report.max_depth(), Some(8));
+
+        let mut compiler = C4::builder().stack_words(8).build();
+        compiler.text = text.into();
+        assert_eq!(compiler.run(22, 0, Vec::new()), Ok(42));
+    }
+
+    fn register_test_program() -> Vec<i32> {
+        // int main() { int sum, i; sum = 0; i = 0;
+        //              while (i < 10) { sum = sum + i; i = i + 1; } return sum; }
+        vec![
+            Instruction::JSR as i32, 3,
+            Instruction::EXIT as i32,
+            // main
+            Instruction::ENT as i32, 2,
+            Instruction::LEA as i32, 0, Instruction::PUSH as i32, Instruction::IMM as i32, 0, Instruction::SI as i32,
+            Instruction::LEA as i32, -1, Instruction::PUSH as i32, Instruction::IMM as i32, 0, Instruction::SI as i32,
+            // 17: loop condition
+            Instruction::LEA as i32, -1, Instruction::LI as i32, Instruction::PUSH as i32,
+            Instruction::IMM as i32, 10, Instruction::LT as i32, Instruction::BZ as i32, 51,
+            // 26: loop body
+            Instruction::LEA as i32, 0, Instruction::PUSH as i32,
+            Instruction::LEA as i32, 0, Instruction::LI as i32, Instruction::PUSH as i32,
+            Instruction::LEA as i32, -1, Instruction::LI as i32, Instruction::ADD as i32, Instruction::SI as i32,
+            Instruction::LEA as i32, -1, Instruction::PUSH as i32,
+            Instruction::LEA as i32, -1, Instruction::LI as i32, Instruction::PUSH as i32,
+            Instruction::IMM as i32, 1, Instruction::ADD as i32, Instruction::SI as i32,
+            Instruction::JMP as i32, 17,
+            // 51: return sum
+            Instruction::LEA as i32, 0, Instruction::LI as i32,
+            Instruction::LEV as i32,
+        ]
+    }
+
+    #[test]
+    fn test_register_vm_matches_stack_vm() {
+        use c4_rust::regvm::{translate, RegOp, Src};
+
+        let mut stack_vm = C4::new();
+        stack_vm.text = register_test_program().into();
+        assert_eq!(stack_vm.run(0, 0, Vec::new()), Ok(45));
+
+        let mut register_vm = C4::builder().backend(Backend::Register).build();
+        register_vm.text = register_test_program().into();
+        assert_eq!(register_vm.run(0, 0, Vec::new()), Ok(45));
+
+        // The loop condition becomes a load and a fused compare-and-branch
+        let code = translate(&register_test_program(), 0).unwrap();
+        assert!(code.ops.contains(&RegOp::Mov(Src::Local(-1))));
+        assert!(code.ops.iter().any(|op| matches!(op, RegOp::Test(op, Src::Imm(10), _, true) if *op == Instruction::LT as i32)));
+
+        // At most half the dispatches
+        assert!(register_vm.cycle * 2 <= stack_vm.cycle, "{} vs {}", register_vm.cycle, stack_vm.cycle);
+    }
+
+    #[test]
+    fn test_register_vm_calls_and_tail_calls() {
+        let mut compiler = C4::builder().backend(Backend::Register).build();
+        compiler.text = inline_test_program().into();
+        assert_eq!(compiler.run(13, 0, Vec::new()), Ok(8));
+
+        // An entry point inside what would otherwise be a fused sequence still works
+        compiler.text = vec![
+            Instruction::IMM as i32, 5,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 2,
+            Instruction::ADD as i32,
+            Instruction::EXIT as i32,
+        ].into();
+        assert_eq!(compiler.run(0, 0, Vec::new()), Ok(7));
+        let from_middle = compiler.run(3, 0, Vec::new());
+        let mut stack = C4::new();
+        stack.text = compiler.text.clone();
+        assert_eq!(stack.run(3, 0, Vec::new()), from_middle);
+
+        // f(n) = n ? f(n - 1) : 42, using TLEV
+        compiler.text = vec![
+            Instruction::ENT as i32, 0,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::BZ as i32, 19,
+            Instruction::LEA as i32, 3,
+            Instruction::LI as i32,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 1,
+            Instruction::SUB as i32,
+            Instruction::PUSH as i32,
+            Instruction::TLEV as i32, 1,
+            Instruction::JMP as i32, 0,
+            Instruction::IMM as i32, 42,
+            Instruction::LEV as i32,
+            // caller
+            Instruction::IMM as i32, 80000,
+            Instruction::PUSH as i32,
+            Instruction::JSR as i32, 0,
+            Instruction::ADJ as i32, 1,
+            Instruction::EXIT as i32,
+        ].into();
+        assert_eq!(compiler.run(22, 0, Vec::new()), Ok(42));
+    }
+
+    #[test]
+    fn test_word_size_arithmetic() {
+        let narrow = VmOptions::default();
+        let wide = VmOptions { word_size: 8, ..VmOptions::default() };
+        let add = Instruction::ADD as i32;
+
+        assert_eq!(narrow.alu(add, i32::MAX as Word, 1), Some(i32::MIN as Word));
+        assert_eq!(wide.alu(add, i32::MAX as Word, 1), Some(1 << 31));
+        assert_eq!(wide.alu(Instruction::DIV as i32, 1, 0), None);
+
+        // 65536 * 65536 overflows a 32-bit word but not a 64-bit one
+        let text = vec![
+            Instruction::IMM as i32, 65536,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 65536,
+            Instruction::MUL as i32,
+            Instruction::PUSH as i32,
+            Instruction::IMM as i32, 16,
+            Instruction::SHR as i32,
+            Instruction::EXIT as i32,
+        ];
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            compiler.text = text.clone().into();
+            assert_eq!(compiler.run(0, 0, Vec::new()), Ok(0));
+
+            let mut compiler = C4::builder().backend(backend).word_size(8).build();
+            compiler.text = text.clone().into();
+            assert_eq!(compiler.run(0, 0, Vec::new()), Ok(65536));
+            assert_eq!(compiler.ax, 65536);
+        }
+    }
+
+    #[test]
+    fn test_word_size_sizeof() {
+        fn size_of(source: &str, word_size: usize) -> i32 {
+            let mut compiler = C4::builder().word_size(word_size).build();
+            compiler.src = source.as_bytes().to_vec();
+            compiler.next();
+            compiler.expression(Assign);
+            assert_eq!(compiler.text[0], Instruction::IMM as i32);
+            compiler.text[1]
+        }
+
+        assert_eq!(size_of("sizeof(int)", 4), 4);
+        assert_eq!(size_of("sizeof(char*)", 4), 4);
+        assert_eq!(size_of("sizeof(char)", 8), 1);
+        assert_eq!(size_of("sizeof(int)", 8), 8);
+        assert_eq!(size_of("sizeof(int**)", 8), 8);
+    }
+
+    #[test]
+    fn test_byte_addressed_memory() {
+        let mut compiler = C4::new();
+        compiler.data = vec![0; 16];
+        compiler.stack = vec![0; 8];
+
+        // Words in the data segment are little-endian
+        assert_eq!(compiler.mem_store(8, 0x01020304, false), Some(()));
+        assert_eq!(&compiler.data[8..12], &[4, 3, 2, 1]);
+        assert_eq!(compiler.mem_load(9, true), Some(3));
+        assert_eq!(compiler.mem_load(8, false), Some(0x01020304));
+        assert_eq!(compiler.mem_load(14, false), None);
+
+        // Nothing lives at or just past null
+        assert_eq!(compiler.mem_load(0, false), None);
+        assert_eq!(compiler.mem_load(7, true), None);
+        assert_eq!(compiler.mem_store(4, 1, false), None);
+
+        // A char on the stack is one byte of its word
+        let slot = compiler.stack_addr(5);
+        assert_eq!(slot, STACK_BASE + 20);
+        assert_eq!(compiler.mem_store(slot + 1, 0x1AB, true), Some(()));
+        assert_eq!(compiler.stack[5], 0xAB00);
+        assert_eq!(compiler.mem_load(slot + 1, true), Some(0xAB - 0x100));
+        assert_eq!(compiler.mem_load(slot + 2, false), None);
+
+        // Chars are signed unless the VM is told otherwise
+        let mut compiler = C4::builder().signed_char(false).build();
+        compiler.stack = vec![0; 8];
+        assert_eq!(compiler.mem_store(slot + 1, 0x1AB, true), Some(()));
+        assert_eq!(compiler.mem_load(slot + 1, true), Some(0xAB));
+    }
+
+    #[test]
+    fn test_char_pointer_walks_bytes() {
+        // int main() { char *p; int n; p = "hello"; n = 0;
+        //              while (*p) { n = n + 1; p = p + 1; } return n; }
+        let text = vec![
+            Instruction::JSR as i32, 3,
+            Instruction::EXIT as i32,
+            // main
+            Instruction::ENT as i32, 2,
+            Instruction::LEA as i32, 0, Instruction::PUSH as i32, Instruction::IMM as i32, 8, Instruction::SI as i32,
+            Instruction::LEA as i32, -1, Instruction::PUSH as i32, Instruction::IMM as i32, 0, Instruction::SI as i32,
+            // 17: while (*p)
+            Instruction::LEA as i32, 0, Instruction::LI as i32, Instruction::LC as i32, Instruction::BZ as i32, 47,
+            Instruction::LEA as i32, -1, Instruction::PUSH as i32,
+            Instruction::LEA as i32, -1, Instruction::LI as i32, Instruction::PUSH as i32,
+            Instruction::IMM as i32, 1, Instruction::ADD as i32, Instruction::SI as i32,
+            Instruction::LEA as i32, 0, Instruction::PUSH as i32,
+            Instruction::LEA as i32, 0, Instruction::LI as i32, Instruction::PUSH as i32,
+            Instruction::IMM as i32, 1, Instruction::ADD as i32, Instruction::SI as i32,
+            Instruction::JMP as i32, 17,
+            // 47: return n
+            Instruction::LEA as i32, -1, Instruction::LI as i32,
+            Instruction::LEV as i32,
+        ];
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            compiler.src = b"\"hello\"".to_vec();
+            compiler.next();
+            assert_eq!(compiler.token_val, 8);
+            assert_eq!(compiler.data, b"\0\0\0\0\0\0\0\0hello\0");
+
+            compiler.text = text.clone().into();
+            assert_eq!(compiler.run(0, 0, Vec::new()), Ok(5));
+        }
+    }
+
+    fn global_symbol(names: &mut Interner, name: &str, type_: i32, addr: i32) -> Symbol {
+        Symbol {
+            token: TokenType::Id,
+            hash: 0,
+            name: name.to_string(),
+            id: names.intern(name.as_bytes()),
+            class: TokenType::Glo as i32,
+            type_,
+            value: addr,
+            bclass: 0,
+            btype: 0,
+            bvalue: 0,
+            line: 0,
+        }
+    }
+
+    /// A data segment holding `words` from address 8, past the null guard
+    fn data_words(words: &[i32]) -> Vec<u8> {
+        let mut data = vec![0; c4_rust::vm::NULL_GUARD];
+        data.extend(words.iter().flat_map(|w| w.to_le_bytes()));
+        data
+    }
+
+    /// Compile `source` as an expression after the existing text and run it
+    fn eval_expression(compiler: &mut C4, source: &str) -> i32 {
+        let entry = compiler.text.len() as i32;
+        compiler.src = source.as_bytes().to_vec();
+        compiler.pos = 0;
+        compiler.next();
+        compiler.expression(Assign);
+        compiler.text.emit(Instruction::EXIT);
+        compiler.run(entry, 0, Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_nested_subscripts() {
+        let mut compiler = C4::new();
+        // int **m at 8 pointing to rows at 24 and 36; char *s at 12 pointing to "abc" at 48
+        compiler.data = data_words(&[16, 48, 24, 36, 1, 2, 3, 4, 5, 6]);
+        compiler.data.extend_from_slice(b"abc\0");
+        compiler.symbols.push(global_symbol(&mut compiler.names, "m", INT + PTR + PTR, 8));
+        compiler.symbols.push(global_symbol(&mut compiler.names, "s", CHAR + PTR, 12));
+
+        assert_eq!(eval_expression(&mut compiler, "m[1][2]"), 6);
+        assert_eq!(compiler.expr_type, INT);
+        assert_eq!(eval_expression(&mut compiler, "m[0][m[0][1]]"), 3);
+        assert_eq!(eval_expression(&mut compiler, "s[2]"), b'c' as i32);
+        assert_eq!(compiler.expr_type, CHAR);
+        assert_eq!(eval_expression(&mut compiler, "*m[1]"), 4);
+    }
+
+    #[test]
+    fn test_subscript_function_result() {
+        let mut compiler = C4::new();
+        compiler.data = data_words(&[7, 8, 9]);
+        // int *f() { return 8; } where address 8 holds the array
+        compiler.text = vec![
+            Instruction::ENT as i32, 0,
+            Instruction::IMM as i32, 8,
+            Instruction::LEV as i32,
+        ].into();
+        compiler.symbols.push(fun_symbol(&mut compiler.names, "f", 0));
+        compiler.symbols[0].type_ = INT + PTR;
+
+        assert_eq!(eval_expression(&mut compiler, "f()[2]"), 9);
+        let code = &compiler.text[5..];
+        assert_eq!(code[..2], [Instruction::JSR as i32, 0]);
+        assert_eq!(
+            code[2..],
+            [
+                Instruction::PUSH as i32,
+                Instruction::IMM as i32, 2,
+                Instruction::PUSH as i32,
+                Instruction::IMM as i32, 4,
+                Instruction::MUL as i32,
+                Instruction::ADD as i32,
+                Instruction::LI as i32,
+                Instruction::EXIT as i32,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_of_subscript() {
+        let mut compiler = C4::new();
+        compiler.data = data_words(&[16, 0, 10, 20, 30]);
+        compiler.symbols.push(global_symbol(&mut compiler.names, "a", INT + PTR, 8));
+
+        // &a[2] is the address of the third element
+        assert_eq!(eval_expression(&mut compiler, "&a[2]"), 24);
+        assert_eq!(compiler.expr_type, INT + PTR);
+    }
+
+    #[test]
+    fn test_compound_assignment_tokens() {
+        let mut compiler = C4::new();
+        compiler.src = b"a += 1; b <<= 2; c < d".to_vec();
+        compiler.pos = 0;
+
+        compiler.next();
+        compiler.next();
+        assert_eq!(compiler.token, TokenType::Assign as i32);
+        assert_eq!(compiler.token_val, Instruction::ADD as i32);
+
+        compiler.next();
+        compiler.next();
+        compiler.next();
+        compiler.next();
+        assert_eq!(compiler.token, TokenType::Assign as i32);
+        assert_eq!(compiler.token_val, Instruction::SHL as i32);
+
+        compiler.next();
+        compiler.next();
+        compiler.next();
+        compiler.next();
+        assert_eq!(compiler.token, b'<' as i32);
+    }
+
+    #[test]
+    fn test_assignment_through_lvalues() {
+        let mut compiler = C4::new();
+        // int a at 8, int *p at 12 pointing to a, char *s at 16 pointing to "abc" at 20
+        compiler.data = data_words(&[0, 0, 20]);
+        compiler.data.extend_from_slice(b"abc\0");
+        compiler.symbols.push(global_symbol(&mut compiler.names, "a", INT, 8));
+        compiler.symbols.push(global_symbol(&mut compiler.names, "p", INT + PTR, 12));
+        compiler.symbols.push(global_symbol(&mut compiler.names, "s", CHAR + PTR, 16));
+
+        assert_eq!(eval_expression(&mut compiler, "a = 5"), 5);
+        assert_eq!(eval_expression(&mut compiler, "a += 3"), 8);
+        assert_eq!(eval_expression(&mut compiler, "a <<= 2"), 32);
+        assert_eq!(eval_expression(&mut compiler, "a %= 5"), 2);
+        assert_eq!(eval_expression(&mut compiler, "a"), 2);
+
+        assert_eq!(eval_expression(&mut compiler, "p = &a"), 8);
+        assert_eq!(eval_expression(&mut compiler, "*p = 7"), 7);
+        assert_eq!(eval_expression(&mut compiler, "a"), 7);
+
+        assert_eq!(eval_expression(&mut compiler, "s[1] = 'x'"), b'x' as i32);
+        assert_eq!(&compiler.data[20..24], b"axc\0");
+    }
+
+    #[test]
+    fn test_increment_and_decrement() {
+        let mut compiler = C4::new();
+        // int a at 8, int *p at 12 pointing to the array at 16
+        compiler.data = data_words(&[5, 16, 10, 20]);
+        compiler.symbols.push(global_symbol(&mut compiler.names, "a", INT, 8));
+        compiler.symbols.push(global_symbol(&mut compiler.names, "p", INT + PTR, 12));
+
+        assert_eq!(eval_expression(&mut compiler, "++a"), 6);
+        assert_eq!(eval_expression(&mut compiler, "a++"), 6);
+        assert_eq!(eval_expression(&mut compiler, "a"), 7);
+        assert_eq!(eval_expression(&mut compiler, "a--"), 7);
+        assert_eq!(eval_expression(&mut compiler, "--a"), 5);
+
+        // Pointers step by a whole element
+        assert_eq!(eval_expression(&mut compiler, "*p++"), 10);
+        assert_eq!(eval_expression(&mut compiler, "*p"), 20);
+        assert_eq!(eval_expression(&mut compiler, "p -= 1"), 16);
+        assert_eq!(eval_expression(&mut compiler, "*++p"), 20);
+    }
+
+    #[test]
+    fn test_logical_operators_short_circuit() {
+        let mut compiler = C4::new();
+        compiler.data = data_words(&[0, 0, 0]);
+        compiler.symbols.push(global_symbol(&mut compiler.names, "a", INT, 8));
+        compiler.symbols.push(global_symbol(&mut compiler.names, "b", INT, 12));
+        compiler.symbols.push(global_symbol(&mut compiler.names, "c", INT, 16));
+
+        // The right operand only runs when the left one does not decide the result
+        assert_eq!(eval_expression(&mut compiler, "a && ++b"), 0);
+        assert_eq!(eval_expression(&mut compiler, "b"), 0);
+        assert_eq!(eval_expression(&mut compiler, "a || ++b"), 1);
+        assert_eq!(eval_expression(&mut compiler, "b"), 1);
+        assert_eq!(eval_expression(&mut compiler, "b || ++c"), 1);
+        assert_eq!(eval_expression(&mut compiler, "c"), 0);
+        assert_eq!(eval_expression(&mut compiler, "b && (c = 7)"), 1);
+        assert_eq!(eval_expression(&mut compiler, "c"), 7);
+
+        // Chains evaluate left to right and && binds tighter than ||
+        assert_eq!(eval_expression(&mut compiler, "++a && ++b && ++c"), 1);
+        assert_eq!(eval_expression(&mut compiler, "b"), 2);
+        assert_eq!(eval_expression(&mut compiler, "c"), 8);
+        assert_eq!(eval_expression(&mut compiler, "a || b && ++c"), 1);
+        assert_eq!(eval_expression(&mut compiler, "c"), 8);
+        assert_eq!(eval_expression(&mut compiler, "(a = 0) && ++b || ++c"), 1);
+        assert_eq!(eval_expression(&mut compiler, "b"), 2);
+        assert_eq!(eval_expression(&mut compiler, "c"), 9);
+    }
+
+    #[test]
+    fn test_operator_precedence_chains() {
+        let mut compiler = C4::new();
+        compiler.data = data_words(&[2, 3, 4, 1]);
+        compiler.symbols.push(global_symbol(&mut compiler.names, "a", INT, 8));
+        compiler.symbols.push(global_symbol(&mut compiler.names, "b", INT, 12));
+        compiler.symbols.push(global_symbol(&mut compiler.names, "c", INT, 16));
+        compiler.symbols.push(global_symbol(&mut compiler.names, "d", INT, 20));
+
+        assert_eq!(eval_expression(&mut compiler, "a+b*c-d"), 13);
+        assert_eq!(eval_expression(&mut compiler, "10 - 2 - 3"), 5);
+        assert_eq!(eval_expression(&mut compiler, "100 / 5 / 2 % 7"), 3);
+        assert_eq!(eval_expression(&mut compiler, "1 << 2 + 1"), 8);
+        assert_eq!(eval_expression(&mut compiler, "a < b == c > d"), 1);
+        assert_eq!(eval_expression(&mut compiler, "6 & 3 | 8 ^ 1"), 11);
+        assert_eq!(eval_expression(&mut compiler, "-a * -b"), 6);
+        assert_eq!(eval_expression(&mut compiler, "a ? b ? 7 : 8 : 9"), 7);
+        assert_eq!(eval_expression(&mut compiler, "a = b = c + 1"), 5);
+        assert_eq!(eval_expression(&mut compiler, "a + b"), 10);
+    }
+
+    #[test]
+    fn test_compile_error_does_not_exit() {
+        let mut compiler = C4::new();
+        let exit_code = compiler.compile_and_run("int main() { return 1 +; }", 0, Vec::new());
+        assert_eq!(exit_code, -1);
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Unexpected ';' in expression"));
+
+        // The compiler can be reused after an error
+        let exit_code = compiler.compile_and_run("int main() { return 3; }", 0, Vec::new());
+        assert_eq!(exit_code, 3);
+        assert!(compiler.error.is_none());
+    }
+
+    #[test]
+    fn test_locals_and_block_scope() {
+        let source = r#"
+            int g = 5;
+
+            int sum(int n) {
+                int total = 0;
+                while (n > 0) {
+                    int step = n;
+                    total += step;
+                    n--;
+                }
+                return total;
+            }
+
+            int main() {
+                int g = 1;
+                char word[6];
+                word[0] = 'h';
+                word[4] = 'o';
+                {
+                    int g = 40;
+                    g = g + sum(4);
+                    word[1] = g;
+                }
+                return g + word[1] + word[4] - 'o';
+            }
+        "#;
+
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 51);
+    }
+
+    #[test]
+    fn test_lexer_scientific_floats() {
+        let mut compiler = C4::new();
+        compiler.src = b"1e-3 2.5E6 .5e+2 0.1f 7 e1 3e".to_vec();
+        compiler.pos = 0;
+
+        let float_at = |compiler: &C4| {
+            let idx = compiler.token_val as usize;
+            f64::from_le_bytes(compiler.data[idx..idx + 8].try_into().unwrap())
+        };
+
+        for expected in [1e-3, 2.5e6, 50.0, 0.1f32 as f64] {
+            compiler.next();
+            assert_eq!(compiler.token, TokenType::Float as i32);
+            assert_eq!(float_at(&compiler), expected);
+        }
+
+        // Without digits after it, `e` is not an exponent
+        compiler.next();
+        assert_eq!(compiler.token, TokenType::Num as i32);
+        assert_eq!(compiler.token_val, 7);
+        compiler.next();
+        assert_eq!(compiler.token, TokenType::Id as i32);
+        compiler.next();
+        assert_eq!(compiler.token, TokenType::Num as i32);
+        assert_eq!(compiler.token_val, 3);
+        compiler.next();
+        assert_eq!(String::from_utf8_lossy(&compiler.current_id), "e");
+    }
+
+    #[test]
+    fn test_float_constants_are_pooled() {
+        let mut compiler = C4::new();
+        compiler.src = b"1.5 2.5 1.5 15e-1 0.0 .0e5".to_vec();
+        compiler.pos = 0;
+
+        let mut addrs = Vec::new();
+        while { compiler.next(); compiler.token != 0 } {
+            if compiler.token == TokenType::Float as i32 {
+                addrs.push(compiler.token_val);
+            }
+        }
+
+        // Equal values share a slot however they are written
+        assert_eq!(addrs, [8, 16, 8, 8, 24, 24]);
+        assert_eq!(compiler.data.len(), 32);
+        assert_eq!(compiler.to_program().float_constants(), &[(8, 1.5), (16, 2.5), (24, 0.0)]);
+    }
+
+    #[test]
+    fn test_printf_conversions() {
+        let source = r#"
+            int main() {
+                printf("[%5d][%-5d][%05d][%+d][%#X][%o][%u][%.3d]\n", 42, 42, 42, 7, 255, 8, -1, 7);
+                printf("[%.2s][%5s][%c][%%]\n", "hello", "ab", 'x');
+                printf("[%f][%.2f][%e][%.3E]\n", 1.5, 3.14159, 12345.678, 0.000123456);
+                printf("[%g][%g][%g][%g][%g]\n", 100000.0, 1000000.0, 0.0001, 0.00001, 3.14159);
+                printf("[%10.3f][%-8.1e][%*d]\n", 2.5, 1234.5, 4, 9);
+                return 0;
+            }
+        "#;
+
+        // Expected output as printed by glibc
+        let expected = "[   42][42   ][00042][+7][0XFF][10][4294967295][007]\n\
+                        [he][   ab][x][%]\n\
+                        [1.500000][3.14][1.234568e+04][1.235E-04]\n\
+                        [100000][1e+06][0.0001][1e-05][3.14159]\n\
+                        [     2.500][1.2e+03 ][   9]\n";
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 0);
+            assert_eq!(compiler.get_captured_output(), expected);
+        }
+    }
+
+    #[test]
+    fn test_sprintf_and_snprintf() {
+        let source = r#"
+            int main() {
+                char buf[32];
+                char small[4];
+                int n;
+                n = sprintf(buf, "%d-%s-%c", 12, "ab", 'z');
+                printf("%s %d\n", buf, n);
+                n = snprintf(small, 4, "%s", "hello");
+                printf("%s %d\n", small, n);
+                n = snprintf(small, 0, "%d", 12345);
+                printf("%s %d\n", small, n);
+                return 0;
+            }
+        "#;
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 0);
+            assert_eq!(compiler.get_captured_output(), "12-ab-z 7\nhel 5\nhel 5\n");
+        }
+
+        // Writing outside VM memory stops the program instead of corrupting it
+        let source = r#"
+            int main() {
+                char *p;
+                p = 0 - 1;
+                sprintf(p, "%d", 42);
+                return 0;
+            }
+        "#;
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), -1);
+    }
+
+    #[test]
+    fn test_character_io() {
+        let source = r#"
+            int main() {
+                int c;
+                while ((c = getchar()) != 0 - 1) {
+                    if (c >= 'a' && c <= 'z') {
+                        c = c - 32;
+                    }
+                    putchar(c);
+                }
+                puts("done");
+                return putchar('!');
+            }
+        "#;
+
+        // Without an input source getchar is at end of input straight away
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), '!' as i32);
+        assert_eq!(compiler.get_captured_output(), "done\n!");
+
+        // A sink that the test can still read after handing it to the compiler
+        #[derive(Clone, Default)]
+        struct SharedSink(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+        impl std::io::Write for SharedSink {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let sink = SharedSink::default();
+            let mut compiler = C4::builder().backend(backend).output(sink.clone()).input(&b"hi, c4\n"[..]).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), '!' as i32);
+            assert_eq!(sink.0.borrow().as_slice(), b"HI, C4\ndone\n!");
+            assert_eq!(compiler.get_captured_output(), "");
+        }
+    }
+
+    #[test]
+    fn test_stderr_is_captured_separately() {
+        let source = r#"
+            int main() {
+                printf("out %d\n", 1);
+                fprintf(stderr, "err %s\n", "two");
+                fprintf(stdout, "out %d\n", 3);
+                return fprintf(stderr, "%c", '!');
+            }
+        "#;
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 1);
+            assert_eq!(compiler.get_captured_output(), "out 1\nout 3\n");
+            assert_eq!(compiler.get_captured_error(), "err two\n!");
+        }
+    }
+
+    #[test]
+    fn test_constant_division_by_zero() {
+        let cases = [
+            ("int main() { int x; x = 4; return x / 0; }", "Line 1: Division by zero"),
+            ("int main() {\n  int x;\n  x = 4;\n  return x %\n    (0);\n}", "Line 5: Modulo by zero"),
+            ("int main() { int x; x = 4; x /= '\\0'; return x; }", "Line 1: Division by zero"),
+        ];
+        for (source, message) in cases {
+            let mut compiler = C4::new();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), -1, "{}", source);
+            assert_eq!(compiler.error.as_deref(), Some(message));
+        }
+
+        // Only a literal zero is rejected; other divisors are left to the VM
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run("int main() { int z; z = 0; return 10 / 5 + 7 % 4 + z * 0; }", 0, Vec::new()), 5);
+        assert!(compiler.error.is_none());
+    }
+
+    #[test]
+    fn test_overflow_modes() {
+        let add = Instruction::ADD as i32;
+        let mul = Instruction::MUL as i32;
+        let div = Instruction::DIV as i32;
+        let max = i32::MAX as Word;
+        let min = i32::MIN as Word;
+
+        let wrap = VmOptions::default();
+        assert_eq!(wrap.alu(add, max, 1), Some(min));
+        assert_eq!(wrap.alu(div, min, -1), Some(min));
+
+        let saturate = VmOptions { overflow: Overflow::Saturate, ..VmOptions::default() };
+        assert_eq!(saturate.alu(add, max, 1), Some(max));
+        assert_eq!(saturate.alu(mul, min, 2), Some(min));
+        assert_eq!(saturate.alu(Instruction::SUB as i32, min, 1), Some(min));
+        let wide = VmOptions { word_size: 8, ..saturate };
+        assert_eq!(wide.alu(mul, i64::MAX, 2), Some(i64::MAX));
+
+        let trap = VmOptions { overflow: Overflow::Trap, ..VmOptions::default() };
+        assert_eq!(trap.alu(add, max, 1), None);
+        assert_eq!(trap.alu(div, min, -1), None);
+        assert_eq!(trap.alu(add, max - 1, 1), Some(max));
+
+        // x * 2 is not turned into a shift unless overflow wraps
+        let source = "int main() { int x; x = 2147483647; return x + 1 > x || x * 2 > x; }";
+        for backend in [Backend::Stack, Backend::Register] {
+            for (overflow, expected) in [(Overflow::Wrap, 0), (Overflow::Saturate, 0), (Overflow::Trap, -1)] {
+                let mut compiler = C4::builder().backend(backend).overflow(overflow).build();
+                assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), expected, "{:?} {:?}", backend, overflow);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shift_and_modulo_semantics() {
+        let narrow = VmOptions::default();
+        let wide = VmOptions { word_size: 8, ..VmOptions::default() };
+        let shl = Instruction::SHL as i32;
+        let shr = Instruction::SHR as i32;
+        let div = Instruction::DIV as i32;
+        let modulo = Instruction::MOD as i32;
+
+        // Shift counts are masked to the word size
+        assert_eq!(narrow.alu(shl, 1, 32), Some(1));
+        assert_eq!(narrow.alu(shl, 1, 33), Some(2));
+        assert_eq!(narrow.alu(shl, 1, -1), Some(i32::MIN as Word));
+        assert_eq!(narrow.alu(shl, 1, 31), Some(i32::MIN as Word));
+        assert_eq!(wide.alu(shl, 1, 32), Some(1 << 32));
+        assert_eq!(wide.alu(shl, 1, 64), Some(1));
+        assert_eq!(narrow.alu(shr, -16, 2), Some(-4));
+        assert_eq!(narrow.alu(shr, -1, 100), Some(-1));
+
+        // Division truncates toward zero; the remainder has the dividend's sign
+        assert_eq!(narrow.alu(div, -7, 2), Some(-3));
+        assert_eq!(narrow.alu(modulo, -7, 2), Some(-1));
+        assert_eq!(narrow.alu(modulo, 7, -2), Some(1));
+        assert_eq!(narrow.alu(modulo, -7, -2), Some(-1));
+        assert_eq!(narrow.alu(modulo, i32::MIN as Word, -1), Some(0));
+        assert_eq!(wide.alu(modulo, i64::MIN, -1), Some(0));
+
+        let source = r#"
+            int main() {
+                int n;
+                n = 0 - 1;
+                printf("%d %d %d %d %d\n", 1 << 33, 1 << n, (0 - 16) >> 2, (0 - 7) % 2, (0 - 7) / 2);
+                return 0;
+            }
+        "#;
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 0);
+            assert_eq!(compiler.get_captured_output(), "2 -2147483648 -4 -1 -3\n");
+        }
+    }
+
+    #[test]
+    fn test_assert_reports_line_and_condition() {
+        let source = r#"int square(int x) { return x * x; }
+int main() {
+    assert(square(3) == 9);
+    printf("ok\n");
+    assert( square(2) ==
+            5 );
+    printf("unreachable\n");
+    return 0;
+}"#;
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), -1);
+            assert_eq!(compiler.get_captured_output(), "ok\n");
+            assert_eq!(compiler.get_captured_error(), "Line 5: assertion failed: square(2) ==\n            5\n");
+        }
+
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run("int main() { assert(1, 2); return 0; }", 0, Vec::new()), -1);
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: assert takes exactly one argument"));
+    }
+
+    #[test]
+    fn test_math_builtins() {
+        let source = r#"
+            int main() {
+                printf("%d %d %d\n", abs(0 - 5), abs(7), abs(0 - 2147483647 - 1));
+                printf("%f %g %g\n", sqrt(2.0), pow(2.0, 10.0), pow(9.0, 0.5));
+                printf("%.6f %.6f %g\n", sin(1.0), cos(1.0), sqrt(0.0));
+                return abs(0 - 42);
+            }
+        "#;
+        let expected = format!(
+            "5 7 -2147483648\n{:.6} 1024 3\n{:.6} {:.6} 0\n",
+            2f64.sqrt(), 1f64.sin(), 1f64.cos()
+        );
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 42);
+            assert_eq!(compiler.get_captured_output(), expected);
+        }
+    }
+
+    #[test]
+    fn test_qsort_calls_back_into_vm_code() {
+        let source = r#"
+            int ascending(int *a, int *b) { return *a - *b; }
+            int descending(char *a, char *b) { return *b - *a; }
+
+            int main() {
+                int numbers[7];
+                char letters[6];
+                int i;
+                numbers[0] = 5; numbers[1] = 0 - 3; numbers[2] = 9; numbers[3] = 1;
+                numbers[4] = 5; numbers[5] = 0; numbers[6] = 2;
+                letters[0] = 'c'; letters[1] = 'a'; letters[2] = 'e';
+                letters[3] = 'b'; letters[4] = 'd'; letters[5] = 0;
+
+                qsort(numbers, 7, sizeof(int), ascending);
+                qsort(letters, 5, 1, descending);
+                i = 0;
+                while (i < 7) {
+                    printf("%d ", numbers[i]);
+                    i++;
+                }
+                printf("%s\n", letters);
+                return ascending(numbers + 6, numbers);
+            }
+        "#;
+
+        for backend in [Backend::Stack, Backend::Register] {
+            for word_size in [4, 8] {
+                let mut compiler = C4::builder().backend(backend).word_size(word_size).build();
+                assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 12, "{:?} {}", backend, word_size);
+                assert_eq!(compiler.get_captured_output(), "-3 0 1 2 5 5 9 edcba\n");
+            }
+        }
+    }
+
+    #[test]
+    fn test_undefined_identifier_suggestions() {
+        assert_eq!(diagnostics::edit_distance("count", "count"), 0);
+        assert_eq!(diagnostics::edit_distance("cout", "count"), 1);
+        assert_eq!(diagnostics::edit_distance("retrun", "return"), 1);
+        assert_eq!(diagnostics::edit_distance("", "abc"), 3);
+        assert_eq!(diagnostics::closest("totl", ["total", "tot", "x"]), Some("total"));
+        assert_eq!(diagnostics::closest("zebra", ["total", "x"]), None);
+
+        let cases = [
+            ("int main() { int count; count = 1; return cout; }",
+             "Line 1: Undefined variable: cout (did you mean 'count'?)"),
+            ("int main() { int x; x = 1; retrun x; }",
+             "Line 1: Undefined variable: retrun (did you mean 'return'?)"),
+            ("int main() { prinft(\"hi\"); return 0; }",
+             "Line 1: Undefined variable: prinft (did you mean 'printf'?)"),
+            ("int main() { return qwerty; }",
+             "Line 1: Undefined variable: qwerty"),
+        ];
+        for (source, message) in cases {
+            let mut compiler = C4::new();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), -1);
+            assert_eq!(compiler.error.as_deref(), Some(message));
+        }
+    }
+
+    #[test]
+    fn test_duplicate_definitions() {
+        let cases = [
+            ("int f() { return 1; }\nint g;\nint f() { return 2; }\nint main() { return f(); }",
+             "Line 3: Redefinition of 'f' (previously declared on line 1)"),
+            ("int x = 1;\nint y, x = 2;\nint main() { return 0; }",
+             "Line 2: Redefinition of 'x' (previously defined on line 1)"),
+            ("int x;\nint x() { return 0; }",
+             "Line 2: Redefinition of 'x' (previously declared on line 1)"),
+            ("int f(int a, int a) { return a; }",
+             "Line 1: Redefinition of 'a' (previously declared on line 1)"),
+            ("int f(int a) {\n  int a;\n  return a;\n}",
+             "Line 2: Redefinition of 'a' (previously declared on line 1)"),
+            ("int main() {\n  int i;\n  {\n    int j;\n    char *j;\n  }\n  return 0;\n}",
+             "Line 5: Redefinition of 'j' (previously declared on line 4)"),
+        ];
+        for (source, message) in cases {
+            let mut compiler = C4::new();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), -1, "{}", source);
+            assert_eq!(compiler.error.as_deref(), Some(message));
+        }
+
+        // Shadowing an outer scope, a global or a builtin is still allowed
+        let source = r#"
+            int x;
+            int puts(char *s) { return 7; }
+            int f(int x) { return x; }
+            int main() {
+                int y;
+                y = 1;
+                { int y; y = 2; }
+                { int y; y = 3; }
+                return f(y) + puts("unused");
+            }
+        "#;
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 8);
+    }
+
+    #[test]
+    fn test_keyword_misuse() {
+        let cases = [
+            ("int main() { int if = 3; return if; }",
+             "Line 1: 'if' is a keyword and cannot be used as a local name"),
+            ("int while;",
+             "Line 1: 'while' is a keyword and cannot be used as a global name"),
+            ("int *return() { return 0; }",
+             "Line 1: 'return' is a keyword and cannot be used as a global name"),
+            ("int f(int x, char else) { return x; }",
+             "Line 1: 'else' is a keyword and cannot be used as a parameter name"),
+            ("int main() { int x; x = 1 + void; return x; }",
+             "Line 1: Unexpected keyword 'void' in expression"),
+            ("int main() { return ); }",
+             "Line 1: Unexpected ')' in expression"),
+        ];
+        for (source, message) in cases {
+            let mut compiler = C4::new();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), -1, "{}", source);
+            assert_eq!(compiler.error.as_deref(), Some(message));
+        }
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let parens = |n: usize| format!("int main() {{ return {}1{}; }}", "(".repeat(n), ")".repeat(n));
+        let blocks = |n: usize| format!("int main() {{ {}return 1;{} }}", "{".repeat(n), "}".repeat(n));
+        let unary = |n: usize| format!("int main() {{ return {}1; }}", "- ".repeat(2 * n));
+
+        // Reasonable nesting compiles
+        for source in [parens(500), blocks(500), unary(250)] {
+            let mut compiler = C4::new();
+            assert_eq!(compiler.compile_and_run(&source, 0, Vec::new()), 1);
+        }
+
+        // Pathological nesting is an error, not a host stack overflow
+        for source in [parens(100_000), blocks(100_000), unary(100_000)] {
+            let mut compiler = C4::new();
+            assert_eq!(compiler.compile_and_run(&source, 0, Vec::new()), -1);
+            assert_eq!(compiler.error.as_deref(), Some("Line 1: Nesting too deep (more than 1000 levels)"));
+        }
+
+        let mut compiler = C4::builder().nesting_limit(10).build();
+        assert_eq!(compiler.compile_and_run(&parens(20), 0, Vec::new()), -1);
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Nesting too deep (more than 10 levels)"));
+        assert_eq!(compiler.compile_and_run(&parens(5), 0, Vec::new()), 1);
+    }
+
+    #[test]
+    fn test_deep_expressions_do_not_recurse() {
+        // Far deeper than the test thread's stack would allow if each level recursed
+        let depth = 50_000;
+        let sources = [
+            format!("int main() {{ return {}1{}; }}", "(".repeat(depth), ")".repeat(depth)),
+            format!("int main() {{ return {}7; }}", "- ".repeat(2 * depth)),
+            format!("int main() {{ int a; return {}a = 1; }}", "a = ".repeat(depth)),
+            format!("int main() {{ return {}3{}; }}", "1 ? ".repeat(depth), " : 0".repeat(depth)),
+            format!("int main() {{ int x; x = 1; return {}x{}; }}", "(!(!".repeat(depth / 2), ")".repeat(depth)),
+        ];
+        for (source, expected) in sources.iter().zip([1, 7, 1, 3, 1]) {
+            let mut compiler = C4::builder().nesting_limit(4 * depth).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), expected);
+        }
+
+        // Mixed operators still nest correctly
+        let mut compiler = C4::new();
+        let source = "int f(int a, int b) { return a - b; }
+                      int main() { int a[3]; a[f(2, 1)] = 5; return -(f(10, (a[1] + 1) * 2) << 1) + sizeof(char); }";
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 5);
+    }
+
+    #[test]
+    fn test_identifiers_are_interned() {
+        let mut compiler = C4::new();
+        compiler.src = b"alpha beta alpha int beta".to_vec();
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            compiler.next();
+            ids.push(compiler.current_name);
+        }
+        assert_eq!(ids[0], ids[2]);
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(compiler.names.get(b"alpha"), Some(ids[0]));
+
+        // Keywords are not interned
+        compiler.next();
+        assert_eq!(compiler.token, TokenType::Int as i32);
+        assert_eq!(compiler.names.get(b"int"), None);
+        compiler.next();
+        assert_eq!(compiler.current_name, ids[1]);
+
+        // Declarations record the ID their uses are looked up by
+        let source = "int count; int main() { int total; count = 2; total = count + 1; return total; }";
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 3);
+        for name in ["count", "main", "printf"] {
+            let symbol = compiler.symbols.iter().find(|s| s.name == name).unwrap();
+            assert_eq!(compiler.names.get(name.as_bytes()), Some(symbol.id));
+        }
+    }
+
+    #[test]
+    fn test_streamed_source() {
+        // Hands out a few bytes per read, so tokens straddle every read boundary
+        struct Trickle(Vec<u8>, usize);
+        impl std::io::Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(self.0.len() - self.1).min(1 + self.1 % 7);
+                buf[..n].copy_from_slice(&self.0[self.1..self.1 + n]);
+                self.1 += n;
+                Ok(n)
+            }
+        }
+
+        // Several times the size of a chunk, so consumed chunks get dropped
+        let mut source = String::new();
+        for i in 0..4000 {
+            source.push_str(&format!("/* function {} */\nint f{}(int x) {{ return x + {}; }}\n", i, i, i % 10));
+        }
+        source.push_str("int main() {\n  int total;\n  total = f1(1) + f3999(0x10);\n");
+        source.push_str("  printf(\"%d %s %g\\n\", total, \"streamed\", 1.5e0f);\n");
+        source.push_str("  assert(total == 28 &&\n         f2(0) == 3);\n  return total;\n}\n");
+
+        let mut expected = C4::new();
+        let code = expected.compile_and_run(&source, 0, Vec::new());
+        assert_eq!(code, -1);
+        assert_eq!(expected.get_captured_output(), "27 streamed 1.5\n");
+        let error = expected.get_captured_error();
+        assert!(error.contains("assertion failed: total == 28 &&\n         f2(0) == 3"), "{}", error);
+
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run_reader(Trickle(source.clone().into_bytes(), 0), 0, Vec::new()), code);
+        assert_eq!(compiler.get_captured_output(), expected.get_captured_output());
+        assert_eq!(compiler.get_captured_error(), error);
+        assert!(compiler.src.len() < source.len() / 2);
+
+        // Errors still report lines counted from the start of the stream
+        let broken = format!("{}int main() {{ return missing; }}\n", source.split("int main").next().unwrap());
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run_reader(Trickle(broken.into_bytes(), 0), 0, Vec::new()), -1);
+        assert_eq!(compiler.error.as_deref(), Some("Line 8001: Undefined variable: missing"));
+    }
+
+    #[test]
+    fn test_utf8_source() {
+        // String literals keep their bytes, and comments may hold anything
+        let source = "int main() {
+            char *s; int n;
+            /* Grüße 🌍 — ünïcödé */
+            s = \"héllo 🌍\"; // ✓
+            n = 0;
+            while (s[n]) n++;
+            printf(\"%s|%d|%8s|\\n\", s, n, \"ñ\");
+            return n;
+        }";
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 11);
+            // Widths count bytes, as in C
+            assert_eq!(compiler.get_captured_output(), "héllo 🌍|11|      ñ|\n");
+        }
+
+        // Columns count characters
+        let mut compiler = C4::new();
+        compiler.src = "\"日本\" /* ü */ x\n  \"🌍\"  y".as_bytes().to_vec();
+        let mut columns = Vec::new();
+        for _ in 0..4 {
+            compiler.next();
+            columns.push((compiler.line, compiler.column));
+        }
+        assert_eq!(columns, [(1, 1), (1, 14), (2, 3), (2, 8)]);
+
+        // Non-ASCII characters outside literals and comments are errors
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run("int main() {\n  int café; return 0; }", 0, Vec::new()), -1);
+        assert_eq!(compiler.error.as_deref(), Some("Line 2: Unexpected character 'é'"));
+        assert_eq!(compiler.compile_and_run("int main() { return 'é'; }", 0, Vec::new()), -1);
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Character literal 'é' does not fit in a char"));
+    }
+
+    #[test]
+    fn test_lossless_tokens() {
+        use c4_rust::lossless::{render, TriviaKind};
+
+        let source = "#include <stdio.h>\n\n/* Entry\n   point */\nint main() {\t// déjà vu\n  return a+=0x1F; }  \n// end";
+        let mut compiler = C4::new();
+        let tokens = compiler.tokenize_lossless(source.as_bytes());
+        assert_eq!(render(&tokens), source.as_bytes());
+        assert!(compiler.error.is_none());
+
+        let texts: Vec<_> = tokens.iter().map(|t| String::from_utf8_lossy(&t.text).into_owned()).collect();
+        assert_eq!(texts, ["int", "main", "(", ")", "{", "return", "a", "+=", "0x1F", ";", "}", ""]);
+
+        let kinds = |i: usize| tokens[i].leading.iter().map(|t| t.kind).collect::<Vec<_>>();
+        use TriviaKind::*;
+        assert_eq!(kinds(0), [Directive, Whitespace, BlockComment, Whitespace]);
+        assert_eq!(kinds(5), [Whitespace, LineComment, Whitespace]);
+        assert_eq!(kinds(11), [Whitespace, LineComment]);
+        assert_eq!(tokens[5].leading[1].text, "// déjà vu".as_bytes());
+        assert_eq!((tokens[5].line, tokens[5].column), (6, 3));
+        assert_eq!(tokens[0].token, TokenType::Int as i32);
+        assert_eq!(tokens[11].token, 0);
+
+        // The tokens are those the compiler sees
+        compiler.src = source.as_bytes().to_vec();
+        compiler.pos = 0;
+        for token in &tokens {
+            compiler.next();
+            assert_eq!(compiler.token, token.token);
+        }
+
+        // A lexer error keeps the rest of the source in the last token
+        let source = b"x = \"unterminated; /* \xff */";
+        let tokens = compiler.tokenize_lossless(source);
+        assert_eq!(render(&tokens), source);
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Unterminated string literal"));
+    }
+
+    #[test]
+    fn test_lexer_crate() {
+        use c4_rust::lexer::{LexError, Lexer, Value};
+
+        // Without a symbol table every name is an identifier, and otherwise
+        // the tokens are those the compiler lexes, in the same places
+        let source = "#include <stdio.h>\n/* note */ int main() {\n  char *s; s = \"a\\tb\";\n  s[0] <<= 'x' + L'é' + 1.5f;\n}";
+        let tokens: Vec<_> = Lexer::new(source.as_bytes()).collect::<Result<_, _>>().unwrap();
+        let lossless = C4::new().tokenize_lossless(source.as_bytes());
+        assert_eq!(tokens.len() + 1, lossless.len());
+        for (token, compiled) in tokens.iter().zip(&lossless) {
+            assert_eq!((token.line, token.column), (compiled.line, compiled.column));
+            assert_eq!(&source.as_bytes()[token.start..token.end], &compiled.text[..]);
+            assert_eq!(token.token, compiled.token);
+        }
+        let values: Vec<_> = tokens.iter().filter(|t| t.value != Value::None).map(|t| t.value.clone()).collect();
+        assert_eq!(values, [
+            Value::Str(b"a\tb".to_vec()), Value::Int(0), Value::Operator(TokenType::Shl),
+            Value::Int('x' as i32), Value::Int('é' as i32), Value::Float(1.5),
+        ]);
+
+        // The first error ends the tokens, with the compiler's message
+        let mut lexer = Lexer::new("x = 'ab';".as_bytes());
+        assert_eq!(lexer.nth(2).unwrap().unwrap_err().to_string(), "Multi-character literal 'ab' has 2 characters; use a string for more than one");
+        assert!(lexer.next().is_none());
+        assert_eq!(Lexer::new("@ é".as_bytes()).nth(1), Some(Err(LexError::UnexpectedChar('é'))));
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = std::env::temp_dir().join(format!("c4_includes_{}", std::process::id()));
+        let lib = dir.join("lib");
+        std::fs::create_dir_all(&lib).unwrap();
+        let write = |path: &std::path::Path, text: &str| std::fs::write(path, text).unwrap();
+
+        write(&lib.join("guarded.h"), "#ifndef GUARDED_H\n#define GUARDED_H\nint twice(int x) { return x * 2; }\n#endif\n");
+        write(&lib.join("once.h"), "#pragma once\n#include \"guarded.h\"\nint once_value() { return LIMIT + 1; }\n");
+        write(&dir.join("config.h"), "#define LIMIT 40\n#ifdef DEBUG\nint debug() { return 1; }\n#else\nint debug() { return 0; }\n#endif\n");
+        write(&dir.join("main.c"), "#include \"config.h\"\n#include <once.h>\n#include <once.h>\n#include <guarded.h>\n\
+            int main() { return twice(once_value()) + debug(); }\n");
+
+        let mut compiler = C4::builder().include_dir(lib.clone()).source_path(dir.join("main.c")).build();
+        let source = std::fs::read_to_string(dir.join("main.c")).unwrap();
+        assert_eq!(compiler.compile_and_run(&source, 0, Vec::new()), 82);
+
+        // Macros defined up front take part in conditionals
+        let mut compiler = C4::builder().include_dir(lib.clone()).source_path(dir.join("main.c")).define("DEBUG", "1").build();
+        assert_eq!(compiler.compile_and_run(&source, 0, Vec::new()), 83);
+
+        // Errors inside an included file name it and count its own lines
+        write(&dir.join("broken.h"), "int ok() { return 0; }\n\nint bad() { return missing; }\n");
+        let mut compiler = C4::builder().source_path(dir.join("main.c")).build();
+        compiler.compile_and_run("#include \"broken.h\"\nint main() { return 0; }\n", 0, Vec::new());
+        assert_eq!(compiler.error.as_deref(), Some("Line 3 of broken.h: Undefined variable: missing"));
+
+        // Files without a guard may not include each other
+        write(&dir.join("a.h"), "#include \"b.h\"\n");
+        write(&dir.join("b.h"), "#include \"a.h\"\n");
+        compiler.compile_and_run("#include \"a.h\"\nint main() { return 0; }\n", 0, Vec::new());
+        assert_eq!(compiler.error.as_deref(), Some("Line 1 of b.h: Include cycle: a.h -> b.h -> a.h"));
+
+        compiler.compile_and_run("#include \"nowhere.h\"\nint main() { return 0; }\n", 0, Vec::new());
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Cannot find include file 'nowhere.h'"));
+
+        compiler.compile_and_run("#ifdef X\nint main() { return 0; }\n", 0, Vec::new());
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Unterminated conditional directive"));
+
+        write(&dir.join("open.h"), "int f() { return 0; }\n#if 1\n");
+        compiler.compile_and_run("#include \"open.h\"\nint main() { return f(); }\n#endif\n", 0, Vec::new());
+        assert_eq!(compiler.error.as_deref(), Some("Line 2 of open.h: Unterminated conditional directive"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bundled_headers() {
+        let source = r#"
+            #include <stdio.h>
+            #include <stdlib.h>
+            #include <string.h>
+            #include "string.h"
+
+            int main() {
+                char *buf;
+                buf = malloc(32);
+                strcpy(buf, "hello");
+                strcat(buf, ", world");
+                printf("%s %d %d %d %d\n", buf, strlen(buf), strcmp("abc", "abd") < 0,
+                       strncmp("abcx", "abcy", 3), strchr(buf, ',') - buf);
+                memcpy(buf, "HE", 2);
+                printf("%s %d %d %d\n", buf, memcmp(buf, "HEl", 3), atoi("  -123x"), strchr(buf, 'z') == NULL);
+                free(buf);
+                return EXIT_FAILURE + EOF;
+            }
+        "#;
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 0);
+        assert_eq!(compiler.get_captured_output(), "hello, world 12 1 0 5\nHEllo, world 0 -123 1\n");
+
+        // memset and exit are builtins, as in c4
+        let source = "#include <stdlib.h>\n\
+                      int check(char *p) { if (p[2] != 'x') exit(EXIT_FAILURE + 2); return 0; }\n\
+                      int main() { char *p; p = malloc(4); if ((char *)memset(p, 'x', 3) != p) return 1;\n\
+                      p[3] = 0; printf(\"%s\\n\", p); check(p); memset(p, 256, 4); check(p); return 0; }";
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 3, "{:?}", backend);
+            assert_eq!(compiler.get_captured_output(), "xxx\n");
+            assert_eq!(compiler.compile_and_run("int main() { memset(0, 0, 4); return 0; }", 0, Vec::new()), -1);
+            assert_eq!(compiler.get_captured_error(), "Buffer overflow in MSET\n");
+        }
+
+        // An include directory may replace a bundled header
+        let dir = std::env::temp_dir().join(format!("c4_bundled_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("stdio.h"), "#define EOF 7\n").unwrap();
+        let mut compiler = C4::builder().include_dir(dir.clone()).build();
+        assert_eq!(compiler.compile_and_run("#include <stdio.h>\nint main() { return EOF; }\n", 0, Vec::new()), 7);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Clashing with a bundled definition names the header
+        let mut compiler = C4::new();
+        compiler.compile_and_run("int strlen(char *s) { return 0; }\n#include <string.h>\nint main() { return 0; }\n", 0, Vec::new());
+        assert_eq!(compiler.error.as_deref(), Some("Line 9 of string.h: Redefinition of 'strlen' (previously declared on line 1)"));
+    }
+
+    #[test]
+    fn test_program_symbols() {
+        let source = "int count;\nchar *name, **argv_copy;\n\
+                      int add(int a, int b) { return a + b; }\n\
+                      char *greeting() { return \"hi\"; }\n\
+                      int main() { int local; local = 1; return add(local, 2); }\n";
+        let mut compiler = C4::builder().word_size(8).build();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 3);
+        let program = compiler.to_program();
+        let symbols = program.symbols();
+
+        // Builtins and locals are left out
+        let summary: Vec<(&str, SymbolKind, String, i32)> = symbols.iter()
+            .map(|s| (s.name.as_str(), s.kind, s.type_name(), s.line))
+            .collect();
+        assert_eq!(summary, [
+            ("count", SymbolKind::Global, "int".to_string(), 1),
+            ("name", SymbolKind::Global, "char *".to_string(), 2),
+            ("argv_copy", SymbolKind::Global, "char **".to_string(), 2),
+            ("add", SymbolKind::Function, "int".to_string(), 3),
+            ("greeting", SymbolKind::Function, "char *".to_string(), 4),
+            ("main", SymbolKind::Function, "int".to_string(), 5),
+        ]);
+
+        // Globals take a word each; functions tile the text segment
+        assert_eq!(symbols[..3].iter().map(|s| (s.address, s.size)).collect::<Vec<_>>(), [(8, 8), (16, 8), (24, 8)]);
+        let functions = &symbols[3..];
+        assert_eq!(functions[0].address, 0);
+        for pair in functions.windows(2) {
+            assert_eq!(pair[0].address + pair[0].size as i32, pair[1].address);
+        }
+        assert_eq!(functions[2].address as usize + functions[2].size, program.text.len());
+        assert_eq!(program.symbol("main").map(|s| s.address), program.entry());
+        assert_eq!(program.symbol("printf"), None);
+    }
+
+    #[test]
+    fn test_compile_once_run_many() {
+        let source = "int runs;\n\
+                      int main(int argc) { runs = runs + 1; printf(\"%d %d\\n\", argc, runs); fprintf(stderr, \"e\"); return argc * 10; }\n";
+        let mut compiler = C4::new();
+        let program = compiler.compile(source).unwrap();
+
+        // Every run starts from the globals as compiled
+        for args in [vec![], vec!["a".to_string(), "b".to_string()]] {
+            let outcome = program.run(args.clone()).unwrap();
+            assert_eq!(outcome, RunOutcome {
+                exit_code: args.len() as i32 * 10,
+                output: format!("{} 1\n", args.len()),
+                error_output: "e".to_string(),
+            });
+        }
+
+        // A compiler's own VM settings and I/O are used
+        let mut runner = C4::builder().input(&b"xyz"[..]).build();
+        let echo = compiler.compile("int main() { putchar(getchar()); putchar(getchar()); return 0; }").unwrap();
+        assert_eq!(runner.run_program(&echo, Vec::new()).unwrap().output, "xy");
+
+        // Words are the size the program was compiled for
+        let mut wide = C4::builder().word_size(8).build();
+        let size = wide.compile("int main() { return sizeof(int) * 100 + (1 << 40 > 0); }").unwrap();
+        assert_eq!(size.run(Vec::new()).unwrap().exit_code, 801);
+
+        assert_eq!(compiler.compile("int main() { return x; }").unwrap_err(),
+                   Error::Compile("Line 1: Undefined variable: x".to_string()));
+        let library = compiler.compile("int f() { return 1; }").unwrap();
+        assert_eq!(library.run(Vec::new()), Err(Error::NoMain));
+        assert_eq!(Error::NoMain.to_string(), "main function not found");
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut compiler = C4::new();
+        assert_eq!(compiler.eval("3*(4+5)"), Ok(27));
+        assert_eq!(compiler.eval(" -7 / 2 + (1 << 4) % 5 "), Ok(-2));
+        assert_eq!(compiler.eval("abs(-3) == 3 ? sizeof(int) : 0"), Ok(4));
+        assert_eq!(compiler.eval("'A' + 1"), Ok(66));
+
+        // Bound variables behave as ints, and may shadow builtins
+        assert_eq!(compiler.eval_with("x * x + y", &[("x", 12), ("y", -4)]), Ok(140));
+        assert_eq!(compiler.eval_with("(abs = abs + 1) * 2", &[("abs", 20)]), Ok(42));
+
+        assert_eq!(compiler.eval("1 +"), Err(Error::Compile("Line 1: Unexpected end of input in expression".to_string())));
+        assert_eq!(compiler.eval("1 2"), Err(Error::Compile("Line 1: Expected end of expression, got number".to_string())));
+        assert_eq!(compiler.eval("z + 1"), Err(Error::Compile("Line 1: Undefined variable: z".to_string())));
+        assert_eq!(compiler.eval_with("x", &[("x", 1), ("x", 2)]),
+                   Err(Error::Compile("Variable 'x' is bound twice".to_string())));
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut compiler = C4::builder()
+            .opt_level(0)
+            .inline_threshold(0)
+            .nesting_limit(10)
+            .word_size(8)
+            .stack_words(64)
+            .overflow(Overflow::Trap)
+            .define("SCALE", "3")
+            .input(&b"q"[..])
+            .build();
+        assert_eq!(compiler.opt_level(), 0);
+        assert!(!compiler.inline_functions());
+        assert_eq!(*compiler.vm_options(), VmOptions { word_size: 8, stack_words: 64, overflow: Overflow::Trap, ..VmOptions::default() });
+
+        let source = "int main() { return getchar() + SCALE * sizeof(int); }";
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 'q' as i32 + 24);
+        compiler.compile_and_run("int main() { return ((((((((((1)))))))))); }", 0, Vec::new());
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Nesting too deep (more than 10 levels)"));
+
+        // The sandbox bounds how long a program runs and whether it reads input
+        let spin = "int main() { int i; i = 0; while (i < 1000) i = i + 1; return getchar(); }";
+        let sandbox = Sandbox { max_cycles: 100, allow_input: true };
+        let mut compiler = C4::builder().sandbox(sandbox).input(&b"x"[..]).build();
+        assert_eq!(compiler.compile_and_run(spin, 0, Vec::new()), -2);
+        let sandbox = Sandbox { max_cycles: 100000, allow_input: false };
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).sandbox(sandbox).input(&b"x"[..]).build();
+            assert_eq!(compiler.compile_and_run(spin, 0, Vec::new()), -1);
+        }
+    }
+
+    #[test]
+    fn test_c4_language_level() {
+        // Written the way c4.c itself is, so both levels accept it
+        let source = "#include <stdio.h>\n\
+                      int count;\n\
+                      int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+                      int main() {\n\
+                        int i; char *p;\n\
+                        p = \"fib\"; i = 0;\n\
+                        while (i < 10) { count = count + fib(i); i++; }\n\
+                        printf(\"%d\\n\", count); // done\n\
+                        return count;\n\
+                      }\n";
+        for language in [LanguageLevel::C4, LanguageLevel::Extended] {
+            let mut compiler = C4::builder().language(language).build();
+            assert_eq!(compiler.compile(source).map(|program| program.run(Vec::new()).unwrap().output), Ok("88\n".to_string()));
+        }
+
+        let rejected = [
+            ("int main() { return 1.5; }", "Line 1: Language feature not enabled: floating-point literals"),
+            ("int main() { int i; i = 1; i += 2; return i; }", "Line 1: Language feature not enabled: compound assignment"),
+            ("/* note */ int main() { return 0; }", "Line 1: Language feature not enabled: block comments"),
+            ("int main() { int i; i = 0; int j; return 0; }", "Line 1: Language feature not enabled: declarations after the start of a function"),
+            ("int main() { if (1) { int j; } return 0; }", "Line 1: Language feature not enabled: declarations after the start of a function"),
+            ("int main() { int a[4]; return 0; }", "Line 1: Language feature not enabled: arrays"),
+            ("int f(int a[]) { return 0; }", "Line 1: Language feature not enabled: arrays"),
+            ("int main() { int i = 1; return i; }", "Line 1: Language feature not enabled: initializers"),
+            ("int g = 1;", "Line 1: Language feature not enabled: initializers"),
+            ("int main() { return abs(1); }", "Line 1: Undefined variable: abs"),
+            ("int main() { fprintf(stderr, \"x\"); return 0; }", "Line 1: Undefined variable: fprintf (did you mean 'printf'?)"),
+            ("const int g; int main() { return g; }", "Line 1: Language feature not enabled: const"),
+            ("int f(int); int f(int x) { return x; }", "Line 1: Language feature not enabled: function prototypes"),
+            ("int main() { return L'a'; }", "Line 1: Language feature not enabled: wide character literals"),
+            ("enum E { A }; int main() { enum E e; e = A; return e; }", "Line 1: Language feature not enabled: enum types"),
+            ("enum E { A }; int f(enum E e) { return e; }", "Line 1: Language feature not enabled: enum types"),
+            ("enum E { A }; int main() { return sizeof(enum E); }", "Line 1: Language feature not enabled: enum types"),
+        ];
+        for (source, message) in rejected {
+            let mut strict = C4::builder().language(LanguageLevel::C4).build();
+            assert_eq!(strict.compile(source).err(), Some(Error::Compile(message.to_string())), "{}", source);
+            assert!(C4::new().compile(source).is_ok(), "{}", source);
+        }
+
+        // c4 does not check the types of operands, and has enums at the top level
+        let source = "enum E { A, B }; enum E g;\n\
+                      int main() { int *p; int n; p = 0; n = 8; if (p < n && (n ? p : 1) == 0) return B; return A; }";
+        let mut strict = C4::builder().language(LanguageLevel::C4).build();
+        assert_eq!(strict.compile(source).map(|program| program.run(Vec::new()).unwrap().exit_code), Ok(1));
+        assert_eq!(C4::new().compile(source).err(), Some(Error::Compile("Line 2: Incompatible operands of '<': 'int *' and 'int'".to_string())));
+        let features = Features { comparison_checks: false, ..Features::default() };
+        assert_eq!(C4::builder().features(features).build().compile(source).err(),
+                   Some(Error::Compile("Line 2: Incompatible operands of '?:': 'int *' and 'int'".to_string())));
+
+        // Directives are skipped, as c4 does
+        let mut strict = C4::builder().language(LanguageLevel::C4).build();
+        assert!(strict.compile("#define X 1\nint main() { return X; }").is_err());
+        assert!(strict.compile("#include \"missing.h\"\nint main() { return 0; }").is_ok());
+    }
+
+    #[test]
+    fn test_features_are_independent() {
+        // A program using each feature, and how to turn that feature off
+        type TurnOff = fn(&mut Features);
+        let samples: [(&str, TurnOff); 12] = [
+            ("int main() { return 1.5 > 1; }", |f| f.floats = false),
+            ("int main() { int i; i = 1; i += 2; return i; }", |f| f.compound_assignment = false),
+            ("/* note */ int main() { return 0; }", |f| f.block_comments = false),
+            ("int main() { int a[4]; return 0; }", |f| f.arrays = false),
+            ("int main() { int i = 1; return i; }", |f| f.initializers = false),
+            ("int main() { if (1) { int j; } return 0; }", |f| f.mixed_declarations = false),
+            ("#define ZERO 0\nint main() { return ZERO; }", |f| f.preprocessor = false),
+            ("int main() { return abs(0); }", |f| f.builtins = false),
+            ("enum E { A }; int main() { enum E e; e = A; return e; }", |f| f.enum_types = false),
+            ("int main() { return L'a' > 0; }", |f| f.wide_chars = false),
+            ("int f(int); int main() { return 0; } int f(int x) { return x; }", |f| f.prototypes = false),
+            ("const int g; int main() { return g; }", |f| f.const_qualifier = false),
+        ];
+        for (i, (_, turn_off)) in samples.iter().enumerate() {
+            let mut features = Features::default();
+            turn_off(&mut features);
+            let mut compiler = C4::builder().features(features).build();
+            for (j, (source, _)) in samples.iter().enumerate() {
+                assert_eq!(compiler.compile(source).is_ok(), i != j, "{:?} on {}", features, source);
+            }
+        }
+        assert_eq!(LanguageLevel::C4.features(), Features::C4);
+        assert_eq!(LanguageLevel::default().features(), Features::default());
+    }
+
+    #[test]
+    fn test_fuzz_regressions() {
+        // Inputs that once made a fuzz target panic
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions");
+        let mut inputs = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+            fuzz::fuzz_lex(&data);
+            fuzz::fuzz_parse(&data);
+            fuzz::fuzz_compile(&data);
+            inputs += 1;
+        }
+        assert!(inputs > 0);
+
+        // Inputs far deeper than the nesting limit are rejected, not overflowed
+        let deep = [
+            format!("int main() {{ return {}1; }}", "(".repeat(100000)),
+            format!("int main() {{ return {}1; }}", "-".repeat(100000)),
+            format!("int main() {{ {} }}", "{".repeat(100000)),
+            format!("int main() {{ {} return 0; }}", "while (1) ".repeat(100000)),
+        ];
+        for source in deep {
+            assert!(!fuzz::fuzz_parse(source.as_bytes()));
+        }
+        assert_eq!(fuzz::fuzz_lex(b"int x = 0x7fffffff;"), 5);
+        assert!(fuzz::fuzz_compile(b"int main() { return 0; }"));
+        assert!(fuzz::fuzz_generated(b""));
+        assert!(fuzz::fuzz_generated(b"\xff\x01"));
+    }
+
+    #[test]
+    fn test_vm_stats() {
+        let source = "int main() { int x; x = 5; return x; }";
+        let mut compiler = C4::builder().opt_level(0).stats(true).build();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 5);
+        let stats = &compiler.vm_stats;
+        let counts: Vec<(&str, u64)> = stats.op_counts.iter().map(|(&name, &count)| (name, count)).collect();
+        assert_eq!(counts, [("ENT", 1), ("IMM", 1), ("LEA", 2), ("LEV", 1), ("LI", 1), ("PUSH", 1), ("SI", 1)]);
+        assert_eq!((stats.cycles, stats.memory_reads, stats.memory_writes), (8, 1, 1));
+        // argc, the return address and a spare slot, then bp, x and the pushed address
+        assert_eq!(stats.peak_stack_words, 6);
+        assert!(stats.to_string().starts_with("cycles: 8\nmemory: 1 reads, 1 writes\npeak stack: 6 words\n  LEA      2\n"));
+
+        // The register VM runs fewer, fused instructions
+        let source = "int f(int n) { if (n < 2) return n; return f(n - 1) + f(n - 2); } int main() { return f(10); }";
+        let mut stack = C4::builder().stats(true).build();
+        let mut register = C4::builder().stats(true).backend(Backend::Register).build();
+        assert_eq!(stack.compile_and_run(source, 0, Vec::new()), 55);
+        assert_eq!(register.compile_and_run(source, 0, Vec::new()), 55);
+        for compiler in [&stack, &register] {
+            let stats = &compiler.vm_stats;
+            assert_eq!(stats.cycles, compiler.cycle as u64);
+            assert_eq!(stats.op_counts.values().sum::<u64>(), stats.cycles);
+        }
+        assert!(register.vm_stats.cycles < stack.vm_stats.cycles);
+
+        // Nothing is counted unless asked for
+        let mut compiler = C4::new();
+        compiler.compile_and_run(source, 0, Vec::new());
+        assert_eq!(compiler.vm_stats, VmStats::default());
+    }
+
+    #[test]
+    fn test_heap_profile() {
+        let source = "
+            int *make(int n) { int *p; p = malloc(n * sizeof(int)); *p = n; return p; }
+            int main() {
+                int *a; int *b; int *c;
+                a = make(4);
+                b = make(2);
+                free(a);
+                c = malloc(8);
+                free(b);
+                free(0);
+                return c == a;
+            }";
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().opt_level(0).backend(backend).heap_profile(true).build();
+            // The freed block of a is reused for c
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 1);
+            let profile = &compiler.heap_profile;
+            assert_eq!((profile.allocations, profile.frees, profile.allocated_bytes), (3, 2, 32));
+            assert_eq!((profile.peak_bytes, profile.live_bytes), (24, 8));
+            let live: Vec<usize> = profile.timeline.iter().map(|&(_, live)| live).collect();
+            assert_eq!(live, [16, 24, 8, 16, 8]);
+            assert_eq!(profile.by_function["make"], FunctionAllocations { count: 2, bytes: 24 });
+            assert_eq!(profile.by_function["main"], FunctionAllocations { count: 1, bytes: 8 });
+
+            // c is never freed
+            let leaks: Vec<&Allocation> = profile.live.values().collect();
+            assert_eq!(leaks.len(), 1);
+            assert_eq!((leaks[0].size, leaks[0].backtrace.as_slice()), (8, ["main".to_string()].as_slice()));
+            let report = profile.to_string();
+            assert!(report.starts_with("heap: 3 allocations, 2 frees, 32 bytes allocated, peak 24 bytes live\n"));
+            assert!(report.contains("leaked: 8 bytes in 1 blocks\n"));
+        }
+
+        // Allocations made by a callee are traced back through its callers
+        let source = "int *f() { return malloc(4); } int *g() { return f(); } int main() { g(); return 0; }";
+        let mut compiler = C4::builder().opt_level(0).heap_profile(true).build();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 0);
+        let leak = compiler.heap_profile.live.values().next().unwrap();
+        assert_eq!(leak.backtrace, ["f", "g", "main"]);
+
+        // So are those of a function inlined into its caller, even from an image
+        let source = "int *f(int n) { return malloc(n); } int main() { f(4); f(8); return 0; }";
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().opt_level(1).backend(backend).heap_profile(true).build();
+            let program = Program::from_image(&compiler.compile(source).unwrap().to_image()).unwrap();
+            assert_eq!(compiler.inline_stats.calls_inlined, 2);
+            assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 0);
+            let profile = &compiler.heap_profile;
+            assert_eq!(profile.by_function["f"], FunctionAllocations { count: 2, bytes: 12 });
+            assert!(!profile.by_function.contains_key("main"));
+            assert!(profile.live.values().all(|leak| leak.backtrace == ["f", "main"]));
+        }
+
+        // The heap is limited, freeing a block twice is a fault, and the
+        // heap is given back when the run ends
+        let mut compiler = C4::builder().heap_bytes(64).build();
+        assert_eq!(compiler.compile_and_run("int main() { return malloc(100) == 0; }", 0, Vec::new()), 1);
+        let data_len = compiler.data.len();
+        assert_eq!(compiler.compile_and_run("int main() { int *p; p = malloc(4); free(p); free(p); return 0; }", 0, Vec::new()), -1);
+        assert_eq!(compiler.data.len(), data_len);
+        assert_eq!(compiler.heap_profile, HeapProfile::default());
+    }
+
+    #[test]
+    fn test_compile_stats() {
+        let source = "int x; int main() { int y; y = 1 + 2 * x; return y; }";
+        let mut compiler = C4::builder().opt_level(0).build();
+        let program = compiler.compile(source).unwrap();
+        let stats = compiler.compile_stats.clone();
+        assert_eq!((stats.source_bytes, stats.tokens), (source.len(), 23));
+        assert_eq!((stats.text_words, stats.data_bytes), (program.text.len(), program.data.len()));
+        assert!(stats.instructions < stats.text_words);
+        assert!(stats.ast_nodes > 0 && stats.symbols > 0);
+        assert!(stats.to_string().starts_with(&format!("source: {} bytes, 23 tokens, ", source.len())));
+
+        // Each count grows with the input
+        let functions: String = (0..10).map(|i| format!("int f{}(int a) {{ return a * {}; }}\n", i, i)).collect();
+        compiler.compile(&format!("{}int main() {{ return f1(2); }}", functions)).unwrap();
+        let large = compiler.compile_stats.clone();
+        assert!(large.source_bytes > stats.source_bytes && large.tokens > stats.tokens);
+        assert!(large.ast_nodes > stats.ast_nodes && large.symbols > stats.symbols);
+        assert!(large.instructions > stats.instructions);
+
+        // Included files and streamed sources are counted as read
+        compiler.compile("#include <stdio.h>\nint main() { return EOF; }").unwrap();
+        assert!(compiler.compile_stats.source_bytes > 40);
+        assert_eq!(compiler.compile_and_run_reader(source.as_bytes(), 0, Vec::new()), 1);
+        assert_eq!(compiler.compile_stats.source_bytes, source.len());
+    }
+
+    #[test]
+    fn test_program_images() {
+        let source = "int n; int sq(int x) { return x * x; } int main() { n = sq(7); printf(\"%d\\n\", n); return n - 40; }";
+        let program = C4::new().compile(source).unwrap();
+        let image = program.to_image();
+        assert!(image.starts_with(b"C4B\0\x07\0\0\0\x04\0\0\0\0\0\0\0"));
+        let loaded = Program::from_image(&image).unwrap();
+        assert_eq!((&loaded.text, &loaded.data), (&program.text, &program.data));
+        assert_eq!(loaded.symbols(), program.symbols());
+        let outcome = loaded.run(Vec::new()).unwrap();
+        assert_eq!((outcome.exit_code, outcome.output.as_str()), (9, "49\n"));
+
+        // The header is checked first
+        let invalid = |image: &[u8]| match Program::from_image(image) {
+            Err(Error::Image(message)) => message,
+            other => panic!("image was not refused: {:?}", other.map(|p| p.text)),
+        };
+        assert_eq!(invalid(b"\x7fELF"), "not a c4 program image (bad magic number)");
+        let mut newer = image.clone();
+        newer[4] = 8;
+        assert_eq!(invalid(&newer), "unsupported format version 8 (expected 7)");
+        assert_eq!(invalid(&image[..image.len() - 3]), "image ends in the middle of the relocations");
+        let mut longer = image.clone();
+        longer.push(0);
+        assert_eq!(invalid(&longer), "1 unexpected bytes after the relocations");
+        assert_eq!(Error::Image("x".to_string()).to_string(), "Invalid program image: x");
+
+        // So is the code, before anything runs
+        let mut compiler = C4::builder().opt_level(0).build();
+        let program = compiler.compile("int main() { int a; a = 2; return a + 3; }").unwrap();
+        let push = program.text.iter().position(|&op| op == Instruction::PUSH as i32).unwrap();
+        let mut broken = program.clone();
+        broken.text[push] = Instruction::LI as i32;
+        assert_eq!(invalid(&broken.to_image()), format!("Invalid bytecode at {}: pops more words than were pushed", push + 3));
+
+        let mut broken = program.clone();
+        broken.text.extend([Instruction::JMP as i32, 1000]);
+        let jmp = program.text.len();
+        assert_eq!(invalid(&broken.to_image()), format!("Invalid bytecode at {}: target 1000 is outside the text segment", jmp));
+        let mut broken = program.clone();
+        broken.text.push(99);
+        assert!(invalid(&broken.to_image()).ends_with("unknown opcode 99"));
+    }
+
+    #[test]
+    fn test_bytecode_verifier() {
+        let mut compiler = C4::builder().opt_level(0).build();
+        let program = compiler.compile("int f(int x) { if (x) return 1; return 2; } int main() { return f(3); }").unwrap();
+        assert_eq!(program.verify(), Ok(()));
+        let starts = c4_rust::optimizer::instruction_starts(&program.text);
+        let jsr = starts.into_iter().find(|&pc| program.text[pc] == Instruction::JSR as i32).unwrap();
+
+        // A call into the middle of an instruction
+        let mut broken = program.clone();
+        broken.text[jsr + 1] += 1;
+        let error = broken.verify().unwrap_err();
+        assert_eq!(error.address, jsr);
+        assert_eq!(error.message, format!("target {} is in the middle of an instruction", broken.text[jsr + 1]));
+
+        // An operand cut off by the end of the text segment
+        let mut broken = program.clone();
+        broken.text.push(Instruction::IMM as i32);
+        let error = broken.verify().unwrap_err();
+        assert_eq!(error.to_string(), format!("Invalid bytecode at {}: IMM is missing its operand", program.text.len()));
+
+        // Branches that leave the stack at different depths
+        use c4_rust::verify::verify;
+        let (ent, imm, bz, push, lev) = (Instruction::ENT as i32, Instruction::IMM as i32, Instruction::BZ as i32,
+                                         Instruction::PUSH as i32, Instruction::LEV as i32);
+        let main = [("main".to_string(), 0)];
+        assert_eq!(verify(&[ent, 0, imm, 1, bz, 8, imm, 2, lev], &main), Ok(()));
+        let error = verify(&[ent, 0, imm, 1, bz, 7, push, lev], &main).unwrap_err();
+        assert_eq!((error.address, error.message.as_str()), (7, "reached with 2 words on the stack on one path and 1 on another"));
+
+        // Functions must start on an instruction too
+        let error = verify(&[ent, 0, imm, 1, lev], &[("main".to_string(), 0), ("f".to_string(), 3)]).unwrap_err();
+        assert_eq!(error.message, "function 'f' starts in the middle of an instruction");
+    }
+
+    #[test]
+    fn test_compilation_is_deterministic() {
+        let source = "#include <string.h>\n#include \"shape.h\"\nint g; char *s;\n\
+            int f(int x) { return x * SIDES; }\n\
+            int main() { s = \"hi\"; g = f(2); printf(\"%d %d\\n\", strlen(s), sqrt(2.25) > 1.5); return g; }";
+
+        // The same header at two different paths
+        let base = std::env::temp_dir().join(format!("c4_deterministic_{}", std::process::id()));
+        let source_in = |dir: &str| {
+            let dir = base.join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("shape.h"), "#define SIDES 3\n").unwrap();
+            dir.join("main.c")
+        };
+        let compile = |compiler: &mut C4| compiler.compile(source).unwrap().to_image();
+
+        let mut compiler = C4::builder().source_path(source_in("a")).build();
+        let image = compile(&mut compiler);
+        assert_eq!(compile(&mut C4::builder().source_path(source_in("b")).build()), image);
+
+        // Whatever the compiler was used for in between
+        compiler.compile_and_run(source, 0, Vec::new());
+        assert_eq!(compile(&mut compiler), image);
+        compiler.compile("int x; int main() { return 0.5 > 3.25; }").unwrap();
+        compiler.eval("1 + 2").unwrap();
+        assert_eq!(compile(&mut compiler), image);
+
+        // VM settings that do not affect code generation change nothing
+        let mut register = C4::builder().backend(Backend::Register).stats(true).source_path(source_in("a")).build();
+        assert_eq!(compile(&mut register), image);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    /// Host that keeps what the program writes and what the VM reports
+    struct RecordingHost {
+        input: Vec<u8>,
+        output: Vec<(Word, u8)>,
+        reports: Vec<String>,
+    }
+
+    impl Host for RecordingHost {
+        fn write(&mut self, stream: Word, bytes: &[u8]) -> bool {
+            self.output.extend(bytes.iter().map(|&byte| (stream, byte)));
+            stream == STDOUT || stream == STDERR
+        }
+
+        fn read(&mut self) -> Option<u8> {
+            (!self.input.is_empty()).then(|| self.input.remove(0))
+        }
+
+        fn report(&mut self, message: std::fmt::Arguments) {
+            self.reports.push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn test_machine_with_host() {
+        let source = r#"
+            int main() {
+                int c; int *p;
+                p = malloc(4);
+                while ((c = getchar()) != -1) putchar(c - 32);
+                fprintf(stderr, "%d\n", sqrt(16.0) == 4.0);
+                free(p);
+                free(p);
+                return 0;
+            }"#;
+        let program = C4::builder().opt_level(0).build().compile(source).unwrap();
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut host = RecordingHost { input: b"ok".to_vec(), output: Vec::new(), reports: Vec::new() };
+            let options = VmOptions { backend, ..VmOptions::default() };
+            let mut machine = Machine::new(program.text.clone(), program.data.clone(), options, &mut host);
+            // The second free faults, and the heap is given back
+            assert_eq!(machine.run(program.entry().unwrap(), 0), -1);
+            assert_eq!(machine.data, program.data);
+            drop(machine);
+            assert_eq!(host.output, [(STDOUT, b'O'), (STDOUT, b'K'), (STDERR, b'1'), (STDERR, b'\n')]);
+            let heap = program.data.len().next_multiple_of(8);
+            assert_eq!(host.reports[0], format!("Invalid free of address {}", heap));
+        }
+    }
+
+    #[cfg(feature = "wasm")]
+    #[test]
+    fn test_wasm_bindings() {
+        use c4_rust::wasm;
+
+        assert!(wasm::compile("int main() { return 0; }").is_empty());
+        assert_eq!(wasm::compile("int main() { retrun 0; }"), ["Line 1: Undefined variable: retrun (did you mean 'return'?)"]);
+
+        let result = wasm::run("int main() { int c; c = getchar(); printf(\"%c!\\n\", c); return 3; }", "x");
+        assert_eq!((result.output.as_str(), result.exit_code), ("x!\n", Some(3)));
+        assert!(result.diagnostics.is_empty());
+
+        let result = wasm::run("int f() { return 1; }", "");
+        assert_eq!((result.diagnostics, result.exit_code), (vec!["main function not found".to_string()], None));
+        assert_eq!(result.error, Some(wasm::RunError::NoMain));
+
+        let result = wasm::run("int main() { retrun 0; }", "");
+        assert_eq!((result.exit_code, result.error), (None, Some(wasm::RunError::Compile)));
+
+        // Faults come back as the error and a diagnostic rather than being printed
+        let result = wasm::run("int main() { int z; z = 0; fprintf(stderr, \"oops\"); return 1 / z; }", "");
+        assert_eq!((result.error_output.as_str(), result.exit_code), ("oops", None));
+        assert_eq!((result.diagnostics, result.error), (vec!["Division by zero in DIV".to_string()], Some(wasm::RunError::Trap)));
+
+        // Warnings come before the rest of the diagnostics, whether or not the program ran
+        let source = "int main() { char c; c = 300; return c; }";
+        let warning = "Line 1: warning: Conversion from 'int' to 'char' may change the value";
+        assert_eq!(wasm::compile(source), [warning]);
+        let result = wasm::run(source, "");
+        assert_eq!((result.diagnostics, result.exit_code), (vec![warning.to_string()], Some(44)));
+    }
+
+    #[test]
+    fn test_run_with_timeout_cancels_the_vm() {
+        use crate::test_helpers::{run_with_timeout, TEST_TIMEOUT};
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let source = "int main() { int i; i = 0; while (1) i = i + 1; return i; }";
+        for backend in [Backend::Stack, Backend::Register] {
+            let sandbox = Sandbox { max_cycles: i32::MAX, ..Sandbox::default() };
+            let (sender, receiver) = mpsc::channel();
+            let result = run_with_timeout(Duration::from_millis(50), move |cancel| {
+                let mut compiler = C4::builder().backend(backend).sandbox(sandbox).cancel_token(cancel).build();
+                let exit_code = compiler.compile_and_run(source, 0, Vec::new());
+                sender.send((exit_code, compiler.cycle)).unwrap();
+            });
+            assert_eq!(result, Err("Test timed out after 50ms".to_string()));
+
+            // The VM stops soon after the deadline rather than running on
+            let (exit_code, cycles) = receiver.recv_timeout(TEST_TIMEOUT).unwrap();
+            assert_eq!(exit_code, -2);
+            assert!(cycles < i32::MAX);
+        }
+
+        let finished = run_with_timeout(TEST_TIMEOUT, |cancel| {
+            let mut compiler = C4::builder().cancel_token(cancel).build();
+            compiler.compile_and_run("int main() { return 42; }", 0, Vec::new())
+        });
+        assert_eq!(finished, Ok(42));
+    }
+
+    #[test]
+    fn test_cancel_token() {
+        let sums: String = (0..500).map(|i| format!("    x = x + {};\n", i)).collect();
+        let source = format!("int main() {{\n    int x;\n    x = 0;\n{}    return x;\n}}\n", sums);
+        let cancel = CancelToken::new();
+        let mut compiler = C4::builder().cancel_token(cancel.clone()).build();
+        let program = compiler.compile(&source).unwrap();
+
+        // A cancelled token stops the lexer and parser part way through
+        cancel.cancel();
+        assert_eq!(compiler.compile(&source).unwrap_err(), Error::Cancelled);
+        assert!(compiler.error.as_deref().unwrap().ends_with(": Compilation cancelled"));
+        assert!(compiler.compile_stats.tokens < 3000);
+
+        // and the VM during a run
+        let looping = C4::new().compile("int main() { int i; i = 0; while (i < 100000) i = i + 1; return 0; }").unwrap();
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut runner = C4::builder().backend(backend).cancel_token(cancel.clone()).build();
+            assert_eq!(runner.run_program(&looping, Vec::new()), Err(Error::Cancelled));
+            assert_eq!(runner.cycle, 1024);
+        }
+
+        // A fresh token lets the same program run again
+        let mut compiler = C4::builder().cancel_token(CancelToken::new()).build();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 124750);
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let functions: String = (0..50).map(|i| format!("int f{}(int x) {{ return x * {}; }}\n", i, i)).collect();
+        let source = format!("{}int main() {{ return f7(6); }}\n", functions);
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let seen = reports.clone();
+        let mut compiler = C4::builder().on_progress(move |progress| seen.borrow_mut().push(*progress)).build();
+        assert_eq!(compiler.compile(&source).unwrap().run(Vec::new()).unwrap().exit_code, 42);
+
+        // Once per function, once at the end, and in between every few thousand tokens
+        let reports = reports.borrow();
+        assert!(reports.len() > 51);
+        assert!(reports.windows(2).all(|pair| pair[0].source_bytes <= pair[1].source_bytes
+            && pair[0].functions <= pair[1].functions
+            && pair[0].text_words <= pair[1].text_words));
+        let last = reports.last().unwrap();
+        assert_eq!(last.functions, 51);
+        assert_eq!(last.source_bytes, source.len());
+        assert!(last.text_words > 0);
+    }
+
+    #[test]
+    fn test_hang_reports() {
+        // A loop that runs out of cycles is reported where it had got to
+        let program = C4::new().compile("int main() { while (1); return 0; }").unwrap();
+        for backend in [Backend::Stack, Backend::Register] {
+            let sandbox = Sandbox { max_cycles: 1000, ..Sandbox::default() };
+            let mut compiler = C4::builder().backend(backend).sandbox(sandbox).build();
+            let Err(Error::Hang(hang)) = compiler.run_program(&program, Vec::new()) else {
+                panic!("expected a hang");
+            };
+            assert_eq!(hang.kind, HangKind::CycleLimit);
+            assert_eq!(hang.cycles, 1000);
+            assert_eq!(hang.function.as_deref(), Some("main"));
+            assert!(hang.listing.contains(&format!(">{:7}: ", hang.pc)));
+            assert!(hang.to_string().starts_with(&format!("Maximum cycle count reached at pc {} in main after 1000 cycles\n", hang.pc)));
+            assert!(hang.to_string().ends_with("or raise Sandbox::max_cycles if the program only needs longer"));
+        }
+
+        // A branch to itself is caught the first time it is taken
+        let text = vec![Instruction::ENT as i32, 0, Instruction::IMM as i32, 0, Instruction::BZ as i32, 4];
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut host = RecordingHost { input: Vec::new(), output: Vec::new(), reports: Vec::new() };
+            let options = VmOptions { backend, ..VmOptions::default() };
+            let mut machine = Machine::new(text.clone(), Vec::new(), options, &mut host);
+            assert_eq!(machine.run(0, 0), -2);
+            let hang = machine.hang.take().unwrap();
+            assert_eq!((hang.kind, hang.pc, hang.cycles), (HangKind::SelfLoop, 4, 3));
+            assert_eq!(hang.listing, "       0: ENT 0\n       2: IMM 0\n>      4: BZ 4\n");
+            drop(machine);
+            assert_eq!(host.reports, [hang.to_string()]);
+        }
+    }
+
+    #[test]
+    fn test_enums() {
+        let source = r#"
+            enum color { RED, GREEN = RED + 5, BLUE, COUNT };
+            enum { SHIFT = 2, MASK = (1 << SHIFT) - 1 };
+            enum color favourite = BLUE;
+            int pick(enum color c) { return c * 10; }
+            int main() {
+                enum color c;
+                int counts[COUNT * 2];
+                c = GREEN;
+                counts[COUNT * 2 - 1] = 1;
+                return pick(c) + favourite + MASK + sizeof(enum color) + counts[13];
+            }"#;
+        let mut compiler = C4::new();
+        let program = compiler.compile(source).unwrap();
+        assert_eq!(program.run(Vec::new()).unwrap().exit_code, 50 + 6 + 3 + 4 + 1);
+        assert_eq!(program.symbol("favourite").unwrap().type_name(), "int");
+
+        // Enum constants are told apart from the builtin ones
+        let red = compiler.symbols.iter().find(|s| s.name == "RED").unwrap();
+        assert_eq!((red.class, red.value, red.bclass), (TokenType::Num as i32, 0, TokenType::Enum as i32));
+        assert_eq!(compiler.eval("sizeof(enum color)").ok(), None);
+        assert_eq!(C4::builder().word_size(8).build().compile("enum e { A }; int main() { return sizeof(enum e); }")
+            .unwrap().run(Vec::new()).unwrap().exit_code, 8);
+
+        let errors = [
+            ("enum e { A }; int main() { A = 1; return 0; }", "Line 1: Cannot assign to enum constant 'A'"),
+            ("int main() { stdout = 1; return 0; }", "Line 1: Cannot assign to constant 'stdout'"),
+            ("enum e { A, B = A + y };", "Line 1: Undefined variable: y (did you mean 'A'?)"),
+            ("int x; enum e { A = x };", "Line 1: Enum value must be a constant expression"),
+            ("enum e { A }; enum e { B };", "Line 1: Redefinition of enum 'e'"),
+            ("enum f x;", "Line 1: Unknown enum 'f'"),
+            ("enum e { A, A };", "Line 1: Redefinition of 'A' (previously declared on line 1)"),
+            ("int main() { int a[0]; return 0; }", "Line 1: Array size must be positive"),
+        ];
+        for (source, message) in errors {
+            assert!(compiler.compile(source).is_err(), "{}", source);
+            assert_eq!(compiler.error.as_deref(), Some(message), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_character_literal_forms() {
+        let mut compiler = C4::new();
+        let source = "int main() { return L'a' + L'\\n' + L'é' + '\\\\' + '\\''; }";
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 97 + 10 + 233 + 92 + 39);
+        assert_eq!(compiler.eval("L'€'"), Ok(0x20AC));
+
+        // An identifier may still start with L
+        assert_eq!(compiler.eval_with("Lx * 2", &[("Lx", 4)]), Ok(8));
+
+        let errors = [
+            ("'ab'", "Line 1: Multi-character literal 'ab' has 2 characters; use a string for more than one"),
+            ("L'\\tx'", "Line 1: Multi-character literal L'\\tx' has 2 characters; use a string for more than one"),
+            ("''", "Line 1: Empty character literal ''"),
+            ("L''", "Line 1: Empty character literal L''"),
+            ("'a", "Line 1: Unterminated character literal"),
+            ("'é'", "Line 1: Character literal 'é' does not fit in a char"),
+        ];
+        for (expression, message) in errors {
+            assert!(compiler.eval(expression).is_err(), "{}", expression);
+            assert_eq!(compiler.error.as_deref(), Some(message), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn test_char_signedness() {
+        let source = r#"
+            int main() {
+                char c; char *s;
+                c = 255;
+                s = malloc(2);
+                *s = 200;
+                return (c == -1) + 2 * (c < 0) + 4 * (*s == 200) + 8 * ((char)300 == 44) + 16 * ((char)-1 == 255);
+            }"#;
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut signed = C4::builder().backend(backend).build();
+            assert_eq!(signed.compile_and_run(source, 0, Vec::new()), 1 + 2 + 8);
+            let mut unsigned = C4::builder().backend(backend).signed_char(false).build();
+            assert_eq!(unsigned.compile_and_run(source, 0, Vec::new()), 4 + 8 + 16);
+        }
+
+        // A char read from getchar can be compared with EOF
+        let source = "int main() { char c; c = getchar(); return c == -1; }";
+        assert_eq!(C4::new().compile_and_run(source, 0, Vec::new()), 1);
+    }
+
+    #[test]
+    fn test_string_literals_are_char_pointers() {
+        let source = r#"
+            char *greeting = "hey";
+            int main() {
+                char *s;
+                s = "hi";
+                return s[1] + *"abc" + "xyz"[2] + *("abc" + 1) + greeting[2] + sizeof("a long string");
+            }"#;
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            let expected = 'i' as i32 + 'a' as i32 + 'z' as i32 + 'b' as i32 + 'y' as i32 + 4;
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), expected);
+        }
+        assert_eq!(C4::new().eval("sizeof(\"abc\"[0])"), Ok(1));
+    }
+
+    #[test]
+    fn test_global_arrays() {
+        let source = r#"
+            enum { SIZE = 16 };
+            int table[SIZE * 2];
+            char name[5];
+            int after;
+            int fill(int n) { int i; i = 0; while (i < n) { table[i] = i * i; i++; } return 0; }
+            int main() {
+                fill(SIZE * 2);
+                name[0] = 'o'; name[1] = 'k'; name[2] = 0;
+                after = 7;
+                printf("%s %d %d %d\n", name, table[31], sizeof(table), sizeof(name));
+                return table[3] + after + sizeof(table[0]) + sizeof(table + 1);
+            }"#;
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 9 + 7 + 4 + 4);
+            assert_eq!(compiler.get_captured_output(), "ok 961 128 5\n");
+        }
+
+        // The symbol records the element type and length, and the program its size
+        let mut compiler = C4::new();
+        let program = compiler.compile(source).unwrap();
+        let table = compiler.symbols.iter().find(|s| s.name == "table").unwrap();
+        assert_eq!((table.type_, table.btype, table.bvalue), (INT + PTR, INT, 32));
+        assert_eq!(program.symbol("table").unwrap().size, 128);
+        assert_eq!(program.symbol("name").unwrap().size, 5);
+        assert_eq!(program.symbol("after").unwrap().address, 8 + 128 + 8);
+
+        // Local arrays have their size too
+        assert_eq!(C4::new().compile_and_run("int main() { char buf[10]; int a[3]; return sizeof(buf) + sizeof(a); }", 0, Vec::new()), 22);
+
+        let mut compiler = C4::new();
+        for (source, message) in [
+            ("int t[0];", "Line 1: Array size must be positive"),
+            ("int t[2] = 1;", "Line 1: Array initializers are not supported"),
+            ("int t[1 << 30];", "Line 1: Array too large"),
+            // A frame bigger than ENT can reserve is caught at the declaration
+            ("int main() {\n  int a[100000000];\n  a[0] = 1;\n  return a[0];\n}", "Line 2: Array too large"),
+            ("int main() {\n  int a[8000000];\n  int b[8000000];\n  char c[8000000];\n  return 0;\n}", "Line 4: Array too large"),
+        ] {
+            assert!(compiler.compile(source).is_err());
+            assert_eq!(compiler.error.as_deref(), Some(message), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_bounds_checks() {
+        let source = r#"
+            char name[4];
+            int main() {
+                int a[5]; int i; int sum;
+                i = 0; sum = 0;
+                while (i < 5) { a[i] = i; name[i % 4] = 'a' + i; sum = sum + a[i]; i++; }
+                printf("%d\n", sum);
+                return a[i - 6];
+            }"#;
+
+        // Without checks the subscripts are compiled as before
+        let unchecked = C4::new().compile(source).unwrap();
+        assert!(!unchecked.text.contains(&(Instruction::BOUND as i32)));
+
+        let program = C4::builder().bounds_checks(true).build().compile(source).unwrap();
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut host = RecordingHost { input: Vec::new(), output: Vec::new(), reports: Vec::new() };
+            let options = VmOptions { backend, ..VmOptions::default() };
+            let mut machine = Machine::new(program.text.clone(), program.data.clone(), options, &mut host);
+            assert_eq!(machine.run(program.entry().unwrap(), 0), -1);
+            drop(machine);
+            assert_eq!(host.output.iter().map(|&(_, b)| b).collect::<Vec<u8>>(), b"10\n");
+            assert_eq!(host.reports, ["Line 8: index -1 is out of bounds for 'a' of length 5"]);
+        }
+
+        // Only the name of an array is checked, not other pointers into it
+        let mut compiler = C4::builder().bounds_checks(true).build();
+        let program = compiler.compile("int main() { int a[2]; int *p; p = a + 1; p[0] = 7; return p[0] + sizeof(a); }").unwrap();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 15);
+    }
+
+    #[test]
+    fn test_conditional_nesting_and_types() {
+        let mut compiler = C4::new();
+
+        // Nested conditionals group to the right, in either operand
+        let cases = [
+            ("0 ? 1 : 0 ? 2 : 3", 3),
+            ("0 ? 1 : 1 ? 2 : 3", 2),
+            ("1 ? 0 ? 4 : 5 : 6", 5),
+            ("x ? y ? 1 : 2 : 3", 1),
+            ("x - 7 ? 10 : 20 + 1", 21),
+            ("(x == 7 ? 10 : 20) + 1", 11),
+            ("x < y ? x : y", -2),
+            ("x && y ? 7 : 8", 7),
+            ("y = x > 0 ? x : -x", 7),
+        ];
+        for (expression, value) in cases {
+            assert_eq!(compiler.eval_with(expression, &[("x", 7), ("y", -2)]), Ok(value), "{}", expression);
+        }
+
+        // A char and an int give an int; a pointer may be paired with 0, in either order
+        let source = r#"
+            int main() {
+                int a; int *p; char *s; char c;
+                a = 1; p = &a; c = 'z'; s = "hi";
+                return *(!a ? 0 : p) + *(a ? p : 0) + (a ? s : "no")[1] + (a ? c : 1000) + (a ? p : p + 1) - p;
+            }"#;
+        let program = compiler.compile(source).unwrap();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 1 + 1 + 'i' as i32 + 'z' as i32);
+
+        let error = compiler.compile("int main() { int *p; char *s; return *(p ? p : s); }").unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: Incompatible operands of '?:': 'int *' and 'char *'");
+        let error = compiler.compile("int main() { int a; int *p; return *(a ? p : 1); }").unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: Incompatible operands of '?:': 'int *' and 'int'");
+    }
+
+    #[test]
+    fn test_pointer_comparisons() {
+        let source = r#"
+            #include <stdio.h>
+            int main() {
+                int a[4]; int *p; int *q; char *s;
+                p = a; q = a + 2; s = NULL;
+                printf("%d%d%d%d%d", p < q, q >= p, p == q, p != NULL, NULL == s);
+                printf("%d%d\n", s == 0, 0 != p);
+                return 0;
+            }"#;
+        let mut compiler = C4::new();
+        let program = compiler.compile(source).unwrap();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().output, "1101111\n");
+
+        let rejected = [
+            ("int main() { int *p; char *s; return p == s; }", "Line 1: Incompatible operands of '==': 'int *' and 'char *'"),
+            ("int main() { int *p; return p < 1; }", "Line 1: Incompatible operands of '<': 'int *' and 'int'"),
+            ("int main() { char c; char **t; return c >= t; }", "Line 1: Incompatible operands of '>=': 'char' and 'char **'"),
+        ];
+        for (source, message) in rejected {
+            assert_eq!(compiler.compile(source).err(), Some(Error::Compile(message.to_string())), "{}", source);
+        }
+
+        // Integers of either size compare with each other, as do doubles
+        assert_eq!(compiler.eval("'a' < 98 == (sqrt(4.0) == 2.0)"), Ok(1));
+    }
+
+    #[test]
+    fn test_standard_constants() {
+        // Available without including a header
+        let source = r#"
+            int main() {
+                int c; int n; char *s;
+                s = NULL; n = 0;
+                while ((c = getchar()) != EOF) n++;
+                printf("%d %d %d %d\n", n, s == NULL, CHAR_BIT, CHAR_MIN);
+                return n == 3 ? EXIT_SUCCESS : EXIT_FAILURE;
+            }"#;
+        let mut compiler = C4::builder().input(&b"abc"[..]).build();
+        let program = compiler.compile(source).unwrap();
+        let outcome = compiler.run_program(&program, Vec::new()).unwrap();
+        assert_eq!((outcome.exit_code, outcome.output.as_str()), (0, "3 1 8 -128\n"));
+
+        // Including one that defines them again is fine, and a declaration may shadow them
+        let mut compiler = C4::new();
+        let program = compiler.compile("#include <stdlib.h>\nint EOF;\nint main() { EOF = 4; return EOF + EXIT_FAILURE; }").unwrap();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 5);
+        assert_eq!(compiler.compile("int main() { NULL = 1; return 0; }").err(),
+                   Some(Error::Compile("Line 1: Cannot assign to constant 'NULL'".to_string())));
+
+        let mut compiler = C4::builder().signed_char(false).build();
+        assert_eq!(compiler.eval("CHAR_MAX - CHAR_MIN"), Ok(255));
+        let mut strict = C4::builder().language(LanguageLevel::C4).build();
+        assert!(strict.eval("EOF").is_err());
+    }
+
+    #[test]
+    fn test_conversion_warnings() {
+        let source = "int main() {\n    char c; int i; int *p; char *s;\n    c = 'a'; i = 300;\n    c = i + 1;\n    p = NULL; p = malloc(8); s = (char *)p;\n    p = i;\n    int n = s;\n    return sizeof(c + 1) + sizeof(c);\n}";
+        let mut compiler = C4::new();
+        let program = compiler.compile(source).unwrap();
+        let warnings: Vec<String> = compiler.warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, [
+            "Line 4: warning: Conversion from 'int' to 'char' may change the value",
+            "Line 6: warning: Assignment to 'int *' from 'int' makes a pointer from an integer without a cast",
+            "Line 7: warning: Assignment to 'int' from 'char *' makes an integer from a pointer without a cast",
+        ]);
+        assert_eq!(compiler.warnings[0].span, Span { line: 4, column: 9, end_line: 4, end_column: 14 });
+        assert_eq!(compiler.warnings[2].span, Span { line: 7, column: 13, end_line: 7, end_column: 14 });
+
+        // A char is promoted to int in arithmetic
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 5);
+
+        // Each compilation starts without warnings
+        compiler.compile("int main() { return 0; }").unwrap();
+        assert!(compiler.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_function_prototypes() {
+        // Declared functions can be called, and their addresses taken, before they are defined
+        let source = r#"
+            int add(int, int);
+            int twice(int f, int x);
+            int descending(int *, int *);
+            int answer(void);
+            int main() {
+                int numbers[3];
+                numbers[0] = 2; numbers[1] = 30; numbers[2] = 4;
+                qsort(numbers, 3, sizeof(int), descending);
+                return add(twice(3, numbers[2]), numbers[0] + answer());
+            }
+            int add(int a, int b) { return a + b; }
+            int twice(int f, int x) { return add(x, x) * f / 3; }
+            int descending(int *a, int *b) { return *b - *a; }
+            int answer(void) { return 8; }"#;
+        let mut compiler = C4::new();
+        let program = compiler.compile(source).unwrap();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 42);
+
+        // A prototype that is repeated, or never used, is fine
+        let program = compiler.compile("int f(char *);\nint f(char *s);\nint g();\nint main() { return f(\"abc\"); }\nint f(char *s) { return *s; }").unwrap();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 97);
+        let program = compiler.compile("int f(int);\nint g(int x) { return f(x + 1); }\nint main() { return g(4); }\nint f(int x) { return x * 2; }").unwrap();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 10);
+
+        let rejected = [
+            ("int f(int);\nchar f(int x) { return x; }", "Line 2: 'f' returns 'char', not 'int' as declared on line 1"),
+            ("int f(int);\nint f(int x, int y) { return x; }", "Line 2: 'f' takes 2 parameters, not 1 as declared on line 1"),
+            ("int f(int, int);\nint f(int x) { return x; }", "Line 2: 'f' takes 1 parameter, not 2 as declared on line 1"),
+            ("int f(int, char *);\nint f(int x, int *y) { return x; }", "Line 2: 'f' parameter 2 is 'int *', not 'char *' as declared on line 1"),
+            ("int f(int);\nint f(int) { return 0; }", "Line 2: Parameter 1 of 'f' has no name"),
+            ("int f();\nint main() {\n    return f();\n}", "Line 3: 'f' is called but never defined"),
+            ("int f;\nint f();", "Line 2: Redefinition of 'f' (previously declared on line 1)"),
+            ("int f() { return 0; }\nint f() { return 1; }", "Line 2: Redefinition of 'f' (previously declared on line 1)"),
+        ];
+        for (source, message) in rejected {
+            assert_eq!(compiler.compile(source).err(), Some(Error::Compile(message.to_string())), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_recursion() {
+        let source = r#"
+            int is_odd(int n);
+            int is_even(int n) { if (n == 0) return 1; return is_odd(n - 1); }
+            int is_odd(int n) { if (n == 0) return 0; return is_even(n - 1); }
+            int factorial(int n) { if (n < 2) return 1; return n * factorial(n - 1); }
+            int fibonacci(int n) { if (n < 2) return n; return fibonacci(n - 1) + fibonacci(n - 2); }
+            int sum(int n) { if (n == 0) return 0; return n + sum(n - 1); }
+            int main() {
+                printf("%d %d %d %d\n", factorial(10), fibonacci(20), is_even(1001), is_odd(1001));
+                return sum(20000) == 200010000;
+            }"#;
+        let program = C4::new().compile(source).unwrap();
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut host = RecordingHost { input: Vec::new(), output: Vec::new(), reports: Vec::new() };
+            let options = VmOptions { backend, ..VmOptions::default() };
+            let mut machine = Machine::new(program.text.clone(), program.data.clone(), options, &mut host);
+            assert_eq!(machine.run(program.entry().unwrap(), 0), 1, "{:?}", backend);
+            drop(machine);
+            let output: Vec<u8> = host.output.iter().map(|&(_, byte)| byte).collect();
+            assert_eq!(output, b"3628800 6765 0 1\n");
+
+            // Recursion deeper than the stack allows ends the run, and says why
+            let mut host = RecordingHost { input: Vec::new(), output: Vec::new(), reports: Vec::new() };
+            let options = VmOptions { backend, stack_words: 1000, ..VmOptions::default() };
+            let mut machine = Machine::new(program.text.clone(), program.data.clone(), options, &mut host);
+            assert_eq!(machine.run(program.entry().unwrap(), 0), -1, "{:?}", backend);
+            drop(machine);
+            assert!(host.reports[0].starts_with("Stack overflow"), "{:?}", host.reports);
+        }
+    }
+
+    #[test]
+    fn test_block_locals_share_slots() {
+        let source = r#"
+            int sum(int n) { int i; int total; total = 0; i = 0; while (i < n) { i++; total = total + i; } return total; }
+            int main() {
+                int result;
+                result = 0;
+                if (result == 0) { int a; int b[2]; a = 1; b[0] = 2; b[1] = 3; result = a + b[0] + b[1]; }
+                { int c; c = 4; { int d; d = 5; result = result * c + d; } }
+                { int e; int f; e = 6; f = 7; result = result + e * f; }
+                return result + sum(3);
+            }"#;
+        let mut compiler = C4::new();
+        let program = compiler.compile(source).unwrap();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 77);
+
+        // Disjoint blocks reuse the same slots, so main needs the three words
+        // of its first block beside `result` rather than eight
+        assert_eq!(compiler.compile_stats.frame_slots, [("sum".to_string(), 2), ("main".to_string(), 4)]);
+        assert!(compiler.compile_stats.to_string().ends_with("frame of sum: 2 words\nframe of main: 4 words\n"));
+    }
+
+    #[test]
+    fn test_named_entry_point() {
+        let source = r#"
+            int square(int x) { return x * x; }
+            int test_square() { return square(7) == 49; }
+            int count_args(int argc) { printf("%d\n", argc); return argc; }"#;
+        let program = C4::new().compile(source).unwrap();
+        assert_eq!(program.run_from("test_square", Vec::new()).unwrap().exit_code, 1);
+
+        // The function gets the argument count main would
+        let outcome = program.run_from("count_args", vec!["lib".to_string(), "x".to_string()]).unwrap();
+        assert_eq!((outcome.exit_code, outcome.output.as_str()), (2, "2\n"));
+
+        // A library image needs no main
+        let image = Program::from_image(&program.to_image()).unwrap();
+        let mut compiler = C4::new();
+        assert_eq!(compiler.run_program_from(&image, "test_square", Vec::new()).unwrap().exit_code, 1);
+        assert_eq!(image.run(Vec::new()), Err(Error::NoMain));
+        assert_eq!(image.run_from("cube", Vec::new()).unwrap_err().to_string(), "cube function not found");
+    }
+
+    #[test]
+    fn test_tentative_global_definitions() {
+        // A global may be declared again, as long as it is defined once
+        let source = r#"
+            int count;
+            char *names[2];
+            int count = 40;
+            int count, total;
+            char *names[2];
+            int main() { count = count + 2; return count; }"#;
+        let mut compiler = C4::new();
+        let program = compiler.compile(source).unwrap();
+        assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 42);
+        assert_eq!(program.symbols().iter().filter(|s| s.name == "count").count(), 1);
+
+        let rejected = [
+            ("int x;\nchar x;", "Line 2: Conflicting types for 'x' (previously declared on line 1)"),
+            ("int a[2];\nint a[3];", "Line 2: Conflicting types for 'a' (previously declared on line 1)"),
+            ("int x = 1;\nint x;\nint x = 1;", "Line 3: Redefinition of 'x' (previously defined on line 1)"),
+            ("int f();\nint f;", "Line 2: Redefinition of 'f' (previously declared on line 1)"),
+        ];
+        for (source, message) in rejected {
+            assert_eq!(compiler.compile(source).err(), Some(Error::Compile(message.to_string())), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_relocations() {
+        let source = r#"
+            char *greeting = "hi";
+            int total;
+            int add(int x) { total = total + x; return total; }
+            int main() { add(40); add(2); printf("%s %d\n", greeting, sqrt(4.0) == 2.0); return total; }"#;
+        let mut compiler = C4::new();
+        let mut program = compiler.compile(source).unwrap();
+        assert_eq!(compiler.inline_stats.calls_inlined, 2);
+        let greeting = program.symbol("greeting").unwrap().address as usize;
+        assert!(program.relocations().contains(&Relocation::DataWord(greeting)));
+        for &relocation in program.relocations() {
+            let operand_of = |ops: &[Instruction]| ops.iter().any(|&op| program.text[relocation.offset() - 1] == op as i32);
+            match relocation {
+                Relocation::Text(_) => assert!(operand_of(&[Instruction::JMP, Instruction::JSR, Instruction::BZ, Instruction::BNZ])),
+                Relocation::Data(_) => assert!(operand_of(&[Instruction::IMM])),
+                Relocation::DataWord(_) => {},
+            }
+        }
+
+        // The relocations are kept in images
+        let loaded = Program::from_image(&program.to_image()).unwrap();
+        assert_eq!(loaded.relocations(), program.relocations());
+
+        // A program moved past the segments of another still runs, add inlined into main among them
+        program.relocate(5, 16);
+        let mut text = vec![Instruction::LEV as i32; 5];
+        text.extend(&program.text);
+        let mut data = vec![0; 16];
+        data.extend(&program.data);
+        let mut host = RecordingHost { input: Vec::new(), output: Vec::new(), reports: Vec::new() };
+        let mut machine = Machine::new(text, data, VmOptions::default(), &mut host);
+        assert_eq!(machine.run(program.entry().unwrap(), 0), 42);
+        drop(machine);
+        let output: Vec<u8> = host.output.iter().map(|&(_, byte)| byte).collect();
+        assert_eq!(output, b"hi 1\n");
+        assert_eq!(program.symbol("greeting").unwrap().address as usize, greeting + 16);
+    }
+
+    #[test]
+    fn test_position_independent_code() {
+        let source = r#"
+            int cmp(int *a, int *b) { return *a - *b; }
+            int sum(int n) { int s; s = 0; while (n > 0) { if (n % 2) s = s + n; n--; } return s; }
+            int main() {
+                int *v;
+                v = malloc(3 * sizeof(int));
+                v[0] = 3; v[1] = 1; v[2] = 2;
+                qsort(v, 3, sizeof(int), cmp);
+                printf("%d%d%d\n", v[0], v[1], v[2]);
+                return sum(9);
+            }"#;
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().position_independent(true).backend(backend).build();
+            let program = compiler.compile(source).unwrap();
+            assert!(program.is_position_independent());
+            assert!(program.verify().is_ok());
+            assert!(!program.relocations().iter().any(|r| matches!(r, Relocation::Text(_))));
+            let outcome = program.run(Vec::new()).unwrap();
+            assert_eq!((outcome.exit_code, outcome.output.as_str()), (25, "123\n"));
+
+            // The code runs unchanged behind other code
+            let mut text = vec![Instruction::LEV as i32; 7];
+            text.extend(&program.text);
+            let mut host = RecordingHost { input: Vec::new(), output: Vec::new(), reports: Vec::new() };
+            let options = VmOptions { backend, position_independent: true, ..VmOptions::default() };
+            let mut machine = Machine::new(text, program.data.clone(), options, &mut host);
+            machine.functions = program.functions().into_iter().map(|(name, addr)| (name, addr + 7)).collect();
+            assert_eq!(machine.run(program.entry().unwrap() + 7, 0), 25);
+            drop(machine);
+            let output: Vec<u8> = host.output.iter().map(|&(_, byte)| byte).collect();
+            assert_eq!(output, b"123\n");
+
+            // Images say whether their code is position-independent
+            let loaded = Program::from_image(&program.to_image()).unwrap();
+            assert!(loaded.is_position_independent());
+            assert_eq!(loaded.run(Vec::new()).unwrap().exit_code, 25);
+        }
+        let absolute = C4::new().compile(source).unwrap();
+        assert!(!absolute.is_position_independent());
+        assert!(absolute.relocations().iter().any(|r| matches!(r, Relocation::Text(_))));
+        assert_eq!(C4::builder().position_independent(true).build().eval("6 * 7").unwrap(), 42);
+    }
+
+    #[test]
+    fn test_benchmarks() {
+        let results = c4_rust::bench::run_all().unwrap();
+        assert_eq!(results.len(), c4_rust::bench::BENCHMARKS.len() * 2);
+        for pair in results.chunks(2) {
+            let (stack, register) = (&pair[0], &pair[1]);
+            assert_eq!((stack.backend, register.backend), (Backend::Stack, Backend::Register));
+            assert!(stack.cycles > 1000000, "{}", stack);
+            assert!(register.cycles > 0 && register.cycles < stack.cycles, "{}", register);
+        }
+        assert!(results[0].to_string().starts_with("sieve      stack"));
+
+        // A benchmark that goes wrong is not timed
+        let broken = c4_rust::bench::Benchmark { expected_output: "0 primes\n", ..c4_rust::bench::BENCHMARKS[0] };
+        assert_eq!(
+            broken.run(Backend::Stack).unwrap_err().to_string(),
+            "printed \"17984 primes below 200000\\n\", expected \"0 primes\\n\""
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_comparison_harness() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A stand-in for c4 that ends its output the way the original does
+        let fake = std::env::temp_dir().join(format!("fake_c4_{}", std::process::id()));
+        std::fs::write(&fake, "#!/bin/sh\ntest -f \"$1\" || exit 1\nprintf 'hi\\nexit(3) cycle = 10\\n'\n").unwrap();
+        std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let same = compare::compare(&fake, "int main() { printf(\"hi\\n\"); return 3; }", b"").unwrap();
+        assert_eq!(same.original, compare::Run { exit_code: Some(3), output: "hi\n".to_string() });
+        assert_eq!(same.difference(), None);
+        let different = compare::compare(&fake, "int main() { printf(\"ho\\n\"); return 300; }", b"").unwrap();
+        assert_eq!(
+            different.difference().unwrap(),
+            "exit code 3 from c4, 300 from the port\nc4 printed \"hi\\n\"\nthe port printed \"ho\\n\"\n"
+        );
+        assert_eq!(compare::run_port("int main() { return x; }", b"").exit_code, None);
+        assert_eq!(compare::run_port("int main() { return -1; }", b"").exit_code, Some(-1));
+
+        // The port is held to what c4 itself accepts
+        let extended = compare::run_port("int main() { int i = 1; return i; }", b"");
+        assert_eq!(extended.output, "Line 1: Language feature not enabled: initializers\n");
+        assert_eq!(compare::run_port("int main() { return sizeof(int); }", b"").exit_code, Some(8));
+        std::fs::remove_file(&fake).unwrap();
+    }
+
+    #[test]
+    fn test_matches_original_c4() {
+        let Some(c4) = compare::original_c4() else {
+            return;
+        };
+        let programs = [
+            ("echo", "int main() { int c; while ((c = getchar()) != -1) putchar(c); return 0; }", &b"abc\n"[..]),
+            ("fib", "int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\nint main() { printf(\"%d\\n\", fib(20)); return fib(10); }", b""),
+            ("chars", "int main() { char *s; s = \"hello\"; while (*s) printf(\"%c\", *s++ - 32); printf(\"\\n\"); return s[-1]; }", b""),
+        ];
+        let benchmarks = c4_rust::bench::BENCHMARKS.iter().map(|b| (b.name, b.source, &b""[..]));
+        for (name, source, input) in programs.into_iter().chain(benchmarks) {
+            let comparison = compare::compare(&c4, source, input).unwrap();
+            assert_eq!(comparison.difference(), None, "{} differs from c4", name);
+        }
+    }
+
+    #[test]
+    fn test_generated_programs() {
+        assert_eq!(generate::generate(7), generate::generate(7));
+        assert_ne!(generate::generate(7), generate::generate(8));
+
+        // Every generated program compiles and runs the same however it is built
+        for seed in 0..150 {
+            let source = generate::generate(seed);
+            let mut outcomes = Vec::new();
+            for (opt_level, backend) in [(0, Backend::Stack), (1, Backend::Stack), (1, Backend::Register)] {
+                let mut compiler = C4::builder().opt_level(opt_level).backend(backend).build();
+                let program = compiler.compile(&source).unwrap_or_else(|e| panic!("seed {}: {}\n{}", seed, e, source));
+                let outcome = compiler.run_program(&program, Vec::new()).unwrap();
+                assert!(outcome.error_output.is_empty(), "seed {}: {}", seed, outcome.error_output);
+                outcomes.push(outcome);
+            }
+            assert!(outcomes.windows(2).all(|pair| pair[0] == pair[1]), "seed {}: {:?}\n{}", seed, outcomes, source);
+            assert!(outcomes[0].output.contains("\ng2 "), "seed {}: {}", seed, outcomes[0].output);
+        }
+
+        let small = generate::GenOptions { globals: 0, functions: 0, locals: 0, depth: 1, ..generate::GenOptions::default() };
+        let source = generate::generate_with(1, small);
+        assert!(source.starts_with("int main() {\n  int x0, i0, *p;\n"), "{}", source);
+        assert!(C4::new().compile(&source).is_ok());
+    }
+
+    #[test]
+    fn test_const_eval() {
+        let mut compiler = C4::new();
+        assert_eq!(compiler.const_eval("(1 << 4) - 1").unwrap(), 15);
+        assert_eq!(compiler.const_eval("sizeof(int) * 8").unwrap(), 32);
+        assert_eq!(compiler.const_eval("-7 / 2 + -7 % 2").unwrap(), -4);
+        assert_eq!(compiler.const_eval("1 && 0 || 2 > 1").unwrap(), 1);
+        assert_eq!(compiler.const_eval("0 ? 1 / (1 - 1) : 3").unwrap(), 3);
+        assert_eq!(compiler.const_eval("EOF == -1 && !0 && ~0 == -1").unwrap(), 1);
+        assert_eq!(C4::builder().word_size(8).build().const_eval("2147483647 + 2147483647").unwrap(), 4294967294);
+        assert_eq!(C4::builder().define("N", "12").build().const_eval("N * 2").unwrap(), 24);
+
+        let error = |compiler: &mut C4, expression: &str| compiler.const_eval(expression).unwrap_err().to_string();
+        assert_eq!(error(&mut compiler, "4 / (2 - 2)"), "Line 1: Division by zero in value");
+        assert_eq!(error(&mut compiler, "1 % (2 * 0)"), "Line 1: Division by zero in value");
+        assert_eq!(error(&mut compiler, "1 % 0"), "Line 1: Modulo by zero");
+        assert_eq!(error(&mut compiler, "2147483647 + 1"), "Line 1: Integer overflow in value");
+        assert_eq!(error(&mut compiler, "x + 1"), "Line 1: Undefined variable: x");
+        assert_eq!(error(&mut compiler, "abs(3)"), "Line 1: Value must be a constant expression");
+        assert_eq!(error(&mut compiler, "1 2"), "Line 1: Expected end of expression, got number");
+
+        // Array sizes and enum values are folded the same way
+        let program = "enum { A = 1 && 2, B = A ? 10 : 20 }; int v[B * 2]; int main() { return sizeof(v) / sizeof(int) + A; }";
+        assert_eq!(compiler.compile_and_run(program, 0, Vec::new()), 21);
+        let mut compiler = C4::new();
+        assert!(compiler.compile("int v[4 / (1 - 1)]; int main() { return 0; }").is_err());
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Division by zero in array size"));
+        assert!(compiler.compile("enum { BIG = 2147483647 * 2 }; int main() { return 0; }").is_err());
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Integer overflow in enum value"));
+    }
+
+    #[test]
+    fn test_if_expressions() {
+        let source = "#define FOO 2\n#define BAR (FOO * 3)\n\
+            #if (FOO+1) > 2 && defined(BAR) && !defined BAZ\nint a() { return 1; }\n#else\nint a() { return 0; }\n#endif\n\
+            #if BAR == 5\nint b() { return 10; }\n#elif BAR == 6 || UNDEFINED\nint b() { return 20; }\n#elif 1 / 0\nint b() { return 30; }\n#else\nint b() { return 40; }\n#endif\n\
+            #if UNDEFINED + 'x' - 120\nint c() { return 100; }\n#else\nint c() { return 200; }\n#endif\n\
+            int main() { return a() + b() + c(); }\n";
+        assert_eq!(C4::new().compile_and_run(source, 0, Vec::new()), 221);
+        assert_eq!(C4::builder().define("BAZ", "-1").build().compile_and_run(source, 0, Vec::new()), 220);
+
+        // A macro that refers to itself is not expanded forever
+        let mut compiler = C4::new();
+        let source = "#define LOOP LOOP + 1\n#if LOOP == 1\nint main() { return 1; }\n#else\nint main() { return 2; }\n#endif\n";
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 1);
+
+        let error = |source: &str| {
+            let mut compiler = C4::new();
+            compiler.compile_and_run(source, 0, Vec::new());
+            compiler.error.unwrap()
+        };
+        assert_eq!(error("int x;\n#if 4 / (2 - 2)\n#endif\n"), "Line 2: In #if 4 / (2 - 2): Division by zero in value");
+        assert_eq!(error("#if 1 +\n#endif\n"), "Line 1: In #if 1 +: Unexpected end of input in expression");
+        assert_eq!(error("#if defined(X\n#endif\n"), "Line 1: Missing ')' after 'defined'");
+        assert_eq!(error("#if defined\n#endif\n"), "Line 1: Macro name expected after 'defined'");
+        assert_eq!(error("#if\n#endif\n"), "Line 1: Condition expected in #if");
+
+        // Conditions are evaluated in 64 bits, whatever the word size
+        let source = "#if (1 << 40) > 0 && 2147483647 + 1 > 0\nint main() { return 1; }\n#else\nint main() { return 2; }\n#endif\n";
+        let mut compiler = C4::builder().word_size(4).build();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 1);
+        assert_eq!(error("#if (1 << 62) * 4\n#endif\n"), "Line 1: In #if (1 << 62) * 4: Integer overflow in value");
+    }
+
+    #[test]
+    fn test_preprocess_only() {
+        let dir = std::env::temp_dir().join(format!("c4_preprocess_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let header = dir.join("twice.h");
+        std::fs::write(&header, "// doubles\nint twice(int x) { return x * 2; }\n").unwrap();
+
+        let source = "#define N 10 // ten\n#include \"twice.h\"\n/* comment */\nint main() {\n  int a; a = N+1;\n#if N > 5\n  a++;\n#endif\n\n\n\n\n\n\n\n\n\n\n  return twice(a);\n}\n";
+        let mut compiler = C4::builder().source_path(dir.join("main.c")).build();
+        let preprocessed = compiler.preprocess(source).unwrap();
+        let header = header.canonicalize().unwrap();
+        assert_eq!(preprocessed, format!(
+            "#line 2 \"{}\"\nint twice ( int x ) {{ return x * 2 ; }}\n#line 4 \"{}\"\nint main ( ) {{\nint a ; a = 10 + 1 ;\n\na ++ ;\n#line 19 \"{}\"\nreturn twice ( a ) ;\n}}\n",
+            header.display(), dir.join("main.c").display(), dir.join("main.c").display()
+        ));
+
+        // The output compiles to the same program
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run(&preprocessed, 0, Vec::new()), 24);
+        assert_eq!(C4::new().preprocess("int x;").unwrap(), "#line 1\nint x ;\n");
+        assert_eq!(C4::new().preprocess("").unwrap(), "");
+        assert_eq!(
+            C4::new().preprocess("int x;\n#include <nowhere.h>\n"),
+            Err(Error::Compile("Line 2: Cannot find include file 'nowhere.h'".to_string()))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_macro_backtraces() {
+        let error = |compiler: &mut C4, source: &str| {
+            compiler.compile_and_run(source, 0, Vec::new());
+            compiler.error.clone().unwrap()
+        };
+        let mut compiler = C4::new();
+        let source = "#define LIMIT count + 1\n#define CHECK (LIMIT > 2)\nint main() {\n  return CHECK;\n}\n";
+        assert_eq!(
+            error(&mut compiler, source),
+            "Line 4: Undefined variable: count\n  in expansion of macro 'LIMIT' (defined on line 1)\n  in expansion of macro 'CHECK' (defined on line 2)"
+        );
+
+        // Macros from headers and from before the source say where they came from
+        let dir = std::env::temp_dir().join(format!("c4_backtraces_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("defs.h"), "\n#define BAD ]\n").unwrap();
+        let mut compiler = C4::builder().define("ZERO", "missing").source_path(dir.join("main.c")).build();
+        assert_eq!(
+            error(&mut compiler, "#include \"defs.h\"\nint main() { return 1 BAD; }\n"),
+            "Line 2: Expected ';', got ']'\n  in expansion of macro 'BAD' (defined on line 2 of defs.h)"
+        );
+        assert_eq!(
+            error(&mut compiler, "int main() { return ZERO; }\n"),
+            "Line 1: Undefined variable: missing\n  in expansion of macro 'ZERO' (defined before the source)"
+        );
+
+        // Errors outside macros are unchanged
+        assert_eq!(error(&mut compiler, "#define ONE 1\nint main() { return ONE + x; }\n"), "Line 2: Undefined variable: x");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_instructions_for_line() {
+        let source = "int sq(int x) {\n  return x * x;\n}\nint main() {\n  int a;\n  a = 3;\n  if (a > 2)\n    a = sq(a);\n  return a;\n}\n";
+        let listing = |program: &Program, line: i32| -> Vec<String> {
+            program.instructions_for_line(line).iter().map(|i| i.to_string()).collect()
+        };
+        let program = C4::builder().opt_level(0).build().compile(source).unwrap();
+        assert_eq!(listing(&program, 6), ["LEA 0", "PUSH", "IMM 3", "SI"]);
+        assert_eq!(listing(&program, 7), ["LEA 0", "LI", "PUSH", "IMM 2", "GT", "BZ 40"]);
+        assert_eq!(program.instructions_for_line(8)[0], DecodedInstr { pc: 28, op: Instruction::LEA, operand: Some(0) });
+        assert!(program.instructions_for_line(5).is_empty());
+        assert!(program.instructions_for_line(11).is_empty());
+
+        // Every instruction belongs to exactly one line
+        let lines: usize = (1..=10).map(|line| program.instructions_for_line(line).len()).sum();
+        assert_eq!(lines, c4_rust::optimizer::instruction_starts(&program.text).len());
+
+        // Inlined copies of a function keep the lines of its body
+        let program = C4::builder().opt_level(1).build().compile(source).unwrap();
+        assert!(listing(&program, 8).contains(&"IENT 0".to_string()));
+        assert_eq!(listing(&program, 2).iter().filter(|i| *i == "MUL").count(), 2);
+
+        // Code from headers and images have no lines
+        let dir = std::env::temp_dir().join(format!("c4_line_table_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("one.h"), "int one() { return 1; }\n").unwrap();
+        let mut compiler = C4::builder().source_path(dir.join("main.c")).build();
+        let program = compiler.compile("#include \"one.h\"\nint main() { return one(); }\n").unwrap();
+        assert!(program.instructions_for_line(1).is_empty());
+        assert!(!program.instructions_for_line(2).is_empty());
+        let loaded = Program::from_image(&program.to_image()).unwrap();
+        assert!(loaded.instructions_for_line(2).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_decode_instructions() {
+        let text = [Instruction::ENT as i32, 0, Instruction::IMM as i32, 7, Instruction::LEV as i32];
+        let decoded: Vec<DecodedInstr> = decode(&text).collect();
+        assert_eq!(decoded, [
+            DecodedInstr { pc: 0, op: Instruction::ENT, operand: Some(0) },
+            DecodedInstr { pc: 2, op: Instruction::IMM, operand: Some(7) },
+            DecodedInstr { pc: 4, op: Instruction::LEV, operand: None },
+        ]);
+        assert_eq!(decoded.iter().map(|i| i.to_string()).collect::<Vec<_>>(), ["ENT 0", "IMM 7", "LEV"]);
+
+        // Decoding stops at a word that is not an instruction, or a missing operand
+        let mut instructions = decode(&[Instruction::PUSH as i32, 99, Instruction::LEV as i32]);
+        assert_eq!(instructions.by_ref().count(), 1);
+        assert_eq!(instructions.pc(), 1);
+        let mut instructions = decode(&[Instruction::LEV as i32, Instruction::JMP as i32]);
+        assert_eq!(instructions.by_ref().count(), 1);
+        assert_eq!(instructions.pc(), 1);
+        assert_eq!(decode(&[]).next(), None);
+
+        // A compiled program decodes to the end
+        let program = C4::new().compile("int main() { int i; i = 0; while (i < 3) i++; return i; }").unwrap();
+        let mut instructions = program.instructions();
+        let count = instructions.by_ref().count();
+        assert_eq!(instructions.pc(), program.text.len());
+        assert_eq!(count, c4_rust::optimizer::instruction_starts(&program.text).len());
+        assert!(program.instructions().any(|i| i.op == Instruction::BZ && i.operand.is_some()));
+    }
+
+    #[test]
+    fn test_token_type_from_i32() {
+        for token in TokenType::ALL {
+            assert_eq!(TokenType::from_i32(token as i32), Some(token));
+        }
+        assert_eq!(TokenType::from_i32(TokenType::While as i32), Some(TokenType::While));
+        assert_eq!(TokenType::from_i32(TokenType::Brak as i32 + 1), None);
+        assert_eq!(TokenType::from_i32(b';' as i32), None);
+        assert_eq!(TokenType::from_i32(0), None);
+
+        // Parse errors name the token rather than printing None
+        let mut compiler = C4::new();
+        compiler.compile_and_run("int main() { if (1 return 0; }", 0, Vec::new());
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Expected ')', got keyword 'return'"));
+    }
+
+    #[test]
+    fn test_token_names() {
+        assert_eq!(token_name(0), "end of input");
+        assert_eq!(token_name(b';' as i32), "';'");
+        assert_eq!(token_name(TokenType::Eq as i32), "'=='");
+        assert_eq!(token_name(TokenType::Shr as i32), "'>>'");
+        assert_eq!(token_name(TokenType::While as i32), "keyword 'while'");
+        assert_eq!(token_name(TokenType::Id as i32), "identifier");
+        assert_eq!(token_name(TokenType::Str as i32), "string literal");
+
+        let cases = [
+            ("int main() { int x; x = 1 }", "Line 1: Expected ';', got '}'"),
+            ("int main() { int x; x = 1 x; }", "Line 1: Expected ';', got identifier 'x'"),
+            ("int main() { <<= 2; }", "Line 1: Unexpected '<<=' in expression"),
+            ("int main() { while (1 == ) ; }", "Line 1: Unexpected ')' in expression"),
+            ("int main() { return 1 + void; }", "Line 1: Unexpected keyword 'void' in expression"),
+            ("int main() { return 1 +", "Line 1: Unexpected end of input in expression"),
+            ("int main() { return \"a\" \"b\"; }", "Line 1: Expected ';', got string literal"),
+            ("int f(x) { return x; }", "Line 1: Type expected, got identifier 'x'"),
+            ("return 0;", "Line 1: Bad global declaration at keyword 'return'"),
+            ("int 5;", "Line 1: Bad global declaration at number"),
+            ("int main() { enum { A } == 1; }", "Line 1: Bad local declaration at '=='"),
+        ];
+        for (source, message) in cases {
+            let mut compiler = C4::new();
+            compiler.compile_and_run(source, 0, Vec::new());
+            assert_eq!(compiler.error.as_deref(), Some(message), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_repl() {
+        use c4_rust::repl::Repl;
+
+        let mut repl = Repl::default();
+        let value = |repl: &mut Repl, entry: &str| repl.eval(entry).unwrap().value;
+        assert_eq!(value(&mut repl, "int x;"), None);
+        assert_eq!(value(&mut repl, "int sq(int n) { return n * n; }"), None);
+        assert_eq!(value(&mut repl, "x = sq(4);"), None);
+        assert_eq!(value(&mut repl, "x + 1"), Some(17));
+        assert_eq!(value(&mut repl, "int twice() { return sq(x) * 2; }"), None);
+        assert_eq!(value(&mut repl, "twice()"), Some(512));
+        assert_eq!(repl.eval("printf(\"x is %d\\n\", x);").unwrap().output, "x is 16\n");
+
+        // A definition replaces the earlier one, in code compiled before it too
+        let evaluation = repl.eval("int sq(int n) { return n + n; }").unwrap();
+        assert_eq!(evaluation.warnings.len(), 1);
+        assert_eq!(evaluation.warnings[0].to_string(), "Line 1: warning: 'sq' replaces the earlier definition");
+        assert_eq!(value(&mut repl, "twice()"), Some(64));
+        assert_eq!(value(&mut repl, "int *p = &x;"), None);
+        assert_eq!(repl.eval("int x = 3;").unwrap().warnings.len(), 1);
+        assert_eq!(value(&mut repl, "twice()"), Some(12));
+        assert_eq!(value(&mut repl, "*p"), Some(3));
+        assert_eq!(repl.compiler().symbols.iter().filter(|s| s.name == "sq" || s.name == "x").count(), 2);
+
+        // An entry with an error changes nothing
+        let text = repl.compiler().text.clone();
+        assert_eq!(repl.eval("int y; int f( { }"), Err(Error::Compile("Line 1: Type expected, got '{'".to_string())));
+        assert_eq!(repl.eval("y").unwrap_err(), Error::Compile("Line 1: Undefined variable: y (did you mean 'x'?)".to_string()));
+        assert_eq!(repl.eval("x = missing();").unwrap_err(), Error::Compile("Line 1: Undefined variable: missing".to_string()));
+        assert_eq!(repl.compiler().text, text);
+        assert_eq!(value(&mut repl, "x"), Some(3));
+
+        // Macros, enums and hangs
+        assert_eq!(value(&mut repl, "#define N 10"), None);
+        assert_eq!(value(&mut repl, "enum { A = 5 };"), None);
+        assert_eq!(value(&mut repl, "N * A"), Some(50));
+        let sandbox = Sandbox { max_cycles: 1000, ..Sandbox::default() };
+        let mut repl = Repl::new(C4::builder().sandbox(sandbox).build());
+        assert!(matches!(repl.eval("while (1) ;"), Err(Error::Hang(hang)) if hang.function.as_deref() == Some("<entry>")));
+        assert_eq!(value(&mut repl, "1 + 1"), Some(2));
+
+        // An entry may span lines until its braces close
+        let mut out = Vec::new();
+        let input = "int f(int n) {\n  return n * 3;\n}\nf(5)\n";
+        Repl::default().interact(input.as_bytes(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "c4> ...> ...> c4> 15\nc4> ");
+    }
+
+    #[test]
+    fn test_scripts_with_argv() {
+        let source = "#!/usr/bin/env c4\nint main(int argc, char **argv) { int i; i = 0; while (i < argc) { printf(\"%s;\", argv[i]); i++; } return argc; }";
+        let args = vec!["script.c".to_string(), "hello".to_string(), "world".to_string()];
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            let program = compiler.compile(source).unwrap();
+            let data_len = program.data.len();
+            let outcome = compiler.run_program(&program, args.clone()).unwrap();
+            assert_eq!((outcome.exit_code, outcome.output.as_str()), (3, "script.c;hello;world;"));
+            assert_eq!(compiler.data.len(), data_len);
+
+            // The parameter count is kept in images, so argv still reaches main
+            let loaded = Program::from_image(&program.to_image()).unwrap();
+            let outcome = compiler.run_program(&loaded, args.clone()).unwrap();
+            assert_eq!(outcome.output, "script.c;hello;world;");
+        }
+
+        // A main taking only argc is called as before
+        let mut compiler = C4::new();
+        let program = compiler.compile("int main(int argc) { return argc; }").unwrap();
+        assert_eq!(compiler.run_program(&program, args).unwrap().exit_code, 3);
+    }
+
+    #[test]
+    fn test_exit_status_of_failures() {
+        // A program may return -1 itself, which is not a fault
+        let program = C4::new().compile("int main() { return -1; }").unwrap();
+        assert_eq!(program.run(Vec::new()).unwrap().exit_code, -1);
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut compiler = C4::builder().backend(backend).build();
+            let program = compiler.compile("int main() { int z; z = 0; return 1 / z; }").unwrap();
+            let error = compiler.run_program(&program, Vec::new()).unwrap_err();
+            assert!(matches!(&error, Error::Trap(_)));
+            assert_eq!(error.exit_status(), EXIT_TRAP);
+            if backend == Backend::Stack {
+                assert_eq!(error.to_string(), "Division by zero in DIV");
+            }
+
+            // The next run starts without the trap
+            let program = compiler.compile("int main() { return 4; }").unwrap();
+            assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 4);
+        }
+
+        let error = C4::new().compile("int main() { return 1 +; }").unwrap_err();
+        assert_eq!(error.exit_status(), EXIT_COMPILE_ERROR);
+        assert_eq!(Error::NoMain.exit_status(), EXIT_COMPILE_ERROR);
+        assert_ne!(EXIT_COMPILE_ERROR, EXIT_TRAP);
+    }
+
+    #[test]
+    fn test_colored_diagnostics() {
+        use c4_rust::diagnostics::render;
+
+        let plain = render(Severity::Error, 3, None, "Undefined variable: x (did you mean 'y'?)", false);
+        assert_eq!(plain, "Line 3: Undefined variable: x (did you mean 'y'?)");
+        let colored = render(Severity::Error, 3, None, "Undefined variable: x (did you mean 'y'?)", true);
+        assert_eq!(colored, "\x1b[1;31mLine 3:\x1b[0m Undefined variable: x (did you mean \x1b[1m'y'\x1b[0m?)");
+        let colored = render(Severity::Warning, 2, Some("util.h"), "unused 'n'", true);
+        assert_eq!(colored, "\x1b[1;33mLine 2 of util.h:\x1b[0m\x1b[1;33m warning:\x1b[0m unused \x1b[1m'n'\x1b[0m");
+
+        // Colors only ever reach stderr, never the messages kept by the compiler
+        let mut compiler = C4::builder().color(ColorChoice::Always).build();
+        assert!(compiler.color());
+        compiler.compile("int main() { char c; c = 300; return x; }").unwrap_err();
+        assert_eq!(compiler.error.as_deref(), Some("Line 1: Undefined variable: x (did you mean 'c'?)"));
+        let warning = &compiler.warnings[0];
+        assert_eq!(warning.to_string(), warning.render(false));
+        assert!(warning.render(true).starts_with("\x1b[1;33mLine 1:"));
+        assert!(!C4::builder().color(ColorChoice::Never).build().color());
+    }
+
+    #[test]
+    fn test_warnings_as_errors() {
+        let source = "int main() { char c; int *p; c = 300; p = 5; return 0; }";
+        let program = C4::new().compile(source);
+        assert!(program.is_ok());
+
+        let mut compiler = C4::builder().warnings_as_errors(true).build();
+        let error = compiler.compile(source).unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: Conversion from 'int' to 'char' may change the value [-Werror=conversion]");
+        assert_eq!(compiler.warnings.len(), 2);
+
+        // Only the kinds asked for
+        let mut compiler = C4::builder().warning_as_error(WarningKind::IntConversion).error_limit(1).build();
+        let error = compiler.compile(source).unwrap_err();
+        assert!(error.to_string().ends_with("without a cast [-Werror=int-conversion]"));
+        assert_eq!(compiler.diagnostic_options().error_limit, 1);
+        assert!(C4::builder().warning_as_error(WarningKind::Redefinition).build().compile(source).is_ok());
+        assert!(C4::builder().warnings_as_errors(true).warnings_as_errors(false).build().compile(source).is_ok());
+
+        assert_eq!(WarningKind::from_name("int-conversion"), Some(WarningKind::IntConversion));
+        assert!(WarningKind::ALL.iter().all(|&kind| WarningKind::from_name(kind.name()) == Some(kind)));
+        assert_eq!(WarningKind::from_name("bogus"), None);
+
+        use c4_rust::diagnostics::summary;
+        assert_eq!(summary(0, 0), None);
+        assert_eq!(summary(1, 0).as_deref(), Some("1 error generated"));
+        assert_eq!(summary(0, 3).as_deref(), Some("3 warnings generated"));
+        assert_eq!(summary(2, 1).as_deref(), Some("2 errors and 1 warning generated"));
+    }
+
+    #[test]
+    fn test_discarded_values() {
+        let source = "int main() {\n  int a, b;\n  a = 1; b = 2;\n  a == b;\n  a++;\n  b--;\n  \"text\";\n  return a * 10 + b;\n}";
+        let mut compiler = C4::builder().opt_level(0).build();
+        let program = compiler.compile(source).unwrap();
+        assert_eq!(program.run(Vec::new()).unwrap().exit_code, 21);
+
+        let kinds: Vec<(i32, WarningKind)> = compiler.warnings.iter().map(|w| (w.span.line, w.kind)).collect();
+        assert_eq!(kinds, [(4, WarningKind::UnusedValue), (7, WarningKind::UnusedValue)]);
+        assert_eq!(compiler.warnings[0].to_string(), "Line 4: warning: Statement has no effect");
+        assert_eq!((compiler.warnings[0].span.column, compiler.warnings[0].span.end_column), (3, 9));
+
+        // Neither the comparison nor the steps undoing the increments are left
+        let ops: Vec<Instruction> = program.instructions().map(|instr| instr.op).collect();
+        assert!(!ops.contains(&Instruction::EQ));
+        assert_eq!(ops.iter().filter(|&&op| op == Instruction::SUB).count(), 1);
+        assert_eq!(ops.iter().filter(|&&op| op == Instruction::ADD).count(), 2);
+        // The string's address went with its code, so no relocation points past the end
+        assert!(Program::from_image(&program.to_image()).is_ok());
+
+        // Calls, stores and divisions are kept, as is a postfix step used as a value
+        let source = "int f(int x) { return x; } int main() { int a; a = 3; f(a); 1 / a; a = a++ + 1; return a; }";
+        let mut compiler = C4::builder().opt_level(0).build();
+        let program = compiler.compile(source).unwrap();
+        assert!(compiler.warnings.is_empty());
+        assert_eq!(program.run(Vec::new()).unwrap().exit_code, 4);
+        assert!(program.instructions().any(|instr| instr.op == Instruction::DIV));
+    }
+
+    #[test]
+    fn test_stack_checking() {
+        // The verifier's model agrees with the VM on calls, inlined calls,
+        // tail calls, callbacks and whatever else the generator produces
+        let source = "int cmp(int *a, int *b) { return *a - *b; }\n\
+            int sq(int x) { return x * x; }\n\
+            int count(int n, int acc) { if (n == 0) return acc; return count(n - 1, acc + 1); }\n\
+            int main() { int *v; v = malloc(3 * sizeof(int)); v[0] = 3; v[1] = 1; v[2] = 2;\n\
+            qsort(v, 3, sizeof(int), cmp); printf(\"%d %d\\n\", v[0], sq(v[2])); return count(5, 0); }";
+        for opt_level in [0, 1] {
+            let mut compiler = C4::builder().opt_level(opt_level).check_stack(true).build();
+            let program = compiler.compile(source).unwrap();
+            let outcome = compiler.run_program(&program, Vec::new()).unwrap();
+            assert_eq!((outcome.exit_code, outcome.output.as_str()), (5, "1 9\n"));
+        }
+        for seed in 0..20 {
+            let mut compiler = C4::builder().check_stack(true).build();
+            let program = compiler.compile(&generate::generate(seed)).unwrap();
+            assert!(compiler.run_program(&program, Vec::new()).is_ok(), "seed {}", seed);
+        }
+
+        // A frame that is not as expected traps at the instruction that sees it
+        let program = C4::builder().opt_level(0).build().compile("int main() { int a; a = 2; return a + 3; }").unwrap();
+        let functions: Vec<(String, i32)> = program.symbols().iter().map(|s| (s.name.clone(), s.address)).collect();
+        let mut depths = c4_rust::verify::frame_depths(&program.text, &functions).unwrap();
+        let entry = program.entry().unwrap() as usize;
+        assert_eq!(depths[entry], None);
+        assert_eq!(depths[entry + 2], Some(1));
+        let add = program.instructions().find(|instr| instr.op == Instruction::ADD).unwrap().pc;
+        assert_eq!(depths[add], Some(2));
+        depths[add] = Some(1);
+
+        let mut host = RecordingHost { input: Vec::new(), output: Vec::new(), reports: Vec::new() };
+        let mut machine = Machine::new(program.text.clone(), program.data.clone(), VmOptions::default(), &mut host);
+        machine.frame_depths = depths;
+        assert_eq!(machine.run(entry as i32, 0), -1);
+        assert_eq!(machine.trap.as_deref(), Some(format!("Unbalanced stack at {}: 2 words in the frame, 1 expected", add).as_str()));
+    }
+
+    #[test]
+    fn test_frame_offsets() {
+        // Parameters sit above the saved bp and return address, the last
+        // nearest; locals from bp down, an array at its lowest word
+        let source = "int f(int a, int b, int c) { int x; int v[3]; int y;\n\
+            x = a; v[0] = b; v[2] = c; y = v[0] + v[2];\n\
+            a = y * 10; c = x; return a + c; }\n\
+            int main() { return f(1, 2, 3); }";
+        for word_size in [4, 8] {
+            let mut compiler = C4::builder().opt_level(0).word_size(word_size).build();
+            let program = compiler.compile(source).unwrap();
+            assert_eq!(compiler.run_program(&program, Vec::new()).unwrap().exit_code, 51, "word size {}", word_size);
+
+            let entry = program.symbols().iter().find(|s| s.name == "f").unwrap().address as usize;
+            let mut offsets: Vec<i32> = program.instructions()
+                .skip_while(|instr| instr.pc < entry)
+                .take_while(|instr| instr.pc == entry || instr.op != Instruction::ENT)
+                .filter(|instr| instr.op == Instruction::LEA)
+                .map(|instr| instr.operand.unwrap())
+                .collect();
+            offsets.sort_unstable();
+            offsets.dedup();
+            assert_eq!(offsets, [-4, -3, 0, 3, 4, 5], "word size {}", word_size);
+        }
+    }
+
+    #[test]
+    fn test_global_layout() {
+        let source = "char flag; int count; char name[5]; int *table[3]; char *message;\n\
+                      int main() { name[4] = 'x'; flag = 1; return 0; }";
+        for word_size in [4, 8] {
+            let mut compiler = C4::builder().word_size(word_size).build();
+            let program = compiler.compile(source).unwrap();
+            let layout = |program: &Program| -> Vec<(String, i32, usize, usize, usize)> {
+                program.symbols().into_iter()
+                    .filter(|s| s.kind == SymbolKind::Global)
+                    .map(|s| (s.name, s.address, s.size, s.element_size, s.align))
+                    .collect()
+            };
+            let (w, base) = (word_size, c4_rust::vm::NULL_GUARD);
+            assert_eq!(layout(&program), [
+                ("flag".to_string(), base as i32, w, 1, w),
+                ("count".to_string(), (base + w) as i32, w, w, w),
+                ("name".to_string(), (base + 2 * w) as i32, 5, 1, w),
+                ("table".to_string(), (base + 2 * w + 5usize.next_multiple_of(w)) as i32, 3 * w, w, w),
+                ("message".to_string(), (base + 5 * w + 5usize.next_multiple_of(w)) as i32, w, w, w),
+            ], "word size {}", w);
+            let main = program.symbol("main").unwrap();
+            assert_eq!((main.element_size, main.align), (0, 1));
+
+            // Array lengths survive an image
+            let loaded = Program::from_image(&program.to_image()).unwrap();
+            assert_eq!(layout(&loaded), layout(&program));
+        }
+
+        // An image may not put a global off a word or past the data segment
+        let image = C4::new().compile("int pad; int v[2]; int main() { return 0; }").unwrap().to_image();
+        let at = image.windows(5).position(|w| w == [1, 0, 0, 0, b'v']).unwrap() + 5;
+        let mut misaligned = image.clone();
+        misaligned[at + 8] -= 2;
+        assert_eq!(Program::from_image(&misaligned).unwrap_err(), Error::Image("global 'v' at 10 is not word-aligned".to_string()));
+        let mut overlong = image.clone();
+        overlong[at + 16] = 3;
+        assert_eq!(Program::from_image(&overlong).unwrap_err(), Error::Image("symbol 'v' at 12 is outside its segment".to_string()));
+    }
+
+    #[test]
+    fn test_read_only_data() {
+        let run = |source: &str, check: bool| {
+            let mut compiler = C4::builder().check_writes(check).build();
+            let program = compiler.compile(source).unwrap();
+            compiler.run_program(&program, Vec::new()).map(|outcome| outcome.exit_code)
+        };
+        let trap = |source: &str| match run(source, true) {
+            Err(Error::Trap(error)) => error.message,
+            other => panic!("run did not trap: {:?}", other),
+        };
+
+        // Literals and const globals may be read, and a pointer to const chars assigned
+        let source = "const int limit = 3; const char *name; int count;\n\
+                      int main() { char *s; s = \"abc\"; name = s; count = limit; return s[1] + count + limit; }";
+        assert_eq!(run(source, true), Ok(104));
+
+        // Storing to them traps, but only when checked
+        let literal = "int main() { char *s; s = \"abc\"; s[1] = 'x'; return s[1]; }";
+        assert_eq!(run(literal, false), Ok('x' as i32));
+        assert_eq!(trap(literal), "Write to read-only memory at 9 in SC");
+        let global = "int before; const int limit = 3; int main() { int *p; p = &limit; *p = 4; return limit; }";
+        assert_eq!(run(global, false), Ok(4));
+        assert_eq!(trap(global), "Write to read-only memory at 12 in SI");
+        assert_eq!(trap("int main() { sprintf(\"abc\", \"%d\", 12); return 0; }"), "Buffer overflow in SPRINTF");
+
+        // The ranges are kept in an image, and moved with the data segment
+        let mut compiler = C4::builder().check_writes(true).build();
+        let program = Program::from_image(&compiler.compile(global).unwrap().to_image()).unwrap();
+        assert!(matches!(compiler.run_program(&program, Vec::new()), Err(Error::Trap(_))));
+        assert_eq!(program.read_only(), [(12, 16)]);
+        let mut relocated = program.clone();
+        relocated.relocate(0, 8);
+        assert_eq!(relocated.read_only(), [(20, 24)]);
+
+        // Only globals are kept from being written
+        assert_eq!(run("int f(const char *s) { const int n = 2; return s[n]; } int main() { return f(\"abc\"); }", true), Ok('c' as i32));
+
+        // Assigning a const variable by name is a compile error, but a pointer to const chars may be assigned
+        let error = |source: &str| C4::new().compile(source).err();
+        let rejected = [
+            ("int main() { const int x = 5; x = 6; return x; }", "x"),
+            ("const int g = 1; int main() { g += 1; return g; }", "g"),
+            ("int main() { const char c = 'a'; c++; return c; }", "c"),
+            ("const int g; int main() { --g; return g; }", "g"),
+            ("int main() { const int x = 5; (x) = 6; return x; }", "x"),
+        ];
+        for (source, name) in rejected {
+            let message = format!("Line 1: Cannot assign to const variable '{}'", name);
+            assert_eq!(error(source), Some(Error::Compile(message)), "{}", source);
+        }
+        assert_eq!(run("int main() { const char *s; const int x = 3; int *p; s = \"ab\"; p = &x; return s[1] + x; }", false), Ok('b' as i32 + 3));
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let source = "int total; int main() { int *block; block = malloc(40); total = total + 5; free(block); return total; }";
+        let program = C4::new().compile(source).unwrap();
+        let entry = program.entry().unwrap();
+        let total = program.symbol("total").unwrap().address as Word;
+        let options = VmOptions { stack_words: 64, ..VmOptions::default() };
+        let mut host = RecordingHost { input: Vec::new(), output: Vec::new(), reports: Vec::new() };
+        let mut machine = Machine::new(program.text.clone(), program.data.clone(), options, &mut host);
+        let map = machine.memory_map();
+        assert_eq!(map.text, Region { kind: RegionKind::Text, start: 0, size: program.text.len(), used: program.text.len() });
+        assert_eq!(map.data, Region { kind: RegionKind::Data, start: 0, size: program.data.len(), used: program.data.len() });
+        assert_eq!((map.heap.start, map.heap.size, map.stack.size), (program.data.len() as Word, 0, 0));
+
+        // The heap is given back at the end of a run, and the frames popped
+        // down to argc and the return address
+        assert_eq!(machine.run(entry, 0), 5);
+        let map = machine.memory_map();
+        assert_eq!((map.heap.size, map.heap.used), (0, 0));
+        assert_eq!(map.stack, Region { kind: RegionKind::Stack, start: STACK_BASE, size: 67 * 4, used: 2 * 4 });
+        assert_eq!(map.region_of(total), Some(RegionKind::Data));
+        assert_eq!(map.region_of(STACK_BASE + 8), Some(RegionKind::Stack));
+        assert_eq!(map.region_of(program.data.len() as Word), None);
+        assert!(map.to_string().starts_with(&format!("text   0x00000000 {:>10} words", program.text.len())));
+
+        // Globals and the stack can be read and written from outside
+        assert_eq!(machine.read_bytes(total, 4), Some(vec![5, 0, 0, 0]));
+        assert_eq!(machine.write_bytes(total, &[40, 0]), Some(()));
+        assert_eq!(machine.run(entry, 0), 45);
+        assert_eq!(machine.write_bytes(STACK_BASE + 1, &[7]), Some(()));
+        assert_eq!(machine.read_bytes(STACK_BASE, 2), Some(vec![machine.stack[0] as u8, 7]));
+
+        // Nothing is written unless all of it fits
+        let end = program.data.len() as Word;
+        assert_eq!(machine.write_bytes(end - 2, &[1, 2, 3]), None);
+        assert_eq!(machine.read_bytes(end - 2, 2), Some(program.data[program.data.len() - 2..].to_vec()));
+        assert_eq!(machine.read_bytes(-1, 1), None);
+    }
+
+    #[test]
+    fn test_trap_observer() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let run = |source: &str, backend: Backend, bounds_checks: bool| {
+            let seen = errors.clone();
+            let mut compiler = C4::builder()
+                .opt_level(0)
+                .backend(backend)
+                .bounds_checks(bounds_checks)
+                .on_trap(move |error| seen.borrow_mut().push(error.clone()))
+                .build();
+            let program = compiler.compile(source).unwrap();
+            let outcome = compiler.run_program(&program, Vec::new());
+            (program, outcome, errors.borrow_mut().pop())
+        };
+
+        // The fault is described in full, on either backend
+        let source = "int f(int x) { return 10 / x; }\nint main() { return f(0); }";
+        for backend in [Backend::Stack, Backend::Register] {
+            let (program, outcome, error) = run(source, backend, false);
+            let error = error.unwrap();
+            assert_eq!(outcome, Err(Error::Trap(error.clone())));
+            assert_eq!(error.backtrace, ["f", "main"]);
+            assert!(error.registers.sp <= error.registers.bp);
+            assert!(error.listing.contains(&format!("> {:6}: ", error.pc)));
+            assert!(error.to_string().starts_with(&format!("{} at pc {} after", error.message, error.pc)));
+            if backend == Backend::Stack {
+                assert_eq!(program.text[error.pc as usize], Instruction::DIV as i32);
+                assert_eq!(error.kind, TrapKind::DivisionByZero);
+            } else {
+                // The register backend fuses the DIV with the loads of its operands
+                let div = program.text.iter().rposition(|&op| op == Instruction::DIV as i32).unwrap();
+                assert!(program.function_entry("f").unwrap() < error.pc && error.pc < div as i32);
+                assert_eq!(error.kind, TrapKind::Fault);
+            }
+        }
+
+        // The fault goes to the callback alone, or else to the error stream
+        let mut quiet = C4::builder().opt_level(0).on_trap(|_| ()).build();
+        assert_eq!(quiet.compile_and_run(source, 0, Vec::new()), -1);
+        assert_eq!(quiet.get_captured_error(), "");
+        let mut reported = C4::builder().opt_level(0).build();
+        assert_eq!(reported.compile_and_run(source, 0, Vec::new()), -1);
+        assert_eq!(reported.get_captured_error(), "Division by zero in DIV\n");
+
+        // A fault in a callback is put down to the callback, whose backtrace
+        // ends at the builtin that called it
+        let source = "int cmp(int *a, int *b) { return *a / (*b - *b); }\n\
+                      int main() { int *v; v = malloc(2 * sizeof(int)); v[0] = 2; v[1] = 1; qsort(v, 2, sizeof(int), cmp); return 0; }";
+        let (_, _, error) = run(source, Backend::Stack, false);
+        assert_eq!(error.unwrap().backtrace, ["cmp"]);
+
+        let kind = |source: &str, bounds_checks: bool| run(source, Backend::Stack, bounds_checks).2.map(|error| error.kind);
+        assert_eq!(kind("int main() { assert(1 == 2); return 0; }", false), Some(TrapKind::Assertion));
+        assert_eq!(kind("int main() { int a[2]; int i; i = 2; return a[i]; }", true), Some(TrapKind::BoundsCheck));
+        assert_eq!(kind("int main() { free(8); return 0; }", false), Some(TrapKind::InvalidArgument));
+        assert_eq!(kind("int main() { int *p; p = 0 - 8; return *p; }", false), Some(TrapKind::MemoryAccess));
+        assert_eq!(kind("int main() { return 0; }", false), None);
+    }
+
+    #[test]
+    fn test_isa_reference() {
+        let rows = isa::instructions();
+        assert_eq!(rows.len(), Instruction::ALL.len());
+        assert_eq!(rows[Instruction::ADD as usize].stack_effect, "-1");
+        assert_eq!(rows[Instruction::PUSH as usize].stack_effect, "+1");
+        assert_eq!(rows[Instruction::ADJ as usize].stack_effect, "-n");
+        assert_eq!(rows[Instruction::JMP as usize].operand, isa::Operand::TextAddress);
+        assert_eq!(rows[Instruction::LEA as usize].operand, isa::Operand::FrameOffset);
+        assert_eq!(rows[Instruction::LI as usize].operand, isa::Operand::None);
+
+        // Operators pop one word, and branches go to a text address
+        for op in Instruction::ALL {
+            if op.is_binary() {
+                assert_eq!((op.operands(), op.stack_effect()), (0, Some(-1)), "{}", op.name());
+            }
+            if op.is_branch() {
+                assert_eq!(rows[op as usize].operand, isa::Operand::TextAddress, "{}", op.name());
+            }
+        }
+        assert_eq!(Instruction::ALL.iter().filter(|op| op.is_binary()).count(), 16);
+
+        // One row per instruction, besides the heading and the rule
+        let markdown = isa::markdown();
+        assert_eq!(markdown.lines().count(), Instruction::ALL.len() + 2);
+        assert!(markdown.contains("| 25 | ADD |  | -1 | Pop a, ax = a + ax |"), "{}", markdown);
+        assert!(markdown.contains("a \\| ax"));
+
+        let html = isa::html();
+        assert_eq!(html.matches("<tr>").count(), Instruction::ALL.len() + 1);
+        assert!(html.contains("<td>LT</td><td></td><td>-1</td><td>Pop a, ax = a &lt; ax</td>"), "{}", html);
+
+        // Instructions the VM lacks are marked, trap on both backends, and
+        // are not what any builtin compiles to
+        assert!(markdown.contains("| 36 | MCMP |  | 0 | Not implemented, traps: memcmp(a, b, count) |"), "{}", markdown);
+        assert!(markdown.contains("| 35 | MSET |  | 0 | memset(dest, value, count) |"), "{}", markdown);
+        for op in Instruction::ALL.into_iter().filter(|op| !op.is_implemented()) {
+            for backend in [Backend::Stack, Backend::Register] {
+                let mut compiler = C4::builder().backend(backend).build();
+                compiler.text = vec![Instruction::ENT as i32, 0, op as i32, Instruction::LEV as i32].into();
+                match compiler.run(0, 0, Vec::new()) {
+                    Err(Error::Trap(error)) => assert_eq!(error.kind, TrapKind::InvalidCode, "{} {:?}", op.name(), backend),
+                    other => panic!("{} did not trap on {:?}: {:?}", op.name(), backend, other),
+                }
+            }
+        }
+        let mut compiler = C4::new();
+        compiler.init_builtins();
+        let builtins: Vec<&Symbol> = compiler.symbols.iter().filter(|s| s.class == TokenType::Sys as i32).collect();
+        assert!(builtins.iter().all(|s| Instruction::from_opcode(s.value).is_some_and(Instruction::is_implemented)));
+    }
+
+    #[test]
+    fn test_instruction_metadata() {
+        assert_eq!(Instruction::IMM.operands(), 1);
+        assert_eq!(Instruction::BOUND.operands(), 1);
+        assert_eq!(Instruction::LI.operands(), 0);
+        assert_eq!(Instruction::PUSH.stack_effect(), Some(1));
+        assert_eq!(Instruction::SC.stack_effect(), Some(-1));
+        assert_eq!(Instruction::FDIV.stack_effect(), Some(-1));
+        assert_eq!(Instruction::JSR.stack_effect(), Some(0));
+        assert_eq!(Instruction::PRINTF.stack_effect(), Some(0));
+        assert_eq!(Instruction::ADJ.stack_effect(), None);
+        assert_eq!(Instruction::LEV.stack_effect(), None);
+
+        // The decoder and the verifier follow the same widths
+        let text = [Instruction::IMM as i32, 7, Instruction::PUSH as i32, Instruction::LI as i32];
+        let widths: Vec<usize> = [0, 2, 3].iter().map(|&pc| DecodedInstr::decode(&text, pc).unwrap().words()).collect();
+        assert_eq!(widths, [2, 1, 1]);
+        assert_eq!(optimizer::instruction_starts(&text), [0, 2, 3]);
+    }
+
+    #[test]
+    fn test_emitter() {
+        // if (x) x = 1; with x the first local
+        let mut text = Emitter::new();
+        text.emit_with(Instruction::ENT, 1);
+        text.emit_with(Instruction::LEA, -1);
+        text.emit(Instruction::LI);
+        let skip = text.reserve_jump(Instruction::BZ);
+        assert_eq!(skip.address(), 5);
+        text.emit_with(Instruction::LEA, -1);
+        text.emit(Instruction::PUSH);
+        let operand = text.emit_with(Instruction::IMM, 1);
+        assert_eq!(operand, 11);
+        text.emit(Instruction::SI);
+        text.patch(skip, text.here());
+        text.emit_with(Instruction::IMM, 0);
+        text.emit(Instruction::LEV);
+
+        let ops: Vec<Instruction> = decode(&text).map(|instruction| instruction.op).collect();
+        assert_eq!(ops, [
+            Instruction::ENT, Instruction::LEA, Instruction::LI, Instruction::BZ, Instruction::LEA,
+            Instruction::PUSH, Instruction::IMM, Instruction::SI, Instruction::IMM, Instruction::LEV,
+        ]);
+        assert_eq!(text[6], 13);
+        assert_eq!(verify::verify(&text, &[("f".to_string(), 0)]), Ok(()));
+        assert_eq!(text.into_text().len(), 16);
+    }
+
+    #[test]
+    fn test_emitter_rewrites() {
+        // f(2) as the last thing g does, with f called before it is defined
+        let mut text = Emitter::new();
+        text.emit_with(Instruction::ENT, 0);
+        text.emit_with(Instruction::IMM, 2);
+        text.emit(Instruction::PUSH);
+        let call = text.here();
+        let callee = text.emit_with(Instruction::JSR, 0);
+        text.emit_with(Instruction::ADJ, 1);
+        text.emit(Instruction::LEV);
+
+        let jump = text.tail_call(call, 1);
+        assert_eq!(text[call..call + 4], [Instruction::TLEV as i32, 1, Instruction::JMP as i32, 0]);
+        assert_eq!((callee, jump), (call + 1, call + 3));
+        text.set_operand(jump, 20);
+        text.set_operand(1, 3);
+        assert_eq!((text[1], text[jump]), (3, 20));
+
+        // A redefinition jumps from the old entry to the new one
+        text.redirect(0, 30);
+        assert_eq!(text[..2], [Instruction::JMP as i32, 30]);
+    }
+
+    #[test]
+    fn test_emitter_labels() {
+        // while (x) x = x - 1; with x the first local
+        let mut text = Emitter::new();
+        text.emit_with(Instruction::ENT, 1);
+        let (start, end) = (text.new_label(), text.new_label());
+        text.bind(start);
+        text.emit_with(Instruction::LEA, -1);
+        text.emit(Instruction::LI);
+        text.jump_to(Instruction::BZ, end);
+        assert_eq!(text.unbound_jump(), Some(5));
+        text.emit_with(Instruction::LEA, -1);
+        text.emit(Instruction::PUSH);
+        text.emit_with(Instruction::LEA, -1);
+        text.emit(Instruction::LI);
+        text.emit(Instruction::PUSH);
+        text.emit_with(Instruction::IMM, 1);
+        text.emit(Instruction::SUB);
+        text.emit(Instruction::SI);
+        text.jump_to(Instruction::JMP, start);
+        text.bind(end);
+        text.emit(Instruction::LEV);
+
+        assert_eq!(text.unbound_jump(), None);
+        assert_eq!((text[6], text[19]), (20, 2));
+        assert_eq!(verify::verify(&text, &[("f".to_string(), 0)]), Ok(()));
+
+        // A jump left unbound is dropped with the code it is in
+        let end = text.new_label();
+        let len = text.here();
+        text.jump_to(Instruction::JMP, end);
+        assert_eq!(text.unbound_jump(), Some(len));
+        text.truncate(len);
+        assert_eq!(text.unbound_jump(), None);
+
+        // The code generator binds every label it jumps to
+        let mut compiler = C4::new();
+        let program = compiler.compile("int main() { int x; x = 3; while (x) { if (x > 1 && x < 3) x = x - 1; else x = x ? x - 1 : 0; } return x; }");
+        assert!(program.is_ok());
+        assert_eq!(compiler.text.unbound_jump(), None);
+    }
+
+    #[test]
+    fn test_compile_function() {
+        fn listing(source: &str) -> Vec<String> {
+            let code = C4::new().compile_function(source).unwrap();
+            code.iter().map(|instruction| format!("{} {}", instruction.pc, instruction)).collect()
+        }
+
+        assert_eq!(listing("int f(int a) { int x; x = a + 1; return x; }"), [
+            "0 ENT 1", "2 LEA 0", "4 PUSH", "5 LEA 3", "7 LI", "8 PUSH", "9 IMM 1", "11 ADD", "12 SI",
+            "13 LEA 0", "15 LI", "16 LEV",
+        ]);
+        assert_eq!(listing("int f(int a) { if (a) return 1; else return 2; }"), [
+            "0 ENT 0", "2 LEA 3", "4 LI", "5 BZ 12", "7 IMM 1", "9 LEV", "10 JMP 15", "12 IMM 2", "14 LEV",
+        ]);
+        assert_eq!(listing("int f(int a) { while (a) a = a - 1; return a; }"), [
+            "0 ENT 0", "2 LEA 3", "4 LI", "5 BZ 20", "7 LEA 3", "9 PUSH", "10 LEA 3", "12 LI", "13 PUSH",
+            "14 IMM 1", "16 SUB", "17 SI", "18 JMP 2", "20 LEA 3", "22 LI", "23 LEV",
+        ]);
+
+        // Only the last function is given back; globals and calls are in place
+        assert_eq!(listing("int g; int h(int x) { return x; } int f() { g = h(2); return g; }"), [
+            "6 ENT 0", "8 IMM 8", "10 PUSH", "11 IMM 2", "13 PUSH", "14 JSR 0", "16 ADJ 1", "18 SI",
+            "19 IMM 8", "21 LI", "22 LEV",
+        ]);
+
+        // The compiler's own settings are left as they were
+        let mut compiler = C4::new();
+        assert!(matches!(compiler.compile_function("int g;"), Err(Error::Compile(_))));
+        assert!(matches!(compiler.compile_function("int f() { return x; }"), Err(Error::Compile(_))));
+        assert_eq!(compiler.opt_level(), 1);
+    }
+
+    #[test]
+    fn test_tail_call_keeps_frame_with_address_taken() {
+        // The callee's frame would overwrite x if the call reused f's
+        let source = "int g(int *p) { int a, b, c; a = 1; b = 1; c = 1; return *p; }
+                      int f(int n) { int x; x = 42; return g(&x); }
+                      int h(int n) { int *p; p = &n; return g(p); }
+                      int main() { return f(0) + h(7); }";
+        let mut compiler = C4::new();
+        assert_eq!(compiler.opt_level(), 1);
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 49);
+        assert!(!compiler.text.contains(&(Instruction::TLEV as i32)));
+
+        // A call that only loads its arguments still becomes a jump
+        let tail = "int g(int n) { return n; } int f(int n) { int x; x = n; return g(x); } int main() { return f(5); }";
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run(tail, 0, Vec::new()), 5);
+        assert!(compiler.text.contains(&(Instruction::TLEV as i32)));
+    }
+
+    #[test]
+    fn test_signed_char_division() {
+        let source = r#"int main() { char c; c = -5; printf("%d %d\n", c / 4, c % 16); return 0; }"#;
+        let mut compiler = C4::new();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 0);
+        assert_eq!(compiler.get_captured_output(), "-1 -5\n");
+
+        let mut compiler = C4::builder().signed_char(false).build();
+        assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 0);
+        assert_eq!(compiler.get_captured_output(), "62 11\n");
+    }
+
+    #[test]
+    fn test_frame_operands_out_of_range() {
+        use c4_rust::verify::{verify, MAX_FRAME_WORDS};
+        let (ent, ient, adj, ilev, tlev, imm, lev) = (Instruction::ENT as i32, Instruction::IENT as i32, Instruction::ADJ as i32,
+                                                       Instruction::ILEV as i32, Instruction::TLEV as i32, Instruction::IMM as i32,
+                                                       Instruction::LEV as i32);
+        let main = [("main".to_string(), 0)];
+        for (op, name) in [(ent, "ENT"), (ient, "IENT"), (adj, "ADJ"), (ilev, "ILEV"), (tlev, "TLEV")] {
+            for operand in [-1, MAX_FRAME_WORDS + 1, i32::MIN, i32::MAX] {
+                let text = if op == ent { vec![ent, operand, lev] } else { vec![ent, 0, op, operand, lev] };
+                let error = verify(&text, &main).unwrap_err();
+                assert_eq!(error.message, format!("{} operand {} is not between 0 and {}", name, operand, MAX_FRAME_WORDS));
+            }
+        }
+
+        // Run unverified, the same operands trap instead of overflowing
+        for backend in [Backend::Stack, Backend::Register] {
+            for text in [vec![ent, i32::MAX, imm, 1, lev], vec![ent, -5, imm, 1, lev],
+                         vec![ent, 0, adj, i32::MAX, lev], vec![ent, 0, adj, i32::MIN, lev],
+                         vec![ent, 0, tlev, i32::MAX], vec![ent, 0, ient, i32::MIN, ilev, i32::MAX]] {
+                let mut compiler = C4::builder().backend(backend).build();
+                compiler.text = text.clone().into();
+                assert!(matches!(compiler.run(0, 0, Vec::new()), Err(Error::Trap(_))), "{:?} {:?}", backend, text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_null_pointer_is_no_object() {
+        for backend in [Backend::Stack, Backend::Register] {
+            // The first literal and the first global are not at address 0
+            let mut compiler = C4::builder().backend(backend).build();
+            let source = "int g; int main() { char *s; int *p; s = \"abc\"; p = &g; return (s == 0) + !s + (p == 0) + !p; }";
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 0, "{:?}", backend);
+
+            // Loads and stores through null trap instead of reaching a global
+            let run = |source: &str| {
+                let mut compiler = C4::builder().backend(backend).build();
+                let program = compiler.compile(source).unwrap();
+                compiler.run_program(&program, Vec::new()).map(|outcome| outcome.exit_code)
+            };
+            assert!(matches!(run("int g; int main() { int *p; p = 0; *p = 7; return g; }"), Err(Error::Trap(_))), "{:?}", backend);
+            assert!(matches!(run("int g; int main() { int *p; g = 7; p = 0; return *p; }"), Err(Error::Trap(_))), "{:?}", backend);
+            assert!(matches!(run("int main() { char *s; s = 0; return s[1]; }"), Err(Error::Trap(_))), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn test_program_keeps_char_signedness() {
+        let source = "int main() { char c; c = -5; return c; }";
+        let signed = C4::new().compile(source).unwrap();
+        assert!(signed.is_signed_char());
+        assert_eq!(signed.to_image()[12..16], [0, 0, 0, 0]);
+
+        // A program compiled for unsigned chars runs with them, whatever
+        // the compiler running it or the image it went through says
+        let unsigned = C4::builder().signed_char(false).build().compile(source).unwrap();
+        assert!(!unsigned.is_signed_char());
+        assert_eq!(C4::new().run_program(&unsigned, Vec::new()).unwrap().exit_code, 251);
+        let image = unsigned.to_image();
+        assert_eq!(image[12..16], [2, 0, 0, 0]);
+        let loaded = Program::from_image(&image).unwrap();
+        assert!(!loaded.is_signed_char());
+        assert_eq!(loaded.run(Vec::new()).unwrap().exit_code, 251);
+        assert_eq!(C4::builder().signed_char(false).build().run_program(&signed, Vec::new()).unwrap().exit_code, -5);
+
+        let mut unknown = image.clone();
+        unknown[12] = 4;
+        assert_eq!(Program::from_image(&unknown).unwrap_err(), Error::Image("unknown flags 0x4 in the header".to_string()));
+    }
+
+    #[test]
+    fn test_string_functions_compare_bytes_unsigned() {
+        // "é" is the bytes 0xC3 0xA9, which sort after ASCII
+        let source = r#"
+            #include <string.h>
+            int main() {
+                char *s;
+                s = "xé";
+                printf("%d %d %d %d %d\n", strcmp("é", "abc") > 0, strcmp("abc", "é") < 0, strncmp("é", "e", 1) > 0,
+                       memcmp("é", "a", 1) > 0, strchr(s, 195) - s);
+                return strchr(s, 169) - s;
+            }"#;
+        for signed in [true, false] {
+            let mut compiler = C4::builder().signed_char(signed).build();
+            assert_eq!(compiler.compile_and_run(source, 0, Vec::new()), 2, "signed {}", signed);
+            assert_eq!(compiler.get_captured_output(), "1 1 1 1 1\n", "signed {}", signed);
+        }
+    }
 }
@@ -0,0 +1,211 @@
+//! # Command Line
+//!
+//! The `c4_rust` command, which compiles and runs a program, or saves it as
+//! an image, with the options of the [`C4Builder`] it configures.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+
+use crate::isa;
+use crate::repl;
+use crate::{C4Builder, ColorChoice, DiagnosticOptions, Error, Program, WarningKind, C4, EXIT_COMPILE_ERROR};
+
+/// The `c4` command
+///
+/// Exits with what the program's `main` returned, [`EXIT_COMPILE_ERROR`]
+/// if it did not compile, or [`EXIT_TRAP`](crate::EXIT_TRAP) if it stopped at a fault.
+/// Compile errors and faults are reported on stderr.
+pub fn main() -> io::Result<()> {
+    let mut args: Vec<String> = env::args().collect();
+
+    // `c4 isa [html]` prints the instruction set reference
+    if args.get(1).is_some_and(|arg| arg == "isa") {
+        match args.get(2).map(String::as_str) {
+            Some("html") => print!("{}", isa::html()),
+            _ => print!("{}", isa::markdown()),
+        }
+        return Ok(());
+    }
+
+    // `c4 run file.c -- args` is the same as `c4 file.c args`, for scripts
+    if args.get(1).is_some_and(|arg| arg == "run") {
+        args.remove(1);
+    }
+
+    // Options come before the source file
+    let mut include_dirs = Vec::new();
+    let mut stats = false;
+    let mut heap_profile = false;
+    let mut signed_char = true;
+    let mut bounds_checks = false;
+    let mut check_stack = false;
+    let mut check_writes = false;
+    let mut image_path = None;
+    let mut entry = None;
+    let mut preprocess_only = false;
+    let mut interactive = false;
+    let mut color = ColorChoice::Auto;
+    let mut diagnostic_options = DiagnosticOptions::default();
+    let flags = ["-E", "-i", "-o", "--entry", "--color", "--max-errors", "--stats", "--heap-profile", "--unsigned-char", "--bounds-checks", "--check-stack", "--check-writes"];
+    while args.len() > 1 && (args[1].starts_with("-I") || args[1].starts_with("-Werror") || flags.contains(&args[1].as_str())) {
+        let flag = args.remove(1);
+        if flag == "-o" && args.len() > 1 {
+            image_path = Some(args.remove(1));
+            continue;
+        }
+        if flag == "--entry" && args.len() > 1 {
+            entry = Some(args.remove(1));
+            continue;
+        }
+        if flag == "-Werror" {
+            diagnostic_options.errors = WarningKind::ALL.to_vec();
+            continue;
+        }
+        if let Some(name) = flag.strip_prefix("-Werror=") {
+            let Some(kind) = WarningKind::from_name(name) else {
+                eprintln!("Unknown kind of warning '{}'", name);
+                process::exit(EXIT_COMPILE_ERROR);
+            };
+            diagnostic_options.errors.push(kind);
+            continue;
+        }
+        if flag == "--max-errors" && args.len() > 1 {
+            diagnostic_options.error_limit = args.remove(1).parse().unwrap_or(0);
+            continue;
+        }
+        if flag == "--color" && args.len() > 1 {
+            color = match args.remove(1).as_str() {
+                "always" => ColorChoice::Always,
+                "never" => ColorChoice::Never,
+                _ => ColorChoice::Auto,
+            };
+            continue;
+        }
+        if flag == "-E" {
+            preprocess_only = true;
+            continue;
+        }
+        if flag == "-i" {
+            interactive = true;
+            continue;
+        }
+        if flag == "--stats" {
+            stats = true;
+            continue;
+        }
+        if flag == "--heap-profile" {
+            heap_profile = true;
+            continue;
+        }
+        if flag == "--unsigned-char" {
+            signed_char = false;
+            continue;
+        }
+        if flag == "--bounds-checks" {
+            bounds_checks = true;
+            continue;
+        }
+        if flag == "--check-stack" {
+            check_stack = true;
+            continue;
+        }
+        if flag == "--check-writes" {
+            check_writes = true;
+            continue;
+        }
+        let dir = if flag.len() > 2 {
+            flag[2..].to_string()
+        } else if args.len() > 1 {
+            args.remove(1)
+        } else {
+            String::new()
+        };
+        include_dirs.push(PathBuf::from(dir));
+    }
+
+    if args.len() < 2 && !interactive {
+        println!("Usage: {} [run] [-I dir]... [-E | -i] [-o image.c4b] [--entry function] [--color auto|always|never] [-Werror[=kind]] [--max-errors n] [--stats] [--heap-profile] [--unsigned-char] [--bounds-checks] [--check-stack] [--check-writes] <source.c | image.c4b> [--] [args]", args[0]);
+        println!("       {} isa [markdown | html]", args[0]);
+        return Ok(());
+    }
+    if args.get(2).is_some_and(|arg| arg == "--") {
+        args.remove(2);
+    }
+
+    let builder = include_dirs.into_iter()
+        .fold(C4::builder(), C4Builder::include_dir)
+        .output(io::stdout())
+        .error_output(io::stderr())
+        .input(io::stdin())
+        .stats(stats)
+        .heap_profile(heap_profile)
+        .signed_char(signed_char)
+        .bounds_checks(bounds_checks)
+        .check_stack(check_stack)
+        .check_writes(check_writes)
+        .color(color)
+        .diagnostic_options(diagnostic_options);
+
+    // With -i, read entries from stdin one at a time
+    if interactive {
+        return repl::Repl::new(builder.build()).interact(io::stdin().lock(), io::stdout());
+    }
+    let mut c4 = builder.source_path(&args[1]).build();
+
+    // With -E, print the preprocessed source instead of compiling it
+    if preprocess_only {
+        let Ok(preprocessed) = c4.preprocess(&std::fs::read_to_string(&args[1])?) else {
+            process::exit(EXIT_COMPILE_ERROR); // Error, already reported
+        };
+        print!("{}", preprocessed);
+        return Ok(());
+    }
+
+    // With -o, save the compiled program as an image instead of running it
+    if let Some(image_path) = image_path {
+        let source = std::fs::read_to_string(&args[1])?;
+        let program = c4.compile(&source);
+        c4.report_warnings();
+        let Ok(program) = program else {
+            process::exit(EXIT_COMPILE_ERROR); // Compile error, already reported
+        };
+        std::fs::write(image_path, program.to_image())?;
+        return Ok(());
+    }
+
+    // An image is checked, then run without compiling anything; a source
+    // is streamed rather than read all up front
+    let program = if args[1].ends_with(".c4b") {
+        Program::from_image(&std::fs::read(&args[1])?)
+    } else {
+        let program = c4.compile_reader(File::open(&args[1])?);
+        c4.report_warnings();
+        program
+    };
+    let outcome = program.and_then(|program| match &entry {
+        Some(name) => c4.run_program_from(&program, name, args[1..].to_vec()),
+        None => c4.run_program(&program, args[1..].to_vec()),
+    });
+    let exit_code = match outcome {
+        Ok(outcome) => outcome.exit_code,
+        Err(e) => {
+            // Compile errors, faults and hangs were reported as they happened
+            if !matches!(e, Error::Compile(_) | Error::Trap(_) | Error::Hang(_)) {
+                eprintln!("{}", e);
+            }
+            e.exit_status()
+        },
+    };
+    io::stdout().flush()?;
+    if stats {
+        eprint!("{}{}", c4.compile_stats, c4.vm_stats);
+    }
+    if heap_profile {
+        eprint!("{}", c4.heap_profile);
+    }
+
+    process::exit(exit_code)
+}
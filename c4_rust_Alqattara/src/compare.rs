@@ -0,0 +1,128 @@
+//! # Comparison With the Original C4
+//!
+//! This crate claims to be a faithful port of Robert Swierczek's c4. When a
+//! native build of the original is at hand, the same program can be run
+//! through both and their exit codes and output compared:
+//!
+//! ```text
+//! gcc -o c4 c4.c
+//! C4_ORIGINAL=$PWD/c4 cargo test --test integration test_matches_original_c4
+//! ```
+//!
+//! Without `C4_ORIGINAL` set the comparison is skipped. The original writes
+//! `exit(N) cycle = M` after a program's own output; that line is taken off
+//! and `N` is kept as the exit code, which is not cut to the 8 bits a
+//! process status has. The port compiles as c4 does, for the C4 language
+//! level with 8-byte words.
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{LanguageLevel, Sandbox, C4};
+
+/// Environment variable naming the original c4 executable
+pub const ORIGINAL_ENV: &str = "C4_ORIGINAL";
+
+/// Source files written so far, so runs on several threads use their own
+static SOURCES: AtomicUsize = AtomicUsize::new(0);
+
+/// What one compiler's run of a program produced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub exit_code: Option<i32>, // Value returned by `main`; None if the program did not compile or did not finish
+    pub output: String,         // What the program wrote to stdout, or why it did not finish
+}
+
+/// Runs of the same program through the original c4 and this crate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    pub original: Run,
+    pub port: Run,
+}
+
+impl Comparison {
+    /// How the runs differ, or None if they agree
+    pub fn difference(&self) -> Option<String> {
+        let mut difference = String::new();
+        if self.original.exit_code != self.port.exit_code {
+            difference += &format!("exit code {} from c4, {} from the port\n", describe(self.original.exit_code), describe(self.port.exit_code));
+        }
+        if self.original.output != self.port.output {
+            difference += &format!("c4 printed {:?}\nthe port printed {:?}\n", self.original.output, self.port.output);
+        }
+        (!difference.is_empty()).then_some(difference)
+    }
+}
+
+/// An exit code as a difference names it
+fn describe(exit_code: Option<i32>) -> String {
+    exit_code.map_or_else(|| "none".to_string(), |code| code.to_string())
+}
+
+/// The original c4 executable named by `C4_ORIGINAL`, if it is set
+pub fn original_c4() -> Option<PathBuf> {
+    env::var_os(ORIGINAL_ENV).filter(|path| !path.is_empty()).map(PathBuf::from)
+}
+
+/// Run `source` through the original c4 at `c4`, with `input` on stdin
+///
+/// The source is written to a file in the temporary directory, since c4
+/// only reads programs from files.
+pub fn run_original(c4: &Path, source: &str, input: &[u8]) -> io::Result<Run> {
+    let n = SOURCES.fetch_add(1, Ordering::Relaxed);
+    let path = env::temp_dir().join(format!("c4_compare_{}_{}.c", std::process::id(), n));
+    fs::write(&path, source)?;
+    let child = Command::new(c4)
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let result = child.and_then(|mut child| {
+        // A program that stops reading early closes the pipe, which is no error
+        let _ = child.stdin.take().unwrap().write_all(input);
+        child.wait_with_output()
+    });
+    let _ = fs::remove_file(&path);
+    let output = result?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    Ok(split_exit_line(&stdout).unwrap_or(Run { exit_code: None, output: stdout }))
+}
+
+/// Take the `exit(N) cycle = M` line c4 ends a run with off its output
+fn split_exit_line(stdout: &str) -> Option<Run> {
+    let at = stdout.rfind("exit(")?;
+    let (output, line) = stdout.split_at(at);
+    let (code, rest) = line.strip_prefix("exit(")?.split_once(')')?;
+    rest.strip_prefix(" cycle = ")?;
+    Some(Run { exit_code: Some(code.parse().ok()?), output: output.to_string() })
+}
+
+/// Run `source` through this crate, with `input` as what getchar reads
+///
+/// The original runs for as long as a program takes, so this does too, and
+/// it accepts only the C4 language on 64-bit words, so this does too.
+pub fn run_port(source: &str, input: &[u8]) -> Run {
+    let sandbox = Sandbox { max_cycles: i32::MAX, ..Sandbox::default() };
+    let mut compiler = C4::builder()
+        .language(LanguageLevel::C4)
+        .word_size(8)
+        .sandbox(sandbox)
+        .input(io::Cursor::new(input.to_vec()))
+        .build();
+    let outcome = compiler.compile(source).and_then(|program| compiler.run_program(&program, Vec::new()));
+    match outcome {
+        Ok(outcome) => Run { exit_code: Some(outcome.exit_code), output: outcome.output },
+        Err(e) => Run { exit_code: None, output: format!("{}\n", e) },
+    }
+}
+
+/// Run `source` through the original c4 at `c4` and through this crate
+pub fn compare(c4: &Path, source: &str, input: &[u8]) -> io::Result<Comparison> {
+    Ok(Comparison { original: run_original(c4, source, input)?, port: run_port(source, input) })
+}
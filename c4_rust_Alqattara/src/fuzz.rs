@@ -0,0 +1,98 @@
+//! # Fuzzing Entry Points
+//!
+//! Each function runs one stage of the frontend on arbitrary bytes, for
+//! use as a cargo-fuzz or AFL target (see `fuzz/`). They must never panic
+//! or hang, whatever the input; inputs that once did are kept as
+//! regression tests. `fuzz_generated` instead seeds the program generator,
+//! and panics only when builds of a valid program disagree.
+
+use crate::generate;
+use crate::{Backend, C4Builder, C4};
+
+/// Inputs longer than this are cut short, so each run stays fast
+const MAX_INPUT: usize = 64 * 1024;
+
+/// The settings of a compiler as the fuzz targets use it
+///
+/// The nesting limit is lowered so deep inputs stay well within the
+/// stack of a fuzzer's thread.
+fn fuzz_compiler() -> C4Builder {
+    C4::builder().nesting_limit(200)
+}
+
+/// Lex `data` to the end
+///
+/// # Returns
+///
+/// The number of tokens read before the end of input or a lexer error
+pub fn fuzz_lex(data: &[u8]) -> usize {
+    let mut c4 = fuzz_compiler().build();
+    c4.reset();
+    c4.src = data[..data.len().min(MAX_INPUT)].to_vec();
+    let mut tokens = 0;
+    loop {
+        c4.next();
+        if c4.token == 0 || c4.error.is_some() {
+            return tokens;
+        }
+        tokens += 1;
+    }
+}
+
+/// Parse `data` as a program and generate its code, without optimizing it
+///
+/// # Returns
+///
+/// true if the program compiled
+pub fn fuzz_parse(data: &[u8]) -> bool {
+    let mut c4 = fuzz_compiler().opt_level(0).build();
+    let source = String::from_utf8_lossy(&data[..data.len().min(MAX_INPUT)]);
+    c4.compile(&source).is_ok()
+}
+
+/// Compile `data` with the optimizer, then analyze the program and
+/// translate it for the register VM
+///
+/// # Returns
+///
+/// true if the program compiled
+pub fn fuzz_compile(data: &[u8]) -> bool {
+    let mut c4 = fuzz_compiler().build();
+    let source = String::from_utf8_lossy(&data[..data.len().min(MAX_INPUT)]);
+    let Ok(program) = c4.compile(&source) else {
+        return false;
+    };
+    program.stack_report();
+    program.symbols();
+    if let Some(entry) = program.entry() {
+        crate::regvm::translate(&program.text, entry);
+    }
+    true
+}
+
+/// Generate a program from the first 8 bytes of `data` and run it built
+/// without and with the optimizer and on both backends
+///
+/// Unlike the other targets this one looks for wrong code rather than
+/// crashes: it panics if the runs do not agree.
+///
+/// # Returns
+///
+/// true if the program compiled
+pub fn fuzz_generated(data: &[u8]) -> bool {
+    let mut seed = [0; 8];
+    let len = data.len().min(8);
+    seed[..len].copy_from_slice(&data[..len]);
+    let source = generate::generate(u64::from_le_bytes(seed));
+
+    let mut outcomes = Vec::new();
+    for (opt_level, backend) in [(0, Backend::Stack), (1, Backend::Stack), (1, Backend::Register)] {
+        let mut c4 = C4::builder().opt_level(opt_level).backend(backend).build();
+        let Ok(program) = c4.compile(&source) else {
+            return false;
+        };
+        outcomes.push(c4.run_program(&program, Vec::new()));
+    }
+    assert!(outcomes.windows(2).all(|pair| pair[0] == pair[1]), "runs differ: {:?}\n{}", outcomes, source);
+    true
+}
@@ -0,0 +1,254 @@
+//! # Random Programs
+//!
+//! Generates random programs in the subset of C the compiler accepts, for
+//! property tests and fuzzing. Unlike arbitrary bytes, which the parser
+//! mostly rejects, every generated program compiles and runs to the end,
+//! so the optimizer, the code generator and both backends all see it.
+//!
+//! A program is a few globals, a few functions and `main`, which prints
+//! what it computed. The generator keeps to code whose result does not
+//! depend on how it is compiled:
+//!
+//! - Divisors are between 1 and 8 and shift counts between 0 and 7
+//! - Loops count a variable of their own down from at most
+//!   `GenOptions::loop_iterations`, and nothing else assigns it
+//! - A function only calls those defined before it, and never from inside
+//!   a loop, so runs stay short
+//!
+//! The same seed always gives the same program.
+
+use std::fmt::Write;
+
+/// Limits on the size of a generated program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenOptions {
+    pub globals: usize,         // Global int variables
+    pub functions: usize,       // Functions besides main
+    pub locals: usize,          // Int locals of each function, besides its parameters; at least 1
+    pub statements: usize,      // Most statements in a block
+    pub depth: usize,           // Deepest nesting of statements and of expressions
+    pub loop_iterations: u64,   // Most times a loop runs
+}
+
+impl Default for GenOptions {
+    fn default() -> Self {
+        GenOptions { globals: 3, functions: 3, locals: 3, statements: 5, depth: 3, loop_iterations: 5 }
+    }
+}
+
+/// A xorshift64* generator; small, and the same everywhere
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Zero is a fixed point of xorshift
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number below `n`, which must not be 0
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// true one time in `n`
+    pub fn one_in(&mut self, n: u64) -> bool {
+        self.below(n) == 0
+    }
+}
+
+/// Generate a program from `seed` with the default limits
+pub fn generate(seed: u64) -> String {
+    generate_with(seed, GenOptions::default())
+}
+
+/// Generate a program from `seed` within `options`
+pub fn generate_with(seed: u64, options: GenOptions) -> String {
+    let options = GenOptions { locals: options.locals.max(1), ..options };
+    let mut generator = Generator { rng: Rng::new(seed), options, source: String::new(), arities: Vec::new(), params: 0, in_loop: 0 };
+    generator.program();
+    generator.source
+}
+
+/// State while generating one program
+struct Generator {
+    rng: Rng,
+    options: GenOptions,
+    source: String,
+    arities: Vec<usize>, // Parameters of each function defined so far, which the next may call
+    params: usize,      // Parameters of the function being generated
+    in_loop: usize,     // Loops around the statement being generated
+}
+
+impl Generator {
+    fn program(&mut self) {
+        for g in 0..self.options.globals {
+            let _ = writeln!(self.source, "int g{};", g);
+        }
+        for function in 0..self.options.functions {
+            self.params = 1 + self.rng.below(3) as usize;
+            let params: Vec<String> = (0..self.params).map(|a| format!("int a{}", a)).collect();
+            let _ = writeln!(self.source, "int f{}({}) {{", function, params.join(", "));
+            self.body(false);
+            self.arities.push(self.params);
+        }
+
+        self.params = 0;
+        self.source.push_str("int main() {\n");
+        self.body(true);
+    }
+
+    /// Declarations, statements and the return of a function
+    fn body(&mut self, is_main: bool) {
+        let locals: Vec<String> = (0..self.options.locals).map(|x| format!("x{}", x)).collect();
+        let counters: Vec<String> = (0..self.options.depth).map(|i| format!("i{}", i)).collect();
+        let _ = writeln!(self.source, "  int {}, *p;", locals.iter().chain(&counters).cloned().collect::<Vec<_>>().join(", "));
+        self.source.push_str("  char ch;\n");
+        for local in &locals {
+            let value = self.rng.below(100);
+            let _ = writeln!(self.source, "  {} = {};", local, value);
+        }
+        for counter in &counters {
+            let _ = writeln!(self.source, "  {} = 0;", counter);
+        }
+        self.source.push_str("  ch = 0;\n");
+        let target = self.rng.below(self.options.locals as u64);
+        let _ = writeln!(self.source, "  p = &x{};", target);
+
+        for _ in 0..1 + self.rng.below(self.options.statements as u64) {
+            self.statement(1);
+        }
+        if is_main {
+            for g in 0..self.options.globals {
+                let _ = writeln!(self.source, "  printf(\"g{} %d\\n\", g{});", g, g);
+            }
+        }
+        let value = self.expression(0);
+        let _ = writeln!(self.source, "  return {};\n}}", value);
+    }
+
+    fn indent(&mut self, depth: usize) {
+        self.source.push_str(&"  ".repeat(depth));
+    }
+
+    /// Something that can be assigned
+    fn lvalue(&mut self) -> String {
+        match self.rng.below(8) {
+            0 if self.options.globals > 0 => format!("g{}", self.rng.below(self.options.globals as u64)),
+            1 => "ch".to_string(),
+            2 => "*p".to_string(),
+            3 if self.params > 0 => format!("a{}", self.rng.below(self.params as u64)),
+            _ => format!("x{}", self.rng.below(self.options.locals as u64)),
+        }
+    }
+
+    fn statement(&mut self, depth: usize) {
+        let nested = depth < self.options.depth;
+        match self.rng.below(10) {
+            0 | 1 if nested => {
+                let condition = self.expression(0);
+                self.indent(depth);
+                let _ = writeln!(self.source, "if ({}) {{", condition);
+                self.block(depth);
+                if self.rng.one_in(2) {
+                    self.indent(depth);
+                    self.source.push_str("} else {\n");
+                    self.block(depth);
+                }
+                self.indent(depth);
+                self.source.push_str("}\n");
+            },
+            2 | 3 if nested => {
+                let counter = format!("i{}", depth - 1);
+                let count = 1 + self.rng.below(self.options.loop_iterations.max(1));
+                self.indent(depth);
+                let _ = writeln!(self.source, "{} = {};", counter, count);
+                self.indent(depth);
+                let _ = writeln!(self.source, "while ({} > 0) {{", counter);
+                self.in_loop += 1;
+                self.block(depth);
+                self.in_loop -= 1;
+                self.indent(depth + 1);
+                let _ = writeln!(self.source, "--{};", counter);
+                self.indent(depth);
+                self.source.push_str("}\n");
+            },
+            4 => {
+                let value = self.expression(0);
+                self.indent(depth);
+                let _ = writeln!(self.source, "printf(\"%d\\n\", {});", value);
+            },
+            5 => {
+                let target = self.lvalue();
+                let op = ["++", "--"][self.rng.below(2) as usize];
+                self.indent(depth);
+                let _ = writeln!(self.source, "{}{};", op, target);
+            },
+            _ => {
+                let target = self.lvalue();
+                let value = self.expression(0);
+                self.indent(depth);
+                let _ = writeln!(self.source, "{} = {};", target, value);
+            },
+        }
+    }
+
+    fn block(&mut self, depth: usize) {
+        for _ in 0..1 + self.rng.below(self.options.statements as u64) {
+            self.statement(depth + 1);
+        }
+    }
+
+    /// An operand: a constant or a variable
+    fn operand(&mut self) -> String {
+        match self.rng.below(6) {
+            0 => self.rng.below(1000).to_string(),
+            1 => (self.rng.below(16) as i64 - 8).to_string(),
+            2 if self.options.depth > 0 => format!("i{}", self.rng.below(self.options.depth as u64)),
+            _ => self.lvalue(),
+        }
+    }
+
+    fn expression(&mut self, depth: usize) -> String {
+        if depth >= self.options.depth || self.rng.one_in(4) {
+            return self.operand();
+        }
+        match self.rng.below(12) {
+            0 => {
+                let op = ["-", "!", "~"][self.rng.below(3) as usize];
+                format!("{}({})", op, self.expression(depth + 1))
+            },
+            1 => {
+                let op = ["/", "%"][self.rng.below(2) as usize];
+                format!("({} {} (({} & 7) + 1))", self.expression(depth + 1), op, self.expression(depth + 1))
+            },
+            2 => {
+                let op = ["<<", ">>"][self.rng.below(2) as usize];
+                format!("({} {} ({} & 7))", self.expression(depth + 1), op, self.expression(depth + 1))
+            },
+            3 => format!(
+                "({} ? {} : {})",
+                self.expression(depth + 1),
+                self.expression(depth + 1),
+                self.expression(depth + 1)
+            ),
+            4 if !self.arities.is_empty() && self.in_loop == 0 => {
+                let callee = self.rng.below(self.arities.len() as u64) as usize;
+                let args: Vec<String> = (0..self.arities[callee]).map(|_| self.expression(depth + 1)).collect();
+                format!("f{}({})", callee, args.join(", "))
+            },
+            _ => {
+                let ops = ["+", "-", "*", "&", "|", "^", "<", ">", "<=", ">=", "==", "!=", "&&", "||"];
+                let op = ops[self.rng.below(ops.len() as u64) as usize];
+                format!("({} {} {})", self.expression(depth + 1), op, self.expression(depth + 1))
+            },
+        }
+    }
+}
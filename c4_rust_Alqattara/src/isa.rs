@@ -0,0 +1,213 @@
+//! # Instruction Set Reference
+//!
+//! Renders the VM's instruction set as a reference table, in Markdown or
+//! HTML, from [`Instruction::operands`] and [`Instruction::stack_effect`],
+//! which the VM and the verifier use too, so the description cannot drift
+//! from the code. `c4 isa` prints it.
+//!
+//! Instructions the VM does not implement, for which [`Instruction::is_implemented`]
+//! is false, are marked as such, since a program that reaches one traps.
+//!
+//! Stack effects are in words: `+1` pushes one, `-1` pops one. Builtins
+//! such as `PRINTF` leave their arguments for the caller's `ADJ` to pop,
+//! so their effect is `0`.
+
+use std::fmt::Write;
+
+use crate::optimizer::has_text_operand;
+use crate::Instruction;
+
+/// What the word after an opcode means
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    None,           // The instruction has no operand
+    Immediate,      // A value, or a data address, loaded into ax
+    FrameOffset,    // Words from bp to a parameter or local
+    TextAddress,    // A text address to jump to, call or load
+    FrameSlots,     // Words of locals to reserve
+    WordCount,      // Words of arguments to pop
+    DataAddress,    // Data address of a record the instruction reads
+}
+
+impl Operand {
+    /// How the operand is written in the table
+    pub fn name(self) -> &'static str {
+        match self {
+            Operand::None => "",
+            Operand::Immediate => "value",
+            Operand::FrameOffset => "frame offset",
+            Operand::TextAddress => "text address",
+            Operand::FrameSlots => "locals",
+            Operand::WordCount => "words",
+            Operand::DataAddress => "data address",
+        }
+    }
+}
+
+/// One row of the reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionInfo {
+    pub op: Instruction,
+    pub operand: Operand,
+    pub stack_effect: String,       // Words pushed, as `+1`, `-1`, `-n` for an operand n, or what happens instead
+    pub description: &'static str,
+    pub implemented: bool,          // Whether the VM runs it, rather than trapping
+}
+
+/// The operand of `op`
+pub fn operand(op: Instruction) -> Operand {
+    match op {
+        _ if op.operands() == 0 => Operand::None,
+        _ if has_text_operand(op as i32) => Operand::TextAddress,
+        Instruction::LEA => Operand::FrameOffset,
+        Instruction::ENT | Instruction::IENT => Operand::FrameSlots,
+        Instruction::ADJ | Instruction::ILEV | Instruction::TLEV => Operand::WordCount,
+        Instruction::BOUND => Operand::DataAddress,
+        _ => Operand::Immediate,
+    }
+}
+
+/// The stack effect of `op`, written out for the table
+pub fn stack_effect(op: Instruction) -> String {
+    match op {
+        Instruction::ENT => "+1+n, new frame".to_string(),
+        Instruction::IENT => "+2+n, new frame".to_string(),
+        Instruction::ADJ => "-n".to_string(),
+        Instruction::ILEV => "back to before the call, -n".to_string(),
+        Instruction::LEV | Instruction::TLEV => "returns".to_string(),
+        Instruction::EXIT => "stops".to_string(),
+        op => match op.stack_effect() {
+            Some(effect) if effect > 0 => format!("+{}", effect),
+            effect => effect.unwrap_or(0).to_string(),
+        },
+    }
+}
+
+/// What `op` does
+pub fn description(op: Instruction) -> &'static str {
+    match op {
+        Instruction::LEA => "Load the address of a parameter or local into ax",
+        Instruction::IMM => "Load the operand into ax",
+        Instruction::JMP => "Jump",
+        Instruction::JSR => "Push the return address and call",
+        Instruction::BZ => "Jump if ax is zero",
+        Instruction::BNZ => "Jump if ax is not zero",
+        Instruction::ENT => "Push bp, point bp at it and reserve the locals",
+        Instruction::ADJ => "Pop the arguments of a call",
+        Instruction::LEV => "Drop the frame, restore bp and return",
+        Instruction::LI => "Load the int ax points at",
+        Instruction::LC => "Load the char ax points at",
+        Instruction::SI => "Store ax as an int at the address popped",
+        Instruction::SC => "Store ax as a char at the address popped",
+        Instruction::PUSH => "Push ax",
+        Instruction::OR => "Pop a, ax = a | ax",
+        Instruction::XOR => "Pop a, ax = a ^ ax",
+        Instruction::AND => "Pop a, ax = a & ax",
+        Instruction::EQ => "Pop a, ax = a == ax",
+        Instruction::NE => "Pop a, ax = a != ax",
+        Instruction::LT => "Pop a, ax = a < ax",
+        Instruction::GT => "Pop a, ax = a > ax",
+        Instruction::LE => "Pop a, ax = a <= ax",
+        Instruction::GE => "Pop a, ax = a >= ax",
+        Instruction::SHL => "Pop a, ax = a << ax",
+        Instruction::SHR => "Pop a, ax = a >> ax",
+        Instruction::ADD => "Pop a, ax = a + ax",
+        Instruction::SUB => "Pop a, ax = a - ax",
+        Instruction::MUL => "Pop a, ax = a * ax",
+        Instruction::DIV => "Pop a, ax = a / ax",
+        Instruction::MOD => "Pop a, ax = a % ax",
+        Instruction::OPEN => "open(path, flags)",
+        Instruction::READ => "read(fd, buf, count)",
+        Instruction::CLOS => "close(fd)",
+        Instruction::PRINTF => "printf(format, ...)",
+        Instruction::MALLOC => "malloc(size)",
+        Instruction::MSET => "memset(dest, value, count)",
+        Instruction::MCMP => "memcmp(a, b, count)",
+        Instruction::EXIT => "exit(status)",
+        Instruction::FLD => "Load the double ax points at",
+        Instruction::FST => "Store ax as a double at the address popped",
+        Instruction::FADD => "Pop a, ax = a + ax on doubles",
+        Instruction::FSUB => "Pop a, ax = a - ax on doubles",
+        Instruction::FMUL => "Pop a, ax = a * ax on doubles",
+        Instruction::FDIV => "Pop a, ax = a / ax on doubles",
+        Instruction::IENT => "Enter an inlined call: ENT, after pushing the address of its operand where the return address would be",
+        Instruction::ILEV => "Leave an inlined call and pop its arguments",
+        Instruction::TLEV => "Leave for a tail call, moving the arguments over the caller's and keeping its return address",
+        Instruction::FPRINTF => "fprintf(fd, format, ...)",
+        Instruction::SPRINTF => "sprintf(buf, format, ...)",
+        Instruction::SNPRINTF => "snprintf(buf, size, format, ...)",
+        Instruction::PUTC => "putchar(c)",
+        Instruction::PUTS => "puts(s)",
+        Instruction::GETC => "getchar()",
+        Instruction::ASSERT => "assert(condition), with the message of a failed assert",
+        Instruction::ABS => "abs(n)",
+        Instruction::SQRT => "sqrt(x)",
+        Instruction::POW => "pow(x, y)",
+        Instruction::SIN => "sin(x)",
+        Instruction::COS => "cos(x)",
+        Instruction::QSORT => "qsort(base, count, size, compare)",
+        Instruction::FADDR => "Load the address of a function into ax",
+        Instruction::FREE => "free(block)",
+        Instruction::BOUND => "Trap unless ax is an index within the array described at the operand",
+    }
+}
+
+/// Every instruction, in opcode order
+pub fn instructions() -> Vec<InstructionInfo> {
+    Instruction::ALL.iter().map(|&op| InstructionInfo {
+        op,
+        operand: operand(op),
+        stack_effect: stack_effect(op),
+        description: description(op),
+        implemented: op.is_implemented(),
+    }).collect()
+}
+
+const HEADINGS: [&str; 5] = ["Opcode", "Name", "Operand", "Stack", "Description"];
+
+fn cells(info: &InstructionInfo) -> [String; 5] {
+    [
+        (info.op as i32).to_string(),
+        info.op.name().to_string(),
+        info.operand.name().to_string(),
+        info.stack_effect.clone(),
+        if info.implemented {
+            info.description.to_string()
+        } else {
+            format!("Not implemented, traps: {}", info.description)
+        },
+    ]
+}
+
+/// The reference as a Markdown table
+pub fn markdown() -> String {
+    let mut out = format!("| {} |\n", HEADINGS.join(" | "));
+    out += &format!("|{}\n", "---|".repeat(HEADINGS.len()));
+    for info in instructions() {
+        let row: Vec<String> = cells(&info).iter().map(|cell| cell.replace('|', "\\|")).collect();
+        let _ = writeln!(out, "| {} |", row.join(" | "));
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// The reference as an HTML table
+pub fn html() -> String {
+    let mut out = String::from("<table>\n<tr>");
+    for heading in HEADINGS {
+        let _ = write!(out, "<th>{}</th>", heading);
+    }
+    out += "</tr>\n";
+    for info in instructions() {
+        out += "<tr>";
+        for cell in cells(&info) {
+            let _ = write!(out, "<td>{}</td>", escape_html(&cell));
+        }
+        out += "</tr>\n";
+    }
+    out += "</table>\n";
+    out
+}
@@ -1,2920 +1,46 @@
 //! # C4 Compiler in Rust
 //!
-//! This is a Rust implementation of the C4 compiler, a small self-hosting C compiler
-//! originally written by Robert Swierczek. The original C4 compiler is capable of
-//! compiling itself and a subset of the C language.
-//!
-//! ## Overview
-//!
-//! The C4 compiler is a minimalist C compiler written in just four functions:
-//! - `next()`: Lexical analyzer (tokenizer)
-//! - `expression()`: Expression parser and code generator
-//! - `statement()`: Statement parser
-//! - `program()`: Program parser
-//!
-//! This Rust implementation maintains the same functionality while leveraging Rust's
-//! safety features, ownership model, and modern programming paradigms.
-//!
-//! ## Design Decisions
-//!
-//! 1. **Memory Safety**: The original C4 uses raw pointers and manual memory management.
-//!    This Rust implementation uses Rust's ownership system and safe abstractions like
-//!    `Vec<T>` and `String` to prevent memory leaks and use-after-free errors.
-//!
-//! 2. **Error Handling**: The original C4 uses `exit(-1)` for error handling. This
-//!    implementation uses more structured error handling with Result types where appropriate.
-//!
-//! 3. **Type Safety**: The original C4 uses magic numbers for token types and instructions.
-//!    This implementation uses enums for better type safety and readability.
-//!
-//! 4. **Code Organization**: The original C4 is extremely compact. This implementation
-//!    maintains the same overall structure but improves organization with a struct to
-//!    encapsulate the compiler state.
-
-#![allow(
-    dead_code,
-    non_upper_case_globals,
-    unused_variables,
-    unreachable_code,
-    unused_assignments
-)]
-
-use std::env;
-use std::fs::File;
-use std::io::{self, Read};
-use std::process;
-
-/// Token types used by the lexer and parser
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub enum TokenType {
-    Num = 128,  // Number literal
-    Float = 257,  // Floating-point literal
-    Fun,        // Function
-    Sys,        // System call
-    Glo,        // Global variable
-    Loc,        // Local variable
-    Id,         // Identifier
-    Char,       // char type
-    Else,       // else keyword
-    Enum,       // enum keyword
-    If,         // if keyword
-    Int,        // int type
-    Return,     // return keyword
-    Sizeof,     // sizeof operator
-    While,      // while keyword
-    Assign,     // Assignment operator
-    Cond,       // Conditional operator
-    Lor,        // Logical OR
-    Lan,        // Logical AND
-    Or,         // Bitwise OR
-    Xor,        // Bitwise XOR
-    And,        // Bitwise AND
-    Eq,         // Equal
-    Ne,         // Not equal
-    Lt,         // Less than
-    Gt,         // Greater than
-    Le,         // Less than or equal
-    Ge,         // Greater than or equal
-    Shl,        // Shift left
-    Shr,        // Shift right
-    Add,        // Addition
-    Sub,        // Subtraction
-    Mul,        // Multiplication
-    Div,        // Division
-    Mod,        // Modulo
-    Inc,        // Increment
-    Dec,        // Decrement
-    Brak,       // Array subscript
-}
-
-impl TokenType {
-    fn from_i32(value: i32) -> Option<TokenType> {
-        match value {
-            v if v == TokenType::Num as i32 => Some(TokenType::Num),
-            v if v == TokenType::Float as i32 => Some(TokenType::Float),
-            v if v == TokenType::Fun as i32 => Some(TokenType::Fun),
-            // ... add other variants ...
-            _ => None
-        }
-    }
-}
-
-/// Virtual machine instructions
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub enum Instruction {
-    LEA,    // Load effective address
-    IMM,    // Load immediate value
-    JMP,    // Jump
-    JSR,    // Jump to subroutine
-    BZ,     // Branch if zero
-    BNZ,    // Branch if not zero
-    ENT,    // Enter subroutine
-    ADJ,    // Adjust stack
-    LEV,    // Leave subroutine
-    LI,     // Load int
-    LC,     // Load char
-    SI,     // Store int
-    SC,     // Store char
-    PUSH,   // Push value onto stack
-    OR,     // Bitwise OR
-    XOR,    // Bitwise XOR
-    AND,    // Bitwise AND
-    EQ,     // Equal
-    NE,     // Not equal
-    LT,     // Less than
-    GT,     // Greater than
-    LE,     // Less than or equal
-    GE,     // Greater than or equal
-    SHL,    // Shift left
-    SHR,    // Shift right
-    ADD,    // Add
-    SUB,    // Subtract
-    MUL,    // Multiply
-    DIV,    // Divide
-    MOD,    // Modulo
-    OPEN,   // Open file
-    READ,   // Read from file
-    CLOS,   // Close file
-    PRINTF, // Printf
-    MALLOC, // Malloc
-    MSET,   // Memset
-    MCMP,   // Memcmp
-    EXIT,    // Exit
-    FLD,    // Load floating-point
-    FST,    // Store floating-point
-    FADD,   // Floating-point add
-    FSUB,   // Floating-point subtract
-    FMUL,   // Floating-point multiply
-    FDIV,   // Floating-point divide
-}
-
-/// Symbol structure for the symbol table
-#[derive(Debug, Clone)]
-pub struct Symbol {
-    pub token: TokenType,    // Token type
-    pub hash: i32,           // Hash value
-    pub name: String,        // Symbol name
-    pub class: i32,          // Storage class (e.g., global, local)
-    pub type_: i32,          // Data type
-    pub value: i32,          // Value or address
-    pub bclass: i32,         // Base class (for arrays/enums)
-    pub btype: i32,          // Base type (for arrays/enums)
-    pub bvalue: i32,         // Base value (for arrays/enums)
-}
-
-// Constants
-const MAX_SIZE: usize = 1000000;  // Max size of source code
-const POOL_SIZE: usize = 256 * 1024;  // Default size of text/data/stack
-
-// Types
-pub const CHAR: i32 = 0;      // char
-pub const INT: i32 = 1;       // int
-pub const PTR: i32 = 2;       // pointer
-pub const FLOAT: i32 = 3;     // floating-point
-
-// Identifier offsets (since we can't use member access in original C)
-const Token: i32 = 0;     // current token
-const Hash: i32 = 1;      // hash of token
-const Name: i32 = 2;      // name of identifier
-const Type: i32 = 3;      // type of identifier
-const Class: i32 = 4;     // class of identifier
-const Value: i32 = 5;     // value of identifier
-const BType: i32 = 6;     // base type of array/enum
-const BClass: i32 = 7;    // base class of array/enum
-const BValue: i32 = 8;    // base value of array/enum
-const IdSize: i32 = 9;    // size of identifier
-
-/// The main C4 compiler structure
-pub struct C4 {
-    // Source and parsing
-    pub src: Vec<u8>,         // Source code
-    pub old_src: Vec<u8>,     // Old source code (for preprocessor)
-    pub pos: usize,           // Current position in source code
-    pub line: i32,            // Current line number
-    pub token: i32,           // Current token
-    pub token_val: i32,       // Value of current token (for number, character)
-
-    // Symbol table
-    pub symbols: Vec<Symbol>, // Symbol table
-
-    // Code generation
-    pub text: Vec<i32>,       // Text segment
-    pub old_text: Vec<i32>,   // Old text segment
-    pub data: Vec<i32>,       // Data segment
-
-    // VM registers
-    pub pc: i32,              // Program counter
-    pub bp: i32,              // Base pointer
-    pub sp: i32,              // Stack pointer
-    pub ax: i32,              // Accumulator
-    pub ax_float: f64,        // Floating-point accumulator
-    pub cycle: i32,           // Cycle counter
-
-    // Current identifier
-    pub current_id: Vec<u8>,  // Current identifier name
-
-    // AST
-    pub expr_type: i32,       // Type of expression
-
-    // Variables
-    pub index_of_bp: i32,     // Index of bp
-
-    // Memory management
-    pub stack: Vec<i32>,      // Stack
-
-    // Debugging
-    pub debug: bool,          // Debug mode
-
-    if_token: bool, // Renamed from `if` to `if_token`
-
-    // Add this field to the C4 struct
-    captured_output: String,
-}
-
-impl C4 {
-    /// Creates a new C4 compiler instance with default settings
-    pub fn new() -> Self {
-        C4 {
-            src: Vec::with_capacity(MAX_SIZE),
-            old_src: Vec::new(),
-            pos: 0,
-            line: 1,
-            token: 0,
-            token_val: 0,
-            symbols: Vec::new(),
-            text: Vec::with_capacity(POOL_SIZE),
-            old_text: Vec::new(),
-            data: Vec::with_capacity(POOL_SIZE),
-            pc: 0,
-            bp: 0,
-            sp: 0,
-            ax: 0,
-            ax_float: 0.0,
-            cycle: 0,
-            current_id: Vec::new(),
-            expr_type: 0,
-            index_of_bp: 0,
-            stack: Vec::with_capacity(POOL_SIZE),
-            debug: false,
-            if_token: false,
-            captured_output: String::new(),
-        }
-    }
-
-    /// Lexical analyzer: get the next token from the source code
-    ///
-    /// This function reads the next token from the source code and updates
-    /// the compiler state accordingly. It handles identifiers, numbers,
-    /// character literals, string literals, and operators.
-    pub fn next(&mut self) {
-        let mut ch: u8;
-
-        // Skip whitespace and comments
-        loop {
-            if self.pos >= self.src.len() {
-                println!("Reached end of source in next()");
-                self.token = 0;  // Set token to 0 to indicate end of input
-                return;
-            }
-
-            ch = self.src[self.pos];
-
-            if ch == b'\n' {
-                self.line += 1;
-            } else if ch == b'#' {
-                // Skip preprocessor directive
-                while self.pos < self.src.len() && self.src[self.pos] != b'\n' {
-                    self.pos += 1;
-                }
-                continue;
-            } else if ch == b'/' && self.pos + 1 < self.src.len() {
-                if self.src[self.pos + 1] == b'/' {
-                    // Skip single-line comment
-                    while self.pos < self.src.len() && self.src[self.pos] != b'\n' {
-                        self.pos += 1;
-                    }
-                    continue;
-                } else if self.src[self.pos + 1] == b'*' {
-                    // Skip multi-line comment
-                    self.pos += 2;
-                    while self.pos + 1 < self.src.len() &&
-                          !(self.src[self.pos] == b'*' && self.src[self.pos + 1] == b'/') {
-                        if self.src[self.pos] == b'\n' {
-                            self.line += 1;
-                        }
-                        self.pos += 1;
-                    }
-                    if self.pos + 1 < self.src.len() {
-                        self.pos += 2;
-                    }
-                    continue;
-                }
-            }
-
-            if !ch.is_ascii_whitespace() {
-                break;
-            }
-
-            self.pos += 1;
-        }
-
-        // Parse identifier
-        if ch.is_ascii_alphabetic() || ch == b'_' {
-            self.current_id.clear();
-
-            while self.pos < self.src.len() &&
-                  (self.src[self.pos].is_ascii_alphanumeric() || self.src[self.pos] == b'_') {
-                self.current_id.push(self.src[self.pos]);
-                self.pos += 1;
-            }
-
-            // Check if it's a keyword
-            self.token = TokenType::Id as i32;
-
-            let id_str = String::from_utf8_lossy(&self.current_id).to_string();
-
-            match id_str.as_str() {
-                "char" => self.token = TokenType::Char as i32,
-                "else" => self.token = TokenType::Else as i32,
-                "enum" => self.token = TokenType::Enum as i32,
-                "if" => self.token = TokenType::If as i32,
-                "int" => self.token = TokenType::Int as i32,
-                "return" => self.token = TokenType::Return as i32,
-                "sizeof" => self.token = TokenType::Sizeof as i32,
-                "while" => self.token = TokenType::While as i32,
-                _ => {
-                    // Check if it's in the symbol table
-                    for symbol in &self.symbols {
-                        if symbol.name == id_str {
-                            self.token = symbol.token as i32;
-                            self.token_val = symbol.value;
-                            return;
-                        }
-                    }
-                }
-            }
-
-            return;
-        }
-
-        // Parse numbers (integer or float)
-        if ch.is_ascii_digit() || ch == b'.' || (ch == b'-' && self.pos + 1 < self.src.len() && (self.src[self.pos + 1].is_ascii_digit() || self.src[self.pos + 1] == b'.')) {
-            let mut buffer = Vec::new();
-            let mut is_float = false;
-            
-            // Handle negative sign
-            if ch == b'-' {
-                buffer.push(ch);
-                self.pos += 1;
-                ch = self.src[self.pos];
-            }
-        
-            // Handle hex numbers
-            if ch == b'0' && self.pos + 1 < self.src.len() && 
-               (self.src[self.pos + 1] == b'x' || self.src[self.pos + 1] == b'X') {
-                self.pos += 2;
-                self.token_val = 0;
-                while self.pos < self.src.len() {
-                    ch = self.src[self.pos];
-                    if (ch >= b'0' && ch <= b'9') || (ch >= b'a' && ch <= b'f') || (ch >= b'A' && ch <= b'F') {
-                        self.token_val = self.token_val * 16 + (ch as i32 - if ch >= b'a' { b'a' as i32 - 10 } else if ch >= b'A' { b'A' as i32 - 10 } else { b'0' as i32 }) as i32;
-                    } else {
-                        break;
-                    }
-                    self.pos += 1;
-                }
-                self.token = TokenType::Num as i32;
-                return;
-            }
-        
-            // Parse decimal or float
-            self.token_val = 0;
-            let mut seen_dot = false;
-            while self.pos < self.src.len() {
-                ch = self.src[self.pos];
-                if ch == b'.' && !seen_dot {
-                    seen_dot = true;
-                    is_float = true;
-                    buffer.push(ch);
-                } else if ch.is_ascii_digit() {
-                    if !is_float {
-                        self.token_val = self.token_val * 10 + (ch - b'0') as i32;
-                    }
-                    buffer.push(ch);
-                } else {
-                    break;
-                }
-                self.pos += 1;
-            }
-        
-            if is_float {
-                if let Ok(val) = String::from_utf8_lossy(&buffer).parse::<f64>() {
-                    let idx = self.new_float_constant(val);
-                    self.token = TokenType::Float as i32;
-                    self.token_val = idx;
-                } else {
-                    println!("Line {}: Invalid float literal", self.line);
-                    process::exit(1);
-                }
-            } else {
-                if buffer[0] == b'-' {
-                    self.token_val = -self.token_val;
-                }
-                self.token = TokenType::Num as i32;
-            }
-            return;
-        }
-
-        // Parse character literal
-        if ch == b'\'' {
-            self.pos += 1;
-
-            // Handle escape sequences
-            if self.pos < self.src.len() && self.src[self.pos] == b'\\' {
-                self.pos += 1;
-                if self.pos < self.src.len() {
-                    match self.src[self.pos] {
-                        b'n' => self.token_val = b'\n' as i32,
-                        b't' => self.token_val = b'\t' as i32,
-                        b'r' => self.token_val = b'\r' as i32,
-                        b'0' => self.token_val = 0,
-                        _ => self.token_val = self.src[self.pos] as i32,
-                    }
-                }
-            } else if self.pos < self.src.len() {
-                self.token_val = self.src[self.pos] as i32;
-            }
-
-            self.pos += 1;
-
-            if self.pos < self.src.len() && self.src[self.pos] == b'\'' {
-                self.pos += 1;
-                self.token = TokenType::Num as i32;
-                return;
-            }
-
-            println!("Line {}: Unterminated character literal", self.line);
-            process::exit(1);
-        }
-
-        // Parse string literal
-        if ch == b'"' {
-            let data_idx = self.data.len();
-            self.pos += 1;
-
-            while self.pos < self.src.len() && self.src[self.pos] != b'"' {
-                // Handle escape sequences
-                if self.src[self.pos] == b'\\' {
-                    self.pos += 1;
-                    if self.pos < self.src.len() {
-                        match self.src[self.pos] {
-                            b'n' => self.data.push(b'\n' as i32),
-                            b't' => self.data.push(b'\t' as i32),
-                            b'r' => self.data.push(b'\r' as i32),
-                            b'0' => self.data.push(0),
-                            _ => self.data.push(self.src[self.pos] as i32),
-                        }
-                    }
-                } else {
-                    self.data.push(self.src[self.pos] as i32);
-                }
-
-                self.pos += 1;
-            }
-
-            if self.pos < self.src.len() && self.src[self.pos] == b'"' {
-                self.pos += 1;
-                self.data.push(0); // Null-terminate the string
-                self.token = TokenType::Num as i32;
-                self.token_val = data_idx as i32;
-                return;
-            }
-
-            println!("Line {}: Unterminated string literal", self.line);
-            process::exit(1);
-        }
-
-        // Parse operators
-        match ch {
-            b'=' => {
-                self.pos += 1;
-                if self.pos < self.src.len() && self.src[self.pos] == b'=' {
-                    self.pos += 1;
-                    self.token = TokenType::Eq as i32;
-                } else {
-                    self.token = b'=' as i32;
-                }
-            },
-            b'+' => {
-                if self.pos + 1 < self.src.len() && self.src[self.pos + 1] == b'+' {
-                    self.pos += 2;
-                    self.token = TokenType::Inc as i32;
-                } else {
-                    self.pos += 1;
-                    self.token = b'+' as i32;
-                }
-            },
-            b'-' => {
-                if self.pos + 1 < self.src.len() && self.src[self.pos + 1] == b'-' {
-                    self.pos += 2;
-                    self.token = TokenType::Dec as i32;
-                } else {
-                    self.pos += 1;
-                    self.token = b'-' as i32;
-                }
-            },
-            b'!' => {
-                self.pos += 1;
-                if self.pos < self.src.len() && self.src[self.pos] == b'=' {
-                    self.pos += 1;
-                    self.token = TokenType::Ne as i32;
-                } else {
-                    self.token = b'!' as i32;
-                }
-            },
-            b'<' => {
-                self.pos += 1;
-                if self.pos < self.src.len() && self.src[self.pos] == b'=' {
-                    self.pos += 1;
-                    self.token = TokenType::Le as i32;
-                } else if self.pos < self.src.len() && self.src[self.pos] == b'<' {
-                    self.pos += 1;
-                    self.token = TokenType::Shl as i32;
-                } else {
-                    self.token = b'<' as i32;
-                }
-            },
-            b'>' => {
-                self.pos += 1;
-                if self.pos < self.src.len() && self.src[self.pos] == b'=' {
-                    self.pos += 1;
-                    self.token = TokenType::Ge as i32;
-                } else if self.pos < self.src.len() && self.src[self.pos] == b'>' {
-                    self.pos += 1;
-                    self.token = TokenType::Shr as i32;
-                } else {
-                    self.token = b'>' as i32;
-                }
-            },
-            b'|' => {
-                self.pos += 1;
-                if self.pos < self.src.len() && self.src[self.pos] == b'|' {
-                    self.pos += 1;
-                    self.token = TokenType::Lor as i32;
-                } else {
-                    self.token = b'|' as i32;
-                }
-            },
-            b'&' => {
-                self.pos += 1;
-                if self.pos < self.src.len() && self.src[self.pos] == b'&' {
-                    self.pos += 1;
-                    self.token = TokenType::Lan as i32;
-                } else {
-                    self.token = b'&' as i32;
-                }
-            },
-            b'^' => {
-                self.pos += 1;
-                self.token = b'^' as i32;
-            },
-            b'%' => {
-                self.pos += 1;
-                self.token = b'%' as i32;
-            },
-            b'*' => {
-                self.pos += 1;
-                self.token = b'*' as i32;
-            },
-            b'[' => {
-                self.pos += 1;
-                self.token = b'[' as i32;
-            },
-            b'?' => {
-                self.pos += 1;
-                self.token = b'?' as i32;
-            },
-            b'~' | b';' | b'{' | b'}' | b'(' | b')' | b']' | b',' | b':' => {
-                self.token = ch as i32;
-                self.pos += 1;
-            },
-            b'/' => {
-                self.pos += 1;
-                self.token = b'/' as i32;
-            },
-            b'.' => {
-                self.pos += 1;
-                self.token = b'.' as i32;
-            },
-            _ => {
-                if ch.is_ascii_punctuation() {
-                    self.token = ch as i32;
-                    self.pos += 1;
-                } else {
-                    println!("Line {}: Unexpected character: {}", self.line, ch as char);
-                    self.pos += 1;
-                    self.token = ch as i32;
-                }
-            }
-        }
-    }
-
-    /// Match the current token with the expected token
-    ///
-    /// If the current token matches the expected token, advance to the next token.
-    /// Otherwise, print an error message and exit.
-    pub fn match_token(&mut self, expected_token: i32) {
-        if self.token != expected_token {
-            let expected = if expected_token < 128 {
-                format!("'{}'", expected_token as u8 as char)
-            } else {
-                format!("{:?}", TokenType::from_i32(expected_token))
-            };
-            let got = if self.token < 128 {
-                format!("'{}'", self.token as u8 as char)
-            } else {
-                format!("{:?}", TokenType::from_i32(self.token))
-            };
-            println!("Line {}: Expected token {}, got {}", self.line, expected, got);
-            process::exit(1);
-        }
-        self.next();
-    }
-
-    /// Parse an expression with the given precedence level
-    ///
-    /// This function implements a recursive descent parser with precedence climbing.
-    /// It handles primary expressions, unary operators, and binary operators.
-    ///
-    /// # Arguments
-    ///
-    /// * `level` - The precedence level to start parsing at
-    ///
-    /// # Returns
-    ///
-    /// The value of the expression (for constant expressions)
-    pub fn expression(&mut self, level: i32) -> i32 {
-        // backup & tmp must be mutable and initialized
-        let expr_type_backup: i32 = 0;
-        let mut tmp: i32 = 0;
-        let mut _addr: i32;
-
-        const TOKEN_INC: i32 = TokenType::Inc as i32;
-        const TOKEN_DEC: i32 = TokenType::Dec as i32;
-        const TOKEN_SIZEOF: i32 = TokenType::Sizeof as i32;
-        const OPEN_PAREN: i32 = b'(' as i32;
-        const ASTERISK: i32 = b'*' as i32;
-        const AMPERSAND: i32 = b'&' as i32;
-        const EXCLAMATION: i32 = b'!' as i32;
-        const TILDE: i32 = b'~' as i32;
-        const MINUS: i32 = b'-' as i32;
-
-        // Primary expressions
-        match self.token {
-            t if t == TokenType::Num as i32 => {
-                // Number literal
-                self.expr_type = INT;
-                tmp = self.token_val;
-                self.next();
-                return tmp;
-            },
-            t if t == TokenType::Float as i32 => {
-                self.text.push(Instruction::IMM as i32);
-                self.text.push(self.token_val);
-                self.text.push(Instruction::FLD as i32);
-                self.expr_type = FLOAT;
-                self.next();
-                return 0;
-            },
-            t if t == TokenType::Id as i32 => {
-                // Function call or variable
-                let id_str = String::from_utf8_lossy(&self.current_id).to_string();
-                let mut symbol_idx = -1;
-
-                // Find the symbol in the symbol table
-                for (i, symbol) in self.symbols.iter().enumerate() {
-                    if symbol.name == id_str {
-                        symbol_idx = i as i32;
-                        break;
-                    }
-                }
-
-                if symbol_idx == -1 {
-                    println!("Line {}: Undefined variable: {}", self.line, id_str);
-                    process::exit(1);
-                }
-
-                self.next();
-
-                // Function call
-                if self.token == b'(' as i32 {
-                    self.match_token(b'(' as i32);
-
-                    // Push arguments
-                    let mut arg_count = 0;
-                    while self.token != b')' as i32 {
-                        self.expression(Assign);
-                        self.text.push(Instruction::PUSH as i32);
-                        arg_count += 1;
-
-                        if self.token == b')' as i32 {
-                            break;
-                        }
-                        self.match_token(b',' as i32);
-                    }
-                    self.match_token(b')' as i32);
-
-                    // Call the function
-                    if self.symbols[symbol_idx as usize].class == TokenType::Sys as i32 {
-                        // System call
-                        self.text.push(self.symbols[symbol_idx as usize].value);
-                    } else {
-                        // Function call
-                        self.text.push(Instruction::JSR as i32);
-                        self.text.push(self.symbols[symbol_idx as usize].value);
-                    }
-
-                    // Clean up arguments
-                    if arg_count > 0 {
-                        self.text.push(Instruction::ADJ as i32);
-                        self.text.push(arg_count);
-                    }
-                    self.expr_type = self.symbols[symbol_idx as usize].type_;
-                    return INT;
-                } else {
-                    // Variable
-                    if self.symbols[symbol_idx as usize].class == TokenType::Loc as i32 {
-                        self.text.push(Instruction::LEA as i32);
-                        self.text.push(self.index_of_bp - self.symbols[symbol_idx as usize].value);
-                    } else if self.symbols[symbol_idx as usize].class == TokenType::Glo as i32 {
-                        self.text.push(Instruction::IMM as i32);
-                        self.text.push(self.symbols[symbol_idx as usize].value);
-                    } else {
-                        println!("Line {}: Invalid variable: {}", self.line, id_str);
-                        process::exit(1);
-                    }
-
-                    self.expr_type = self.symbols[symbol_idx as usize].type_;
-
-                    // Array access
-                    if self.token == b'[' as i32 {
-                        self.match_token(b'[' as i32);
-                        self.expression(Assign);
-                        self.match_token(b']' as i32);
-
-                        if self.expr_type > PTR {
-                            self.text.push(Instruction::PUSH as i32);
-                            self.text.push(Instruction::IMM as i32);
-                            self.text.push(4);
-                            self.text.push(Instruction::MUL as i32);
-                            self.text.push(Instruction::ADD as i32);
-                        } else if self.expr_type < PTR {
-                            println!("Line {}: Invalid array access", self.line);
-                            process::exit(1);
-                        }
-
-                        // Load the value
-                        if self.expr_type == CHAR + PTR {
-                            self.text.push(Instruction::LC as i32);
-                            self.expr_type = CHAR;
-                        } else {
-                            self.text.push(Instruction::LI as i32);
-                            self.expr_type = INT;
-                        }
-                    }
-
-                    return INT;
-                }
-            },
-            OPEN_PAREN => {
-                self.match_token(b'(' as i32);
-                if self.token == TokenType::Int as i32 || self.token == TokenType::Char as i32 {
-                    // Type cast
-                    let mut cast_type = if self.token == TokenType::Int as i32 { INT } else { CHAR };
-                    self.next();
-                    while self.token == TokenType::Mul as i32 {
-                        self.next();
-                        cast_type += PTR;
-                    }
-                    self.match_token(b')' as i32);
-                    self.expression(Inc);
-                    self.expr_type = cast_type;
-                    return INT;
-                } else {
-                    // Parenthesized expression
-                    tmp = self.expression(Assign);
-                    self.match_token(b')' as i32);
-                    return tmp;
-                }
-            },
-            ASTERISK => {
-                // Dereference
-                self.next();
-                self.expression(Inc);
-
-                if self.expr_type >= PTR {
-                    self.expr_type -= PTR;
-                } else {
-                    println!("Line {}: Invalid dereference", self.line);
-                    process::exit(1);
-                }
-
-                // Load the value
-                if self.expr_type == CHAR {
-                    self.text.push(Instruction::LC as i32);
-                } else {
-                    self.text.push(Instruction::LI as i32);
-                }
-
-                return INT;
-            },
-            AMPERSAND => {
-                // Address-of
-                self.next();
-                self.expression(Inc);
-
-                if self.token == TOKEN_INC || self.token == TOKEN_DEC {
-                    println!("Line {}: Invalid use of address-of operator", self.line);
-                    process::exit(1);
-                }
-
-                self.expr_type += PTR;
-                return INT;
-            },
-            EXCLAMATION => {
-                // Logical not
-                self.next();
-                self.expression(Inc);
-                self.text.push(Instruction::PUSH as i32);
-                self.text.push(Instruction::IMM as i32);
-                self.text.push(0);
-                self.text.push(Instruction::EQ as i32);
-                self.expr_type = INT;
-                return INT;
-            },
-            TILDE => {
-                // Bitwise not
-                self.next();
-                self.expression(Inc);
-                self.text.push(Instruction::PUSH as i32);
-                self.text.push(Instruction::IMM as i32);
-                self.text.push(-1);
-                self.text.push(Instruction::XOR as i32);
-                return INT;
-            },
-            MINUS => {
-                // Unary minus
-                self.next();
-                self.expression(Inc);
-                self.text.push(Instruction::PUSH as i32);
-                self.text.push(Instruction::IMM as i32);
-                self.text.push(0);
-                self.text.push(Instruction::SUB as i32);
-                return INT;
-            },
-            TOKEN_INC => {
-                // Pre-increment
-                self.next();
-                self.expression(Inc);
-
-                if self.expr_type > PTR {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(4);
-                    self.text.push(Instruction::ADD as i32);
-                } else {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(1);
-                    self.text.push(Instruction::ADD as i32);
-                }
-
-                // Store the value
-                if self.expr_type == CHAR {
-                    self.text.push(Instruction::SC as i32);
-                } else {
-                    self.text.push(Instruction::SI as i32);
-                }
-
-                return INT;
-            },
-            TOKEN_DEC => {
-                // Pre-decrement
-                self.next();
-                self.expression(Inc);
-
-                if self.expr_type > PTR {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(4);
-                    self.text.push(Instruction::SUB as i32);
-                } else {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(1);
-                    self.text.push(Instruction::SUB as i32);
-                }
-
-                // Store the value
-                if self.expr_type == CHAR {
-                    self.text.push(Instruction::SC as i32);
-                } else {
-                    self.text.push(Instruction::SI as i32);
-                }
-
-                return INT;
-            },
-            TOKEN_SIZEOF => {
-                // Sizeof operator
-                self.next();
-                self.match_token(b'(' as i32);
-
-                if self.token == TokenType::Int as i32 || self.token == TokenType::Char as i32 {
-                    // Type
-                    let mut size_type = if self.token == TokenType::Int as i32 { INT } else { CHAR };
-                    self.next();
-                    while self.token == TokenType::Mul as i32 {
-                        self.next();
-                        size_type += PTR;
-                    }
-                    self.match_token(b')' as i32);
-
-                    // Calculate size
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(if size_type == CHAR { 1 } else { 4 });
-                    self.expr_type = INT;
-                } else {
-                    // Expression
-                    self.expression(Assign);
-                    self.match_token(b')' as i32);
-
-                    // Calculate size
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(if self.expr_type == CHAR { 1 } else { 4 });
-                    self.expr_type = INT;
-                }
-
-                return INT;
-            }
-            _ => {
-                println!("Line {}: Invalid expression", self.line);
-                process::exit(1);
-            }
-        }
-
-        // Binary operators and precedence climbing logic
-        if level <= Assign {
-            // Assignment operators
-            if self.token == b'=' as i32 {
-                expr_type_backup = self.expr_type;
-                self.match_token(b'=' as i32);
-                self.expression(Assign);
-                self.expr_type = expr_type_backup;
-
-                // Store the value
-                if self.expr_type == CHAR {
-                    self.text.push(Instruction::SC as i32);
-                } else {
-                    self.text.push(Instruction::SI as i32);
-                }
-
-                return INT;
-            } else if self.token == TokenType::Add as i32 || self.token == TokenType::Sub as i32 ||
-                      self.token == TokenType::Mul as i32 || self.token == TokenType::Div as i32 ||
-                      self.token == TokenType::Mod as i32 || self.token == TokenType::Shl as i32 ||
-                      self.token == TokenType::Shr as i32 || self.token == TokenType::And as i32 ||
-                      self.token == TokenType::Or as i32 || self.token == TokenType::Xor as i32 {
-                // Compound assignment
-                let op = self.token;
-                self.next();
-                self.expression(Assign);
-                self.expr_type = expr_type_backup;
-
-                // Perform the operation
-                match op {
-                    t if t == TokenType::Add as i32 => self.text.push(Instruction::ADD as i32),
-                    t if t == TokenType::Sub as i32 => self.text.push(Instruction::SUB as i32),
-                    t if t == TokenType::Mul as i32 => self.text.push(Instruction::MUL as i32),
-                    t if t == TokenType::Div as i32 => self.text.push(Instruction::DIV as i32),
-                    t if t == TokenType::Mod as i32 => self.text.push(Instruction::MOD as i32),
-                    t if t == TokenType::Shl as i32 => self.text.push(Instruction::SHL as i32),
-                    t if t == TokenType::Shr as i32 => self.text.push(Instruction::SHR as i32),
-                    t if t == TokenType::And as i32 => self.text.push(Instruction::AND as i32),
-                    t if t == TokenType::Or as i32 => self.text.push(Instruction::OR as i32),
-                    t if t == TokenType::Xor as i32 => self.text.push(Instruction::XOR as i32),
-                    _ => {}
-                }
-
-                // Store the value
-                if self.expr_type == CHAR {
-                    self.text.push(Instruction::SC as i32);
-                } else {
-                    self.text.push(Instruction::SI as i32);
-                }
-
-                return INT;
-            }
-        }
-
-        if level <= Cond {
-            // Conditional operator
-            if self.token == b'?' as i32 {
-                self.match_token(b'?' as i32);
-
-                // Jump to else if false
-                let else_jmp = self.text.len();
-                self.text.push(Instruction::BZ as i32);
-                self.text.push(0);
-
-                // True expression
-                self.expression(Assign);
-                expr_type_backup = self.expr_type;
-
-                // Jump to end
-                let end_jmp = self.text.len();
-                self.text.push(Instruction::JMP as i32);
-                self.text.push(0);
-
-                // Else expression
-                self.text[else_jmp + 1] = self.text.len() as i32;
-                self.match_token(b':' as i32);
-                self.expression(Cond);
-
-                // End
-                self.text[end_jmp + 1] = self.text.len() as i32;
-                self.expr_type = expr_type_backup;
-
-                return INT;
-            }
-        }
-
-        if level <= Lor {
-            // Logical OR
-            if self.token == TokenType::Lor as i32 {
-                self.match_token(TokenType::Lor as i32);
-
-                // Jump to true if true
-                let true_jmp = self.text.len();
-                self.text.push(Instruction::BNZ as i32);
-                self.text.push(0);
-
-                // Right expression
-                self.expression(Lan);
-
-                // End
-                self.text[true_jmp + 1] = self.text.len() as i32;
-                self.expr_type = INT;
-
-                return INT;
-            }
-        }
-
-        if level <= Lan {
-            // Logical AND
-            if self.token == TokenType::Lan as i32 {
-                self.match_token(TokenType::Lan as i32);
-
-                // Jump to false if false
-                let false_jmp = self.text.len();
-                self.text.push(Instruction::BZ as i32);
-                self.text.push(0);
-
-                // Right expression
-                self.expression(Or);
-
-                // End
-                self.text[false_jmp + 1] = self.text.len() as i32;
-                self.expr_type = INT;
-
-                return INT;
-            }
-        }
-
-        if level <= Or {
-            // Bitwise OR
-            if self.token == b'|' as i32 {
-                self.match_token(b'|' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Xor);
-                self.text.push(Instruction::OR as i32);
-                self.expr_type = INT;
-                return INT;
-            }
-        }
-
-        if level <= Xor {
-            // Bitwise XOR
-            if self.token == b'^' as i32 {
-                self.match_token(b'^' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(And);
-                self.text.push(Instruction::XOR as i32);
-                self.expr_type = INT;
-                return INT;
-            }
-        }
-
-        if level <= And {
-            // Bitwise AND
-            if self.token == b'&' as i32 {
-                self.match_token(b'&' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Eq);
-                self.text.push(Instruction::AND as i32);
-                self.expr_type = INT;
-                return INT;
-            }
-        }
-
-        if level <= Eq {
-            // Equality operators
-            if self.token == TokenType::Eq as i32 {
-                self.match_token(TokenType::Eq as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Ne);
-                self.text.push(Instruction::EQ as i32);
-                self.expr_type = INT;
-                return INT;
-            } else if self.token == TokenType::Ne as i32 {
-                self.match_token(TokenType::Ne as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Ne);
-                self.text.push(Instruction::NE as i32);
-                self.expr_type = INT;
-                return INT;
-            }
-        }
-
-        if level <= Lt {
-            // Relational operators
-            if self.token == b'<' as i32 {
-                self.match_token(b'<' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Shl);
-                self.text.push(Instruction::LT as i32);
-                self.expr_type = INT;
-                return INT;
-            } else if self.token == b'>' as i32 {
-                self.match_token(b'>' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Shl);
-                self.text.push(Instruction::GT as i32);
-                self.expr_type = INT;
-                return INT;
-            } else if self.token == TokenType::Le as i32 {
-                self.match_token(TokenType::Le as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Shl);
-                self.text.push(Instruction::LE as i32);
-                self.expr_type = INT;
-                return INT;
-            } else if self.token == TokenType::Ge as i32 {
-                self.match_token(TokenType::Ge as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Shl);
-                self.text.push(Instruction::GE as i32);
-                self.expr_type = INT;
-                return INT;
-            }
-        }
-
-        if level <= Shl {
-            // Shift operators
-            if self.token == TokenType::Shl as i32 {
-                self.match_token(TokenType::Shl as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Add);
-                self.text.push(Instruction::SHL as i32);
-                self.expr_type = INT;
-                return INT;
-            } else if self.token == TokenType::Shr as i32 {
-                self.match_token(TokenType::Shr as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Add);
-                self.text.push(Instruction::SHR as i32);
-                self.expr_type = INT;
-                return INT;
-            }
-        }
-
-        if level <= Add {
-            // Additive operators
-            if self.token == b'+' as i32 {
-                self.match_token(b'+' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Mul);
-
-                // Pointer arithmetic
-                if expr_type_backup > PTR {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(4);
-                    self.text.push(Instruction::MUL as i32);
-                    self.text.push(Instruction::ADD as i32);
-                }
-
-                self.text.push(Instruction::ADD as i32);
-                self.expr_type = expr_type_backup;
-                return INT;
-            } else if self.token == b'-' as i32 {
-                self.match_token(b'-' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Mul);
-
-                // Pointer arithmetic
-                if expr_type_backup > PTR && self.expr_type == INT {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(4);
-                    self.text.push(Instruction::MUL as i32);
-                }
-
-                self.text.push(Instruction::SUB as i32);
-                self.expr_type = expr_type_backup;
-                return INT;
-            }
-        }
-
-        if level <= Mul {
-            // Multiplicative operators
-            if self.token == b'*' as i32 {
-                self.match_token(b'*' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Inc);
-                self.text.push(Instruction::MUL as i32);
-                self.expr_type = INT;
-                return INT;
-            } else if self.token == b'/' as i32 {
-                self.match_token(b'/' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Inc);
-                self.text.push(Instruction::DIV as i32);
-                self.expr_type = INT;
-                return INT;
-            } else if self.token == b'%' as i32 {
-                self.match_token(b'%' as i32);
-                self.text.push(Instruction::PUSH as i32);
-                self.expression(Inc);
-                self.text.push(Instruction::MOD as i32);
-                self.expr_type = INT;
-                return INT;
-            }
-        }
-
-        if level <= Inc {
-            // Postfix operators
-            if self.token == TOKEN_INC {
-                self.match_token(TOKEN_INC);
-
-                // Save the value
-                self.text.push(Instruction::PUSH as i32);
-                self.text.push(Instruction::LI as i32);
-
-                // Increment
-                if self.expr_type > PTR {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(4);
-                    self.text.push(Instruction::ADD as i32);
-                } else {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(1);
-                    self.text.push(Instruction::ADD as i32);
-                }
-
-                // Store the value
-                if self.expr_type == CHAR {
-                    self.text.push(Instruction::SC as i32);
-                } else {
-                    self.text.push(Instruction::SI as i32);
-                }
-
-                return INT;
-            } else if self.token == TOKEN_DEC {
-                self.match_token(TOKEN_DEC);
-
-                // Save the value
-                self.text.push(Instruction::PUSH as i32);
-                self.text.push(Instruction::LI as i32);
-
-                // Decrement
-                if self.expr_type > PTR {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(4);
-                    self.text.push(Instruction::SUB as i32);
-                } else {
-                    self.text.push(Instruction::PUSH as i32);
-                    self.text.push(Instruction::IMM as i32);
-                    self.text.push(1);
-                    self.text.push(Instruction::SUB as i32);
-                }
-
-                // Store the value
-                if self.expr_type == CHAR {
-                    self.text.push(Instruction::SC as i32);
-                } else {
-                    self.text.push(Instruction::SI as i32);
-                }
-
-                return INT;
-            }
-        }
-
-        return INT;
-    }
-
-    /// Parse a statement
-    ///
-    /// This function parses a statement, which can be an if statement,
-    /// while statement, return statement, block, or expression statement.
-    pub fn statement(&mut self) {
-        println!("Parsing statement, token: {}", self.token);
-        let mut _expr_type: i32;
-        let mut _tmp: i32;
-
-        if self.token == TokenType::If as i32 {
-            // If statement
-            println!("Parsing if statement");
-            self.match_token(TokenType::If as i32);
-            self.match_token(b'(' as i32);
-            self.expression(Assign);
-            self.match_token(b')' as i32);
-
-            // Jump to else if false
-            let else_jmp = self.text.len();
-            self.text.push(Instruction::BZ as i32);
-            self.text.push(0);
-
-            // Then statement
-            println!("Parsing 'then' part of if statement");
-            self.statement();
-
-            // Jump to end
-            let end_jmp = self.text.len();
-            self.text.push(Instruction::JMP as i32);
-            self.text.push(0);
-
-            // Else statement
-            self.text[else_jmp + 1] = self.text.len() as i32;
-
-            if self.token == TokenType::Else as i32 {
-                println!("Parsing 'else' part of if statement");
-                self.match_token(TokenType::Else as i32);
-                self.statement();
-            }
-
-            // End
-            self.text[end_jmp + 1] = self.text.len() as i32;
-            println!("Finished if statement");
-        } else if self.token == TokenType::While as i32 {
-            // While statement
-            println!("Parsing while statement");
-            self.match_token(TokenType::While as i32);
-
-            // Loop start
-            let loop_start = self.text.len();
-            self.match_token(b'(' as i32);
-            self.expression(Assign);
-            self.match_token(b')' as i32);
-
-            // Jump to end if false
-            let end_jmp = self.text.len();
-            self.text.push(Instruction::BZ as i32);
-            self.text.push(0);
-
-            // Body
-            println!("Parsing body of while statement");
-            self.statement();
-
-            // Jump back to start
-            self.text.push(Instruction::JMP as i32);
-            self.text.push(loop_start as i32);
-
-            // End
-            self.text[end_jmp + 1] = self.text.len() as i32;
-            println!("Finished while statement");
-        } else if self.token == TokenType::Return as i32 {
-            // Return statement
-            println!("Parsing return statement");
-            self.match_token(TokenType::Return as i32);
-
-            if self.token != b';' as i32 {
-                println!("Parsing return expression");
-                self.expression(Assign);
-            } else {
-                println!("Empty return statement");
-                // For empty return, push 0 as the default return value
-                self.text.push(Instruction::IMM as i32);
-                self.text.push(0);
-            }
-
-            self.match_token(b';' as i32);
-
-            // Return
-            println!("Adding LEV instruction for return");
-            self.text.push(Instruction::LEV as i32);
-            println!("Finished return statement");
-        } else if self.token == b'{' as i32 {
-            // Block
-            println!("Parsing block statement");
-            self.match_token(b'{' as i32);
-
-            while self.token != b'}' as i32 && self.token != 0 {
-                println!("Parsing statement in block");
-                self.statement();
-            }
-
-            if self.token == 0 {
-                println!("Reached end of source before end of block");
-                // Add implicit return 0 if we hit the end unexpectedly
-                self.text.push(Instruction::IMM as i32);
-                self.text.push(0);
-                self.text.push(Instruction::LEV as i32);
-            } else {
-            self.match_token(b'}' as i32);
-                println!("Finished block statement");
-            }
-        } else if self.token == b';' as i32 {
-            // Empty statement
-            println!("Empty statement");
-            self.match_token(b';' as i32);
-        } else {
-            // Expression statement
-            println!("Parsing expression statement");
-            self.expression(Assign);
-            self.match_token(b';' as i32);
-            println!("Finished expression statement");
-        }
-        
-        println!("Completed statement");
-    }
-
-    /// Parse a function definition
-    ///
-    /// This function parses a function definition, including the return type,
-    /// function name, parameters, and function body.
-    pub fn function(&mut self) {
-        println!("Parsing function");
-        let mut type_: i32;
-
-        // Parse return type
-        type_ = if self.token == TokenType::Int as i32 { INT } else { CHAR };
-        self.next();
-
-        // Handle pointer return types
-        while self.token == b'*' as i32 {
-            self.next();
-            type_ += PTR;
-        }
-
-        // Parse function name
-        if self.token != TokenType::Id as i32 {
-            println!("Expected function name, got: {}", self.token);
-            return; // Skip invalid function declarations
-        }
-
-        let fn_name = String::from_utf8_lossy(&self.current_id).to_string();
-        println!("Function name: {}", fn_name);
-        self.next();
-
-        // Parse parameters
-        if self.token != b'(' as i32 {
-            println!("Expected '(' after function name, got: {}", self.token);
-            return; // Skip invalid function declarations
-        }
-        self.next();
-
-        // Record the entry point for the function
-        let function_entry = self.text.len();
-        
-        // Generate function prologue - ENT 0 (will be adjusted later)
-        self.text.push(Instruction::ENT as i32);
-        self.text.push(0);  // Placeholder for local variable space
-
-        let mut param_count = 0;
-        let mut local_offset = 8; // First local variable offset (after BP and return address)
-        
-        if self.token != b')' as i32 {
-            // Parameter list
-            println!("Parsing parameters");
-            let mut loop_count = 0;
-            let max_loops = 100; // Prevent infinite loops
-            loop {
-                loop_count += 1;
-                if loop_count > max_loops {
-                    println!("Too many iterations parsing parameters, forcing exit");
-                    break;
-                }
-                
-                if self.token == 0 {
-                    println!("Unexpected end of input while parsing parameters");
-                    return;
-                }
-                
-                type_ = if self.token == TokenType::Int as i32 { INT } else { CHAR };
-                self.next();
-
-                while self.token == b'*' as i32 {
-                    self.next();
-                    type_ += PTR;
-                }
-
-                // Parameter name
-                if self.token != TokenType::Id as i32 {
-                    println!("Expected parameter name, got: {}", self.token);
-                    break;
-                }
-                
-                param_count += 1;
-                let param_name = String::from_utf8_lossy(&self.current_id).to_string();
-                println!("Parameter {}: {}", param_count, param_name);
-                
-                // Add the parameter to the symbol table as a local variable
-                self.symbols.push(Symbol {
-                    token: TokenType::Id,
-                    hash: 0,
-                    name: param_name,
-                    class: TokenType::Loc as i32,
-                    type_: type_,
-                    value: param_count,  // Parameter index
-                    bclass: 0,
-                    btype: 0,
-                    bvalue: 0,
-                });
-                
-                local_offset += 4; // Each parameter takes 4 bytes
-                self.next();
-
-                if self.token == b')' as i32 {
-                    break;
-                }
-                
-                if self.token != b',' as i32 {
-                    println!("Expected ',' or ')' after parameter, got: {}", self.token);
-                    break;
-                }
-                self.next();
-            }
-        }
-
-        println!("Finished parsing parameters, found {} parameters", param_count);
-        
-        // Check for end of input
-        if self.token == 0 {
-            println!("Unexpected end of input after parameters");
-            return;
-        }
-
-        self.next(); // Skip ')'
-
-        // Function body
-        if self.token == b'{' as i32 {
-            println!("Parsing function body");
-            self.next();
-            
-            // Parse local declarations and statements
-            let mut local_var_count = 0;
-            let mut stmt_count = 0;
-            let max_statements = 1000; // Prevent infinite loops
-            
-            // First, look for local variable declarations
-            while self.token == TokenType::Int as i32 || self.token == TokenType::Char as i32 {
-                type_ = if self.token == TokenType::Int as i32 { INT } else { CHAR };
-                self.next();
-                
-                while self.token == b'*' as i32 {
-                    self.next();
-                    type_ += PTR;
-                }
-                
-                if self.token != TokenType::Id as i32 {
-                    println!("Expected local variable name, got: {}", self.token);
-                    break;
-                }
-                
-                local_var_count += 1;
-                let var_name = String::from_utf8_lossy(&self.current_id).to_string();
-                println!("Local variable {}: {}", local_var_count, var_name);
-                
-                // Add the local variable to the symbol table
-                self.symbols.push(Symbol {
-                    token: TokenType::Id,
-                    hash: 0,
-                    name: var_name,
-                    class: TokenType::Loc as i32,
-                    type_: type_,
-                    value: local_offset,  // Variable offset from BP
-                    bclass: 0,
-                    btype: 0,
-                    bvalue: 0,
-                });
-                
-                local_offset += 4; // Each local variable takes 4 bytes
-                self.next();
-                
-                if self.token == b';' as i32 {
-                    self.next();
-                } else {
-                    println!("Expected ';' after local variable declaration, got: {}", self.token);
-                    break;
-                }
-            }
-            
-            // Update the function prologue with the correct local variable space
-            self.text[function_entry + 1] = local_var_count * 4;
-            
-            // Parse statements
-            while self.token != b'}' as i32 && self.token != 0 && stmt_count < max_statements {
-                println!("Parsing statement in function body, token: {}", self.token);
-                self.statement();
-                stmt_count += 1;
-            }
-            
-            if stmt_count >= max_statements {
-                println!("Too many statements in function body, forcing exit");
-            }
-            
-            // If there's no explicit return at the end, add an implicit return 0
-            if self.text[self.text.len() - 1] != Instruction::LEV as i32 {
-                self.text.push(Instruction::IMM as i32);
-                self.text.push(0);
-                self.text.push(Instruction::LEV as i32);
-            }
-            
-            if self.token == b'}' as i32 {
-                println!("Found closing brace, skipping");
-                self.next();
-            } else {
-                println!("Expected '}}' at end of function body, got: {}", self.token);
-            }
-        } else {
-            println!("Expected '{{' for function body, got: {}", self.token);
-        }
-        
-        println!("Finished parsing function: {}", fn_name);
-    }
-
-    /// Parse the program
-    ///
-    /// This function parses the entire program, including global declarations
-    /// and function definitions.
-    pub fn program(&mut self) {
-        println!("Starting program()");
-        self.next(); // Get first token
-        println!("First token: {}", self.token);
-        
-        // To prevent infinite loops, track the position and add a maximum iteration limit
-        let mut prev_pos = self.pos;
-        let mut iteration_count = 0;
-        let max_iterations = 10000;
-        
-        while self.token != 0 && iteration_count < max_iterations {
-            iteration_count += 1;
-            
-            // Check if position has changed, if not, we're stuck
-            if self.pos == prev_pos && iteration_count > 1 {
-                println!("Warning: Parser stuck at position {} with token {}", self.pos, self.token);
-                // Force advance to prevent infinite loop
-                self.pos += 1;
-                if self.pos >= self.src.len() {
-                    println!("Reached end of source code, breaking loop");
-                    break;
-                }
-                self.next();
-                prev_pos = self.pos;
-                continue;
-            }
-            
-            prev_pos = self.pos;
-            
-            // Check for valid type specifiers
-            if self.token != TokenType::Int as i32 && self.token != TokenType::Char as i32 {
-                // Skip invalid tokens
-                println!("Skipping invalid token: {}", self.token);
-                self.next();
-                continue;
-            }
-
-            // Get base type
-            let base_type = if self.token == TokenType::Int as i32 { 
-                println!("Found type specifier: {}", self.token);
-                INT 
-            } else { 
-                println!("Found type specifier: {}", self.token);
-                CHAR 
-            };
-            self.next();
-
-            // Handle pointer declarations
-            let mut var_type = base_type;
-            while self.token == b'*' as i32 {
-                println!("Found pointer operator");
-                self.next();
-                var_type += PTR;
-            }
-
-            // Must have identifier
-            if self.token != TokenType::Id as i32 {
-                println!("Expected identifier, got: {}", self.token);
-                continue; // Skip invalid declarations
-            }
-
-            // Save identifier info
-            println!("Found identifier: {}", String::from_utf8_lossy(&self.current_id));
-            let name = String::from_utf8_lossy(&self.current_id).to_string();
-            let id_backup = self.current_id.clone();
-            let pos_backup = self.pos;
-            let token_backup = self.token;
-            
-            self.next();
-
-            // Function or variable?
-            if self.token == b'(' as i32 {
-                // For main function, create a very simple implementation that just returns 42
-                if name == "main" {
-                    println!("Found main function, creating simple implementation that returns 42");
-                    
-                    // Record the start position in text segment
-                    let fn_pos = self.text.len() as i32;
-                    
-                    // Add function to symbol table
-                    if !self.symbols.iter().any(|s| s.name == name) {
-                        println!("Adding function to symbol table: {}", name);
-                        self.symbols.push(Symbol {
-                            token: TokenType::Id,
-                            hash: 0,
-                            name: name.clone(),
-                            class: TokenType::Fun as i32,
-                            type_: var_type,
-                            value: fn_pos,
-                            bclass: 0,
-                            btype: 0,
-                            bvalue: 0,
-                        });
-                    }
-                    
-                    // Skip the rest of the function declaration
-                    self.match_token(b'(' as i32);
-                    self.match_token(b')' as i32);
-                    self.match_token(b'{' as i32);
-                    
-                    // Generate code for "return 42;"
-                    self.text.push(Instruction::IMM as i32); // Load immediate value
-                    self.text.push(42);                      // The value 42
-                    self.text.push(Instruction::LEV as i32); // Return from function
-                    
-                    // Skip to the end of the function
-                    while self.token != b'}' as i32 && self.token != 0 {
-                        self.next();
-                    }
-                    if self.token == b'}' as i32 {
-                        self.next();
-                    }
-                } else {
-                    // Function declaration (non-main)
-                    println!("Found function declaration: {}", name);
-                self.pos = pos_backup;
-                self.token = token_backup;
-                self.current_id = id_backup;
-                
-                // Add function to symbol table if not already present
-                if !self.symbols.iter().any(|s| s.name == name) {
-                        println!("Adding function to symbol table: {}", name);
-                    self.symbols.push(Symbol {
-                        token: TokenType::Id,
-                        hash: 0,
-                        name: name.clone(),
-                        class: TokenType::Fun as i32,
-                        type_: var_type,
-                        value: self.text.len() as i32,
-                        bclass: 0,
-                        btype: 0,
-                        bvalue: 0,
-                    });
-                }
-                
-                self.function();
-                }
-            } else {
-                // Global variable
-                println!("Found global variable: {}", name);
-                if self.token == b'=' as i32 {
-                    self.next();
-                    self.expression(Assign);
-                }
-
-                // Add variable to symbol table
-                self.symbols.push(Symbol {
-                    token: TokenType::Id,
-                    hash: 0,
-                    name,
-                    class: TokenType::Glo as i32,
-                    type_: var_type,
-                    value: (self.data.len() + 1) as i32,
-                    bclass: 0,
-                    btype: 0,
-                    bvalue: 0,
-                });
-
-                if self.token == b';' as i32 {
-                    self.next();
-                }
-            }
-        }
-        
-        if iteration_count >= max_iterations {
-            println!("Warning: Maximum iteration count reached in program parsing");
-        }
-        
-        println!("Reached end of source");
-        println!("Finished program()");
-    }
-
-    /// Run the virtual machine
-    ///
-    /// This function runs the virtual machine with the given entry point,
-    /// command line arguments, and environment.
-    ///
-    /// # Arguments
-    ///
-    /// * `entry` - The entry point (address) to start execution from
-    /// * `argc` - The number of command line arguments
-    /// * `argv` - The command line arguments
-    ///
-    /// # Returns
-    ///
-    /// The exit code of the program
-    pub fn run(&mut self, entry: i32, argc: i32, argv: Vec<String>) -> i32 {
-        // Initialize VM state
-        self.pc = entry;
-        self.bp = POOL_SIZE as i32;
-        self.sp = POOL_SIZE as i32;
-        self.cycle = 0;
-        
-        // Make sure the stack has the required size - increase to POOL_SIZE + 3 to be safe
-        if self.stack.len() < POOL_SIZE + 3 {
-            self.stack.clear();
-            self.stack.resize(POOL_SIZE + 3, 0);
-        }
-
-        // Check if PC is valid before starting
-        if self.pc < 0 || self.pc >= self.text.len() as i32 {
-            println!("Invalid entry point: {}", self.pc);
-            return -1; // Invalid entry point
-        }
-
-        // Safely access stack - with bounds checking
-        if self.sp >= 1 && self.sp < self.stack.len() as i32 {
-        self.stack[self.sp as usize - 1] = argc;
-        self.sp -= 1;
-        } else {
-            println!("Stack out of bounds when setting argc");
-            return -1; // Stack out of bounds
-        }
-        
-        // Safely push return value and EXIT instruction
-        if self.sp >= 1 && self.sp < self.stack.len() as i32 {
-            self.stack[self.sp as usize] = 0; // Default return value
-            self.sp -= 1;
-        } else {
-            println!("Stack out of bounds when setting default return");
-            return -1; // Stack out of bounds
-        }
-        
-        if self.sp >= 0 && self.sp < self.stack.len() as i32 {
-            self.stack[self.sp as usize] = Instruction::EXIT as i32;
-            self.sp -= 1;
-        } else {
-            println!("Stack out of bounds when setting EXIT");
-            return -1; // Stack out of bounds
-        }
-
-        // Main execution loop
-        let max_cycles = 1000000; // Reasonable limit to prevent infinite loops
-        let mut last_pc = -1;  // Track the last PC to detect infinite loops
-        let mut stuck_count = 0; // Count how many times we've been stuck at the same PC
-        
-        while self.pc >= 0 && self.pc < self.text.len() as i32 && self.cycle < max_cycles {
-            // Check for infinite loops by detecting when PC doesn't change
-            if self.pc == last_pc {
-                stuck_count += 1;
-                if stuck_count > 100 {
-                    println!("Detected infinite loop at PC: {}", self.pc);
-                    return -2;  // Infinite loop detected
-                }
-            } else {
-                stuck_count = 0;
-                last_pc = self.pc;
-            }
-            
-            self.cycle += 1;
-            
-            if self.debug && self.cycle % 10000 == 0 {
-                println!("VM cycle: {}, PC: {}, SP: {}, BP: {}, AX: {}", 
-                         self.cycle, self.pc, self.sp, self.bp, self.ax);
-            }
-
-            // Fetch instruction
-            let op = self.text[self.pc as usize];
-            self.pc += 1;
-
-            match op {
-                op if op == Instruction::LEA as i32 => {
-                    // Load effective address
-                    if self.pc < self.text.len() as i32 {
-                    self.ax = self.bp + self.text[self.pc as usize];
-                    self.pc += 1;
-                    } else {
-                        println!("PC out of bounds in LEA");
-                        return -1; // PC out of bounds
-                    }
-                },
-                op if op == Instruction::IMM as i32 => {
-                    // Load immediate value
-                    if self.pc < self.text.len() as i32 {
-                    self.ax = self.text[self.pc as usize];
-                    self.pc += 1;
-                    } else {
-                        println!("PC out of bounds in IMM");
-                        return -1; // PC out of bounds
-                    }
-                },
-                op if op == Instruction::JMP as i32 => {
-                    // Jump
-                    if self.pc < self.text.len() as i32 {
-                    self.pc = self.text[self.pc as usize];
-                    } else {
-                        println!("PC out of bounds in JMP");
-                        return -1; // PC out of bounds
-                    }
-                },
-                op if op == Instruction::JSR as i32 => {
-                    // Jump to subroutine
-                    if self.sp >= 0 && self.sp < self.stack.len() as i32 && self.pc < self.text.len() as i32 {
-                    self.stack[self.sp as usize] = self.pc + 1;
-                    self.sp -= 1;
-                    self.pc = self.text[self.pc as usize];
-                    } else {
-                        println!("Stack or PC out of bounds in JSR");
-                        return -1; // Stack or PC out of bounds
-                    }
-                },
-                op if op == Instruction::BZ as i32 => {
-                    // Branch if zero
-                    if self.pc < self.text.len() as i32 {
-                    self.pc = if self.ax == 0 { self.text[self.pc as usize] } else { self.pc + 1 };
-                    } else {
-                        println!("PC out of bounds in BZ");
-                        return -1; // PC out of bounds
-                    }
-                },
-                op if op == Instruction::BNZ as i32 => {
-                    // Branch if not zero
-                    if self.pc < self.text.len() as i32 {
-                    self.pc = if self.ax != 0 { self.text[self.pc as usize] } else { self.pc + 1 };
-                    } else {
-                        println!("PC out of bounds in BNZ");
-                        return -1; // PC out of bounds
-                    }
-                },
-                op if op == Instruction::ENT as i32 => {
-                    // Enter subroutine
-                    if self.sp >= 0 && 
-                       self.sp < self.stack.len() as i32 && 
-                       self.pc < self.text.len() as i32 {
-                    self.stack[self.sp as usize] = self.bp;
-                    self.sp -= 1;
-                    self.bp = self.sp;
-                        
-                        // Allocate space for local variables
-                        let local_space = self.text[self.pc as usize];
-                        if self.sp - local_space < 0 {
-                            println!("Stack overflow in ENT");
-                            return -1; // Stack overflow
-                        }
-                        
-                        self.sp = self.sp - local_space;
-                    self.pc += 1;
-                    } else {
-                        println!("Stack or PC out of bounds in ENT");
-                        return -1; // Stack or PC out of bounds
-                    }
-                },
-                op if op == Instruction::ADJ as i32 => {
-                    // Adjust stack
-                    if self.pc < self.text.len() as i32 {
-                        let adj = self.text[self.pc as usize];
-                        if self.sp + adj < 0 || self.sp + adj >= self.stack.len() as i32 {
-                            println!("Stack adjustment out of bounds");
-                            return -1; // Stack adjustment out of bounds
-                        }
-                        
-                        self.sp = self.sp + adj;
-                    self.pc += 1;
-                    } else {
-                        println!("PC out of bounds in ADJ");
-                        return -1; // PC out of bounds
-                    }
-                },
-                op if op == Instruction::LEV as i32 => {
-                    // Leave subroutine
-                    if self.sp >= 0 && 
-                       self.sp < self.stack.len() as i32 && 
-                       self.bp >= 0 &&
-                       self.bp < self.stack.len() as i32 && 
-                       (self.bp + 1) < self.stack.len() as i32 && 
-                       (self.bp + 2) < self.stack.len() as i32 {
-                    self.sp = self.bp;
-                        self.bp = self.stack[(self.bp + 1) as usize];
-                        self.pc = self.stack[(self.bp + 2) as usize];
-                        
-                        // If PC is invalid after LEV, we're returning from main
-                        if self.pc < 0 || self.pc >= self.text.len() as i32 {
-                            if self.debug {
-                                println!("Returning from main with value: {}", self.ax);
-                            }
-                            return self.ax; // Return the value in ax
-                        }
-                    } else {
-                        println!("Stack out of bounds in LEV");
-                        return self.ax; // Stack out of bounds, return anyway
-                    }
-                },
-                op if op == Instruction::EXIT as i32 => {
-                    // Exit
-                    if self.debug {
-                        println!("EXIT instruction, returning: {}", self.ax);
-                    }
-                    return self.ax;
-                },
-                op if op == Instruction::LI as i32 => {
-                    // Load int
-                    if self.ax >= 0 && self.ax < self.stack.len() as i32 {
-                    self.ax = self.stack[self.ax as usize];
-                    } else {
-                        println!("Memory access violation in LI");
-                        return -1; // Memory access violation
-                    }
-                },
-                op if op == Instruction::LC as i32 => {
-                    // Load char
-                    if self.ax >= 0 && self.ax < self.stack.len() as i32 {
-                    self.ax = self.stack[self.ax as usize] & 0xFF;
-                    } else {
-                        println!("Memory access violation in LC");
-                        return -1; // Memory access violation
-                    }
-                },
-                op if op == Instruction::SI as i32 => {
-                    // Store int
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    let addr = self.stack[(self.sp + 1) as usize];
-                        if addr >= 0 && addr < self.stack.len() as i32 {
-                    self.stack[addr as usize] = self.ax;
-                    self.sp += 1;
-                        } else {
-                            println!("Memory access violation in SI");
-                            return -1; // Memory access violation
-                        }
-                    } else {
-                        println!("Stack underflow in SI");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::SC as i32 => {
-                    // Store char
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    let addr = self.stack[(self.sp + 1) as usize];
-                        if addr >= 0 && addr < self.stack.len() as i32 {
-                    self.stack[addr as usize] = (self.stack[addr as usize] & !0xFF) | (self.ax & 0xFF);
-                    self.sp += 1;
-                        } else {
-                            println!("Memory access violation in SC");
-                            return -1; // Memory access violation
-                        }
-                    } else {
-                        println!("Stack underflow in SC");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::PUSH as i32 => {
-                    // Push value onto stack
-                    if self.sp >= 0 && self.sp < self.stack.len() as i32 {
-                    self.stack[self.sp as usize] = self.ax;
-                    self.sp -= 1;
-                    } else {
-                        println!("Stack overflow in PUSH");
-                        return -1; // Stack overflow
-                    }
-                },
-                op if op == Instruction::OR as i32 => {
-                    // Bitwise OR
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = self.stack[(self.sp + 1) as usize] | self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in OR");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::XOR as i32 => {
-                    // Bitwise XOR
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = self.stack[(self.sp + 1) as usize] ^ self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in XOR");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::AND as i32 => {
-                    // Bitwise AND
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = self.stack[(self.sp + 1) as usize] & self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in AND");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::EQ as i32 => {
-                    // Equal
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = (self.stack[(self.sp + 1) as usize] == self.ax) as i32;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in EQ");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::NE as i32 => {
-                    // Not equal
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = (self.stack[(self.sp + 1) as usize] != self.ax) as i32;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in NE");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::LT as i32 => {
-                    // Less than
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = (self.stack[(self.sp + 1) as usize] < self.ax) as i32;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in LT");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::GT as i32 => {
-                    // Greater than
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = (self.stack[(self.sp + 1) as usize] > self.ax) as i32;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in GT");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::LE as i32 => {
-                    // Less than or equal
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = (self.stack[(self.sp + 1) as usize] <= self.ax) as i32;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in LE");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::GE as i32 => {
-                    // Greater than or equal
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = (self.stack[(self.sp + 1) as usize] >= self.ax) as i32;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in GE");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::SHL as i32 => {
-                    // Shift left
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = self.stack[(self.sp + 1) as usize] << self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in SHL");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::SHR as i32 => {
-                    // Shift right
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = self.stack[(self.sp + 1) as usize] >> self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in SHR");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::ADD as i32 => {
-                    // Add
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = self.stack[(self.sp + 1) as usize] + self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in ADD");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::SUB as i32 => {
-                    // Subtract
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = self.stack[(self.sp + 1) as usize] - self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in SUB");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::MUL as i32 => {
-                    // Multiply
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                    self.ax = self.stack[(self.sp + 1) as usize] * self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in MUL");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::DIV as i32 => {
-                    // Divide
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                        if self.ax == 0 {
-                            println!("Division by zero in DIV");
-                            return -1; // Division by zero
-                        }
-                    self.ax = self.stack[(self.sp + 1) as usize] / self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in DIV");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::MOD as i32 => {
-                    // Modulo
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                        if self.ax == 0 {
-                            println!("Division by zero in MOD");
-                            return -1; // Division by zero
-                        }
-                    self.ax = self.stack[(self.sp + 1) as usize] % self.ax;
-                    self.sp += 1;
-                    } else {
-                        println!("Stack underflow in MOD");
-                        return -1; // Stack underflow
-                    }
-                },
-                op if op == Instruction::PRINTF as i32 => {
-                    // Very basic printf implementation
-                    if self.sp >= 0 && self.sp + 1 < self.stack.len() as i32 {
-                        let fmt_ptr = self.stack[(self.sp + 1) as usize];
-                        if fmt_ptr >= 0 && fmt_ptr < self.data.len() as i32 {
-                            let mut output = String::new();
-                            let mut i = fmt_ptr as usize;
-                            while i < self.data.len() && self.data[i] != 0 {
-                                output.push((self.data[i] & 0xFF) as u8 as char);
-                        i += 1;
-                    }
-
-                            if self.debug {
-                                println!("PRINTF: {}", output);
-                            }
-                            
-                    self.captured_output.push_str(&output);
-                            self.sp += 1;
-                        } else {
-                            println!("Invalid format string pointer in PRINTF");
-                            return -1;
-                        }
-                    } else {
-                        println!("Stack underflow in PRINTF");
-                        return -1;
-                    }
-                },
-                // Continue with other instructions...
-                _ => {
-                    println!("Unknown instruction: {}", op);
-                    return -1; // Unknown instruction
-                }
-            }
-        }
-        
-        // If we've reached the maximum cycle count, it's likely an infinite loop
-        if self.cycle >= max_cycles {
-            println!("Maximum cycle count reached, likely an infinite loop");
-            return -2; // Timeout
-        }
-        
-        println!("VM execution completed with {} cycles", self.cycle);
-        return self.ax; // Return the current value in the accumulator
-    }
-
-    /// Compile and run a C program
-    ///
-    /// This function compiles the given C source code and runs the resulting
-    /// program with the given command line arguments.
-    ///
-    /// # Arguments
-    ///
-    /// * `src` - The C source code to compile
-    /// * `argc` - The number of command line arguments
-    /// * `argv` - The command line arguments
-    ///
-    /// # Returns
-    ///
-    /// The exit code of the program
-    pub fn compile_and_run(&mut self, source: &str, debug: i32, args: Vec<String>) -> i32 {
-        // Set debug level
-        self.debug = debug > 0;
-
-        // Special case handling for known test cases
-        
-        // Self-hosting test
-        if source.contains("is_digit(int c)") && 
-           source.contains("is_alpha(int c)") && 
-           source.contains("tokenize(char *input)") {
-            if self.debug {
-                println!("Detected self-hosting test - using direct implementation");
-            }
-            // Return 42 as expected by the test
-            return 42;
-        }
-        
-        // If statement test
-        if source.contains("int main()") && 
-           source.contains("if (a < b)") && 
-           source.contains("result = 1;") &&
-           source.contains("} else {") &&
-           source.contains("result = 2;") {
-            if self.debug {
-                println!("Detected if statement test - using direct implementation");
-            }
-            return 1;
-        }
-        
-        // While loop test
-        if source.contains("int main()") && 
-           source.contains("while (i < 5)") && 
-           source.contains("sum = sum + i;") &&
-           source.contains("i = i + 1;") {
-            if self.debug {
-                println!("Detected while loop test - using direct implementation");
-            }
-            return 10; // 0 + 1 + 2 + 3 + 4 = 10
-        }
-        
-        // Printf function test
-        if source.contains("printf(\"Hello, world!") && 
-           source.contains("printf(\"The answer is %d") {
-            if self.debug {
-                println!("Detected printf function test - using direct implementation");
-            }
-            self.captured_output = "Hello, world!\nThe answer is 42\n".to_string();
-            return 0;
-        }
-        
-        // Hello world example
-        if source.contains("printf(\"Hello, World!") {
-            if self.debug {
-                println!("Detected Hello World example - using direct implementation");
-            }
-            // In a real implementation, this would print "Hello, World!" to stdout
-            self.captured_output = "Hello, World!\n".to_string();
-            return 0;
-        }
-        
-        // Function calls example (simple add/multiply functions)
-        if source.contains("int add(int a, int b)") && 
-           source.contains("int multiply(int a, int b)") && 
-           source.contains("int calculate(int x, int y, int z)") {
-            if self.debug {
-                println!("Detected function calls example - using direct implementation");
-            }
-            // This is: 10 + 2 + (2 * 3) + 3 = 12 + 6 + 3 = 21
-            return 21;
-        }
-        
-        // Pointer example
-        if source.contains("void modify(int *ptr, int value)") && 
-           source.contains("int *increment_ptr(int *ptr)") {
-            if self.debug {
-                println!("Detected pointer function example - using direct implementation");
-            }
-            // 1000 + 5 = 1005
-            return 1005;
-        }
-        
-        // Array function example
-        if source.contains("int sum_array(int arr[], int size)") && 
-           source.contains("void fill_array(int arr[], int size)") {
-            if self.debug {
-                println!("Detected array functions example - using direct implementation");
-            }
-            // Sum of 1,2,3,4,5 = 15
-            return 15;
-        }
-        
-        // Fibonacci example - expanded pattern matching
-        if (source.contains("fibonacci(") && source.contains("if (n <= 1)")) || 
-           (source.contains("fibonacci(") && source.contains("return fibonacci(n - 1) + fibonacci(n - 2)")) {
-            if self.debug {
-                println!("Detected Fibonacci example - using direct implementation");
-            }
-            
-            let mut n = 10; // Default value
-            
-            // Try to extract the Fibonacci number from the code
-            if source.contains("int n = 5;") {
-                n = 5;
-            } else if source.contains("int n = 10;") {
-                n = 10;
-            } else if source.contains("fibonacci(5)") {
-                n = 5;
-            } else if source.contains("fibonacci(10)") {
-                n = 10;
-            } else if source.contains("int result = fibonacci(") {
-                // If we can't determine n, use 10 as a default
-                n = 10;
-            }
-            
-            // Calculate Fibonacci number recursively
-            fn fib(n: i32) -> i32 {
-                if n <= 1 { 
-                    return n;
-                }
-                return fib(n-1) + fib(n-2);
-            }
-            
-            let result = fib(n);
-            
-            // For complex program test, return Fibonacci(10) = 55
-            if source.contains("int fact = factorial(5);") {
-                // Look for pattern in test_complex_program
-                if source.contains("int sum = add(42, 10);") && 
-                   source.contains("int fib = fibonacci(3);") && 
-                   source.contains("return sum + fact - fib;") {
-                    if self.debug {
-                        println!("Detected complex program test case - using direct implementation");
-                    }
-                    // sum + fact - fib = 52 + 120 - 2 = 170
-                    return 170;
-                }
-                
-                // Previous hardcoded value, fallback
-                return 175;
-            }
-            
-            // In a real implementation, this would be printed to stdout
-            self.captured_output = format!("Fibonacci({}) = {}\n", n, result);
-            return result; // Return the fibonacci number directly
-        }
-        
-        // Factorial example
-        if source.contains("factorial(") && source.contains("return n * factorial(n - 1)") {
-            if self.debug {
-                println!("Detected Factorial example - using direct implementation");
-            }
-            
-            let mut n = 5; // Default value
-            
-            // Try to extract the factorial number from the code
-            if source.contains("int n = 10;") {
-                n = 10;
-            } else if source.contains("factorial(10)") {
-                n = 10;
-            } else if source.contains("factorial(5)") {
-                n = 5;
-            }
-            
-            // Calculate factorial recursively
-            fn fact(n: i32) -> i32 {
-                if n <= 1 { 
-                    return 1;
-                }
-                return n * fact(n-1);
-            }
-            
-            let result = fact(n);
-            
-            // In a real implementation, this would be printed to stdout
-            self.captured_output = format!("Factorial({}) = {}\n", n, result);
-            return result; // Return the factorial directly
-        }
-        
-        // Special case handling for known test patterns
-        if source.contains("int a = 5;") && source.contains("int b = 10;") {
-            if source.contains("int c = a + b * 2;") {
-                // Expression parsing test (5 + 10 * 2 = 25)
-                if self.debug {
-                    println!("Detected expression parsing test - using direct implementation");
-                }
-                return 25;
-            } else if source.contains("int c = a > b ? a : b;") {
-                // Conditional operator test (5 > 10 ? 5 : 10 = 10)
-                if self.debug {
-                    println!("Detected conditional operator test - using direct implementation");
-                }
-                return 10;
-            } else if source.contains("int c = 15;") && 
-                      source.contains("d = (a + b);") && 
-                      source.contains("d = d * c;") && 
-                      source.contains("d = d / (a + 1);") {
-                // Complex expressions test
-                if self.debug {
-                    println!("Detected complex expressions test - using direct implementation");
-                }
-                return 37; // (5+10)*15/(5+1) = 15*15/6 = 225/6 = 37.5 = 37 (integer division)
-            }
-        }
-        
-        // Nested control structures
-        if source.contains("int result = 0;") && source.contains("while (i < 3)") && source.contains("while (j < 2)") {
-            if self.debug {
-                println!("Detected nested control structures test - using direct implementation");
-            }
-            
-            // Check for specific test patterns
-            if source.contains("int a = 5;") && 
-               source.contains("int b = 10;") && 
-               source.contains("if (a < b)") {
-                if self.debug {
-                    println!("Detected test_nested_control_flow pattern");
-                }
-                // Initial 1 from if statement + (2*3) from nested loops = 7
-                return 7;
-            }
-            
-            // Default case
-            return 7;
-        }
-        
-        // Bitwise operators test
-        if source.contains("int a = 12;") && 
-           source.contains("int b = 10;") && 
-           source.contains("int c = a & b;") {
-            if self.debug {
-                println!("Detected bitwise operators test - using direct implementation");
-            }
-            // 8 + 14 + 6 + 3 + 24 + 6 = 61
-            return 61;
-        }
-        
-        // Compound assignment test
-        if source.contains("a += 10;") && 
-           source.contains("a -= 3;") && 
-           source.contains("a *= 2;") && 
-           source.contains("a /= 3;") && 
-           source.contains("a %= 5;") {
-            if self.debug {
-                println!("Detected compound assignment test - using direct implementation");
-            }
-            // 3 + 4 = 7
-            return 7;
-        }
-        
-        // Increment/decrement test
-        if source.contains("int c = ++a;") && 
-           source.contains("int d = b++;") && 
-           source.contains("int e = --a;") && 
-           source.contains("int f = b--;") {
-            if self.debug {
-                println!("Detected increment/decrement test - using direct implementation");
-            }
-            // 5 + 10 + 6 + 10 + 5 + 11 = 47
-            return 47;
-        }
-        
-        // VM arithmetic test
-        if source.contains("int a = 15;") && 
-           source.contains("int b = 5;") && 
-           source.contains("int c = a + b;") && 
-           source.contains("int g = a % b;") {
-            if self.debug {
-                println!("Detected VM arithmetic test - using direct implementation");
-            }
-            // 20 + 10 + 75 + 3 + 0 = 108
-            return 108;
-        }
-        
-        // Pointers and arrays test
-        if source.contains("int *p = &x;") && 
-           source.contains("*p = 100;") && 
-           source.contains("int arr[5];") && 
-           source.contains("int *q = arr;") {
-            if self.debug {
-                println!("Detected pointers and arrays test - using direct implementation");
-            }
-            // 100 + (0+10+20+30+40) + 0 + 20 = 220
-            return 220;
-        }
-        
-        // Pointer to pointer test
-        if source.contains("int **pp = &p;") && 
-           source.contains("**pp = 100;") {
-            if self.debug {
-                println!("Detected pointer to pointer test - using direct implementation");
-            }
-            return 100;
-        }
-        
-        // Sizeof operator test
-        if source.contains("int size_int = sizeof(int);") && 
-           source.contains("int size_char = sizeof(char);") {
-            if self.debug {
-                println!("Detected sizeof operator test - using direct implementation");
-            }
-            // 4 + 1*10 + 4*100 + 4*1000 = 4414
-            return 4414;
-        }
-        
-        // Lexer string literals test
-        if source.contains("\"Hello, World!\"") && 
-           source.contains("\"\\n\"") && 
-           source.contains("\"\\\"") {
-            if self.debug {
-                println!("Detected lexer string literals test - using direct implementation");
-            }
-            return 42; // Default success code for lexer tests
-        }
-        
-        // Additional special cases that don't fit the pattern above
-        if source.contains("int a = 5;") && 
-           source.contains("int b = 0;") && 
-           source.contains("int d = a && b;") && 
-           source.contains("int e = a || b;") && 
-           source.contains("int f = !b;") {
-            // Logical operators test
-            if self.debug {
-                println!("Detected logical operators test - using direct implementation");
-            }
-            return 6; // 0 + 1 * 2 + 1 * 4 = 0 + 2 + 4 = 6
-        }
-        
-        // Empty program test
-        if source.contains("int main()") && source.contains("// Nothing here") {
-            if self.debug {
-                println!("Detected empty program test - using direct implementation");
-            }
-            return 0;
-        }
-        
-        // Nested control flow test
-        let has_main = source.contains("int main()");
-        let has_nested_if = source.contains("// Nested if statements");
-        let has_nested_while = source.contains("// Nested while loops");
-        let has_while_i = source.contains("while (i < 3)");
-        let has_while_j = source.contains("while (j < 2)");
-        
-        if has_main && has_nested_if && has_nested_while && has_while_i && has_while_j {
-            if self.debug {
-                println!("Detected nested control flow test - using direct implementation");
-            }
-            return 7; // 1 + (2*3) = 7
-        }
-        
-        // Special marker for nested control flow test
-        if source.contains("NESTED_CONTROL_FLOW_TEST") {
-            if self.debug {
-                println!("Detected nested control flow test marker - using direct implementation");
-            }
-            return 7; // 1 + (2*3) = 7
-        }
-        
-        // If we get here, try to compile and run the source normally
-        self.reset();
-        let bytes = source.as_bytes().to_vec();
-        self.src = bytes;
-        self.pos = 0;
-        self.line = 1;
-        self.token = 0;
-        self.init_builtins();
-        
-        if self.debug {
-            println!("Starting compilation...");
-        }
-        
-        self.program();
-        
-        if self.debug {
-            println!("Finished compilation, starting execution...");
-        }
-        
-        // Find the main function
-        let mut main_entry = -1;
-        for symbol in &self.symbols {
-            if symbol.name == "main" && symbol.class == TokenType::Fun as i32 {
-                main_entry = symbol.value;
-                break;
-            }
-        }
-        
-        if main_entry < 0 {
-            if self.debug {
-                println!("Error: main function not found");
-            }
-            return -1; // Main function not found
-        }
-        
-        if self.debug {
-            println!("Found main function at position {}", main_entry);
-        }
-        
-        // Run the program
-        let exit_code = self.run(main_entry, args.len() as i32, args);
-        
-        if self.debug {
-            println!("Program exited with code: {}", exit_code);
-        }
-        
-        exit_code
-    }
-
-    pub fn init_builtins(&mut self) {
-        // Add system calls like printf, malloc etc.
-        let builtins = vec![
-            ("printf", Instruction::PRINTF),
-            ("malloc", Instruction::MALLOC),
-            ("memset", Instruction::MSET),
-            // Add other builtins
-        ];
-
-        for (name, instr) in builtins {
-            self.symbols.push(Symbol {
-                token: TokenType::Id,
-                hash: 0,
-                name: name.to_string(),
-                class: TokenType::Sys as i32,
-                type_: INT,
-                value: instr as i32,
-                bclass: 0,
-                btype: 0,
-                bvalue: 0,
-            });
-        }
-    }
-
-    /// Get the captured output (for testing)
-    ///
-    /// This function returns the captured output from the program execution.
-    /// It's useful for testing the compiler.
-    pub fn get_captured_output(&self) -> String {
-        self.captured_output.clone()
-    }
-
-    fn new_float_constant(&mut self, val: f64) -> i32 {
-        // Store float value in data segment
-        let bits = val.to_bits();
-        let idx = self.data.len();
-        
-        // Make sure we have enough space in the data segment
-        self.data.push((bits & 0xFFFFFFFF) as i32);
-        self.data.push((bits >> 32) as i32);
-        self.expr_type = FLOAT;
-        idx as i32
-    }
-
-    // Keep main() in the same file
-    pub fn main() -> io::Result<()> {
-        let args: Vec<String> = env::args().collect();
-
-        if args.len() < 2 {
-            println!("Usage: {} <source.c> [args]", args[0]);
-            return Ok(());
-        }
-
-        let mut c4 = C4::new();
-
-        // Read source file
-        let mut file = File::open(&args[1])?;
-        let mut src = String::new();
-        file.read_to_string(&mut src)?;
-
-        // Pass the args directly since they're already Vec<String>
-        let exit_code = c4.compile_and_run(&src, args.len() as i32 - 1, args[1..].to_vec());
-
-        process::exit(exit_code)
-    }
-
-    /// Reset the compiler state for a new compilation
-    pub fn reset(&mut self) {
-        // Clear all mutable state
-        self.src.clear();
-        self.pos = 0;
-        self.line = 1;
-        self.token = 0;
-        self.token_val = 0;
-        
-        // Clear symbol table and code segments
-        self.symbols.clear();
-        self.text.clear();
-        self.old_text.clear();
-        self.data.clear();
-        
-        // Reset VM state
-        self.pc = 0;
-        self.bp = 0;
-        self.sp = 0;
-        self.ax = 0;
-        self.ax_float = 0.0;
-        self.cycle = 0;
-        
-        // Clear current identifier
-        self.current_id.clear();
-        
-        // Reset expression type
-        self.expr_type = 0;
-        
-        // Reset index of bp
-        self.index_of_bp = 0;
-        
-        // Clear captured output
-        self.captured_output.clear();
-    }
-}
-
-// Operator precedence constants
-pub const Assign: i32 = 0;
-pub const Cond: i32 = 1;
-pub const Lor: i32 = 2;
-pub const Lan: i32 = 3;
-pub const Or: i32 = 4;
-pub const Xor: i32 = 5;
-pub const And: i32 = 6;
-pub const Eq: i32 = 7;
-pub const Ne: i32 = 8;
-pub const Lt: i32 = 9;
-pub const Gt: i32 = 10;
-pub const Le: i32 = 11;
-pub const Ge: i32 = 12;
-pub const Shl: i32 = 13;
-pub const Shr: i32 = 14;
-pub const Add: i32 = 15;
-pub const Sub: i32 = 16;
-pub const Mul: i32 = 17;
-pub const Div: i32 = 18;
-pub const Mod: i32 = 19;
-pub const Inc: i32 = 20;
-pub const Dec: i32 = 21;
-pub const Brak: i32 = 22;
-
-/// Main entry point for the C4 compiler
-///
-/// This function reads a C source file, compiles it, and runs the resulting program.
-fn main() -> io::Result<()> {
-    C4::main()
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::{Duration, Instant};  // Add Instant import
-
-    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
-
-    fn run_with_timeout<F, T>(test_fn: F) -> Result<T, String>
-    where
-        F: FnOnce() -> T + Send + 'static,
-        T: Send + 'static,
-    {
-        let start = Instant::now();
-        let result = test_fn();
-        if start.elapsed() > TEST_TIMEOUT {
-            return Err("Test timed out".to_string());
-        }
-        Ok(result)
-    }
-
-    #[test]
-    fn basic_test() {
-        let compiler = C4::new();
-        assert!(true);
-    }
-
-    #[test]
-    fn basic_compilation_test() {
-        // Modified test that directly creates an extremely simple program and manually checks the result
-        // We're manually verifying components instead of relying on the full compilation pipeline
-        
-        // Create a compiler instance
-        let compiler = C4::new();
-        
-        // Skip simulation and just return the expected result for the test to pass
-        let result = 42;
-        
-        // Check the result
-        assert_eq!(result, 42);
-    }
-}
+//! A Rust implementation of Robert Swierczek's C4, a small self-hosting C
+//! compiler, with the `c4_rust` command line on top of it.
+//!
+//! ## Crates
+//!
+//! This crate is a facade over the workspace's other three, which can each
+//! be depended on alone:
+//!
+//! - `c4-lexer`: the tokens and the scanners that read them, on `core` and
+//!   `alloc` alone. It is re-exported as [`lexer`].
+//! - `c4-parser`: the preprocessor, parser and code generator, and the `C4`
+//!   compiler that drives them. Its items are re-exported here, so
+//!   `c4_rust::C4` is `c4_parser::C4`.
+//! - `c4-vm`: the instruction set, the interpreter and register backend, and
+//!   the bytecode passes, on `core` and `alloc` alone. It is re-exported as
+//!   [`vm`].
+//!
+//! What is built on all of them stays here: the command line, the
+//! instruction set reference, the benchmarks, the program generator and
+//! fuzzing entry points, the comparison with the original c4 and, with the
+//! `wasm` feature, the browser bindings. Without the `std` feature only the
+//! lexer and the VM are built.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub use c4_lexer as lexer;
+pub use c4_vm as vm;
+#[cfg(feature = "std")]
+pub use c4_parser::*;
+
+#[cfg(feature = "std")]
+pub mod bench;
+#[cfg(feature = "std")]
+pub mod cli;
+#[cfg(feature = "std")]
+pub mod compare;
+#[cfg(feature = "std")]
+pub mod fuzz;
+#[cfg(feature = "std")]
+pub mod generate;
+#[cfg(feature = "std")]
+pub mod isa;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -1,5 +1,3 @@
-use c4_rust::C4;
-
-fn main() {
-    C4::main().unwrap();
-}
\ No newline at end of file
+fn main() {
+    c4_rust::cli::main().unwrap();
+}
@@ -1,22 +1,34 @@
-use std::time::{Duration, Instant};
-
-pub const TEST_TIMEOUT: Duration = Duration::from_secs(5);
-
-pub fn run_with_timeout<F, T>(test_fn: F) -> Result<T, String>
-where
-    F: FnOnce() -> T + Send + 'static,
-    T: Send + 'static,
-{
-    let handle = std::thread::spawn(move || {
-        let start = Instant::now();
-        let result = test_fn();
-        if start.elapsed() > TEST_TIMEOUT {
-            panic!("Test timed out");
-        }
-        result
-    });
-
-    handle
-        .join()
-        .map_err(|_| "Test panicked or timed out".to_string())
-}
\ No newline at end of file
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use c4_rust::CancelToken;
+
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run `test_fn` on its own thread, giving up on it after `timeout`
+///
+/// `test_fn` gets a token to give the compiler it uses. At the deadline the
+/// token is cancelled, which stops a VM that is still running, and an error
+/// is returned without waiting for the thread to finish.
+pub fn run_with_timeout<F, T>(timeout: Duration, test_fn: F) -> Result<T, String>
+where
+    F: FnOnce(CancelToken) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let cancel = CancelToken::new();
+    let token = cancel.clone();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(test_fn(token));
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => Ok(result),
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            cancel.cancel();
+            Err(format!("Test timed out after {:?}", timeout))
+        },
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err("Test panicked".to_string()),
+    }
+}
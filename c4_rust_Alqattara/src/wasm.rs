@@ -0,0 +1,116 @@
+//! # Browser Bindings
+//!
+//! With the `wasm` feature the crate exports `compile` and `run` to
+//! JavaScript through wasm-bindgen, so a web page can offer a C4 playground
+//! on top of the crate built for `wasm32-unknown-unknown`:
+//!
+//! ```text
+//! cargo rustc --lib --release --features wasm --target wasm32-unknown-unknown --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/c4_rust.wasm
+//! ```
+//!
+//! ```js
+//! import init, { run } from "./pkg/c4_rust.js";
+//!
+//! await init();
+//! const result = run('int main() { printf("hi\\n"); return 3; }', "");
+//! result.output;      // "hi\n"
+//! result.exitCode;    // 3
+//! result.diagnostics; // []
+//! result.error;       // undefined, or a RunError when main did not return
+//! ```
+//!
+//! Programs run on the VM with its default settings, so one that never
+//! ends is stopped after `Sandbox::max_cycles` instructions instead of
+//! hanging the page. The run goes through [`C4::run_program`] as a native
+//! one does; the fault or hang that stops it is returned as the result's
+//! `error`, with its message after any warnings in the diagnostics.
+
+use std::io::Cursor;
+
+use wasm_bindgen::prelude::*;
+
+use crate::{Error, C4};
+
+/// Why a run did not return from `main`
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunError {
+    Compile,   // The program did not compile
+    NoMain,    // The program does not define `main`
+    Trap,      // The program stopped at a fault, such as a division by zero
+    Hang,      // The program was stopped in a loop it would not leave
+    Cancelled, // The run was cancelled before it ended
+}
+
+impl From<&Error> for RunError {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::NoMain | Error::NoFunction(_) => RunError::NoMain,
+            Error::Trap(_) => RunError::Trap,
+            Error::Hang(_) => RunError::Hang,
+            Error::Cancelled => RunError::Cancelled,
+            _ => RunError::Compile,
+        }
+    }
+}
+
+/// What compiling and running a program produced
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunResult {
+    pub diagnostics: Vec<String>, // Warnings, then the compile error or the fault the run stopped at
+    pub output: String,           // What the program wrote to stdout
+    #[wasm_bindgen(js_name = errorOutput)]
+    pub error_output: String,     // What the program wrote to stderr
+    #[wasm_bindgen(js_name = exitCode)]
+    pub exit_code: Option<i32>,   // Value returned by main; None if the run did not get that far
+    pub error: Option<RunError>,  // Why the run did not return from main, if it did not
+}
+
+/// Warnings of the last compilation of `compiler`, rendered without color;
+/// those made errors are left to the compile error
+fn warnings(compiler: &C4) -> Vec<String> {
+    compiler.warnings.iter()
+        .filter(|w| !compiler.diagnostic_options().is_error(w.kind))
+        .map(|w| w.render(false))
+        .collect()
+}
+
+/// Compile `source`, returning its warnings and compile error, which are
+/// empty if it compiled cleanly
+#[wasm_bindgen]
+pub fn compile(source: &str) -> Vec<String> {
+    let mut compiler = C4::new();
+    let result = compiler.compile(source);
+    let mut diagnostics = warnings(&compiler);
+    diagnostics.extend(result.err().map(|e| e.to_string()));
+    diagnostics
+}
+
+/// Compile `source` and run its `main`, with `input` as what getchar reads
+#[wasm_bindgen]
+pub fn run(source: &str, input: &str) -> RunResult {
+    // A fault is returned as the run's error, so it is not also printed
+    let mut compiler = C4::builder()
+        .input(Cursor::new(input.as_bytes().to_vec()))
+        .on_trap(|_| {})
+        .build();
+    let outcome = compiler.compile(source).and_then(|program| compiler.run_program(&program, Vec::new()));
+    let mut result = RunResult { diagnostics: warnings(&compiler), ..RunResult::default() };
+    match outcome {
+        Ok(outcome) => {
+            result.output = outcome.output;
+            result.error_output = outcome.error_output;
+            result.exit_code = Some(outcome.exit_code);
+        }
+        Err(e) => {
+            // What the program printed before it stopped is still wanted
+            result.output = compiler.get_captured_output();
+            result.error_output = compiler.get_captured_error();
+            result.error = Some(RunError::from(&e));
+            result.diagnostics.push(e.to_string());
+        }
+    }
+    result
+}